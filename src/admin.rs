@@ -0,0 +1,359 @@
+//! Local admin API for sending downlinks without the Urbit outbox (`[admin]`)
+//!
+//!   POST /downlink  {"dev_addr": "260B1234", "f_port": 1,
+//!                    "payload_hex": "48656c6c6f", "confirmed": false}
+//!
+//! The downlink takes the outbox's send path: the frame is built on the
+//! device's next downlink FCnt (with a MIC if it has session keys) and sent
+//! at its RX2 data rate, or in its next ping slot for Class B. The response
+//! is the TX result and the txpk sent:
+//!
+//!   200 {"result": "sent" | "no_ack", "txpk": {...}}
+//!   502 {"result": "error", "error": "TOO_LATE", "txpk": {...}}
+//!   400 {"error": "..."} for a malformed request
+//!   401 {"error": "..."} without `Authorization: Bearer <admin.token>`
+//!
+//! It listens on loopback unless `admin.bind` says otherwise.
+
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::lorawan::channel_plan::ChannelPlan;
+use crate::lorawan::keys::{SharedDownlinkCounters, SharedKeyStore};
+use crate::udp::{build_device_frame, DownlinkSender, TxResult};
+
+/// Largest request (head and body) read before answering
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// How long to wait for the gateway's TX_ACK, as for outbox downlinks
+const TX_ACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a client gets to send its whole request
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The downlink state the API sends through, shared with the UDP server
+#[derive(Clone)]
+pub struct AdminApi {
+    pub sender: DownlinkSender,
+    pub keys: SharedKeyStore,
+    pub counters: SharedDownlinkCounters,
+    pub channel_plan: ChannelPlan,
+    /// `lorawan.encrypt_downlink`
+    pub encrypt: bool,
+    /// `admin.token`
+    pub token: String,
+}
+
+/// Body of `POST /downlink`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DownlinkRequest {
+    /// DevAddr as 8 hex digits
+    pub dev_addr: String,
+    /// Application port, 1-223
+    pub f_port: u8,
+    /// FRMPayload as hex, before encryption
+    pub payload_hex: String,
+    #[serde(default)]
+    pub confirmed: bool,
+}
+
+impl AdminApi {
+    /// Build and send `request`, returning the HTTP status and JSON body
+    pub async fn send_downlink(&self, request: &DownlinkRequest) -> (&'static str, String) {
+        let dev_addr = match u32::from_str_radix(&request.dev_addr, 16) {
+            Ok(addr) if request.dev_addr.len() == 8 => addr,
+            _ => return bad_request(format!("Invalid dev_addr '{}'", request.dev_addr)),
+        };
+        if !(1..=223).contains(&request.f_port) {
+            return bad_request(format!("f_port {} is not 1-223", request.f_port));
+        }
+        let payload = match hex::decode(&request.payload_hex) {
+            Ok(payload) => payload,
+            Err(e) => return bad_request(format!("Invalid payload_hex: {}", e)),
+        };
+
+        let frame = match build_device_frame(
+            &self.keys,
+            &self.counters,
+            dev_addr,
+            request.f_port,
+            payload,
+            request.confirmed,
+            self.encrypt,
+        ) {
+            Ok(frame) => frame,
+            Err(e) => return bad_request(format!("Failed to build frame: {}", e)),
+        };
+        info!("Admin downlink to {:08X} on FPort {}", dev_addr, request.f_port);
+        let sent = self
+            .sender
+            .send_frame(&self.channel_plan, &self.keys, &frame, Some(dev_addr), TX_ACK_TIMEOUT)
+            .await;
+        let (status, body) = match sent {
+            Ok((txpk, TxResult::Success)) => ("200 OK", json!({"result": "sent", "txpk": txpk})),
+            Ok((txpk, TxResult::NoAck)) => ("200 OK", json!({"result": "no_ack", "txpk": txpk})),
+            Ok((txpk, TxResult::Error(err))) => {
+                warn!("Admin downlink to {:08X} not sent: {}", dev_addr, err);
+                let body = json!({"result": "error", "error": err.to_string(), "txpk": txpk});
+                ("502 Bad Gateway", body)
+            }
+            Err(e) => {
+                warn!("Admin downlink to {:08X} not sent: {}", dev_addr, e);
+                ("502 Bad Gateway", json!({"result": "error", "error": e.to_string()}))
+            }
+        };
+        (status, format!("{}\n", body))
+    }
+}
+
+fn bad_request(error: String) -> (&'static str, String) {
+    ("400 Bad Request", format!("{}\n", json!({ "error": error })))
+}
+
+/// Whether the request `head` carries `Authorization: Bearer <token>`
+///
+/// Compares every byte, so the time taken doesn't reveal how much matched.
+fn authorized(head: &str, token: &str) -> bool {
+    let Some(presented) = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| value.trim().strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    let presented = presented.trim().as_bytes();
+    let token = token.as_bytes();
+    let diff = presented.iter().zip(token).fold(0, |diff, (a, b)| diff | (a ^ b));
+    presented.len() == token.len() && diff == 0
+}
+
+/// Answer admin requests on `listener` until `shutdown` is cancelled
+pub async fn serve(listener: TcpListener, api: AdminApi, shutdown: CancellationToken) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Admin API listening on http://{}", addr);
+    }
+    loop {
+        let stream = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    debug!("Admin API accept failed: {}", e);
+                    continue;
+                }
+            },
+        };
+        let api = api.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &api).await {
+                debug!("Admin request failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Read one request (head, then `Content-Length` bytes of body) and answer it
+async fn respond(mut stream: TcpStream, api: &AdminApi) -> std::io::Result<()> {
+    let (head, body) = tokio::time::timeout(READ_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "request too slow"))??;
+
+    let mut parts = head.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        _ if !authorized(&head, &api.token) => {
+            let body = json!({ "error": "missing or wrong bearer token" });
+            ("401 Unauthorized", format!("{}\n", body))
+        }
+        (Some("POST"), Some("/downlink")) => match serde_json::from_slice(&body) {
+            Ok(request) => api.send_downlink(&request).await,
+            Err(e) => bad_request(format!("Invalid request body: {}", e)),
+        },
+        (_, Some("/downlink")) => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// The request head and its `Content-Length` bytes of body, read up to
+/// `MAX_REQUEST_LEN`
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<(String, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(512);
+    let mut chunk = [0u8; 512];
+    let head_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break Some(pos + 4);
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 || buf.len() >= MAX_REQUEST_LEN {
+            break None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+    let head = String::from_utf8_lossy(&buf[..head_end.unwrap_or(buf.len())]).into_owned();
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    let body_start = head_end.unwrap_or(buf.len());
+    while buf.len() < body_start + content_length && buf.len() < MAX_REQUEST_LEN {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let body = buf[body_start..buf.len().min(body_start + content_length)].to_vec();
+    Ok((head, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lorawan::channel_plan::Region;
+    use crate::lorawan::decode_phy_payload;
+    use crate::lorawan::keys::{DownlinkCounters, KeyStore};
+    use crate::lorawan::LoRaWANFrame;
+    use crate::udp::protocol::GwmpPacket;
+    use std::sync::{Arc, Mutex, RwLock};
+    use tokio::net::UdpSocket;
+
+    async fn post(addr: std::net::SocketAddr, path: &str, body: &str) -> String {
+        post_with_token(addr, path, body, "s3cret").await
+    }
+
+    async fn post_with_token(
+        addr: std::net::SocketAddr,
+        path: &str,
+        body: &str,
+        token: &str,
+    ) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            path,
+            token,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn test_post_downlink() {
+        use base64::Engine;
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let gateway_addr = gateway.local_addr().unwrap();
+            let sender = DownlinkSender::to_gateway("127.0.0.1:0", gateway_addr, Region::US915)
+                .await
+                .unwrap();
+            let counters = Arc::new(Mutex::new(DownlinkCounters::new()));
+            let api = AdminApi {
+                sender,
+                keys: Arc::new(RwLock::new(KeyStore::new())),
+                counters: counters.clone(),
+                channel_plan: ChannelPlan::from_config(&Default::default()).unwrap(),
+                encrypt: true,
+                token: "s3cret".to_string(),
+            };
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let shutdown = CancellationToken::new();
+            let task = tokio::spawn(serve(listener, api, shutdown.clone()));
+
+            // Fake gateway: take the PULL_RESP and acknowledge it
+            let fake = tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let (len, from) = gateway.recv_from(&mut buf).await.unwrap();
+                let (token, json) = match GwmpPacket::parse(&buf[..len]).unwrap() {
+                    GwmpPacket::PullResp { random_token, json_payload } => {
+                        (random_token, json_payload)
+                    }
+                    other => panic!("expected PULL_RESP, got {:?}", other),
+                };
+                let ack = GwmpPacket::tx_ack(token, &[0; 8], None);
+                gateway.send_to(&ack, from).await.unwrap();
+                serde_json::from_str::<serde_json::Value>(&json).unwrap()
+            });
+
+            let body = r#"{"dev_addr":"260B1234","f_port":7,"payload_hex":"48656c6c6f","confirmed":true}"#;
+            let response = post(addr, "/downlink", body).await;
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            let (_, json) = response.split_once("\r\n\r\n").unwrap();
+            let json: serde_json::Value = serde_json::from_str(json).unwrap();
+            assert_eq!(json["result"], "sent");
+
+            // The gateway got the txpk the API reported
+            let pull_resp = fake.await.unwrap();
+            assert_eq!(pull_resp["txpk"], json["txpk"]);
+            let phy = base64::engine::general_purpose::STANDARD
+                .decode(json["txpk"]["data"].as_str().unwrap())
+                .unwrap();
+            match decode_phy_payload(&phy).unwrap() {
+                LoRaWANFrame::Data {
+                    mtype,
+                    dev_addr,
+                    fcnt,
+                    f_port,
+                    ..
+                } => {
+                    assert_eq!(mtype, crate::lorawan::MType::ConfirmedDataDown);
+                    assert_eq!((dev_addr, fcnt, f_port), (0x260B1234, 0, Some(7)));
+                }
+                other => panic!("expected a data frame, got {:?}", other),
+            }
+            assert_eq!(counters.lock().unwrap().peek(0x260B1234), 1);
+
+            // Malformed requests are refused before anything is sent
+            let body = r#"{"dev_addr":"260B1234","f_port":0,"payload_hex":"00"}"#;
+            assert!(post(addr, "/downlink", body).await.starts_with("HTTP/1.1 400"));
+            let body = r#"{"dev_addr":"nope","f_port":1,"payload_hex":"00"}"#;
+            assert!(post(addr, "/downlink", body).await.starts_with("HTTP/1.1 400"));
+            assert!(post(addr, "/downlink", "{").await.starts_with("HTTP/1.1 400"));
+            assert!(post(addr, "/uplink", "{}").await.starts_with("HTTP/1.1 404"));
+            assert_eq!(counters.lock().unwrap().peek(0x260B1234), 1);
+
+            // So are requests without the token
+            let body = r#"{"dev_addr":"260B1234","f_port":1,"payload_hex":"00"}"#;
+            for token in ["", "s3cre", "s3cret2", "S3CRET"] {
+                let response = post_with_token(addr, "/downlink", body, token).await;
+                assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+            }
+            assert_eq!(counters.lock().unwrap().peek(0x260B1234), 1);
+
+            // A client that never finishes its request is cut off
+            let mut idle = TcpStream::connect(addr).await.unwrap();
+            idle.write_all(b"POST /downlink HTTP/1.1\r\n").await.unwrap();
+            let mut response = Vec::new();
+            let read = idle.read_to_end(&mut response);
+            let closed = tokio::time::timeout(READ_TIMEOUT + Duration::from_secs(2), read).await;
+            assert!(response.is_empty() && closed.is_ok());
+
+            shutdown.cancel();
+            task.await.unwrap();
+        });
+    }
+}
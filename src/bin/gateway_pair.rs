@@ -26,10 +26,32 @@
 //!   GW_B_BIND=0.0.0.0:1701       Gateway B listen address
 //!   BRIDGE_A_ADDR=127.0.0.1:1680 Bridge A address
 //!   BRIDGE_B_ADDR=127.0.0.1:1681 Bridge B address
-
+//!   GW_PAIR_SECRET=<secret>      Authenticate the A ↔ B link (see below)
+//!   GW_PAIR_LATENCY=1            Measure uplink → downlink round trips
+//!
+//! With `GW_PAIR_SECRET` set, relayed packets cross the link itself: they
+//! go to the partner gateway with an HMAC-SHA256 tag appended, and the
+//! partner forwards them to its bridge only if the tag checks out. Any
+//! PUSH_DATA without a valid tag is dropped, so only the partner can inject
+//! uplinks.
+//!
+//! With `GW_PAIR_LATENCY=1`, each relayed data uplink gets a correlation id
+//! and a monotonic timestamp, kept by the pair (the frame itself is
+//! MIC-protected, so it is not modified). The first downlink to the same
+//! DevAddr that comes back through either bridge closes it, and the
+//! uplink → Urbit → outbox → downlink time is printed. A histogram of all
+//! round trips is printed on exit.
+
+use std::collections::{HashMap, VecDeque};
 use std::env;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Instant;
+
+use base64::Engine;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use tokio::net::UdpSocket;
 use tokio::sync::Mutex;
 use tokio::time::{interval, Duration};
@@ -48,6 +70,141 @@ const TX_ACK: u8 = 0x05;
 const GATEWAY_A_EUI: [u8; 8] = [0xAA, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01];
 const GATEWAY_B_EUI: [u8; 8] = [0xBB, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02];
 
+/// Environment variable holding the link's shared secret
+const SECRET_ENV: &str = "GW_PAIR_SECRET";
+
+/// Length of the HMAC-SHA256 tag appended to packets on the link
+const TAG_LEN: usize = 32;
+
+/// Environment variable that turns on round-trip measurement
+const LATENCY_ENV: &str = "GW_PAIR_LATENCY";
+
+/// Uplinks unanswered this long are given up on
+const LATENCY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Upper bounds (ms) of the round-trip histogram buckets; the last is open
+const LATENCY_BUCKETS_MS: [u64; 7] = [100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Round trips shared by both gateways: uplinks waiting for their downlink
+#[derive(Default)]
+struct LatencyTracker {
+    next_id: u64,
+    /// Correlation id and send time of open uplinks, oldest first, by DevAddr
+    pending: HashMap<u32, VecDeque<(u64, Instant)>>,
+    /// Completed round trips
+    samples: Vec<Duration>,
+    /// Uplinks dropped after `LATENCY_TIMEOUT` without a downlink
+    expired: usize,
+}
+
+impl LatencyTracker {
+    /// Record a relayed uplink, returning its correlation id
+    fn uplink(&mut self, dev_addr: u32, now: Instant) -> u64 {
+        self.next_id += 1;
+        self.pending.entry(dev_addr).or_default().push_back((self.next_id, now));
+        self.next_id
+    }
+
+    /// Match a downlink to the oldest open uplink of `dev_addr`
+    ///
+    /// Returns its correlation id and the round-trip time.
+    fn downlink(&mut self, dev_addr: u32, now: Instant) -> Option<(u64, Duration)> {
+        let queue = self.pending.get_mut(&dev_addr)?;
+        while let Some((id, sent)) = queue.pop_front() {
+            let elapsed = now.duration_since(sent);
+            if elapsed > LATENCY_TIMEOUT {
+                self.expired += 1;
+                continue;
+            }
+            if queue.is_empty() {
+                self.pending.remove(&dev_addr);
+            }
+            self.samples.push(elapsed);
+            return Some((id, elapsed));
+        }
+        self.pending.remove(&dev_addr);
+        None
+    }
+
+    /// Sample counts per `LATENCY_BUCKETS_MS` bucket, plus the open-ended one
+    fn histogram(&self) -> [usize; LATENCY_BUCKETS_MS.len() + 1] {
+        let mut counts = [0; LATENCY_BUCKETS_MS.len() + 1];
+        for sample in &self.samples {
+            let ms = sample.as_millis() as u64;
+            let bucket = LATENCY_BUCKETS_MS
+                .iter()
+                .position(|&bound| ms < bound)
+                .unwrap_or(LATENCY_BUCKETS_MS.len());
+            counts[bucket] += 1;
+        }
+        counts
+    }
+
+    fn print_summary(&self) {
+        let open: usize = self.pending.values().map(VecDeque::len).sum();
+        println!(
+            "⏱  Round trips: {} measured, {} unanswered",
+            self.samples.len(),
+            open + self.expired
+        );
+        if self.samples.is_empty() {
+            return;
+        }
+        let total: Duration = self.samples.iter().sum();
+        println!(
+            "   min {:?}, avg {:?}, max {:?}",
+            self.samples.iter().min().unwrap(),
+            total / self.samples.len() as u32,
+            self.samples.iter().max().unwrap()
+        );
+        let mut lower = 0;
+        for (i, count) in self.histogram().iter().enumerate() {
+            let label = match LATENCY_BUCKETS_MS.get(i) {
+                Some(upper) => format!("{:>5}-{:<5} ms", lower, upper),
+                None => format!("{:>5}+      ms", lower),
+            };
+            println!("   {} {:>5} {}", label, count, "█".repeat((*count).min(50)));
+            lower = LATENCY_BUCKETS_MS.get(i).copied().unwrap_or(lower);
+        }
+    }
+}
+
+/// DevAddrs of the data uplinks in a PUSH_DATA's rxpk array
+fn uplink_dev_addrs(json_payload: &[u8]) -> Vec<u32> {
+    let Ok(json) = serde_json::from_slice::<serde_json::Value>(json_payload) else {
+        return Vec::new();
+    };
+    json.get("rxpk")
+        .and_then(|rxpks| rxpks.as_array())
+        .map(|rxpks| {
+            rxpks
+                .iter()
+                .filter_map(|rxpk| frame_dev_addr(rxpk, &[0x40, 0x80]))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// DevAddr of a txpk/rxpk's data frame whose MType (top 3 bits) is one of `mtypes`
+fn frame_dev_addr(pk: &serde_json::Value, mtypes: &[u8]) -> Option<u32> {
+    let data = pk.get("data")?.as_str()?;
+    let phy = base64::engine::general_purpose::STANDARD.decode(data).ok()?;
+    if phy.len() < 5 || !mtypes.contains(&(phy[0] & 0xE0)) {
+        return None;
+    }
+    Some(u32::from_le_bytes([phy[1], phy[2], phy[3], phy[4]]))
+}
+
+/// How one gateway reaches its partner
+struct Link {
+    /// Shared secret; None relays straight to the partner's bridge, untagged
+    secret: Option<Vec<u8>>,
+    /// Partner gateway's socket, where tagged relays are sent
+    peer_gateway: SocketAddr,
+    /// Our bridge, for relays the partner hands us before it has pulled
+    bridge_default: SocketAddr,
+}
+
 /// State for one side of the gateway pair
 struct GatewayState {
     /// Last known bridge address (updated from PULL_DATA or PUSH_DATA)
@@ -68,6 +225,10 @@ async fn main() -> anyhow::Result<()> {
     let bridge_b_addr: SocketAddr = env::var("BRIDGE_B_ADDR")
         .unwrap_or_else(|_| "127.0.0.1:1681".to_string())
         .parse()?;
+    let secret = env::var(SECRET_ENV).ok().filter(|s| !s.is_empty()).map(String::into_bytes);
+    let latency = env::var(LATENCY_ENV)
+        .is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .then(|| Arc::new(std::sync::Mutex::new(LatencyTracker::default())));
 
     println!("🌊 LoraUrbit Gateway Pair Simulator");
     println!("══════════════════════════════════════════");
@@ -76,7 +237,11 @@ async fn main() -> anyhow::Result<()> {
     println!("  Gateway B: {} (EUI: {})", gw_b_bind, hex::encode(GATEWAY_B_EUI));
     println!("    → Bridge B: {}", bridge_b_addr);
     println!("══════════════════════════════════════════");
-    println!("  Relay: Gateway A ←→ Gateway B (localhost)");
+    if secret.is_some() {
+        println!("  Relay: Gateway A ←→ Gateway B (HMAC-tagged link)");
+    } else {
+        println!("  Relay: Gateway A ←→ Gateway B (localhost)");
+    }
     println!();
 
     // Bind both gateway sockets
@@ -90,6 +255,17 @@ async fn main() -> anyhow::Result<()> {
     let state_a = Arc::new(Mutex::new(GatewayState { bridge_addr: None }));
     let state_b = Arc::new(Mutex::new(GatewayState { bridge_addr: None }));
 
+    let link_a = Arc::new(Link {
+        secret: secret.clone(),
+        peer_gateway: link_addr(gw_b_bind),
+        bridge_default: bridge_a_addr,
+    });
+    let link_b = Arc::new(Link {
+        secret,
+        peer_gateway: link_addr(gw_a_bind),
+        bridge_default: bridge_b_addr,
+    });
+
     // Token counter for generated packets (shared across tasks)
     let token_counter = Arc::new(std::sync::atomic::AtomicU16::new(0x1000));
 
@@ -99,8 +275,10 @@ async fn main() -> anyhow::Result<()> {
     let sta = state_a.clone();
     let stb = state_b.clone();
     let tc = token_counter.clone();
+    let lt = latency.clone();
     tokio::spawn(async move {
-        gateway_recv_loop("A", &GATEWAY_A_EUI, sa, sb, sta, stb, bridge_b_addr, tc).await;
+        gateway_recv_loop("A", &GATEWAY_A_EUI, sa, sb, sta, stb, bridge_b_addr, link_a, lt, tc)
+            .await;
     });
 
     // Spawn Gateway B receiver
@@ -109,8 +287,10 @@ async fn main() -> anyhow::Result<()> {
     let sta = state_a.clone();
     let stb = state_b.clone();
     let tc = token_counter.clone();
+    let lt = latency.clone();
     tokio::spawn(async move {
-        gateway_recv_loop("B", &GATEWAY_B_EUI, sb, sa, stb, sta, bridge_a_addr, tc).await;
+        gateway_recv_loop("B", &GATEWAY_B_EUI, sb, sa, stb, sta, bridge_a_addr, link_b, lt, tc)
+            .await;
     });
 
     // Spawn PULL_DATA keepalive senders
@@ -133,6 +313,9 @@ async fn main() -> anyhow::Result<()> {
     // Wait forever
     tokio::signal::ctrl_c().await?;
     println!("\n👋 Gateway pair shutting down.");
+    if let Some(latency) = &latency {
+        latency.lock().unwrap().print_summary();
+    }
     Ok(())
 }
 
@@ -145,7 +328,10 @@ async fn main() -> anyhow::Result<()> {
 /// - `my_state`: this gateway's state
 /// - `peer_state`: the other gateway's state
 /// - `peer_bridge_addr`: the other side's bridge address (for relay)
+/// - `link`: how to reach the other gateway, and the link secret
+/// - `latency`: round-trip bookkeeping, if measuring
 /// - `token_counter`: shared counter for generated packet tokens
+#[allow(clippy::too_many_arguments)]
 async fn gateway_recv_loop(
     name: &str,
     my_eui: &[u8; 8],
@@ -154,6 +340,8 @@ async fn gateway_recv_loop(
     my_state: Arc<Mutex<GatewayState>>,
     peer_state: Arc<Mutex<GatewayState>>,
     peer_bridge_default: SocketAddr,
+    link: Arc<Link>,
+    latency: Option<Arc<std::sync::Mutex<LatencyTracker>>>,
     token_counter: Arc<std::sync::atomic::AtomicU16>,
 ) {
     let mut buf = vec![0u8; 65535];
@@ -179,6 +367,20 @@ async fn gateway_recv_loop(
 
         match ptype {
             PUSH_DATA => {
+                if let Some(secret) = &link.secret {
+                    // Secured link: only the partner's tagged relays get through
+                    let Some(packet) = verify_tag(data, secret) else {
+                        eprintln!("[GW-{}] 🚫 dropped untagged/tampered PUSH_DATA from {}", name, src);
+                        continue;
+                    };
+                    let bridge = my_state.lock().await.bridge_addr.unwrap_or(link.bridge_default);
+                    match my_sock.send_to(packet, bridge).await {
+                        Ok(_) => println!("[GW-{}] 📥 Partner relay sent to bridge {}", name, bridge),
+                        Err(e) => eprintln!("[GW-{}] failed to forward partner relay: {}", name, e),
+                    }
+                    continue;
+                }
+
                 // Uplink from our bridge — ACK it and relay to the other bridge
                 if len < 12 {
                     eprintln!("[GW-{}] PUSH_DATA too short from {}", name, src);
@@ -218,12 +420,22 @@ async fn gateway_recv_loop(
                     state.bridge_addr.unwrap_or(peer_bridge_default)
                 };
 
-                let relay_pkt = build_push_data(relay_token, peer_eui, json_payload);
-                match peer_sock.send_to(&relay_pkt, peer_bridge).await {
-                    Ok(_) => {
+                if let Some(latency) = &latency {
+                    let now = Instant::now();
+                    let mut latency = latency.lock().unwrap();
+                    for dev_addr in uplink_dev_addrs(json_payload) {
+                        let id = latency.uplink(dev_addr, now);
+                        println!("[GW-{}] ⏱  #{} uplink from {:08X}", name, id, dev_addr);
+                    }
+                }
+
+                let secret = link.secret.as_deref();
+                let relay_pkt = build_push_data(relay_token, peer_eui, json_payload, secret);
+                match send_relay(&link, &my_sock, &peer_sock, peer_bridge, &relay_pkt).await {
+                    Ok(to) => {
                         println!(
-                            "[GW-{}] 📤 Relayed to peer bridge {} (token=0x{:04x})",
-                            name, peer_bridge, relay_token
+                            "[GW-{}] 📤 Relayed to {} (token=0x{:04x})",
+                            name, to, relay_token
                         );
                     }
                     Err(e) => {
@@ -277,6 +489,19 @@ async fn gateway_recv_loop(
                 match serde_json::from_slice::<serde_json::Value>(json_payload) {
                     Ok(pull_resp_json) => {
                         if let Some(txpk) = pull_resp_json.get("txpk") {
+                            let answered = latency.as_ref().and_then(|latency| {
+                                let dev_addr = frame_dev_addr(txpk, &[0x60, 0xA0])?;
+                                let mut latency = latency.lock().unwrap();
+                                let (id, rtt) = latency.downlink(dev_addr, Instant::now())?;
+                                Some((id, dev_addr, rtt))
+                            });
+                            if let Some((id, dev_addr, rtt)) = answered {
+                                println!(
+                                    "[GW-{}] ⏱  #{} downlink to {:08X} after {:?}",
+                                    name, id, dev_addr, rtt
+                                );
+                            }
+
                             // Convert txpk → rxpk for the other side
                             let rxpk_json = txpk_to_rxpk(txpk, my_eui);
 
@@ -288,12 +513,19 @@ async fn gateway_recv_loop(
                                 state.bridge_addr.unwrap_or(peer_bridge_default)
                             };
 
-                            let relay_pkt = build_push_data(relay_token, peer_eui, rxpk_json.as_bytes());
-                            match peer_sock.send_to(&relay_pkt, peer_bridge).await {
-                                Ok(_) => {
+                            let relay_pkt = build_push_data(
+                                relay_token,
+                                peer_eui,
+                                rxpk_json.as_bytes(),
+                                link.secret.as_deref(),
+                            );
+                            match send_relay(&link, &my_sock, &peer_sock, peer_bridge, &relay_pkt)
+                                .await
+                            {
+                                Ok(to) => {
                                     println!(
-                                        "[GW-{}] 📤 Downlink relayed as uplink to peer bridge {} (token=0x{:04x})",
-                                        name, peer_bridge, relay_token
+                                        "[GW-{}] 📤 Downlink relayed as uplink to {} (token=0x{:04x})",
+                                        name, to, relay_token
                                     );
                                 }
                                 Err(e) => {
@@ -357,6 +589,40 @@ async fn keepalive_loop(
     }
 }
 
+/// Send a relayed PUSH_DATA towards the partner's bridge
+///
+/// Without a secret it goes straight to the partner's bridge from the
+/// partner's socket; with one it crosses the link to the partner gateway,
+/// which checks the tag. Returns where it was sent.
+async fn send_relay(
+    link: &Link,
+    my_sock: &UdpSocket,
+    peer_sock: &UdpSocket,
+    peer_bridge: SocketAddr,
+    packet: &[u8],
+) -> std::io::Result<SocketAddr> {
+    if link.secret.is_some() {
+        my_sock.send_to(packet, link.peer_gateway).await?;
+        Ok(link.peer_gateway)
+    } else {
+        peer_sock.send_to(packet, peer_bridge).await?;
+        Ok(peer_bridge)
+    }
+}
+
+/// Where to reach a gateway bound to `bind` (loopback for a wildcard address)
+fn link_addr(bind: SocketAddr) -> SocketAddr {
+    match bind.ip() {
+        std::net::IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, bind.port()))
+        }
+        std::net::IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, bind.port()))
+        }
+        _ => bind,
+    }
+}
+
 /// Convert a txpk JSON object to an rxpk JSON string (for relay)
 ///
 /// When Gateway A receives a PULL_RESP (downlink), it "transmits" the
@@ -407,17 +673,45 @@ fn build_pull_ack(token: u16) -> Vec<u8> {
     ]
 }
 
-fn build_push_data(token: u16, gateway_eui: &[u8; 8], json_payload: &[u8]) -> Vec<u8> {
-    let mut pkt = Vec::with_capacity(12 + json_payload.len());
+/// PUSH_DATA, with the link tag appended if `secret` is set
+fn build_push_data(
+    token: u16,
+    gateway_eui: &[u8; 8],
+    json_payload: &[u8],
+    secret: Option<&[u8]>,
+) -> Vec<u8> {
+    let mut pkt = Vec::with_capacity(12 + json_payload.len() + TAG_LEN);
     pkt.push(PROTOCOL_VERSION);
     pkt.push((token >> 8) as u8);
     pkt.push(token as u8);
     pkt.push(PUSH_DATA);
     pkt.extend_from_slice(gateway_eui);
     pkt.extend_from_slice(json_payload);
+    if let Some(secret) = secret {
+        let tag = link_tag(secret, &pkt);
+        pkt.extend_from_slice(&tag);
+    }
     pkt
 }
 
+/// HMAC-SHA256 of `packet` under the link secret
+fn link_tag(secret: &[u8], packet: &[u8]) -> [u8; TAG_LEN] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(packet);
+    mac.finalize().into_bytes().into()
+}
+
+/// The packet without its tag, if the tag matches (checked in constant time)
+fn verify_tag<'a>(data: &'a [u8], secret: &[u8]) -> Option<&'a [u8]> {
+    if data.len() < 12 + TAG_LEN {
+        return None;
+    }
+    let (packet, tag) = data.split_at(data.len() - TAG_LEN);
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(packet);
+    mac.verify_slice(tag).ok().map(|_| packet)
+}
+
 fn build_pull_data(token: u16, gateway_eui: &[u8; 8]) -> Vec<u8> {
     let mut pkt = Vec::with_capacity(12);
     pkt.push(PROTOCOL_VERSION);
@@ -437,3 +731,58 @@ fn build_tx_ack(token: u16, gateway_eui: &[u8; 8]) -> Vec<u8> {
     pkt.extend_from_slice(gateway_eui);
     pkt
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_tag() {
+        let secret = b"shared secret";
+        let payload = br#"{"rxpk":[{"freq":902.3,"data":"QAEAAAA="}]}"#;
+        let pkt = build_push_data(0x1234, &GATEWAY_A_EUI, payload, Some(secret));
+        assert_eq!(pkt.len(), 12 + payload.len() + TAG_LEN);
+
+        // The partner strips a valid tag and gets the plain PUSH_DATA back
+        let plain = build_push_data(0x1234, &GATEWAY_A_EUI, payload, None);
+        assert_eq!(verify_tag(&pkt, secret), Some(plain.as_slice()));
+
+        // A flipped payload bit, a different secret or no tag at all is rejected
+        let mut tampered = pkt.clone();
+        tampered[20] ^= 0x01;
+        assert_eq!(verify_tag(&tampered, secret), None);
+        assert_eq!(verify_tag(&pkt, b"other secret"), None);
+        assert_eq!(verify_tag(&plain, secret), None);
+        assert_eq!(verify_tag(&pkt[..20], secret), None);
+    }
+
+    #[test]
+    fn test_latency_correlation() {
+        let mut tracker = LatencyTracker::default();
+        let t0 = Instant::now();
+        let a = tracker.uplink(0x260B_0001, t0);
+        let b = tracker.uplink(0x260B_0001, t0 + Duration::from_millis(50));
+        let c = tracker.uplink(0x260B_0002, t0);
+        assert_eq!((a, b, c), (1, 2, 3));
+
+        // Downlinks close the oldest open uplink of their own DevAddr
+        let t1 = t0 + Duration::from_millis(300);
+        assert_eq!(tracker.downlink(0x260B_0001, t1), Some((1, Duration::from_millis(300))));
+        assert_eq!(tracker.downlink(0x260B_0002, t1), Some((3, Duration::from_millis(300))));
+        assert_eq!(tracker.downlink(0x260B_0002, t1), None);
+        assert_eq!(tracker.downlink(0x260B_0003, t1), None);
+
+        // Stale uplinks are skipped rather than matched
+        let late = t0 + LATENCY_TIMEOUT + Duration::from_secs(1);
+        assert_eq!(tracker.downlink(0x260B_0001, late), None);
+        assert_eq!(tracker.expired, 1);
+        assert!(tracker.pending.is_empty());
+
+        // Both 300 ms samples land in the 250-500 ms bucket
+        assert_eq!(tracker.histogram(), [0, 0, 2, 0, 0, 0, 0, 0]);
+
+        // DevAddrs come from data uplinks only (0x40 unconfirmed up; not 0x60 down)
+        let push = br#"{"rxpk":[{"data":"QAEAAAA="},{"data":"YAIAAAA="},{"data":"AA=="}]}"#;
+        assert_eq!(uplink_dev_addrs(push), vec![0x0000_0001]);
+    }
+}
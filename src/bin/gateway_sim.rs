@@ -3,10 +3,24 @@
 //! Simulates a LoRa gateway sending Semtech UDP Packet Forwarder
 //! frames to the LoraUrbit server. Useful for testing without hardware.
 //!
-//! Usage: cargo run --bin gateway-sim [server_addr]
+//! Usage: cargo run --bin gateway-sim -- [server_addr] [--scenario <file>]
+//!
+//! Without `--scenario` a built-in demo of five frames is sent. A scenario
+//! file (TOML, or JSON if it ends in `.json`) lists data frames to send in
+//! order; see `scenarios/example.toml`.
+//!
+//! `--loop` is a soak test instead: uplinks from `--devices` synthetic
+//! DevAddrs with random FCnt gaps, RSSI and payloads, sent at `--rate`
+//! packets per second until Ctrl-C, with periodic sent/acked/timed-out stats.
 
-use std::env;
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use clap::Parser;
+use serde::Deserialize;
 use tokio::net::UdpSocket;
 use tokio::time::{sleep, Duration};
 
@@ -16,37 +30,141 @@ const PUSH_DATA: u8 = 0x00;
 /// Fake gateway EUI
 const GATEWAY_EUI: [u8; 8] = [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF, 0x00, 0x11];
 
+/// Pause after each built-in demo frame
+const DEMO_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long to wait for a PUSH_ACK
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Soak test stats are printed this often
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// First synthetic DevAddr in `--loop` mode
+const LOOP_DEV_ADDR_BASE: u32 = 0x260C_0000;
+
+#[derive(Parser)]
+#[command(name = "gateway-sim")]
+#[command(about = "Send simulated gateway PUSH_DATA frames to a LoraUrbit server")]
+struct Args {
+    /// LoraUrbit UDP address
+    #[arg(default_value = "127.0.0.1:1680")]
+    server_addr: SocketAddr,
+
+    /// Scenario file (TOML, or JSON with a .json extension) instead of the demo
+    #[arg(long, conflicts_with = "continuous")]
+    scenario: Option<PathBuf>,
+
+    /// Send random uplinks continuously until Ctrl-C
+    #[arg(long = "loop")]
+    continuous: bool,
+
+    /// Packets per second in --loop mode
+    #[arg(long, default_value_t = 10.0)]
+    rate: f64,
+
+    /// Synthetic devices in --loop mode
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u32).range(1..))]
+    devices: u32,
+}
+
+/// One PUSH_DATA to send, then wait `interval`
+#[derive(Debug, Clone, PartialEq)]
+struct Step {
+    desc: String,
+    rxpk_json: String,
+    phy_note: Option<String>,
+    interval: Duration,
+}
+
+/// A scenario file: `[[frame]]` entries (TOML) or `{"frame": [...]}` (JSON)
+#[derive(Debug, Deserialize)]
+struct ScenarioFile {
+    frame: Vec<ScenarioFrame>,
+}
+
+/// A data uplink in a scenario file
+#[derive(Debug, Deserialize)]
+struct ScenarioFrame {
+    /// Shown while sending (defaults to the DevAddr and FCnt)
+    description: Option<String>,
+    /// DevAddr (hex)
+    dev_addr: String,
+    #[serde(default)]
+    fcnt: u16,
+    #[serde(default = "default_fport")]
+    fport: u8,
+    /// FRMPayload (hex), sent as-is
+    #[serde(default)]
+    payload: String,
+    #[serde(default)]
+    confirmed: bool,
+    #[serde(default = "default_rssi")]
+    rssi: f64,
+    #[serde(default = "default_lsnr")]
+    lsnr: f64,
+    #[serde(default = "default_freq")]
+    freq: f64,
+    #[serde(default = "default_datr")]
+    datr: String,
+    /// Pause after sending (ms)
+    #[serde(default = "default_interval_ms")]
+    interval_ms: u64,
+}
+
+fn default_fport() -> u8 {
+    1
+}
+
+fn default_rssi() -> f64 {
+    -65.0
+}
+
+fn default_lsnr() -> f64 {
+    7.5
+}
+
+fn default_freq() -> f64 {
+    902.3
+}
+
+fn default_datr() -> String {
+    "SF7BW125".to_string()
+}
+
+fn default_interval_ms() -> u64 {
+    2000
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let server_addr: SocketAddr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:1680".to_string())
-        .parse()?;
+    let args = Args::parse();
+    let server_addr = args.server_addr;
+    if args.continuous {
+        return run_loop(server_addr, args.rate, args.devices).await;
+    }
+    let steps = match &args.scenario {
+        Some(path) => load_scenario(path)?,
+        None => demo_steps(),
+    };
 
     println!("🌊 LoraUrbit Gateway Simulator");
     println!("  Target: {}", server_addr);
     println!("  Gateway EUI: {}", hex::encode(GATEWAY_EUI));
+    if let Some(path) = &args.scenario {
+        println!("  Scenario: {} ({} frames)", path.display(), steps.len());
+    }
     println!();
 
     let socket = UdpSocket::bind("0.0.0.0:0").await?;
     let mut token: u16 = 0;
 
-    // Send a mix of packets
-    let scenarios = vec![
-        ("Unconfirmed Data Up (temperature sensor)", build_unconfirmed_data_up()),
-        ("Confirmed Data Up (door sensor)", build_confirmed_data_up()),
-        ("Join Request", build_join_request()),
-        ("Gateway Status", build_gateway_status()),
-        ("Unconfirmed Data Up (humidity sensor)", build_unconfirmed_data_up_2()),
-    ];
-
-    for (desc, (rxpk_json, phy_note)) in &scenarios {
+    for step in &steps {
         token = token.wrapping_add(1);
 
-        let packet = build_push_data(token, &GATEWAY_EUI, rxpk_json);
+        let packet = build_push_data(token, &GATEWAY_EUI, &step.rxpk_json);
 
-        println!("📡 Sending: {}", desc);
-        if let Some(note) = phy_note {
+        println!("📡 Sending: {}", step.desc);
+        if let Some(note) = &step.phy_note {
             println!("   PHY: {}", note);
         }
         println!("   Size: {} bytes", packet.len());
@@ -55,7 +173,7 @@ async fn main() -> anyhow::Result<()> {
 
         // Wait for ACK
         let mut ack_buf = [0u8; 64];
-        match tokio::time::timeout(Duration::from_secs(2), socket.recv_from(&mut ack_buf)).await {
+        match tokio::time::timeout(ACK_TIMEOUT, socket.recv_from(&mut ack_buf)).await {
             Ok(Ok((len, from))) => {
                 if len >= 4 && ack_buf[3] == 0x01 {
                     println!("   ✅ PUSH_ACK received from {}", from);
@@ -68,15 +186,287 @@ async fn main() -> anyhow::Result<()> {
         }
         println!();
 
-        sleep(Duration::from_secs(2)).await;
+        sleep(step.interval).await;
     }
 
     println!("✨ Simulation complete!");
     Ok(())
 }
 
+/// Token bucket: `rate` tokens per second, holding at most `burst`
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    /// Take a token, or return how long until one is available
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+/// xorshift64*: plenty for synthetic traffic, no extra dependency
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn from_time() -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Self(nanos | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in `lo..=hi`
+    fn range(&mut self, lo: u32, hi: u32) -> u32 {
+        lo + (self.next_u64() % (hi - lo + 1) as u64) as u32
+    }
+}
+
+/// Random uplinks from a pool of synthetic devices
+struct UplinkGenerator {
+    rng: Rng,
+    /// Next FCnt per device (index i is DevAddr LOOP_DEV_ADDR_BASE + i)
+    fcnts: Vec<u16>,
+}
+
+impl UplinkGenerator {
+    fn new(devices: u32, mut rng: Rng) -> Self {
+        let fcnts = (0..devices).map(|_| rng.range(0, 1000) as u16).collect();
+        Self { rng, fcnts }
+    }
+
+    /// rxpk JSON of the next uplink, from a random device
+    fn next_rxpk(&mut self) -> String {
+        let device = self.rng.range(0, self.fcnts.len() as u32 - 1) as usize;
+        let fcnt = self.fcnts[device];
+        // Occasionally skip FCnts, as if uplinks were lost
+        self.fcnts[device] = fcnt.wrapping_add(self.rng.range(1, 3) as u16);
+
+        let payload: Vec<u8> = (0..self.rng.range(1, 16))
+            .map(|_| self.rng.next_u64() as u8)
+            .collect();
+        let step = frame_step(&ScenarioFrame {
+            description: None,
+            dev_addr: format!("{:08X}", LOOP_DEV_ADDR_BASE + device as u32),
+            fcnt,
+            fport: self.rng.range(1, 223) as u8,
+            payload: hex::encode(payload),
+            confirmed: false,
+            rssi: -(self.rng.range(40, 120) as f64),
+            lsnr: self.rng.range(0, 300) as f64 / 10.0 - 20.0,
+            freq: default_freq(),
+            datr: default_datr(),
+            interval_ms: 0,
+        })
+        .expect("synthetic frames are valid");
+        step.rxpk_json
+    }
+}
+
+/// PUSH_DATA counters for --loop mode
+#[derive(Debug, Default)]
+struct LoopStats {
+    sent: u64,
+    acked: u64,
+    timed_out: u64,
+    /// Unacknowledged tokens and when they were sent
+    pending: HashMap<u16, Instant>,
+}
+
+impl LoopStats {
+    /// Count pending PUSH_DATA older than the ACK timeout as timed out
+    fn expire(&mut self, now: Instant) {
+        let before = self.pending.len();
+        self.pending
+            .retain(|_, sent| now.saturating_duration_since(*sent) < ACK_TIMEOUT);
+        self.timed_out += (before - self.pending.len()) as u64;
+    }
+}
+
+/// Soak test: send random uplinks at `rate` per second until Ctrl-C
+async fn run_loop(server_addr: SocketAddr, rate: f64, devices: u32) -> anyhow::Result<()> {
+    if rate.is_nan() || rate <= 0.0 {
+        anyhow::bail!("--rate must be positive");
+    }
+    println!("🌊 LoraUrbit Gateway Simulator (soak test)");
+    println!("  Target: {}", server_addr);
+    println!("  Rate: {} packets/s from {} devices", rate, devices);
+    println!("  Ctrl-C to stop");
+    println!();
+
+    let socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let stats = Arc::new(Mutex::new(LoopStats::default()));
+
+    let ack_socket = socket.clone();
+    let ack_stats = stats.clone();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 64];
+        while let Ok((len, _)) = ack_socket.recv_from(&mut buf).await {
+            if len >= 4 && buf[3] == 0x01 {
+                let token = u16::from_be_bytes([buf[1], buf[2]]);
+                let mut stats = ack_stats.lock().unwrap();
+                if stats.pending.remove(&token).is_some() {
+                    stats.acked += 1;
+                }
+            }
+        }
+    });
+
+    let mut generator = UplinkGenerator::new(devices, Rng::from_time());
+    let mut bucket = TokenBucket::new(rate, rate.max(1.0), Instant::now());
+    let mut stats_tick = tokio::time::interval(STATS_INTERVAL);
+    stats_tick.tick().await;
+    let mut token: u16 = 0;
+
+    loop {
+        if let Err(wait) = bucket.take(Instant::now()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => break,
+                _ = stats_tick.tick() => print_stats(&stats),
+                _ = sleep(wait) => {}
+            }
+            continue;
+        }
+
+        token = token.wrapping_add(1);
+        let packet = build_push_data(token, &GATEWAY_EUI, &generator.next_rxpk());
+        // Recorded first: the PUSH_ACK can arrive before send_to returns
+        {
+            let mut counts = stats.lock().unwrap();
+            counts.sent += 1;
+            counts.pending.insert(token, Instant::now());
+        }
+        socket.send_to(&packet, server_addr).await?;
+    }
+
+    // Give in-flight PUSH_DATA their chance to be acknowledged
+    sleep(ACK_TIMEOUT).await;
+    println!();
+    print_stats(&stats);
+    Ok(())
+}
+
+fn print_stats(stats: &Mutex<LoopStats>) {
+    let mut stats = stats.lock().unwrap();
+    stats.expire(Instant::now());
+    println!(
+        "📊 sent {}  acked {}  timed out {}  pending {}",
+        stats.sent,
+        stats.acked,
+        stats.timed_out,
+        stats.pending.len()
+    );
+}
+
+/// The built-in mix of packets
+fn demo_steps() -> Vec<Step> {
+    let demo = [
+        ("Unconfirmed Data Up (temperature sensor)", build_unconfirmed_data_up()),
+        ("Confirmed Data Up (door sensor)", build_confirmed_data_up()),
+        ("Join Request", build_join_request()),
+        ("Gateway Status", build_gateway_status()),
+        ("Unconfirmed Data Up (humidity sensor)", build_unconfirmed_data_up_2()),
+    ];
+    demo.into_iter()
+        .map(|(desc, (rxpk_json, phy_note))| Step {
+            desc: desc.to_string(),
+            rxpk_json,
+            phy_note,
+            interval: DEMO_INTERVAL,
+        })
+        .collect()
+}
+
+/// Read a scenario file into steps
+fn load_scenario(path: &Path) -> anyhow::Result<Vec<Step>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read scenario {:?}: {}", path, e))?;
+    let json = path.extension().is_some_and(|ext| ext == "json");
+    parse_scenario(&content, json)
+        .map_err(|e| anyhow::anyhow!("Invalid scenario {:?}: {}", path, e))
+}
+
+/// Parse scenario TOML (or JSON) into steps
+fn parse_scenario(content: &str, json: bool) -> anyhow::Result<Vec<Step>> {
+    let file: ScenarioFile = match json {
+        true => serde_json::from_str(content)?,
+        false => toml::from_str(content)?,
+    };
+    file.frame
+        .iter()
+        .enumerate()
+        .map(|(i, frame)| frame_step(frame).map_err(|e| anyhow::anyhow!("frame {}: {}", i + 1, e)))
+        .collect()
+}
+
+/// Build a data uplink with a zero MIC from a scenario frame
+fn frame_step(frame: &ScenarioFrame) -> anyhow::Result<Step> {
+    let dev_addr = u32::from_str_radix(&frame.dev_addr, 16)
+        .map_err(|e| anyhow::anyhow!("invalid dev_addr {:?}: {}", frame.dev_addr, e))?;
+    let payload = hex::decode(&frame.payload)
+        .map_err(|e| anyhow::anyhow!("invalid payload hex: {}", e))?;
+
+    let mut phy = vec![if frame.confirmed { 0x80 } else { 0x40 }];
+    phy.extend_from_slice(&dev_addr.to_le_bytes());
+    phy.push(0x00); // FCtrl
+    phy.extend_from_slice(&frame.fcnt.to_le_bytes());
+    phy.push(frame.fport);
+    phy.extend_from_slice(&payload);
+    phy.extend_from_slice(&[0; 4]); // MIC
+
+    let rxpk_json = serde_json::json!({
+        "rxpk": [{
+            "freq": frame.freq,
+            "rssi": frame.rssi,
+            "lsnr": frame.lsnr,
+            "datr": frame.datr,
+            "codr": "4/5",
+            "size": phy.len(),
+            "data": b64(&phy),
+        }]
+    })
+    .to_string();
+    let note = format!("DevAddr={:08X} FCnt={} FPort={}", dev_addr, frame.fcnt, frame.fport);
+    Ok(Step {
+        desc: frame.description.clone().unwrap_or_else(|| note.clone()),
+        rxpk_json,
+        phy_note: Some(note),
+        interval: Duration::from_millis(frame.interval_ms),
+    })
+}
+
 fn build_push_data(token: u16, gateway_eui: &[u8; 8], json: &str) -> Vec<u8> {
-    let mut packet = Vec::new();
+    let mut packet = Vec::with_capacity(12 + json.len());
     packet.push(PROTOCOL_VERSION);
     packet.push((token >> 8) as u8);
     packet.push(token as u8);
@@ -173,3 +563,64 @@ fn build_gateway_status() -> (String, Option<String>) {
     let json = r#"{"stat":{"time":"2026-02-18 17:30:00 UTC","lati":29.7604,"long":-95.3698,"alti":15,"rxnb":47,"rxok":44,"rxfw":44,"ackr":100.0,"dwnb":3,"txnb":3}}"#.to_string();
     (json, None)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_rate() {
+        // 10/s with a burst of 10, over a simulated 3 s window in 1 ms steps
+        let t0 = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 10.0, t0);
+        let sent = (0..3000)
+            .filter(|ms| bucket.take(t0 + Duration::from_millis(*ms)).is_ok())
+            .count();
+        // The initial burst, then 10 per second
+        assert!((39..=41).contains(&sent), "{}", sent);
+
+        // An empty bucket says how long to wait for the next token
+        let mut bucket = TokenBucket::new(4.0, 1.0, t0);
+        assert!(bucket.take(t0).is_ok());
+        assert_eq!(bucket.take(t0), Err(Duration::from_millis(250)));
+        assert!(bucket.take(t0 + Duration::from_millis(250)).is_ok());
+    }
+
+    #[test]
+    fn test_generator_devices() {
+        use base64::Engine;
+        let mut generator = UplinkGenerator::new(3, Rng(42));
+        for _ in 0..50 {
+            let rxpk: serde_json::Value = serde_json::from_str(&generator.next_rxpk()).unwrap();
+            let data = &rxpk["rxpk"][0]["data"];
+            let phy = base64::engine::general_purpose::STANDARD
+                .decode(data.as_str().unwrap())
+                .unwrap();
+            let dev_addr = u32::from_le_bytes(phy[1..5].try_into().unwrap());
+            assert!((LOOP_DEV_ADDR_BASE..LOOP_DEV_ADDR_BASE + 3).contains(&dev_addr));
+        }
+    }
+
+    #[test]
+    fn test_parse_example_scenario() {
+        let steps = parse_scenario(include_str!("../../scenarios/example.toml"), false).unwrap();
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[0].desc, "Soil moisture sensor");
+        assert_eq!(steps[0].phy_note.as_deref(), Some("DevAddr=260B1234 FCnt=310 FPort=2"));
+        assert_eq!(steps[0].interval, Duration::from_millis(500));
+        assert_eq!(steps[2].interval, Duration::from_millis(2000));
+
+        let rxpk: serde_json::Value = serde_json::from_str(&steps[1].rxpk_json).unwrap();
+        let rxpk = &rxpk["rxpk"][0];
+        assert_eq!(rxpk["rssi"], -117.0);
+        assert_eq!(rxpk["datr"], "SF10BW125");
+        // Confirmed, DevAddr LE, FCnt 311, FPort 2, payload, zero MIC
+        assert_eq!(rxpk["data"], b64(&hex::decode("8034120B2600370102017F00000000").unwrap()));
+
+        // The same frames as JSON
+        let json = r#"{"frame": [{"dev_addr": "260B1234", "payload": "01"}]}"#;
+        let steps = parse_scenario(json, true).unwrap();
+        assert_eq!(steps[0].phy_note.as_deref(), Some("DevAddr=260B1234 FCnt=0 FPort=1"));
+        assert!(parse_scenario(r#"{"frame": [{"dev_addr": "xyz"}]}"#, true).is_err());
+    }
+}
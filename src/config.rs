@@ -1,70 +1,1514 @@
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::path::Path;
 
-#[derive(Debug, Deserialize)]
+use crate::lorawan::channel_plan::{ChannelPlan, Region};
+use crate::lorawan::codec::CodecKind;
+use crate::lorawan::datarate::DataRate;
+use crate::lorawan::keys::{DeviceClass, KeyStore, LorawanVersion};
+use crate::lorawan::NetId;
+
+/// Prefix of the environment variables that override config fields
+///
+/// Environment variables take precedence over the config file, which takes
+/// precedence over defaults. Each overridable field names its variable.
+pub const ENV_PREFIX: &str = "LORAURBIT_";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Config {
+    /// Bridge-wide switches (`[general]`)
+    #[serde(default)]
+    pub general: GeneralConfig,
     pub udp: UdpConfig,
     pub lorawan: LorawanConfig,
-    pub urbit: Option<UrbitConfig>,
+    /// Regional channel plan for downlinks; defaults to all of US915
+    #[serde(default)]
+    pub channel_plan: ChannelPlanConfig,
+    /// Downlink airtime limits; defaults follow the channel plan's region
+    #[serde(default)]
+    pub duty_cycle: DutyCycleConfig,
+    /// Downlink radio settings; defaults to 4/5 coding at the RX2 data rate
+    #[serde(default)]
+    pub downlink: DownlinkConfig,
+    /// Urbit targets: one `[urbit]` table or several `[[urbit]]` entries
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub urbit: Vec<UrbitConfig>,
     pub helium: Option<HeliumConfig>,
     pub logging: LoggingConfig,
+    /// Raw GWMP datagram capture; off unless set here or with `--capture`
+    #[serde(default)]
+    pub capture: Option<CaptureConfig>,
+    /// Liveness/readiness HTTP endpoint; off unless set
+    #[serde(default)]
+    pub health: Option<HealthConfig>,
+    /// Admin API for sending downlinks without the outbox; off unless set
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    /// Per-gateway settings (`[[gateways]]`)
+    #[serde(default)]
+    pub gateways: Vec<GatewayConfig>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct UdpConfig {
-    pub bind: String,
+    /// One address or several, each with its own socket
+    ///
+    /// Env: `LORAURBIT_UDP_BIND` (comma-separated)
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind: Vec<String>,
+    /// Source IPs/CIDRs or gateway EUI prefixes of Helium Packet Router traffic
+    #[serde(default)]
+    pub helium_sources: Vec<String>,
+    /// Warn when a gateway has been silent this long (seconds, 0 = never)
+    #[serde(default = "default_gateway_timeout_secs")]
+    pub gateway_timeout_secs: u64,
+    /// Also poke the agent with `gateway-status` when a gateway goes down or comes back
+    #[serde(default)]
+    pub gateway_status_pokes: bool,
+    /// Warn about downlinks sent this long after the gateway's last keepalive,
+    /// when a NAT may have dropped its mapping (seconds, 0 = never)
+    #[serde(default = "default_keepalive_window_secs")]
+    pub keepalive_window_secs: u64,
+    /// Only handle datagrams from these gateway EUIs (hex; empty = all)
+    #[serde(default)]
+    pub allowed_gateways: Vec<String>,
+    /// Drop datagrams from these gateway EUIs, even if allowed
+    #[serde(default)]
+    pub denied_gateways: Vec<String>,
+    /// Also accept GWMP version 1 datagrams from legacy forwarders
+    #[serde(default)]
+    pub accept_protocol_v1: bool,
+    /// Collect copies of an uplink from other gateways this long before
+    /// forwarding it with all their receptions (ms, 0 = forward at once)
+    #[serde(default)]
+    pub dedup_window_ms: u64,
+    /// Drop datagrams whose JSON payload is longer than this (bytes, after inflating)
+    #[serde(default = "default_max_json_len")]
+    pub max_json_len: usize,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct LorawanConfig {
+    /// Decrypt uplink FRMPayloads (requires AppSKey)
     pub decrypt_payload: bool,
+    /// Encrypt and sign downlinks with the device's session keys; off sends
+    /// the FRMPayload in plaintext with a zero MIC. Needs (and without it
+    /// defaults off) the `phase4` feature
+    #[serde(default = "default_encrypt_downlink")]
+    pub encrypt_downlink: bool,
+    /// Decode application payloads with this codec before poking Urbit
+    #[serde(default)]
+    pub codec: Option<CodecKind>,
+    /// Per-device codec overrides, keyed by DevAddr hex
+    #[serde(default)]
+    pub device_codecs: HashMap<String, CodecKind>,
+    /// Directory for persistent state (downlink frame counters); None keeps it in memory
+    ///
+    /// Env: `LORAURBIT_LORAWAN_STATE_DIR`
+    #[serde(default)]
+    pub state_dir: Option<String>,
+    /// ABP devices and their session keys
+    #[serde(default)]
+    pub devices: Vec<AbpDeviceConfig>,
+    /// Accept an uplink FCnt at or below this as a device reset if its MIC verifies
+    #[serde(default = "default_fcnt_reset_tolerance")]
+    pub fcnt_reset_tolerance: u32,
+    /// Drop data frames whose DevAddr is outside these NetIDs (empty = accept all)
+    #[serde(default)]
+    pub accept_net_ids: Vec<NetId>,
+    /// Answer confirmed uplinks with an empty ACK downlink in RX1
+    #[serde(default = "default_auto_ack_confirmed")]
+    pub auto_ack_confirmed: bool,
+    /// Drop frames whose MHDR Major isn't LoRaWAN R1 instead of only logging them
+    #[serde(default)]
+    pub reject_unknown_major: bool,
+}
+
+/// Three missed keepalives at the packet forwarder's default 30 s interval
+fn default_gateway_timeout_secs() -> u64 {
+    90
+}
+
+fn default_max_json_len() -> usize {
+    crate::udp::protocol::MAX_JSON_LEN
+}
+
+/// Two keepalives at the packet forwarder's default 30 s interval
+fn default_keepalive_window_secs() -> u64 {
+    60
+}
+
+fn default_fcnt_reset_tolerance() -> u32 {
+    16
+}
+
+fn default_encrypt_downlink() -> bool {
+    cfg!(feature = "phase4")
+}
+
+fn default_auto_ack_confirmed() -> bool {
+    true
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// `[channel_plan]`: region and enabled channels
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct ChannelPlanConfig {
+    #[serde(default)]
+    pub region: Region,
+    /// US915/AU915 sub-band (1-8) the gateway listens on; None enables all 72
+    #[serde(default)]
+    pub sub_band: Option<u8>,
+    /// EU868 uplink channels (MHz) beyond the three default ones
+    #[serde(default)]
+    pub channels: Vec<f64>,
+    /// Override the region's RX2 frequency (MHz)
+    #[serde(default)]
+    pub rx2_freq: Option<f64>,
+    /// Override the region's RX2 data rate (e.g. "SF9BW125")
+    #[serde(default, deserialize_with = "crate::lorawan::datarate::deserialize_known")]
+    pub rx2_datr: Option<DataRate>,
+}
+
+/// `[downlink]`: radio settings applied to every downlink txpk
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct DownlinkConfig {
+    /// LoRa coding rate ("4/5" to "4/8"); None keeps 4/5 (RX1: the uplink's)
+    #[serde(default)]
+    pub coding_rate: Option<String>,
+    /// Data rate of immediate (Class C) downlinks; None uses the RX2 data rate
+    ///
+    /// Class A RX2 and devices with their own `rx2_datr` keep theirs.
+    #[serde(default, deserialize_with = "crate::lorawan::datarate::deserialize_known")]
+    pub datr: Option<DataRate>,
+    /// When downlinks not answering a specific uplink are transmitted
+    #[serde(default)]
+    pub mode: DownlinkMode,
+    /// Hold confirmed outbox downlinks until the device ACKs them, resending
+    /// up to this many times; None pokes tx-ack once the gateway sends them
+    #[serde(default)]
+    pub confirmed_retries: Option<u32>,
+}
+
+/// `downlink.mode`: how a downlink txpk is timed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownlinkMode {
+    /// As soon as the gateway gets it (`imme`), for Class C
+    #[default]
+    Immediate,
+    /// In RX1, one second after a reference uplink's `tmst`
+    Rx1,
+    /// In RX2, two seconds after a reference uplink's `tmst`
+    Rx2,
+}
+
+/// Coding rates a LoRa concentrator can transmit with
+pub const CODING_RATES: [&str; 4] = ["4/5", "4/6", "4/7", "4/8"];
+
+/// `[duty_cycle]`: downlink airtime budget per sub-band
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DutyCycleConfig {
+    /// Enforce the limits; None enables them only where the region requires it (EU868)
+    #[serde(default)]
+    pub enabled: Option<bool>,
+    /// Sliding window the duty cycle is measured over (seconds)
+    #[serde(default = "default_duty_cycle_window_secs")]
+    pub window_secs: u64,
+    /// Sub-band limits; empty uses the region's defaults
+    #[serde(default)]
+    pub bands: Vec<DutyCycleBand>,
+}
+
+impl Default for DutyCycleConfig {
+    fn default() -> Self {
+        Self {
+            enabled: None,
+            window_secs: default_duty_cycle_window_secs(),
+            bands: Vec::new(),
+        }
+    }
+}
+
+fn default_duty_cycle_window_secs() -> u64 {
+    3600
+}
+
+/// One sub-band's share of airtime (`[[duty_cycle.bands]]`)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DutyCycleBand {
+    /// Lower edge in MHz (inclusive)
+    pub min_freq: f64,
+    /// Upper edge in MHz (inclusive)
+    pub max_freq: f64,
+    /// Allowed fraction of the window, in percent (e.g. 1.0 for 1%)
+    pub percent: f64,
+}
+
+/// `[general]`: bridge-wide switches
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct GeneralConfig {
+    /// Decode, check and build everything, but log pokes and downlinks
+    /// instead of sending them (also `--dry-run`)
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// `[capture]`: record every GWMP datagram to a binary log
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CaptureConfig {
+    /// Capture file (appended to if it exists)
+    pub path: String,
+}
+
+/// `[health]`: HTTP liveness (`/healthz`) and readiness (`/readyz`) probes
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HealthConfig {
+    /// Address to listen on, e.g. "0.0.0.0:8081"
+    pub bind: String,
+}
+
+/// `[admin]`: local HTTP API to send downlinks (`POST /downlink`)
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct AdminConfig {
+    /// Address to listen on; anyone with the token who reaches it can send
+    /// downlinks, so it stays on loopback unless set otherwise
+    #[serde(default = "default_admin_bind")]
+    pub bind: String,
+    /// Token requests must carry as `Authorization: Bearer <token>`
+    pub token: String,
+}
+
+fn default_admin_bind() -> String {
+    "127.0.0.1:8082".to_string()
+}
+
+impl std::fmt::Debug for AdminConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminConfig")
+            .field("bind", &self.bind)
+            .field("token", &REDACTED)
+            .finish()
+    }
+}
+
+/// Settings for one gateway (`[[gateways]]`)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GatewayConfig {
+    /// Gateway EUI (16 hex digits)
+    pub eui: String,
+    /// Downlink TX power in dBm instead of the region default, e.g. to keep
+    /// the EIRP within limits with a high-gain antenna
+    #[serde(default)]
+    pub tx_power: Option<u8>,
+    /// Friendly name shown in logs and metrics instead of the EUI
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// An ABP-provisioned device (`[[lorawan.devices]]`)
+///
+/// Debug output shows the session keys as `***`
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct AbpDeviceConfig {
+    /// DevAddr (hex)
+    pub dev_addr: String,
+    /// Network session key (32 hex digits)
+    pub nwk_s_key: String,
+    /// Application session key (32 hex digits)
+    pub app_s_key: String,
+    /// "1.0" (default) or "1.1"; 1.1 uplink MICs use both network integrity keys
+    #[serde(default)]
+    pub lorawan_version: LorawanVersion,
+    /// SNwkSIntKey (32 hex digits), required for 1.1 devices, whose `nwk_s_key`
+    /// is the FNwkSIntKey
+    #[serde(default)]
+    pub s_nwk_s_int_key: Option<String>,
+    /// "A", "B" or "C"; Class B downlinks wait for the device's next ping slot
+    #[serde(default)]
+    pub class: DeviceClass,
+    /// Class B ping slot periodicity (0-7, as set by the device's PingSlotInfoReq)
+    #[serde(default = "default_ping_slot_periodicity")]
+    pub ping_slot_periodicity: u8,
+    /// RX2 data rate (e.g. "SF9BW500") if the device does not use the region default
+    #[serde(default, deserialize_with = "crate::lorawan::datarate::deserialize_known")]
+    pub rx2_datr: Option<DataRate>,
+    /// Seconds from the end of an uplink to the device's RX1 window (1-15)
+    #[serde(default = "default_rx1_delay")]
+    pub rx1_delay: u8,
+}
+
+impl std::fmt::Debug for AbpDeviceConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AbpDeviceConfig")
+            .field("dev_addr", &self.dev_addr)
+            .field("nwk_s_key", &REDACTED)
+            .field("app_s_key", &REDACTED)
+            .field("lorawan_version", &self.lorawan_version)
+            .field("s_nwk_s_int_key", &self.s_nwk_s_int_key.as_ref().map(|_| REDACTED))
+            .field("class", &self.class)
+            .field("ping_slot_periodicity", &self.ping_slot_periodicity)
+            .field("rx2_datr", &self.rx2_datr)
+            .field("rx1_delay", &self.rx1_delay)
+            .finish()
+    }
+}
+
+fn default_ping_slot_periodicity() -> u8 {
+    7
+}
+
+fn default_rx1_delay() -> u8 {
+    1
+}
+
+/// Debug output shows `code` as `***`
+#[derive(Clone, PartialEq, Deserialize)]
 pub struct UrbitConfig {
+    /// Env: `LORAURBIT_URBIT_URL`
     pub url: String,
+    /// Env: `LORAURBIT_URBIT_SHIP`
     pub ship: String,
+    /// Airlock `+code`; prefer `code_file` or `code_env` so it isn't written to disk
+    ///
+    /// Env: `LORAURBIT_URBIT_CODE`
+    #[serde(default)]
     pub code: String,
+    /// Read the `+code` from this file at startup (e.g. `/run/secrets/urbit_code`)
+    #[serde(default)]
+    pub code_file: Option<String>,
+    /// Read the `+code` from this environment variable at startup
+    #[serde(default)]
+    pub code_env: Option<String>,
+    /// Env: `LORAURBIT_URBIT_AGENT`
     pub agent: String,
+    /// Maximum outbox messages sent but not yet cleared by the agent (0 = unlimited)
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+    /// Sends of one outbox message before a transient failure (no gateway
+    /// yet, a timing collision) pokes tx-fail; retries back off exponentially
+    #[serde(default = "default_max_tx_attempts")]
+    pub max_tx_attempts: u32,
+    /// Send confirmed outbox messages before unconfirmed ones
+    #[serde(default = "default_true")]
+    pub prioritize_confirmed: bool,
+    /// Only forward uplinks whose DevAddr starts with one of these hex prefixes
+    #[serde(default)]
+    pub dev_addr_prefixes: Vec<String>,
+    /// Only forward uplinks received by these gateways (EUI hex)
+    #[serde(default)]
+    pub gateway_euis: Vec<String>,
+    /// Poke uplinks under a DevAddr prefix with another mark; the longest
+    /// matching prefix wins
+    #[serde(default)]
+    pub mark_routes: Vec<MarkRoute>,
+    /// Seconds between heartbeat pokes to the agent (0 = off)
+    #[serde(default = "default_heartbeat_secs")]
+    pub heartbeat_secs: u64,
+    /// Poke with a mark per action (`%lora-uplink`, `%lora-txack`, ...)
+    /// instead of `%json`; the agent's desk must have the marks
+    #[serde(default)]
+    pub typed_marks: bool,
+    /// How uplink-task pokes carry each action (`json` or `msgpack`)
+    #[serde(default)]
+    pub encoding: PokeEncoding,
+    /// Only poke joins from DevEUIs the agent's `/allowed-devices` scry lists
+    #[serde(default)]
+    pub authorize_joins: bool,
+    /// Send up to this many uplinks in one channel PUT (1 = one per PUT)
+    #[serde(default = "default_poke_batch_max")]
+    pub poke_batch_max: usize,
+    /// Milliseconds to wait for more uplinks before sending a partial batch
+    #[serde(default = "default_poke_batch_ms")]
+    pub poke_batch_ms: u64,
+    /// Agent scry path polled for downlinks
+    #[serde(default = "default_outbox_path")]
+    pub outbox_path: String,
+    /// Milliseconds between outbox polls
+    #[serde(default = "default_outbox_poll_ms")]
+    pub outbox_poll_ms: u64,
+    /// Up to this many milliseconds added at random to each poll interval, so
+    /// bridges sharing a ship don't poll in lockstep
+    #[serde(default)]
+    pub outbox_poll_jitter_ms: u64,
+    /// Agent path to watch for newly queued messages (e.g. "/outbox-updates"),
+    /// sent as they arrive; polling continues as the fallback
+    #[serde(default)]
+    pub outbox_subscribe_path: Option<String>,
+    /// Agent scry path serving settings that override this file (e.g. "/config")
+    #[serde(default)]
+    pub config_path: Option<String>,
+    /// Seconds between scries of `config_path` after startup (0 = startup only)
+    #[serde(default = "default_config_poll_secs")]
+    pub config_poll_secs: u64,
+    /// Give up on connecting to the ship after this many milliseconds
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    /// Give up on a login, poke or scry after this many milliseconds (the
+    /// poke then fails and the client reconnects)
+    #[serde(default = "default_request_timeout_ms")]
+    pub request_timeout_ms: u64,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl UrbitConfig {
+    /// A target with every other setting at its `[urbit]` default
+    pub fn new(url: String, ship: String, code: String, agent: String) -> Self {
+        // Deserialized, so the defaults are the serde ones above
+        let table = serde_json::json!({ "url": url, "ship": ship, "code": code, "agent": agent });
+        serde_json::from_value(table).expect("url, ship, code and agent are all it requires")
+    }
+}
+
+impl std::fmt::Debug for UrbitConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UrbitConfig")
+            .field("url", &self.url)
+            .field("ship", &self.ship)
+            .field("code", &REDACTED)
+            .field("code_file", &self.code_file)
+            .field("code_env", &self.code_env)
+            .field("agent", &self.agent)
+            .field("max_in_flight", &self.max_in_flight)
+            .field("max_tx_attempts", &self.max_tx_attempts)
+            .field("prioritize_confirmed", &self.prioritize_confirmed)
+            .field("dev_addr_prefixes", &self.dev_addr_prefixes)
+            .field("gateway_euis", &self.gateway_euis)
+            .field("mark_routes", &self.mark_routes)
+            .field("heartbeat_secs", &self.heartbeat_secs)
+            .field("typed_marks", &self.typed_marks)
+            .field("encoding", &self.encoding)
+            .field("authorize_joins", &self.authorize_joins)
+            .field("poke_batch_max", &self.poke_batch_max)
+            .field("poke_batch_ms", &self.poke_batch_ms)
+            .field("outbox_path", &self.outbox_path)
+            .field("outbox_poll_ms", &self.outbox_poll_ms)
+            .field("outbox_poll_jitter_ms", &self.outbox_poll_jitter_ms)
+            .field("outbox_subscribe_path", &self.outbox_subscribe_path)
+            .field("config_path", &self.config_path)
+            .field("config_poll_secs", &self.config_poll_secs)
+            .field("connect_timeout_ms", &self.connect_timeout_ms)
+            .field("request_timeout_ms", &self.request_timeout_ms)
+            .finish()
+    }
+}
+
+/// Stand-in for secret fields in Debug output
+struct Redacted;
+
+const REDACTED: Redacted = Redacted;
+
+impl std::fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("***")
+    }
+}
+
+/// Accept either a single value (or table) or an array of them
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+fn default_max_in_flight() -> usize {
+    8
+}
+
+fn default_max_tx_attempts() -> u32 {
+    3
+}
+
+fn default_heartbeat_secs() -> u64 {
+    60
+}
+
+fn default_poke_batch_max() -> usize {
+    1
+}
+
+fn default_poke_batch_ms() -> u64 {
+    20
+}
+
+fn default_config_poll_secs() -> u64 {
+    300
+}
+
+fn default_outbox_path() -> String {
+    "/outbox".to_string()
+}
+
+fn default_outbox_poll_ms() -> u64 {
+    2000
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_request_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Debug output shows `delegate_keypair` as `***`
+#[derive(Clone, PartialEq, Deserialize)]
 pub struct HeliumConfig {
     pub oui: u64,
     pub net_id: String,
     pub config_host: String,
     pub delegate_keypair: String,
+    /// Existing Config Service route to add device EUIs to
+    #[serde(default)]
+    pub route_id: Option<String>,
+    /// Warn when the OUI's DC escrow balance drops below this many DC
+    #[serde(default = "default_low_dc_threshold")]
+    pub low_dc_threshold: u64,
+    /// How often to check the DC balance (seconds)
+    #[serde(default = "default_dc_check_interval_secs")]
+    pub dc_check_interval_secs: u64,
+    /// Packet Router to stream uplinks from over gRPC (helium-grpc feature)
+    #[serde(default)]
+    pub packet_router_host: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+impl std::fmt::Debug for HeliumConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HeliumConfig")
+            .field("oui", &self.oui)
+            .field("net_id", &self.net_id)
+            .field("config_host", &self.config_host)
+            .field("delegate_keypair", &REDACTED)
+            .field("route_id", &self.route_id)
+            .field("low_dc_threshold", &self.low_dc_threshold)
+            .field("dc_check_interval_secs", &self.dc_check_interval_secs)
+            .field("packet_router_host", &self.packet_router_host)
+            .finish()
+    }
+}
+
+/// Helium halts traffic for an OUI below 3.5M DC in escrow
+fn default_low_dc_threshold() -> u64 {
+    3_500_000
+}
+
+fn default_dc_check_interval_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct LoggingConfig {
+    /// Env: `LORAURBIT_LOGGING_LEVEL`
     pub level: String,
+    /// Write every decoded uplink as a JSON line to this file
+    #[serde(default)]
+    pub packet_log: Option<String>,
+    /// Roll the packet log over to `<packet_log>.1` at this size (0 = never)
+    #[serde(default = "default_packet_log_max_mb")]
+    pub packet_log_max_mb: u64,
+    /// Log line format, on the console and in `file`
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Also write logs to this file, rolled over per `file_rotation`
+    #[serde(default)]
+    pub file: Option<String>,
+    #[serde(default)]
+    pub file_rotation: LogRotation,
+}
+
+/// `urbit.mark_routes` entry: the mark uplinks from a device group are poked with
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct MarkRoute {
+    /// Hex DevAddr prefix (1-8 digits)
+    pub dev_addr_prefix: String,
+    /// Mark on the agent's desk, e.g. "sensor-uplink"
+    pub mark: String,
+}
+
+/// `urbit.encoding`: how a `LoRaAction` is serialized into its poke
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PokeEncoding {
+    /// The action's JSON, as the agent sees it in `%json` pokes
+    #[default]
+    Json,
+    /// MessagePack, base64 in a `%lora-msgpack` poke
+    Msgpack,
+}
+
+/// Log line format (`logging.format`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human-readable line per event
+    #[default]
+    Text,
+    /// Multi-line, indented events for local debugging
+    Pretty,
+    /// One JSON object per event, for log aggregation
+    Json,
+}
+
+/// When `logging.file` starts a new file, suffixed with the date (and hour)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    #[default]
+    Daily,
+    Hourly,
+    /// A single file at exactly `logging.file`
+    Never,
+}
+
+fn default_packet_log_max_mb() -> u64 {
+    100
 }
 
 impl Config {
+    /// Read, parse and validate a config file
     pub fn load(path: &Path) -> anyhow::Result<Self> {
         let content = std::fs::read_to_string(path)
             .map_err(|e| anyhow::anyhow!("Failed to read config file {:?}: {}", path, e))?;
-        let config: Config = toml::from_str(&content)
+        let mut config: Config = toml::from_str(&content)
             .map_err(|e| anyhow::anyhow!("Failed to parse config file: {}", e))?;
+        config.apply_env(env_var)?;
+        config.resolve_codes(env_var)?;
+        config
+            .validate()
+            .map_err(|e| anyhow::anyhow!("Invalid config file {:?}: {}", path, e))?;
         Ok(config)
     }
+
+    /// Defaults overlaid with environment variables (no config file)
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut config = Config::default();
+        config.apply_env(env_var)?;
+        config.resolve_codes(env_var)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Override fields from `LORAURBIT_*` variables looked up with `var`
+    ///
+    /// The `URBIT_*` variables apply to the first Urbit target. Without an
+    /// `[urbit]` section, `LORAURBIT_URBIT_URL`, `_SHIP` and `_CODE`
+    /// together enable the Urbit bridge.
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        let var = |name: &str| var(&format!("{}{}", ENV_PREFIX, name));
+
+        if let Some(bind) = var("UDP_BIND") {
+            self.udp.bind = bind.split(',').map(|b| b.trim().to_string()).collect();
+        }
+        if let Some(state_dir) = var("LORAWAN_STATE_DIR") {
+            self.lorawan.state_dir = Some(state_dir);
+        }
+        if let Some(level) = var("LOGGING_LEVEL") {
+            self.logging.level = level;
+        }
+
+        let (url, ship, code, agent) = (
+            var("URBIT_URL"),
+            var("URBIT_SHIP"),
+            var("URBIT_CODE"),
+            var("URBIT_AGENT"),
+        );
+        match self.urbit.first_mut() {
+            Some(urbit) => {
+                urbit.url = url.unwrap_or(urbit.url.clone());
+                urbit.ship = ship.unwrap_or(urbit.ship.clone());
+                urbit.code = code.unwrap_or(urbit.code.clone());
+                urbit.agent = agent.unwrap_or(urbit.agent.clone());
+            }
+            None => match (url, ship, code) {
+                (Some(url), Some(ship), Some(code)) => {
+                    let agent = agent.unwrap_or_else(|| "lora-agent".to_string());
+                    self.urbit.push(UrbitConfig::new(url, ship, code, agent));
+                }
+                (None, None, None) => {}
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "Without an [urbit] section, {0}URBIT_URL, {0}URBIT_SHIP and \
+                         {0}URBIT_CODE must all be set",
+                        ENV_PREFIX
+                    ))
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Fill in each Urbit target's `code` from `code_file` or `code_env`
+    ///
+    /// The file wins over the variable, and either over an inline `code`.
+    /// Errors name the file or variable but never include the code.
+    pub fn resolve_codes(&mut self, var: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        for urbit in &mut self.urbit {
+            if let Some(path) = &urbit.code_file {
+                let code = std::fs::read_to_string(path).map_err(|e| {
+                    anyhow::anyhow!("Failed to read urbit.code_file {:?}: {}", path, e)
+                })?;
+                urbit.code = code.trim().to_string();
+            } else if let Some(name) = &urbit.code_env {
+                urbit.code = var(name)
+                    .ok_or_else(|| anyhow::anyhow!("urbit.code_env {} is not set", name))?
+                    .trim()
+                    .to_string();
+            }
+            if urbit.code.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "No +code for ship {}: set urbit.code, urbit.code_file or urbit.code_env",
+                    urbit.ship
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Settings changed in `new` that only take effect after a restart
+    ///
+    /// `logging.level` and `lorawan.devices` are applied on reload (SIGHUP);
+    /// everything else is read once at startup.
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.general != new.general {
+            changed.push("general");
+        }
+        if self.udp.bind != new.udp.bind {
+            changed.push("udp.bind");
+        }
+        if self.udp.helium_sources != new.udp.helium_sources {
+            changed.push("udp.helium_sources");
+        }
+        if (self.udp.gateway_timeout_secs, self.udp.gateway_status_pokes)
+            != (new.udp.gateway_timeout_secs, new.udp.gateway_status_pokes)
+        {
+            changed.push("udp.gateway_timeout_secs");
+        }
+        if self.udp.keepalive_window_secs != new.udp.keepalive_window_secs {
+            changed.push("udp.keepalive_window_secs");
+        }
+        if (&self.udp.allowed_gateways, &self.udp.denied_gateways)
+            != (&new.udp.allowed_gateways, &new.udp.denied_gateways)
+        {
+            changed.push("udp.allowed_gateways");
+        }
+        let without_devices = |lorawan: &LorawanConfig| LorawanConfig {
+            devices: Vec::new(),
+            ..lorawan.clone()
+        };
+        if without_devices(&self.lorawan) != without_devices(&new.lorawan) {
+            changed.push("lorawan");
+        }
+        if self.channel_plan != new.channel_plan {
+            changed.push("channel_plan");
+        }
+        if self.duty_cycle != new.duty_cycle {
+            changed.push("duty_cycle");
+        }
+        if self.downlink != new.downlink {
+            changed.push("downlink");
+        }
+        if self.urbit != new.urbit {
+            changed.push("urbit");
+        }
+        if self.helium != new.helium {
+            changed.push("helium");
+        }
+        if self.health != new.health {
+            changed.push("health");
+        }
+        if self.admin != new.admin {
+            changed.push("admin");
+        }
+        if self.gateways != new.gateways {
+            changed.push("gateways");
+        }
+        if (&self.logging.packet_log, self.logging.packet_log_max_mb)
+            != (&new.logging.packet_log, new.logging.packet_log_max_mb)
+        {
+            changed.push("logging.packet_log");
+        }
+        if (self.logging.format, &self.logging.file, self.logging.file_rotation)
+            != (new.logging.format, &new.logging.file, new.logging.file_rotation)
+        {
+            changed.push("logging.format");
+        }
+        changed
+    }
+
+    /// Check field formats that serde cannot, naming the offending field
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.udp.bind.is_empty() {
+            return Err(anyhow::anyhow!("udp.bind needs at least one address"));
+        }
+        for bind in &self.udp.bind {
+            validate_bind(bind)?;
+        }
+
+        for urbit in &self.urbit {
+            validate_url(&urbit.url)?;
+            if !is_patp(&urbit.ship) {
+                return Err(anyhow::anyhow!(
+                    "urbit.ship {:?} is not a ship name like \"zod\" or \"~sampel-palnet\"",
+                    urbit.ship
+                ));
+            }
+            for prefix in &urbit.dev_addr_prefixes {
+                if !is_dev_addr_prefix(prefix) {
+                    return Err(anyhow::anyhow!(
+                        "urbit.dev_addr_prefixes entry {:?} must be 1-8 hex digits",
+                        prefix
+                    ));
+                }
+            }
+            for (i, route) in urbit.mark_routes.iter().enumerate() {
+                if !is_dev_addr_prefix(&route.dev_addr_prefix) {
+                    return Err(anyhow::anyhow!(
+                        "urbit.mark_routes dev_addr_prefix {:?} must be 1-8 hex digits",
+                        route.dev_addr_prefix
+                    ));
+                }
+                if !is_term(&route.mark) {
+                    return Err(anyhow::anyhow!(
+                        "urbit.mark_routes mark {:?} must be lowercase letters, digits and \
+                         '-', starting with a letter",
+                        route.mark
+                    ));
+                }
+                let prefix = &route.dev_addr_prefix;
+                if urbit.mark_routes[..i]
+                    .iter()
+                    .any(|earlier| earlier.dev_addr_prefix.eq_ignore_ascii_case(prefix))
+                {
+                    return Err(anyhow::anyhow!(
+                        "urbit.mark_routes has dev_addr_prefix {:?} more than once",
+                        prefix
+                    ));
+                }
+            }
+            for eui in &urbit.gateway_euis {
+                if eui.len() != 16 || !eui.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(anyhow::anyhow!(
+                        "urbit.gateway_euis entry {:?} must be 16 hex digits",
+                        eui
+                    ));
+                }
+            }
+            if urbit.outbox_poll_ms == 0 {
+                return Err(anyhow::anyhow!("urbit.outbox_poll_ms must be greater than 0"));
+            }
+            if urbit.connect_timeout_ms == 0 || urbit.request_timeout_ms == 0 {
+                return Err(anyhow::anyhow!(
+                    "urbit.connect_timeout_ms and request_timeout_ms must be greater than 0"
+                ));
+            }
+        }
+
+        for gateway in &self.gateways {
+            if gateway.eui.len() != 16 || !gateway.eui.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(anyhow::anyhow!(
+                    "gateways.eui {:?} must be 16 hex digits",
+                    gateway.eui
+                ));
+            }
+        }
+
+        if self.lorawan.encrypt_downlink && !cfg!(feature = "phase4") {
+            return Err(anyhow::anyhow!(
+                "lorawan.encrypt_downlink needs the phase4 feature; set it to false"
+            ));
+        }
+
+        if let Some(codr) = &self.downlink.coding_rate {
+            if !CODING_RATES.contains(&codr.as_str()) {
+                return Err(anyhow::anyhow!(
+                    "downlink.coding_rate {:?} must be one of {}",
+                    codr,
+                    CODING_RATES.join(", ")
+                ));
+            }
+        }
+
+        if let Some(admin) = &self.admin {
+            if admin.token.is_empty() {
+                return Err(anyhow::anyhow!("admin.token must be set to enable the admin API"));
+            }
+        }
+
+        if let Some(helium) = &self.helium {
+            if helium.net_id.len() != 6 || u32::from_str_radix(&helium.net_id, 16).is_err() {
+                return Err(anyhow::anyhow!(
+                    "helium.net_id {:?} must be 3 bytes of hex (6 digits, e.g. \"00003C\")",
+                    helium.net_id
+                ));
+            }
+        }
+
+        validate_log_level(&self.logging.level)
+    }
+}
+
+/// Settings the agent serves at `urbit.config_path`, each replacing the file's
+///
+/// `{"devices": [...], "channel_plan": {...}, "downlink": {...}}`, with the
+/// same fields as `lorawan.devices`, `[channel_plan]` and `[downlink]`; a
+/// section left out keeps the file's.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteConfig {
+    #[serde(default)]
+    pub devices: Option<Vec<AbpDeviceConfig>>,
+    #[serde(default)]
+    pub channel_plan: Option<ChannelPlanConfig>,
+    #[serde(default)]
+    pub downlink: Option<DownlinkConfig>,
+}
+
+impl RemoteConfig {
+    /// Parse a `/config` scry result
+    pub fn parse(value: &serde_json::Value) -> anyhow::Result<Self> {
+        serde_json::from_value(value.clone())
+            .map_err(|e| anyhow::anyhow!("unusable config scry result: {}", e))
+    }
+
+    /// `base` with these settings in place of its own, if the result is valid
+    pub fn merge_over(&self, base: &Config) -> anyhow::Result<Config> {
+        let mut merged = base.clone();
+        if let Some(devices) = &self.devices {
+            merged.lorawan.devices = devices.clone();
+        }
+        if let Some(channel_plan) = &self.channel_plan {
+            merged.channel_plan = channel_plan.clone();
+        }
+        if let Some(downlink) = &self.downlink {
+            merged.downlink = downlink.clone();
+        }
+        merged.validate()?;
+        KeyStore::from_config(&merged.lorawan.devices)?;
+        ChannelPlan::from_config(&merged.channel_plan)?;
+        Ok(merged)
+    }
+}
+
+/// Process environment lookup for `apply_env`
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// `udp.bind` must be `ip:port` or `host:port`
+fn validate_bind(bind: &str) -> anyhow::Result<()> {
+    if bind.parse::<std::net::SocketAddr>().is_ok() {
+        return Ok(());
+    }
+    let valid_host_port = bind
+        .rsplit_once(':')
+        .map(|(host, port)| !host.is_empty() && !host.contains(':') && port.parse::<u16>().is_ok())
+        .unwrap_or(false);
+    if valid_host_port {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "udp.bind {:?} must be an address like \"0.0.0.0:1680\" or \"[::]:1680\"",
+            bind
+        ))
+    }
+}
+
+/// `urbit.url` must be an http(s) URL with a host
+fn validate_url(url: &str) -> anyhow::Result<()> {
+    let host = url
+        .strip_prefix("http://")
+        .or_else(|| url.strip_prefix("https://"))
+        .map(|rest| rest.split(['/', '?', '#']).next().unwrap_or(""));
+    match host {
+        Some(host) if !host.is_empty() && !host.contains(char::is_whitespace) => Ok(()),
+        _ => Err(anyhow::anyhow!(
+            "urbit.url {:?} must be an http:// or https:// URL (e.g. \"http://localhost:8080\")",
+            url
+        )),
+    }
+}
+
+/// Whether `ship` looks like an @p: an optional `~`, then one 3-letter
+/// galaxy name or 6-letter words joined by `-` (`--` for comets)
+fn is_patp(ship: &str) -> bool {
+    let name = ship.strip_prefix('~').unwrap_or(ship);
+    if name.len() == 3 {
+        return name.bytes().all(|b| b.is_ascii_lowercase());
+    }
+    let words: Vec<&str> = name.split('-').filter(|w| !w.is_empty()).collect();
+    !words.is_empty()
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && words
+            .iter()
+            .all(|w| w.len() == 6 && w.bytes().all(|b| b.is_ascii_lowercase()))
+}
+
+/// Whether `prefix` is 1-8 hex digits of a DevAddr
+fn is_dev_addr_prefix(prefix: &str) -> bool {
+    (1..=8).contains(&prefix.len()) && prefix.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Whether `name` is a Hoon `@tas` (as marks are): lowercase letters,
+/// digits and `-`, starting with a letter
+fn is_term(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+/// `logging.level` must be a level, optionally as `target=level` directives
+fn validate_log_level(level: &str) -> anyhow::Result<()> {
+    const LEVELS: [&str; 6] = ["trace", "debug", "info", "warn", "error", "off"];
+    for directive in level.split(',') {
+        let lvl = directive.rsplit_once('=').map(|(_, l)| l).unwrap_or(directive);
+        if !LEVELS.contains(&lvl.trim().to_ascii_lowercase().as_str()) {
+            return Err(anyhow::anyhow!(
+                "logging.level {:?}: unknown level {:?} (expected one of {})",
+                level,
+                lvl,
+                LEVELS.join(", ")
+            ));
+        }
+    }
+    Ok(())
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            general: GeneralConfig::default(),
             udp: UdpConfig {
-                bind: "0.0.0.0:1680".to_string(),
+                bind: vec!["0.0.0.0:1680".to_string()],
+                helium_sources: Vec::new(),
+                gateway_timeout_secs: default_gateway_timeout_secs(),
+                keepalive_window_secs: default_keepalive_window_secs(),
+                gateway_status_pokes: false,
+                allowed_gateways: Vec::new(),
+                denied_gateways: Vec::new(),
+                accept_protocol_v1: false,
+                dedup_window_ms: 0,
+                max_json_len: default_max_json_len(),
             },
             lorawan: LorawanConfig {
                 decrypt_payload: false,
+                encrypt_downlink: default_encrypt_downlink(),
+                codec: None,
+                device_codecs: HashMap::new(),
+                state_dir: None,
+                devices: Vec::new(),
+                fcnt_reset_tolerance: default_fcnt_reset_tolerance(),
+                accept_net_ids: Vec::new(),
+                auto_ack_confirmed: default_auto_ack_confirmed(),
+                reject_unknown_major: false,
             },
-            urbit: None,
+            channel_plan: ChannelPlanConfig::default(),
+            duty_cycle: DutyCycleConfig::default(),
+            downlink: DownlinkConfig::default(),
+            urbit: Vec::new(),
             helium: None,
             logging: LoggingConfig {
                 level: "info".to_string(),
+                packet_log: None,
+                packet_log_max_mb: default_packet_log_max_mb(),
+                format: LogFormat::Text,
+                file: None,
+                file_rotation: LogRotation::Daily,
             },
+            capture: None,
+            health: None,
+            admin: None,
+            gateways: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mark_route(dev_addr_prefix: &str, mark: &str) -> MarkRoute {
+        MarkRoute {
+            dev_addr_prefix: dev_addr_prefix.to_string(),
+            mark: mark.to_string(),
+        }
+    }
+
+    fn with_urbit() -> Config {
+        Config {
+            urbit: vec![UrbitConfig {
+                url: "http://localhost:8080".to_string(),
+                ship: "zod".to_string(),
+                code: "lidlut-tabwed-pillex-ridrup".to_string(),
+                code_file: None,
+                code_env: None,
+                agent: "lora-agent".to_string(),
+                max_in_flight: default_max_in_flight(),
+                max_tx_attempts: default_max_tx_attempts(),
+                prioritize_confirmed: true,
+                dev_addr_prefixes: Vec::new(),
+                gateway_euis: Vec::new(),
+                mark_routes: Vec::new(),
+                heartbeat_secs: default_heartbeat_secs(),
+                typed_marks: false,
+                encoding: PokeEncoding::Json,
+                authorize_joins: false,
+                poke_batch_max: 1,
+                poke_batch_ms: 20,
+                outbox_path: default_outbox_path(),
+                outbox_poll_ms: default_outbox_poll_ms(),
+                outbox_poll_jitter_ms: 0,
+                outbox_subscribe_path: None,
+                config_path: None,
+                config_poll_secs: 300,
+                connect_timeout_ms: default_connect_timeout_ms(),
+                request_timeout_ms: default_request_timeout_ms(),
+            }],
+            helium: Some(HeliumConfig {
+                oui: 1,
+                net_id: "00003C".to_string(),
+                config_host: "http://localhost:50051".to_string(),
+                delegate_keypair: "./delegate.key".to_string(),
+                route_id: None,
+                low_dc_threshold: default_low_dc_threshold(),
+                dc_check_interval_secs: default_dc_check_interval_secs(),
+                packet_router_host: None,
+            }),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_validate_ok() {
+        Config::default().validate().unwrap();
+        let mut config = with_urbit();
+        config.validate().unwrap();
+
+        config.udp.bind = vec!["localhost:1700".to_string(), "[::]:1701".to_string()];
+        config.logging.level = "lora_urbit=debug,warn".to_string();
+        config.urbit[0].ship = "~sampel-palnet".to_string();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_validate_failures() {
+        type Breaker = fn(&mut Config);
+        let cases: [(&str, Breaker); 15] = [
+            ("udp.bind", |c| c.udp.bind = vec!["0.0.0.0".to_string()]),
+            ("udp.bind", |c| c.udp.bind.clear()),
+            ("urbit.url", |c| c.urbit[0].url = "localhost:8080".to_string()),
+            ("urbit.ship", |c| c.urbit[0].ship = "Zod".to_string()),
+            ("urbit.ship", |c| c.urbit[0].ship = "~sampel-pal".to_string()),
+            ("urbit.dev_addr_prefixes", |c| c.urbit[0].dev_addr_prefixes = vec!["26G".into()]),
+            ("urbit.gateway_euis", |c| c.urbit[0].gateway_euis = vec!["aabb".into()]),
+            ("urbit.mark_routes", |c| c.urbit[0].mark_routes = vec![mark_route("26", "Sensor")]),
+            ("urbit.mark_routes", |c| {
+                c.urbit[0].mark_routes = vec![mark_route("26", "a"), mark_route("26", "b")]
+            }),
+            ("urbit.outbox_poll_ms", |c| c.urbit[0].outbox_poll_ms = 0),
+            ("urbit.connect_timeout_ms", |c| c.urbit[0].request_timeout_ms = 0),
+            ("helium.net_id", |c| c.helium.as_mut().unwrap().net_id = "3C".to_string()),
+            ("downlink.coding_rate", |c| c.downlink.coding_rate = Some("4/9".to_string())),
+            ("admin.token", |c| {
+                c.admin = Some(AdminConfig { bind: default_admin_bind(), token: String::new() })
+            }),
+            ("logging.level", |c| c.logging.level = "verbose".to_string()),
+        ];
+        for (field, break_config) in cases {
+            let mut config = with_urbit();
+            break_config(&mut config);
+            let err = config.validate().unwrap_err().to_string();
+            assert!(err.starts_with(field), "{}: {}", field, err);
+        }
+    }
+
+    #[test]
+    fn test_encrypt_downlink_needs_phase4() {
+        let mut config = Config::default();
+        assert_eq!(config.lorawan.encrypt_downlink, cfg!(feature = "phase4"));
+        config.validate().unwrap();
+
+        config.lorawan.encrypt_downlink = true;
+        let result = config.validate();
+        assert_eq!(result.is_ok(), cfg!(feature = "phase4"));
+        if let Err(e) = result {
+            assert!(e.to_string().starts_with("lorawan.encrypt_downlink"), "{}", e);
         }
     }
+
+    #[test]
+    fn test_load_rejects_invalid_file() {
+        let path = std::env::temp_dir()
+            .join(format!("lora-urbit-config-{}.toml", std::process::id()));
+        let content = r#"
+            [udp]
+            bind = "0.0.0.0:1680"
+            [lorawan]
+            decrypt_payload = false
+            [logging]
+            level = "loud"
+        "#;
+        std::fs::write(&path, content).unwrap();
+        let err = Config::load(&path).unwrap_err().to_string();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.contains("logging.level"), "{}", err);
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let env: HashMap<&str, &str> = [
+            ("LORAURBIT_UDP_BIND", "[::]:1700, 10.8.0.1:1701"),
+            ("LORAURBIT_URBIT_CODE", "from-env"),
+            ("LORAURBIT_LOGGING_LEVEL", "debug"),
+        ]
+        .into_iter()
+        .collect();
+        let lookup = |name: &str| env.get(name).map(|v| v.to_string());
+
+        // Env wins over file values; fields without a variable keep theirs
+        let mut config = with_urbit();
+        config.apply_env(lookup).unwrap();
+        assert_eq!(config.udp.bind, vec!["[::]:1700", "10.8.0.1:1701"]);
+        assert_eq!(config.logging.level, "debug");
+        let urbit = &config.urbit[0];
+        assert_eq!(urbit.code, "from-env");
+        assert_eq!(urbit.ship, "zod");
+
+        // Without a file section, URL + ship + code create one
+        let env: HashMap<&str, &str> = [
+            ("LORAURBIT_URBIT_URL", "https://ship.example.com"),
+            ("LORAURBIT_URBIT_SHIP", "~sampel-palnet"),
+            ("LORAURBIT_URBIT_CODE", "secret"),
+        ]
+        .into_iter()
+        .collect();
+        let mut config = Config::default();
+        config.apply_env(|name| env.get(name).map(|v| v.to_string())).unwrap();
+        let urbit = &config.urbit[0];
+        assert_eq!(urbit.url, "https://ship.example.com");
+        assert_eq!(urbit.agent, "lora-agent");
+        config.validate().unwrap();
+        // Everything else as if the file had a minimal [urbit] section
+        let section: UrbitConfig = toml::from_str(
+            r#"
+            url = "https://ship.example.com"
+            ship = "~sampel-palnet"
+            code = "secret"
+            agent = "lora-agent"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(urbit, &section);
+
+        // ...but a partial set is an error
+        let mut config = Config::default();
+        let partial = |name: &str| (name == "LORAURBIT_URBIT_CODE").then(|| "secret".to_string());
+        assert!(config.apply_env(partial).is_err());
+    }
+
+    #[test]
+    fn test_debug_redacts_secrets() {
+        let mut config = with_urbit();
+        config.helium = Some(HeliumConfig {
+            oui: 1,
+            net_id: "00003C".to_string(),
+            config_host: "https://config.example.com".to_string(),
+            delegate_keypair: "./keys/delegate-secret.bin".to_string(),
+            route_id: None,
+            low_dc_threshold: default_low_dc_threshold(),
+            dc_check_interval_secs: default_dc_check_interval_secs(),
+            packet_router_host: None,
+        });
+        config.admin = Some(AdminConfig {
+            bind: default_admin_bind(),
+            token: "admin-secret".to_string(),
+        });
+        config.lorawan.devices = vec![AbpDeviceConfig {
+            dev_addr: "260B1234".to_string(),
+            nwk_s_key: "44024241ed4ce9a68c6a8bc055233fd3".to_string(),
+            app_s_key: "ec925802ae430ca77fd3dd73cb2cc588".to_string(),
+            lorawan_version: LorawanVersion::V1_1,
+            s_nwk_s_int_key: Some("00112233445566778899aabbccddeeff".to_string()),
+            class: DeviceClass::A,
+            ping_slot_periodicity: default_ping_slot_periodicity(),
+            rx2_datr: None,
+            rx1_delay: default_rx1_delay(),
+        }];
+
+        let dump = format!("{:?}", config);
+        assert!(!dump.contains("lidlut-tabwed-pillex-ridrup"), "{}", dump);
+        assert!(!dump.contains("delegate-secret"), "{}", dump);
+        assert!(!dump.contains("admin-secret"), "{}", dump);
+        for key in ["44024241", "ec925802", "00112233"] {
+            assert!(!dump.contains(key), "{}", dump);
+        }
+        assert!(dump.contains("s_nwk_s_int_key: Some(***)"), "{}", dump);
+        assert!(dump.contains("code: ***"), "{}", dump);
+        // Everything else is still there
+        assert!(dump.contains(r#"ship: "zod""#), "{}", dump);
+        assert!(dump.contains(r#"net_id: "00003C""#), "{}", dump);
+        assert!(dump.contains(r#"dev_addr: "260B1234""#), "{}", dump);
+    }
+
+    #[test]
+    fn test_resolve_codes() {
+        let path = std::env::temp_dir()
+            .join(format!("lora-urbit-code-{}", std::process::id()));
+        std::fs::write(&path, "lidlut-tabwed-pillex-ridrup\n").unwrap();
+        let no_env = |_: &str| None;
+
+        // The file wins over the inline code; trailing newline is trimmed
+        let mut config = with_urbit();
+        config.urbit[0].code = "inline".to_string();
+        config.urbit[0].code_file = Some(path.to_string_lossy().into_owned());
+        config.resolve_codes(no_env).unwrap();
+        assert_eq!(config.urbit[0].code, "lidlut-tabwed-pillex-ridrup");
+        std::fs::remove_file(&path).unwrap();
+
+        // A missing file is an error naming the file
+        let err = config.resolve_codes(no_env).unwrap_err().to_string();
+        assert!(err.contains("urbit.code_file"), "{}", err);
+
+        // Env var reference
+        let mut config = with_urbit();
+        config.urbit[0].code = String::new();
+        config.urbit[0].code_env = Some("URBIT_CODE".to_string());
+        assert!(config.clone().resolve_codes(no_env).is_err());
+        config
+            .resolve_codes(|name| (name == "URBIT_CODE").then(|| "from-env".to_string()))
+            .unwrap();
+        assert_eq!(config.urbit[0].code, "from-env");
+
+        // Neither inline code nor a reference
+        let mut config = with_urbit();
+        config.urbit[0].code = String::new();
+        let err = config.resolve_codes(no_env).unwrap_err().to_string();
+        assert!(err.contains("urbit.code_file"), "{}", err);
+    }
+
+    #[test]
+    fn test_urbit_one_or_many() {
+        let base = r#"
+            [udp]
+            bind = "0.0.0.0:1680"
+            [lorawan]
+            decrypt_payload = false
+            [logging]
+            level = "info"
+        "#;
+        let single: Config = toml::from_str(&format!(
+            r#"{}
+            [urbit]
+            url = "http://localhost:8080"
+            ship = "zod"
+            code = "c"
+            agent = "lora-agent"
+            "#,
+            base
+        ))
+        .unwrap();
+        assert_eq!(single.urbit.len(), 1);
+        assert!(single.urbit[0].dev_addr_prefixes.is_empty());
+
+        let many: Config = toml::from_str(&format!(
+            r#"{}
+            [[urbit]]
+            url = "http://localhost:8080"
+            ship = "zod"
+            code = "c"
+            agent = "lora-agent"
+            dev_addr_prefixes = ["260B"]
+            mark_routes = [{{ dev_addr_prefix = "260B", mark = "sensor-uplink" }}]
+
+            [[urbit]]
+            url = "http://localhost:8081"
+            ship = "nec"
+            code = "c"
+            agent = "fleet-agent"
+            gateway_euis = ["aabbccddeeff0011"]
+            "#,
+            base
+        ))
+        .unwrap();
+        assert_eq!(many.urbit.len(), 2);
+        assert_eq!(many.urbit[1].ship, "nec");
+        assert_eq!(many.urbit[0].mark_routes, vec![mark_route("260B", "sensor-uplink")]);
+        many.validate().unwrap();
+
+        let none: Config = toml::from_str(base).unwrap();
+        assert!(none.urbit.is_empty());
+    }
+
+    #[test]
+    fn test_restart_required() {
+        let current = with_urbit();
+        let mut new = current.clone();
+        new.logging.level = "debug".to_string();
+        new.lorawan.devices.push(AbpDeviceConfig {
+            dev_addr: "260B1234".to_string(),
+            nwk_s_key: "00000000000000000000000000000000".to_string(),
+            app_s_key: "00000000000000000000000000000000".to_string(),
+            lorawan_version: LorawanVersion::V1_0,
+            s_nwk_s_int_key: None,
+            class: DeviceClass::A,
+            ping_slot_periodicity: default_ping_slot_periodicity(),
+            rx2_datr: None,
+            rx1_delay: default_rx1_delay(),
+        });
+        // Hot-reloadable changes only
+        assert!(current.restart_required(&new).is_empty());
+
+        new.udp.bind = vec!["0.0.0.0:1700".to_string()];
+        new.lorawan.fcnt_reset_tolerance = 4;
+        new.urbit[0].code = "new-code".to_string();
+        assert_eq!(current.restart_required(&new), vec!["udp.bind", "lorawan", "urbit"]);
+    }
+
+    #[test]
+    fn test_remote_config_merge() {
+        let scry = serde_json::json!({
+            "devices": [{
+                "dev_addr": "260B1234",
+                "nwk_s_key": "44024241ed4ce9a68c6a8bc055233fd3",
+                "app_s_key": "ec925802ae430ca77fd3dd73cb2cc588",
+                "class": "C"
+            }],
+            "channel_plan": {"region": "eu868", "rx2_datr": "SF9BW125"}
+        });
+        let base = Config::default();
+        let merged = RemoteConfig::parse(&scry).unwrap().merge_over(&base).unwrap();
+        assert_eq!(merged.lorawan.devices.len(), 1);
+        assert_eq!(merged.lorawan.devices[0].class, DeviceClass::C);
+        assert_eq!(merged.channel_plan.region, Region::EU868);
+        assert_eq!(merged.channel_plan.rx2_datr, Some(DataRate::lora(9, 125)));
+        // Sections the scry leaves out keep the file's
+        assert_eq!(merged.downlink, base.downlink);
+        assert_eq!(merged.udp, base.udp);
+
+        // An empty result changes nothing; invalid ones are refused
+        let empty = RemoteConfig::parse(&serde_json::json!({})).unwrap();
+        assert_eq!(empty.merge_over(&base).unwrap(), base);
+        assert!(RemoteConfig::parse(&serde_json::json!({"channel-plan": {}})).is_err());
+        assert!(RemoteConfig::parse(&serde_json::json!(["260B1234"])).is_err());
+        let bad_key = serde_json::json!({"devices": [{
+            "dev_addr": "260B1234", "nwk_s_key": "00", "app_s_key": "00"
+        }]});
+        assert!(RemoteConfig::parse(&bad_key).unwrap().merge_over(&base).is_err());
+        let bad_codr = serde_json::json!({"downlink": {"coding_rate": "4/9"}});
+        assert!(RemoteConfig::parse(&bad_codr).unwrap().merge_over(&base).is_err());
+    }
 }
@@ -0,0 +1,251 @@
+//! Liveness and readiness probes over HTTP (`[health]`)
+//!
+//! A deliberately tiny HTTP/1.1 responder for container orchestrators:
+//!
+//!   GET /healthz  200 while the process is up
+//!   GET /readyz   200 once the UDP socket is bound, a gateway has been
+//!                 heard from and every Airlock target is connected;
+//!                 503 with the first unmet condition otherwise
+//!   GET /devices  JSON statistics of every device heard from, by DevAddr
+//!   GET /metrics  OpenMetrics histograms of uplink RSSI and SNR, drop counters
+//!
+//! Each connection gets one response and is then closed.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+use crate::metrics::UplinkMetrics;
+use crate::udp::{DeviceRegistry, GatewaysSeen};
+
+/// Largest request head read before answering
+const MAX_REQUEST_LEN: usize = 4096;
+
+/// Readiness conditions, updated by the UDP server and Airlock tasks
+#[derive(Debug, Clone, Default)]
+pub struct Health {
+    inner: Arc<Mutex<HealthState>>,
+}
+
+#[derive(Debug, Default)]
+struct HealthState {
+    /// Set once the UDP server is listening
+    gateways_seen: Option<GatewaysSeen>,
+    /// Connection state per Airlock target
+    airlock: BTreeMap<String, bool>,
+    /// Set once the UDP server is listening
+    devices: Option<DeviceRegistry>,
+    /// Set once the UDP server is listening
+    uplink_metrics: Option<UplinkMetrics>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The UDP server is listening and records gateways in `gateways_seen`
+    pub fn set_udp_bound(&self, gateways_seen: GatewaysSeen) {
+        self.lock().gateways_seen = Some(gateways_seen);
+    }
+
+    /// Serve the UDP server's device statistics on `/devices`
+    pub fn set_devices(&self, devices: DeviceRegistry) {
+        self.lock().devices = Some(devices);
+    }
+
+    /// Device statistics as JSON (empty until the UDP server is listening)
+    pub fn devices_json(&self) -> serde_json::Value {
+        match &self.lock().devices {
+            Some(devices) => devices.to_json(),
+            None => serde_json::json!({}),
+        }
+    }
+
+    /// Serve the UDP server's uplink histograms on `/metrics`
+    pub fn set_uplink_metrics(&self, metrics: UplinkMetrics) {
+        self.lock().uplink_metrics = Some(metrics);
+    }
+
+    /// The OpenMetrics exposition (just `# EOF` until the UDP server is listening)
+    pub fn metrics_text(&self) -> String {
+        match &self.lock().uplink_metrics {
+            Some(metrics) => metrics.render(),
+            None => "# EOF\n".to_string(),
+        }
+    }
+
+    /// Record whether an Airlock target is connected (registering it if new)
+    pub fn set_airlock_connected(&self, target: &str, connected: bool) {
+        self.lock().airlock.insert(target.to_string(), connected);
+    }
+
+    /// Why the bridge is not ready, or None if it is
+    pub fn not_ready_reason(&self) -> Option<String> {
+        let state = self.lock();
+        match &state.gateways_seen {
+            None => return Some("UDP server not listening".to_string()),
+            Some(seen) if seen.count() == 0 => return Some("no gateway seen yet".to_string()),
+            Some(_) => {}
+        }
+        state
+            .airlock
+            .iter()
+            .find(|(_, connected)| !**connected)
+            .map(|(target, _)| format!("Airlock {} not connected", target))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HealthState> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Answer probes on `listener` until `shutdown` is cancelled
+pub async fn serve(listener: TcpListener, health: Health, shutdown: CancellationToken) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Health endpoint listening on http://{}", addr);
+    }
+    loop {
+        let stream = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    debug!("Health endpoint accept failed: {}", e);
+                    continue;
+                }
+            },
+        };
+        let health = health.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &health).await {
+                debug!("Health probe failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Read one request head and write the probe's response
+async fn respond(mut stream: TcpStream, health: &Health) -> std::io::Result<()> {
+    let mut buf = Vec::with_capacity(512);
+    let mut chunk = [0u8; 512];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < MAX_REQUEST_LEN {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let request_line = String::from_utf8_lossy(&buf);
+    let mut parts = request_line.split_whitespace();
+    let mut content_type = "text/plain";
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/healthz")) => ("200 OK", "ok\n".to_string()),
+        (Some("GET"), Some("/readyz")) => match health.not_ready_reason() {
+            None => ("200 OK", "ready\n".to_string()),
+            Some(reason) => ("503 Service Unavailable", format!("{}\n", reason)),
+        },
+        (Some("GET"), Some("/devices")) => {
+            content_type = "application/json";
+            ("200 OK", format!("{}\n", health.devices_json()))
+        }
+        (Some("GET"), Some("/metrics")) => {
+            content_type = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+            ("200 OK", health.metrics_text())
+        }
+        (Some("GET"), _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn test_probes() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let health = Health::new();
+            let shutdown = CancellationToken::new();
+            let task = tokio::spawn(serve(listener, health.clone(), shutdown.clone()));
+
+            assert!(get(addr, "/healthz").await.starts_with("HTTP/1.1 200"));
+            let response = get(addr, "/readyz").await;
+            assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+            assert!(response.ends_with("UDP server not listening\n"), "{}", response);
+
+            // Bound, but no gateway yet
+            let gateways = GatewaysSeen::new();
+            health.set_udp_bound(gateways.clone());
+            health.set_airlock_connected("%lora-agent on ~zod", false);
+            assert!(get(addr, "/readyz").await.ends_with("no gateway seen yet\n"));
+
+            // Gateway seen, Airlock still disconnected
+            gateways.record("aabbccddeeff0011");
+            let response = get(addr, "/readyz").await;
+            assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+            assert!(response.contains("Airlock %lora-agent on ~zod not connected"));
+
+            health.set_airlock_connected("%lora-agent on ~zod", true);
+            assert!(get(addr, "/readyz").await.starts_with("HTTP/1.1 200"));
+            // Liveness doesn't depend on readiness
+            health.set_airlock_connected("%lora-agent on ~zod", false);
+            assert!(get(addr, "/healthz").await.starts_with("HTTP/1.1 200"));
+            assert!(get(addr, "/nope").await.starts_with("HTTP/1.1 404"));
+
+            let response = get(addr, "/devices").await;
+            assert!(response.ends_with("\r\n\r\n{}\n"), "{}", response);
+            let devices = DeviceRegistry::new();
+            health.set_devices(devices.clone());
+            devices.record(&crate::udp::devices::Sighting {
+                dev_addr: 0x260B_1234,
+                fcnt: 3,
+                rssi: -40.0,
+                snr: None,
+                gateway_eui: "aabbccddeeff0011",
+                path: Default::default(),
+            });
+            let response = get(addr, "/devices").await;
+            assert!(response.contains("Content-Type: application/json"), "{}", response);
+            assert!(response.contains(r#""260B1234":{"#), "{}", response);
+
+            let metrics = UplinkMetrics::new();
+            health.set_uplink_metrics(metrics.clone());
+            metrics.observe(-40.0, None);
+            let response = get(addr, "/metrics").await;
+            assert!(response.contains("application/openmetrics-text"), "{}", response);
+            assert!(response.contains("lora_uplink_rssi_dbm_count 1\n"), "{}", response);
+
+            shutdown.cancel();
+            task.await.unwrap();
+        });
+    }
+}
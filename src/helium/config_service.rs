@@ -0,0 +1,178 @@
+//! Helium IoT Config Service gRPC client
+//!
+//! The Config Service manages an OUI's routes, EUI pairs and DevAddr ranges.
+//! Every mutating request carries a timestamp and is signed with the OUI's
+//! delegate keypair; the service rejects unsigned or stale requests with
+//! `PERMISSION_DENIED`.
+//!
+//! Messages come from the hand-written `proto` module and are sent with
+//! tonic's generic client, so no protoc/codegen step is needed.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tonic::client::Grpc;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tonic_prost::ProstCodec;
+use tracing::{debug, info};
+
+use super::keypair::DelegateKeypair;
+use super::proto::{self, path, ActionV1};
+
+/// Authenticated connection to the Config Service
+#[derive(Clone)]
+pub struct ConfigServiceClient {
+    grpc: Grpc<Channel>,
+    keypair: std::sync::Arc<DelegateKeypair>,
+}
+
+impl ConfigServiceClient {
+    /// Dial the Config Service at `host` (http:// or https://)
+    pub async fn connect(host: &str, keypair: DelegateKeypair) -> anyhow::Result<Self> {
+        let channel = dial(host, "config_host", "Config Service").await?;
+
+        info!("Connected to Helium Config Service at {}", host);
+        Ok(Self {
+            grpc: Grpc::new(channel),
+            keypair: std::sync::Arc::new(keypair),
+        })
+    }
+
+    /// Create a route for `oui` (route_v1 id is assigned by the service)
+    pub async fn create_route(
+        &self,
+        oui: u64,
+        route: proto::RouteV1,
+    ) -> anyhow::Result<proto::RouteV1> {
+        let mut request = proto::RouteCreateReqV1 {
+            oui,
+            route: Some(route),
+            timestamp: now_millis(),
+            ..Default::default()
+        };
+        self.keypair.sign(&mut request);
+
+        let response: proto::RouteResV1 = self.unary(path::ROUTE_CREATE, request).await?;
+        response
+            .route
+            .ok_or_else(|| anyhow::anyhow!("Config Service route/create returned no route"))
+    }
+
+    /// Fetch the OUI's org record, including its DC escrow balance if reported
+    pub async fn get_org(&self, oui: u64) -> anyhow::Result<proto::OrgResV1> {
+        self.unary(path::ORG_GET, proto::OrgGetReqV1 { oui }).await
+    }
+
+    /// Add or remove AppEUI/DevEUI pairs on a route
+    pub async fn update_euis(
+        &self,
+        action: ActionV1,
+        pairs: Vec<proto::EuiPairV1>,
+    ) -> anyhow::Result<()> {
+        let requests: Vec<proto::RouteUpdateEuisReqV1> = pairs
+            .into_iter()
+            .map(|pair| {
+                let mut request = proto::RouteUpdateEuisReqV1 {
+                    action: action as i32,
+                    eui_pair: Some(pair),
+                    timestamp: now_millis(),
+                    ..Default::default()
+                };
+                self.keypair.sign(&mut request);
+                request
+            })
+            .collect();
+
+        let mut grpc = self.grpc.clone();
+        grpc.ready()
+            .await
+            .map_err(|e| anyhow::anyhow!("Config Service not ready: {}", e))?;
+        let _: tonic::Response<proto::RouteEuisResV1> = grpc
+            .client_streaming(
+                tonic::Request::new(tokio_stream::iter(requests)),
+                PathAndQuery::from_static(path::ROUTE_UPDATE_EUIS),
+                ProstCodec::default(),
+            )
+            .await
+            .map_err(|status| status_error(path::ROUTE_UPDATE_EUIS, status))?;
+        Ok(())
+    }
+
+    /// Send a single unary request
+    pub(crate) async fn unary<Req, Res>(
+        &self,
+        rpc: &'static str,
+        request: Req,
+    ) -> anyhow::Result<Res>
+    where
+        Req: prost::Message + Send + Sync + 'static,
+        Res: prost::Message + Default + Send + Sync + 'static,
+    {
+        debug!("Config Service call {}", rpc);
+        let mut grpc = self.grpc.clone();
+        grpc.ready()
+            .await
+            .map_err(|e| anyhow::anyhow!("Config Service not ready: {}", e))?;
+        let response = grpc
+            .unary(
+                tonic::Request::new(request),
+                PathAndQuery::from_static(rpc),
+                ProstCodec::default(),
+            )
+            .await
+            .map_err(|status| status_error(rpc, status))?;
+        Ok(response.into_inner())
+    }
+}
+
+/// Open a channel to `host` (http:// or https://), naming `setting` and `service` in errors
+pub(crate) async fn dial(host: &str, setting: &str, service: &str) -> anyhow::Result<Channel> {
+    let mut endpoint = Endpoint::from_shared(host.to_string())
+        .map_err(|e| anyhow::anyhow!("Invalid {} {:?}: {}", setting, host, e))?;
+    if host.starts_with("https://") {
+        endpoint = endpoint
+            .tls_config(ClientTlsConfig::new().with_webpki_roots())
+            .map_err(|e| anyhow::anyhow!("Failed to configure TLS for {}: {}", host, e))?;
+    }
+
+    endpoint
+        .connect()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to connect to {} {}: {}", service, host, e))
+}
+
+/// Turn a gRPC status into an error naming the RPC, status code and message
+pub fn status_error(rpc: &str, status: tonic::Status) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Config Service {} failed: {:?}: {}",
+        rpc,
+        status.code(),
+        status.message()
+    )
+}
+
+/// Milliseconds since the Unix epoch (signed request timestamps)
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Parse a 16-hex-digit EUI into its 64-bit value
+pub fn parse_eui(eui: &str) -> anyhow::Result<u64> {
+    if eui.len() != 16 {
+        return Err(anyhow::anyhow!("EUI {:?} must be 16 hex digits", eui));
+    }
+    u64::from_str_radix(eui, 16).map_err(|e| anyhow::anyhow!("Invalid EUI {:?}: {}", eui, e))
+}
+
+/// Parse a NetID hex string (e.g. "00003C") into its 24-bit value
+pub fn parse_net_id(net_id: &str) -> anyhow::Result<u32> {
+    let value = u32::from_str_radix(net_id, 16)
+        .map_err(|e| anyhow::anyhow!("Invalid NetID {:?}: {}", net_id, e))?;
+    if value > 0xFF_FFFF {
+        return Err(anyhow::anyhow!("NetID {:?} exceeds 24 bits", net_id));
+    }
+    Ok(value)
+}
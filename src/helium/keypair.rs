@@ -0,0 +1,166 @@
+//! OUI delegate keypair used to sign Config Service requests
+//!
+//! The keypair file is in helium-crypto binary format as written by
+//! `helium-config-service-cli env generate-keypair`:
+//!
+//!   KeyTag(1) | Ed25519 secret(32) | Ed25519 public(32)
+//!
+//! KeyTag = network (0x00 mainnet, 0x10 testnet) | key type (0x01 Ed25519).
+//! The signer bytes sent with each request are KeyTag | public key.
+
+use ed25519_dalek::{Signer, SigningKey};
+use std::path::Path;
+
+use super::proto::SignedRequest;
+
+/// Helium key type for Ed25519 keys (low nibble of the KeyTag)
+const KEY_TYPE_ED25519: u8 = 0x01;
+
+/// Length of a binary Ed25519 keypair: tag + secret + public
+const KEYPAIR_LEN: usize = 1 + 32 + 32;
+
+/// Ed25519 delegate keypair for signing Config Service requests
+pub struct DelegateKeypair {
+    tag: u8,
+    signing_key: SigningKey,
+}
+
+impl DelegateKeypair {
+    /// Load a binary keypair file (see module docs for the format)
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read delegate keypair {:?}: {}", path, e))?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Parse a binary keypair
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        if bytes.len() != KEYPAIR_LEN {
+            return Err(anyhow::anyhow!(
+                "Delegate keypair must be {} bytes, got {}",
+                KEYPAIR_LEN,
+                bytes.len()
+            ));
+        }
+
+        let tag = bytes[0];
+        if tag & 0x0F != KEY_TYPE_ED25519 {
+            return Err(anyhow::anyhow!(
+                "Unsupported delegate key type 0x{:02x} (only Ed25519 is supported)",
+                tag & 0x0F
+            ));
+        }
+
+        let secret: [u8; 32] = bytes[1..33].try_into()?;
+        let signing_key = SigningKey::from_bytes(&secret);
+        if signing_key.verifying_key().as_bytes() != &bytes[33..] {
+            return Err(anyhow::anyhow!(
+                "Delegate keypair public key does not match its secret key"
+            ));
+        }
+
+        Ok(Self { tag, signing_key })
+    }
+
+    /// Build a mainnet keypair from a raw Ed25519 secret key
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        Self {
+            tag: KEY_TYPE_ED25519,
+            signing_key: SigningKey::from_bytes(&secret),
+        }
+    }
+
+    /// Encode as a binary keypair file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(KEYPAIR_LEN);
+        bytes.push(self.tag);
+        bytes.extend_from_slice(self.signing_key.as_bytes());
+        bytes.extend_from_slice(self.signing_key.verifying_key().as_bytes());
+        bytes
+    }
+
+    /// Public key in Helium binary form (KeyTag | public key)
+    pub fn public_key_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(33);
+        bytes.push(self.tag);
+        bytes.extend_from_slice(self.signing_key.verifying_key().as_bytes());
+        bytes
+    }
+
+    /// Set the signer and sign the request over its encoding with an empty signature
+    pub fn sign<R: SignedRequest>(&self, request: &mut R) {
+        request.set_signer(self.public_key_bytes());
+        request.set_signature(Vec::new());
+        let signature = self.signing_key.sign(&request.encode_to_vec());
+        request.set_signature(signature.to_bytes().to_vec());
+    }
+}
+
+/// Verify a request signed by `DelegateKeypair::sign`
+pub fn verify<R: SignedRequest + Clone>(request: &R, signer: &[u8]) -> bool {
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+    let Some(public) = signer.get(1..).and_then(|b| <[u8; 32]>::try_from(b).ok()) else {
+        return false;
+    };
+    let Ok(key) = VerifyingKey::from_bytes(&public) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(request.signature()) else {
+        return false;
+    };
+
+    let mut unsigned = request.clone();
+    unsigned.set_signature(Vec::new());
+    key.verify(&unsigned.encode_to_vec(), &signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helium::proto::RouteCreateReqV1;
+
+    #[test]
+    fn test_keypair_bytes_roundtrip() {
+        let keypair = DelegateKeypair::from_secret([7u8; 32]);
+        let bytes = keypair.to_bytes();
+        assert_eq!(bytes.len(), KEYPAIR_LEN);
+        assert_eq!(bytes[0], KEY_TYPE_ED25519);
+
+        let parsed = DelegateKeypair::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.public_key_bytes(), keypair.public_key_bytes());
+    }
+
+    #[test]
+    fn test_keypair_rejects_bad_input() {
+        assert!(DelegateKeypair::from_bytes(&[0x01; 10]).is_err());
+
+        // ECC compact key type (0x00) is not supported
+        let mut bytes = DelegateKeypair::from_secret([7u8; 32]).to_bytes();
+        bytes[0] = 0x00;
+        assert!(DelegateKeypair::from_bytes(&bytes).is_err());
+
+        // Mismatched public key
+        let mut bytes = DelegateKeypair::from_secret([7u8; 32]).to_bytes();
+        bytes[40] ^= 0xFF;
+        assert!(DelegateKeypair::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let keypair = DelegateKeypair::from_secret([9u8; 32]);
+        let mut request = RouteCreateReqV1 {
+            oui: 42,
+            timestamp: 1_700_000_000_000,
+            ..Default::default()
+        };
+        keypair.sign(&mut request);
+
+        assert_eq!(request.signer, keypair.public_key_bytes());
+        assert_eq!(request.signature.len(), 64);
+        assert!(verify(&request, &request.signer));
+
+        request.oui = 43;
+        assert!(!verify(&request, &request.signer));
+    }
+}
@@ -25,25 +25,448 @@
 //!
 //! Reference: <https://docs.helium.com/iot/run-an-lns/>
 
+#[cfg(feature = "helium-grpc")]
+pub mod config_service;
+#[cfg(feature = "helium-grpc")]
+pub mod keypair;
+#[cfg(feature = "helium-grpc")]
+pub mod proto;
+#[cfg(feature = "helium-grpc")]
 pub mod router;
 
 use crate::config::HeliumConfig;
 use tracing::info;
 
+#[cfg(feature = "helium-grpc")]
+use config_service::ConfigServiceClient;
+
 /// Helium network client (Phase 4 implementation)
 pub struct HeliumClient {
-    _config: HeliumConfig,
+    config: HeliumConfig,
+    /// Authenticated Config Service connection (after `connect_config_service`)
+    #[cfg(feature = "helium-grpc")]
+    config_service: Option<ConfigServiceClient>,
+    /// Route that device EUIs are added to
+    #[cfg(feature = "helium-grpc")]
+    route_id: Option<String>,
 }
 
 impl HeliumClient {
     pub fn new(config: HeliumConfig) -> Self {
         info!("Helium client configured for OUI {}", config.oui);
-        Self { _config: config }
+        Self {
+            #[cfg(feature = "helium-grpc")]
+            route_id: config.route_id.clone(),
+            config,
+            #[cfg(feature = "helium-grpc")]
+            config_service: None,
+        }
+    }
+
+    /// Get the Helium config
+    pub fn config(&self) -> &HeliumConfig {
+        &self.config
+    }
+}
+
+/// How worried to be about a DC escrow balance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DcBalanceLevel {
+    /// At or above the configured threshold
+    Ok,
+    /// Below `helium.low_dc_threshold` — top up soon
+    Low,
+    /// Zero — packet delivery has stopped
+    Empty,
+}
+
+impl DcBalanceLevel {
+    pub fn classify(balance: u64, threshold: u64) -> Self {
+        if balance == 0 {
+            DcBalanceLevel::Empty
+        } else if balance < threshold {
+            DcBalanceLevel::Low
+        } else {
+            DcBalanceLevel::Ok
+        }
+    }
+}
+
+#[cfg(feature = "helium-grpc")]
+impl HeliumClient {
+    /// Dial `config_host` and load the delegate keypair used to sign requests
+    pub async fn connect_config_service(&mut self) -> anyhow::Result<()> {
+        let keypair = keypair::DelegateKeypair::from_file(std::path::Path::new(
+            &self.config.delegate_keypair,
+        ))?;
+        let client = ConfigServiceClient::connect(&self.config.config_host, keypair).await?;
+        self.config_service = Some(client);
+        Ok(())
+    }
+
+    /// Register a route sending this OUI's traffic to `endpoint:port` over GWMP
+    ///
+    /// Returns the route id assigned by the Config Service, which is also
+    /// used for subsequent `add_device_eui` calls.
+    pub async fn register_route(&mut self, endpoint: &str, port: u16) -> anyhow::Result<String> {
+        let route = proto::RouteV1 {
+            net_id: config_service::parse_net_id(&self.config.net_id)?,
+            oui: self.config.oui,
+            server: Some(proto::ServerV1 {
+                host: endpoint.to_string(),
+                port: port as u32,
+                protocol: Some(proto::server_v1::Protocol::Gwmp(proto::ProtocolGwmpV1 {
+                    mapping: Vec::new(),
+                })),
+            }),
+            max_copies: 1,
+            active: true,
+            ..Default::default()
+        };
+
+        let created = self.service()?.create_route(self.config.oui, route).await?;
+        info!(
+            "Registered Helium route {} for OUI {} → {}:{}",
+            created.id, self.config.oui, endpoint, port
+        );
+        self.route_id = Some(created.id.clone());
+        Ok(created.id)
+    }
+
+    /// Route a device's AppEUI/DevEUI pair (16 hex digits each) to our route
+    pub async fn add_device_eui(&self, dev_eui: &str, app_eui: &str) -> anyhow::Result<()> {
+        let route_id = self.route_id.clone().ok_or_else(|| {
+            anyhow::anyhow!("no Helium route — set helium.route_id or call register_route() first")
+        })?;
+        let pair = proto::EuiPairV1 {
+            route_id,
+            app_eui: config_service::parse_eui(app_eui)?,
+            dev_eui: config_service::parse_eui(dev_eui)?,
+        };
+
+        self.service()?
+            .update_euis(proto::ActionV1::Add, vec![pair])
+            .await?;
+        info!("Added DevEUI {} / AppEUI {} to Helium route", dev_eui, app_eui);
+        Ok(())
+    }
+
+    /// Whether `connect_config_service()` has succeeded
+    pub fn is_connected(&self) -> bool {
+        self.config_service.is_some()
+    }
+
+    /// Query the Config Service for the OUI's Data Credit escrow balance
+    pub async fn check_dc_balance(&self) -> anyhow::Result<u64> {
+        let org = self.service()?.get_org(self.config.oui).await?;
+        org.escrow_dc_balance.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Config Service did not report a DC escrow balance for OUI {}",
+                self.config.oui
+            )
+        })
+    }
+
+    fn service(&self) -> anyhow::Result<&ConfigServiceClient> {
+        self.config_service.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("not connected — call connect_config_service() first")
+        })
+    }
+}
+
+#[cfg(all(test, feature = "helium-grpc"))]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+    use tonic::codegen::{http, BoxFuture, Context, Poll, Service};
+    use tonic::server::{ClientStreamingService, NamedService, UnaryService};
+    use tonic::{Request, Response, Status, Streaming};
+    use tonic_prost::ProstCodec;
+
+    /// Requests seen by the mock Config Service
+    #[derive(Default)]
+    struct Seen {
+        routes: Vec<proto::RouteCreateReqV1>,
+        euis: Vec<proto::RouteUpdateEuisReqV1>,
+    }
+
+    /// Mock `helium.iot_config.route` service that verifies request signatures
+    #[derive(Clone, Default)]
+    struct MockRouteService {
+        seen: Arc<Mutex<Seen>>,
+    }
+
+    impl NamedService for MockRouteService {
+        const NAME: &'static str = "helium.iot_config.route";
     }
 
-    // Phase 4 TODOs:
-    // - pub async fn connect_config_service(&mut self) -> anyhow::Result<()>
-    // - pub async fn register_route(&self, endpoint: &str, port: u16) -> anyhow::Result<()>
-    // - pub async fn add_device_eui(&self, dev_eui: &str, app_eui: &str) -> anyhow::Result<()>
-    // - pub async fn check_dc_balance(&self) -> anyhow::Result<u64>
+    struct CreateSvc(Arc<Mutex<Seen>>);
+
+    impl UnaryService<proto::RouteCreateReqV1> for CreateSvc {
+        type Response = proto::RouteResV1;
+        type Future = BoxFuture<Response<Self::Response>, Status>;
+
+        fn call(&mut self, request: Request<proto::RouteCreateReqV1>) -> Self::Future {
+            let seen = self.0.clone();
+            Box::pin(async move {
+                let req = request.into_inner();
+                if !keypair::verify(&req, &req.signer) {
+                    return Err(Status::permission_denied("invalid signature"));
+                }
+                let mut route = req.route.clone().unwrap_or_default();
+                route.id = "route-1".to_string();
+                seen.lock().unwrap().routes.push(req);
+                Ok(Response::new(proto::RouteResV1 {
+                    route: Some(route),
+                    ..Default::default()
+                }))
+            })
+        }
+    }
+
+    struct UpdateEuisSvc(Arc<Mutex<Seen>>);
+
+    impl ClientStreamingService<proto::RouteUpdateEuisReqV1> for UpdateEuisSvc {
+        type Response = proto::RouteEuisResV1;
+        type Future = BoxFuture<Response<Self::Response>, Status>;
+
+        fn call(&mut self, request: Request<Streaming<proto::RouteUpdateEuisReqV1>>) -> Self::Future {
+            let seen = self.0.clone();
+            Box::pin(async move {
+                let mut stream = request.into_inner();
+                while let Some(req) = stream.message().await? {
+                    if !keypair::verify(&req, &req.signer) {
+                        return Err(Status::permission_denied("invalid signature"));
+                    }
+                    seen.lock().unwrap().euis.push(req);
+                }
+                Ok(Response::new(proto::RouteEuisResV1::default()))
+            })
+        }
+    }
+
+    impl<B> Service<http::Request<B>> for MockRouteService
+    where
+        B: tonic::codegen::Body + Send + 'static,
+        B::Error: Into<tonic::codegen::StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::Body>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let seen = self.seen.clone();
+            match req.uri().path() {
+                proto::path::ROUTE_CREATE => Box::pin(async move {
+                    let mut grpc = tonic::server::Grpc::new(ProstCodec::default());
+                    Ok(grpc.unary(CreateSvc(seen), req).await)
+                }),
+                proto::path::ROUTE_UPDATE_EUIS => Box::pin(async move {
+                    let mut grpc = tonic::server::Grpc::new(ProstCodec::default());
+                    Ok(grpc.client_streaming(UpdateEuisSvc(seen), req).await)
+                }),
+                _ => Box::pin(async move { Ok(Status::unimplemented("").into_http()) }),
+            }
+        }
+    }
+
+    /// Mock `helium.iot_config.org` service reporting a fixed escrow balance
+    #[derive(Clone, Default)]
+    struct MockOrgService {
+        balance: Option<u64>,
+    }
+
+    impl NamedService for MockOrgService {
+        const NAME: &'static str = "helium.iot_config.org";
+    }
+
+    struct OrgGetSvc(Option<u64>);
+
+    impl UnaryService<proto::OrgGetReqV1> for OrgGetSvc {
+        type Response = proto::OrgResV1;
+        type Future = BoxFuture<Response<Self::Response>, Status>;
+
+        fn call(&mut self, request: Request<proto::OrgGetReqV1>) -> Self::Future {
+            let balance = self.0;
+            Box::pin(async move {
+                let oui = request.into_inner().oui;
+                Ok(Response::new(proto::OrgResV1 {
+                    org: Some(proto::OrgV1 {
+                        oui,
+                        ..Default::default()
+                    }),
+                    net_id: 0x00003C,
+                    escrow_dc_balance: balance,
+                }))
+            })
+        }
+    }
+
+    impl<B> Service<http::Request<B>> for MockOrgService
+    where
+        B: tonic::codegen::Body + Send + 'static,
+        B::Error: Into<tonic::codegen::StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::Body>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let balance = self.balance;
+            match req.uri().path() {
+                proto::path::ORG_GET => Box::pin(async move {
+                    let mut grpc = tonic::server::Grpc::new(ProstCodec::default());
+                    Ok(grpc.unary(OrgGetSvc(balance), req).await)
+                }),
+                _ => Box::pin(async move { Ok(Status::unimplemented("").into_http()) }),
+            }
+        }
+    }
+
+    /// Start the mock services on an ephemeral port, returning its URL
+    async fn start_mock(service: MockRouteService) -> String {
+        start_mock_with_org(service, MockOrgService::default()).await
+    }
+
+    async fn start_mock_with_org(service: MockRouteService, org: MockOrgService) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .add_service(org)
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener))
+                .await
+                .unwrap();
+        });
+        format!("http://{}", addr)
+    }
+
+    fn helium_config(config_host: String, keypair_path: &std::path::Path) -> HeliumConfig {
+        HeliumConfig {
+            oui: 42,
+            net_id: "00003C".to_string(),
+            config_host,
+            delegate_keypair: keypair_path.to_string_lossy().to_string(),
+            route_id: None,
+            low_dc_threshold: 3_500_000,
+            dc_check_interval_secs: 300,
+            packet_router_host: None,
+        }
+    }
+
+    async fn connected_client(org: MockOrgService) -> HeliumClient {
+        let url = start_mock_with_org(MockRouteService::default(), org).await;
+        let keypair = keypair::DelegateKeypair::from_secret([3u8; 32]);
+        let mut client = HeliumClient::new(helium_config(url.clone(), std::path::Path::new("")));
+        client.config_service = Some(ConfigServiceClient::connect(&url, keypair).await.unwrap());
+        client
+    }
+
+    #[test]
+    fn test_check_dc_balance() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let client = connected_client(MockOrgService {
+                balance: Some(1_234_567),
+            })
+            .await;
+            assert_eq!(client.check_dc_balance().await.unwrap(), 1_234_567);
+
+            // A response without a balance is an error, not a zero balance
+            let client = connected_client(MockOrgService { balance: None }).await;
+            assert!(client.check_dc_balance().await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_dc_balance_level() {
+        assert_eq!(DcBalanceLevel::classify(0, 3_500_000), DcBalanceLevel::Empty);
+        assert_eq!(DcBalanceLevel::classify(1_000, 3_500_000), DcBalanceLevel::Low);
+        assert_eq!(DcBalanceLevel::classify(3_500_000, 3_500_000), DcBalanceLevel::Ok);
+    }
+
+    #[test]
+    fn test_config_service_route_and_euis() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let keypair = keypair::DelegateKeypair::from_secret([3u8; 32]);
+            let keypair_path = std::env::temp_dir()
+                .join(format!("loraurbit-delegate-{}.bin", std::process::id()));
+            std::fs::write(&keypair_path, keypair.to_bytes()).unwrap();
+
+            let mock = MockRouteService::default();
+            let url = start_mock(mock.clone()).await;
+
+            let mut client = HeliumClient::new(helium_config(url, &keypair_path));
+            client.connect_config_service().await.unwrap();
+
+            let route_id = client.register_route("lns.example.com", 1680).await.unwrap();
+            assert_eq!(route_id, "route-1");
+
+            client
+                .add_device_eui("A1A2A3A4A5A6A7A8", "0102030405060708")
+                .await
+                .unwrap();
+
+            let seen = mock.seen.lock().unwrap();
+            let route = seen.routes[0].route.as_ref().unwrap();
+            assert_eq!(seen.routes[0].oui, 42);
+            assert_eq!(route.net_id, 0x00003C);
+            assert_eq!(route.server.as_ref().unwrap().port, 1680);
+            assert_eq!(seen.routes[0].signer, keypair.public_key_bytes());
+
+            let pair = seen.euis[0].eui_pair.as_ref().unwrap();
+            assert_eq!(pair.route_id, "route-1");
+            assert_eq!(pair.dev_eui, 0xA1A2A3A4A5A6A7A8);
+            assert_eq!(pair.app_eui, 0x0102030405060708);
+
+            std::fs::remove_file(&keypair_path).ok();
+        });
+    }
+
+    #[test]
+    fn test_config_service_status_error() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let url = start_mock(MockRouteService::default()).await;
+            let keypair = keypair::DelegateKeypair::from_secret([3u8; 32]);
+            let service = ConfigServiceClient::connect(&url, keypair).await.unwrap();
+
+            // An unsigned request is rejected by the mock with PERMISSION_DENIED
+            let err = service
+                .unary::<_, proto::RouteResV1>(
+                    proto::path::ROUTE_CREATE,
+                    proto::RouteCreateReqV1::default(),
+                )
+                .await
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("route/create"), "{}", err);
+            assert!(err.contains("PermissionDenied"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_requires_connection() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut config = helium_config(
+            "http://127.0.0.1:1".to_string(),
+            std::path::Path::new("/nonexistent"),
+        );
+        config.route_id = Some("route-1".to_string());
+
+        let client = HeliumClient::new(config);
+        let result = rt.block_on(client.add_device_eui("A1A2A3A4A5A6A7A8", "0102030405060708"));
+        assert!(result.unwrap_err().to_string().contains("not connected"));
+    }
 }
@@ -0,0 +1,343 @@
+//! Hand-written subset of the `helium/proto` IoT Config Service messages
+//!
+//! Field numbers and names mirror `iot_config.proto` from helium/proto so the
+//! messages are wire-compatible with the Config Service. Only the messages
+//! LoraUrbit actually uses are defined here, which avoids a protoc build step.
+//!
+//! The Packet Router stream messages likewise mirror `packet_router.proto`.
+//!
+//! Reference: <https://github.com/helium/proto/blob/master/src/service/iot_config.proto>
+
+/// gRPC paths for the Config Service RPCs we call
+pub mod path {
+    pub const ROUTE_CREATE: &str = "/helium.iot_config.route/create";
+    pub const ROUTE_UPDATE_EUIS: &str = "/helium.iot_config.route/update_euis";
+    pub const ORG_GET: &str = "/helium.iot_config.org/get";
+    pub const PACKET_ROUTE: &str = "/helium.packet_router.packet/route";
+}
+
+/// `route_v1` — an OUI route pointing Packet Router traffic at an LNS
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RouteV1 {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(uint32, tag = "2")]
+    pub net_id: u32,
+    #[prost(uint64, tag = "3")]
+    pub oui: u64,
+    #[prost(message, optional, tag = "4")]
+    pub server: Option<ServerV1>,
+    #[prost(uint32, tag = "5")]
+    pub max_copies: u32,
+    #[prost(bool, tag = "6")]
+    pub active: bool,
+    #[prost(bool, tag = "7")]
+    pub locked: bool,
+    #[prost(bool, tag = "8")]
+    pub ignore_empty_skf: bool,
+}
+
+/// `server_v1` — LNS endpoint and the protocol Packet Router speaks to it
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ServerV1 {
+    #[prost(string, tag = "1")]
+    pub host: String,
+    #[prost(uint32, tag = "2")]
+    pub port: u32,
+    #[prost(oneof = "server_v1::Protocol", tags = "3, 4")]
+    pub protocol: Option<server_v1::Protocol>,
+}
+
+pub mod server_v1 {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Protocol {
+        #[prost(message, tag = "3")]
+        PacketRouter(super::ProtocolPacketRouterV1),
+        #[prost(message, tag = "4")]
+        Gwmp(super::ProtocolGwmpV1),
+    }
+}
+
+/// `protocol_packet_router_v1` — gRPC Packet Router streaming
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProtocolPacketRouterV1 {}
+
+/// `protocol_gwmp_v1` — Semtech UDP; an empty mapping uses the server port
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProtocolGwmpV1 {
+    #[prost(message, repeated, tag = "1")]
+    pub mapping: Vec<ProtocolGwmpMappingV1>,
+}
+
+/// `protocol_gwmp_mapping_v1` — per-region GWMP port
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProtocolGwmpMappingV1 {
+    #[prost(int32, tag = "1")]
+    pub region: i32,
+    #[prost(uint32, tag = "2")]
+    pub port: u32,
+}
+
+/// `route_create_req_v1`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RouteCreateReqV1 {
+    #[prost(uint64, tag = "1")]
+    pub oui: u64,
+    #[prost(message, optional, tag = "2")]
+    pub route: Option<RouteV1>,
+    #[prost(uint64, tag = "3")]
+    pub timestamp: u64,
+    #[prost(bytes = "vec", tag = "4")]
+    pub signer: Vec<u8>,
+    #[prost(bytes = "vec", tag = "5")]
+    pub signature: Vec<u8>,
+}
+
+/// `route_res_v1`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RouteResV1 {
+    #[prost(message, optional, tag = "1")]
+    pub route: Option<RouteV1>,
+    #[prost(uint64, tag = "2")]
+    pub timestamp: u64,
+    #[prost(bytes = "vec", tag = "3")]
+    pub signer: Vec<u8>,
+    #[prost(bytes = "vec", tag = "4")]
+    pub signature: Vec<u8>,
+}
+
+/// `action_v1` — add or remove an entry from a route
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ActionV1 {
+    Add = 0,
+    Remove = 1,
+}
+
+/// `eui_pair_v1` — AppEUI/DevEUI pair routed to a route
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct EuiPairV1 {
+    #[prost(string, tag = "1")]
+    pub route_id: String,
+    #[prost(uint64, tag = "2")]
+    pub app_eui: u64,
+    #[prost(uint64, tag = "3")]
+    pub dev_eui: u64,
+}
+
+/// `route_update_euis_req_v1` (client-streamed)
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RouteUpdateEuisReqV1 {
+    #[prost(enumeration = "ActionV1", tag = "1")]
+    pub action: i32,
+    #[prost(message, optional, tag = "2")]
+    pub eui_pair: Option<EuiPairV1>,
+    #[prost(uint64, tag = "3")]
+    pub timestamp: u64,
+    #[prost(bytes = "vec", tag = "4")]
+    pub signer: Vec<u8>,
+    #[prost(bytes = "vec", tag = "5")]
+    pub signature: Vec<u8>,
+}
+
+/// `route_euis_res_v1`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RouteEuisResV1 {
+    #[prost(uint64, tag = "1")]
+    pub timestamp: u64,
+    #[prost(bytes = "vec", tag = "2")]
+    pub signer: Vec<u8>,
+    #[prost(bytes = "vec", tag = "3")]
+    pub signature: Vec<u8>,
+}
+
+/// `org_get_req_v1`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OrgGetReqV1 {
+    #[prost(uint64, tag = "1")]
+    pub oui: u64,
+}
+
+/// `org_v1`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OrgV1 {
+    #[prost(uint64, tag = "1")]
+    pub oui: u64,
+    #[prost(bytes = "vec", tag = "2")]
+    pub owner: Vec<u8>,
+    #[prost(bytes = "vec", tag = "3")]
+    pub payer: Vec<u8>,
+    #[prost(bytes = "vec", repeated, tag = "4")]
+    pub delegate_keys: Vec<Vec<u8>>,
+    #[prost(bool, tag = "5")]
+    pub locked: bool,
+}
+
+/// `org_res_v1`
+///
+/// `escrow_dc_balance` is the OUI's Data Credit escrow balance. It is only
+/// reported by Config Service deployments that expose escrow balances; when
+/// absent it decodes as `None` rather than a misleading zero.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OrgResV1 {
+    #[prost(message, optional, tag = "1")]
+    pub org: Option<OrgV1>,
+    #[prost(uint32, tag = "2")]
+    pub net_id: u32,
+    #[prost(uint64, optional, tag = "10")]
+    pub escrow_dc_balance: Option<u64>,
+}
+
+/// `data_rate` — LoRa spreading factor and bandwidth of a Packet Router packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum DataRateV1 {
+    Sf12bw125 = 0,
+    Sf11bw125 = 1,
+    Sf10bw125 = 2,
+    Sf9bw125 = 3,
+    Sf8bw125 = 4,
+    Sf7bw125 = 5,
+    Sf12bw250 = 6,
+    Sf11bw250 = 7,
+    Sf10bw250 = 8,
+    Sf9bw250 = 9,
+    Sf8bw250 = 10,
+    Sf7bw250 = 11,
+    Sf12bw500 = 12,
+    Sf11bw500 = 13,
+    Sf10bw500 = 14,
+    Sf9bw500 = 15,
+    Sf8bw500 = 16,
+    Sf7bw500 = 17,
+}
+
+/// `packet_router_packet_up_v1` — an uplink heard by a hotspot
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PacketRouterPacketUpV1 {
+    #[prost(bytes = "vec", tag = "1")]
+    pub payload: Vec<u8>,
+    /// Concentrator timestamp of the reception (microseconds)
+    #[prost(uint64, tag = "2")]
+    pub timestamp: u64,
+    #[prost(sint32, tag = "3")]
+    pub rssi: i32,
+    #[prost(float, tag = "4")]
+    pub snr: f32,
+    /// Hz
+    #[prost(uint32, tag = "5")]
+    pub frequency: u32,
+    #[prost(enumeration = "DataRateV1", tag = "6")]
+    pub datarate: i32,
+    #[prost(int32, tag = "7")]
+    pub region: i32,
+    /// Hotspot public key
+    #[prost(bytes = "vec", tag = "8")]
+    pub gateway: Vec<u8>,
+    #[prost(bytes = "vec", tag = "9")]
+    pub signature: Vec<u8>,
+    #[prost(uint64, tag = "10")]
+    pub hold_time: u64,
+}
+
+/// `packet_router_register_v1` — first message on the stream, signed by its sender
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PacketRouterRegisterV1 {
+    #[prost(uint64, tag = "1")]
+    pub timestamp: u64,
+    #[prost(bytes = "vec", tag = "2")]
+    pub gateway: Vec<u8>,
+    #[prost(bytes = "vec", tag = "3")]
+    pub signature: Vec<u8>,
+    #[prost(bool, tag = "4")]
+    pub session_capable: bool,
+}
+
+/// `window_v1` — when, where and how fast to transmit a downlink
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WindowV1 {
+    /// Concentrator timestamp to transmit at (microseconds)
+    #[prost(uint64, tag = "1")]
+    pub timestamp: u64,
+    /// Hz
+    #[prost(uint32, tag = "2")]
+    pub frequency: u32,
+    #[prost(enumeration = "DataRateV1", tag = "3")]
+    pub datarate: i32,
+    #[prost(bool, tag = "4")]
+    pub immediate: bool,
+}
+
+/// `packet_router_packet_down_v1` — a downlink with its RX1 and optional RX2 window
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PacketRouterPacketDownV1 {
+    #[prost(bytes = "vec", tag = "1")]
+    pub payload: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub rx1: Option<WindowV1>,
+    #[prost(message, optional, tag = "3")]
+    pub rx2: Option<WindowV1>,
+}
+
+/// `envelope_up_v1` — Packet Router stream message
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct EnvelopeUpV1 {
+    #[prost(oneof = "envelope_up_v1::Data", tags = "1, 2, 4")]
+    pub data: Option<envelope_up_v1::Data>,
+}
+
+pub mod envelope_up_v1 {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Data {
+        #[prost(message, tag = "1")]
+        Packet(super::PacketRouterPacketUpV1),
+        #[prost(message, tag = "2")]
+        Register(super::PacketRouterRegisterV1),
+        /// Downlink from the LNS, sent back on the stream its uplink came in on
+        #[prost(message, tag = "4")]
+        PacketDown(super::PacketRouterPacketDownV1),
+    }
+}
+
+/// Requests signed by the OUI delegate key
+///
+/// Helium signs the protobuf encoding of the request with an empty
+/// `signature` field, then fills the signature in.
+pub trait SignedRequest: prost::Message + Sized {
+    fn set_signer(&mut self, signer: Vec<u8>);
+    fn set_signature(&mut self, signature: Vec<u8>);
+    fn signature(&self) -> &[u8];
+}
+
+macro_rules! impl_signed_request {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl SignedRequest for $ty {
+                fn set_signer(&mut self, signer: Vec<u8>) {
+                    self.signer = signer;
+                }
+                fn set_signature(&mut self, signature: Vec<u8>) {
+                    self.signature = signature;
+                }
+                fn signature(&self) -> &[u8] {
+                    &self.signature
+                }
+            }
+        )*
+    };
+}
+
+impl_signed_request!(RouteCreateReqV1, RouteUpdateEuisReqV1);
+
+/// Registration names its signer `gateway`
+impl SignedRequest for PacketRouterRegisterV1 {
+    fn set_signer(&mut self, signer: Vec<u8>) {
+        self.gateway = signer;
+    }
+    fn set_signature(&mut self, signature: Vec<u8>) {
+        self.signature = signature;
+    }
+    fn signature(&self) -> &[u8] {
+        &self.signature
+    }
+}
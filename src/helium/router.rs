@@ -6,7 +6,6 @@
 //! UDP server can receive Helium packets with zero changes.
 //!
 //! In Packet Router mode (more efficient), it uses a gRPC stream.
-//! This module will implement the gRPC client for Phase 4.
 //!
 //! ## Protocol options:
 //! - **GWMP**: Helium Packet Router sends Semtech UDP to our bind address
@@ -16,10 +15,478 @@
 //!   - Pro: More efficient, bidirectional, supports downlinks
 //!   - Con: Requires protobuf/gRPC setup
 //!
-//! For Phase 4 MVP, we'll use GWMP mode (our UDP server already handles it).
-//! gRPC Packet Router mode is a Phase 5 optimization.
+//! `PacketRouterClient` dials `helium.packet_router_host`, opens the
+//! bidirectional `route` stream and registers with a request signed by the
+//! delegate keypair. Each `packet_router_packet_up_v1` the router relays is
+//! turned into the rxpk metadata the UDP server works with and converted by
+//! the same `frame_to_action`/`frame_to_lora_packet` path, so the pokes are
+//! identical apart from `source: helium`.
+//!
+//! Downlinks for devices last heard over the stream go back on it as
+//! `packet_router_packet_down_v1` (see `packet_down`) instead of a GWMP
+//! PULL_RESP; the `DownlinkSender` picks the path from the device registry.
 //!
 //! Reference: https://github.com/helium/gateway-rs
 
-// Phase 4: Implement gRPC Packet Router client
-// Will use helium/proto definitions and tonic for gRPC
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tonic::client::Grpc;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::Channel;
+use tonic_prost::ProstCodec;
+use tracing::{debug, error, info, warn};
+
+use super::config_service::{dial, now_millis};
+use super::keypair::DelegateKeypair;
+use super::proto::{envelope_up_v1, path, DataRateV1, EnvelopeUpV1, WindowV1};
+use super::proto::{PacketRouterPacketDownV1, PacketRouterPacketUpV1, PacketRouterRegisterV1};
+use crate::lorawan::codec::CodecRegistry;
+use crate::lorawan::datarate::DataRate;
+use crate::lorawan::keys::SharedKeyStore;
+use crate::lorawan::{self, LoRaWANFrame};
+use crate::udp::devices::{Sighting, UplinkPath};
+use crate::udp::protocol::{Rxpk, Txpk};
+use crate::udp::{self, ClassATxpks, DeviceRegistry, PokeRouter};
+use crate::urbit::types::{LoRaPacket, PacketSource};
+
+/// Sending half of the open `route` stream, shared with the DownlinkSender
+#[derive(Clone, Default)]
+pub struct PacketRouterStream {
+    outbound: Arc<Mutex<Option<mpsc::Sender<EnvelopeUpV1>>>>,
+}
+
+impl PacketRouterStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a stream is currently registered
+    pub fn is_open(&self) -> bool {
+        self.lock().as_ref().is_some_and(|tx| !tx.is_closed())
+    }
+
+    /// Queue a downlink on the stream
+    pub async fn send(&self, packet: PacketRouterPacketDownV1) -> anyhow::Result<()> {
+        let tx = self
+            .lock()
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Packet Router stream is not open"))?;
+        let envelope = EnvelopeUpV1 {
+            data: Some(envelope_up_v1::Data::PacketDown(packet)),
+        };
+        tx.send(envelope)
+            .await
+            .map_err(|_| anyhow::anyhow!("Packet Router stream closed"))
+    }
+
+    fn set(&self, outbound: Option<mpsc::Sender<EnvelopeUpV1>>) {
+        *self.lock() = outbound;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<mpsc::Sender<EnvelopeUpV1>>> {
+        self.outbound.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Where `PacketRouterClient::run` delivers uplinks and takes downlinks from
+#[derive(Clone)]
+pub struct RouterContext {
+    /// Channels to the Airlock task of each Urbit target
+    pub pokes: PokeRouter,
+    /// Payload codecs (`lorawan.codec`, `lorawan.device_codecs`)
+    pub codecs: CodecRegistry,
+    /// Devices heard from, shared with the UDP server and DownlinkSender
+    pub devices: DeviceRegistry,
+    /// Session keys, shared with the UDP server (for each device's class)
+    pub keys: SharedKeyStore,
+    /// The stream handle downlinks are sent through
+    pub stream: PacketRouterStream,
+}
+
+/// Authenticated connection to the Helium Packet Router
+pub struct PacketRouterClient {
+    grpc: Grpc<Channel>,
+    keypair: Arc<DelegateKeypair>,
+}
+
+impl PacketRouterClient {
+    /// Dial the Packet Router at `host` (http:// or https://)
+    pub async fn connect(host: &str, keypair: DelegateKeypair) -> anyhow::Result<Self> {
+        let channel = dial(host, "packet_router_host", "Packet Router").await?;
+        info!("Connected to Helium Packet Router at {}", host);
+        Ok(Self {
+            grpc: Grpc::new(channel),
+            keypair: Arc::new(keypair),
+        })
+    }
+
+    /// Registration envelope signed by the delegate keypair
+    fn register(&self) -> EnvelopeUpV1 {
+        let mut register = PacketRouterRegisterV1 {
+            timestamp: now_millis(),
+            ..Default::default()
+        };
+        self.keypair.sign(&mut register);
+        EnvelopeUpV1 {
+            data: Some(envelope_up_v1::Data::Register(register)),
+        }
+    }
+
+    /// Register on the `route` stream and forward uplinks until `shutdown`
+    ///
+    /// While the stream is open, `ctx.stream` carries downlinks back on it.
+    /// Returns an error if the router rejects the stream or closes it.
+    pub async fn run(
+        &self,
+        ctx: &RouterContext,
+        shutdown: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let (outbound, rx) = mpsc::channel(16);
+        outbound
+            .send(self.register())
+            .await
+            .map_err(|_| anyhow::anyhow!("Packet Router stream closed"))?;
+
+        let mut grpc = self.grpc.clone();
+        grpc.ready()
+            .await
+            .map_err(|e| anyhow::anyhow!("Packet Router not ready: {}", e))?;
+        let response: tonic::Response<tonic::Streaming<EnvelopeUpV1>> = grpc
+            .streaming(
+                tonic::Request::new(tokio_stream::wrappers::ReceiverStream::new(rx)),
+                PathAndQuery::from_static(path::PACKET_ROUTE),
+                ProstCodec::default(),
+            )
+            .await
+            .map_err(|status| stream_error(path::PACKET_ROUTE, status))?;
+        let mut inbound = response.into_inner();
+        info!("Registered with Helium Packet Router");
+        ctx.stream.set(Some(outbound));
+
+        let result = loop {
+            let message = tokio::select! {
+                _ = shutdown.cancelled() => break Ok(()),
+                message = inbound.message() => message,
+            };
+            let envelope = match message {
+                Ok(Some(envelope)) => envelope,
+                Ok(None) => break Err(anyhow::anyhow!("Packet Router closed the stream")),
+                Err(status) => break Err(stream_error(path::PACKET_ROUTE, status)),
+            };
+            match envelope.data {
+                Some(envelope_up_v1::Data::Packet(packet)) => {
+                    forward_packet_up(&packet, ctx).await;
+                }
+                other => debug!("Ignoring Packet Router message: {:?}", other),
+            }
+        };
+        ctx.stream.set(None);
+        result
+    }
+}
+
+/// Decode a relayed uplink and poke it to the matching Urbit targets
+async fn forward_packet_up(packet: &PacketRouterPacketUpV1, ctx: &RouterContext) {
+    let gateway = hex::encode(&packet.gateway);
+    let (rxpk, frame) = match decode_packet_up(packet) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            warn!("Dropped Packet Router uplink from {}: {}", gateway, e);
+            return;
+        }
+    };
+    info!(
+        "Packet Router uplink from {}: freq={} MHz, rssi={} dBm, datr={}",
+        gateway, rxpk.freq, rxpk.rssi, rxpk.datr
+    );
+    info!("  LoRaWAN: {}", frame);
+
+    if let LoRaWANFrame::Data { dev_addr, fcnt, .. } = &frame {
+        ctx.devices.record(&Sighting {
+            dev_addr: *dev_addr,
+            fcnt: *fcnt as u32,
+            rssi: rxpk.rssi,
+            snr: rxpk.lsnr,
+            gateway_eui: &gateway,
+            path: UplinkPath::PacketRouter,
+        });
+    }
+
+    let source = PacketSource::Helium;
+    let Some(action) =
+        udp::frame_to_action(&frame, &rxpk, &gateway, source, &ctx.codecs, &ctx.keys)
+    else {
+        return;
+    };
+    for tx in ctx.pokes.route_for(&action, &gateway) {
+        if let Err(e) = tx.send(action.clone()).await {
+            error!("Failed to forward packet to Airlock task: {}", e);
+        }
+    }
+}
+
+/// Rxpk metadata and decoded frame for an uplink relayed by the Packet Router
+pub fn decode_packet_up(packet: &PacketRouterPacketUpV1) -> anyhow::Result<(Rxpk, LoRaWANFrame)> {
+    use base64::Engine;
+
+    let datr = DataRateV1::try_from(packet.datarate)
+        .map(data_rate)
+        .map_err(|_| anyhow::anyhow!("unknown data rate {}", packet.datarate))?;
+    let frame = lorawan::decode_phy_payload(&packet.payload)?;
+    let rxpk = Rxpk {
+        time: None,
+        tmst: Some(packet.timestamp),
+        tmms: None,
+        chan: None,
+        rfch: None,
+        freq: packet.frequency as f64 / 1_000_000.0,
+        lsnr: Some(packet.snr as f64),
+        rssi: packet.rssi as f64,
+        modu: Some("LORA".to_string()),
+        datr,
+        codr: None,
+        size: packet.payload.len() as u16,
+        data: base64::engine::general_purpose::STANDARD.encode(&packet.payload),
+    };
+    Ok((rxpk, frame))
+}
+
+/// Convert an uplink relayed by the Packet Router into a LoRaPacket for Urbit
+///
+/// `None` for frames the UDP path doesn't forward as uplinks either.
+pub fn packet_up_to_lora_packet(
+    packet: &PacketRouterPacketUpV1,
+    codecs: &CodecRegistry,
+    keys: &SharedKeyStore,
+) -> anyhow::Result<Option<LoRaPacket>> {
+    let (rxpk, frame) = decode_packet_up(packet)?;
+    let gateway = hex::encode(&packet.gateway);
+    let source = PacketSource::Helium;
+    Ok(udp::frame_to_lora_packet(&frame, &rxpk, &gateway, source, codecs, keys))
+}
+
+/// The LoRa data rate of a Packet Router `data_rate`
+pub fn data_rate(datarate: DataRateV1) -> DataRate {
+    let index = datarate as u8;
+    let sf = 12 - index % 6;
+    let bw_khz = match index / 6 {
+        0 => 125,
+        1 => 250,
+        _ => 500,
+    };
+    DataRate::lora(sf, bw_khz)
+}
+
+/// The Packet Router `data_rate` of a LoRa data rate, if it has one
+pub fn data_rate_v1(datr: DataRate) -> Option<DataRateV1> {
+    let (sf, bw_khz) = (datr.spreading_factor()?, datr.bandwidth_khz()?);
+    let bw_index = match bw_khz {
+        125 => 0,
+        250 => 1,
+        500 => 2,
+        _ => return None,
+    };
+    if !(7..=12).contains(&sf) {
+        return None;
+    }
+    DataRateV1::try_from(bw_index * 6 + (12 - sf as i32)).ok()
+}
+
+/// The `window_v1` a txpk describes
+fn window(txpk: &Txpk) -> anyhow::Result<WindowV1> {
+    let datarate = data_rate_v1(txpk.datr.clone())
+        .ok_or_else(|| anyhow::anyhow!("{} has no Packet Router data rate", txpk.datr))?;
+    Ok(WindowV1 {
+        timestamp: txpk.tmst.unwrap_or_default(),
+        frequency: (txpk.freq * 1_000_000.0).round() as u32,
+        datarate: datarate as i32,
+        immediate: txpk.imme.unwrap_or(false),
+    })
+}
+
+/// Build the Packet Router downlink for a Class A downlink's txpks
+///
+/// The payload comes from the first window; a lone RX2 txpk becomes RX1,
+/// the window the router always transmits in.
+pub fn packet_down(txpks: &ClassATxpks) -> anyhow::Result<PacketRouterPacketDownV1> {
+    use base64::Engine;
+
+    let (rx1, rx2) = match (&txpks.rx1, &txpks.rx2) {
+        (Some(rx1), rx2) => (rx1, rx2.as_ref()),
+        (None, Some(rx2)) => (rx2, None),
+        (None, None) => return Err(anyhow::anyhow!("No RX1 or RX2 window to send in")),
+    };
+    let payload = base64::engine::general_purpose::STANDARD
+        .decode(&rx1.data)
+        .map_err(|e| anyhow::anyhow!("Invalid txpk data: {}", e))?;
+    Ok(PacketRouterPacketDownV1 {
+        payload,
+        rx1: Some(window(rx1)?),
+        rx2: rx2.map(window).transpose()?,
+    })
+}
+
+/// Turn a gRPC status on the Packet Router stream into an error
+fn stream_error(rpc: &str, status: tonic::Status) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Packet Router {} failed: {:?}: {}",
+        rpc,
+        status.code(),
+        status.message()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helium::keypair;
+
+    fn packet_up() -> PacketRouterPacketUpV1 {
+        use base64::Engine;
+        PacketRouterPacketUpV1 {
+            payload: base64::engine::general_purpose::STANDARD
+                .decode("QPF9vkkAAgABlUN4disR/w0=")
+                .unwrap(),
+            timestamp: 1_000_000,
+            rssi: -97,
+            snr: 7.5,
+            frequency: 904_300_000,
+            datarate: DataRateV1::Sf10bw125 as i32,
+            gateway: vec![0x00, 0xAB, 0xCD],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_packet_up_to_lora_packet() {
+        let keys = SharedKeyStore::default();
+        let packet = packet_up_to_lora_packet(&packet_up(), &CodecRegistry::default(), &keys)
+            .unwrap()
+            .unwrap();
+        assert_eq!(packet.dev_addr, "49BE7DF1");
+        assert_eq!(packet.gateway_eui, "00abcd");
+        assert_eq!(packet.source, PacketSource::Helium);
+        assert_eq!(packet.rssi, -97.0);
+        assert_eq!(packet.snr, Some(7.5));
+        assert_eq!(packet.freq, 904.3);
+        assert_eq!(packet.data_rate.to_string(), "SF10BW125");
+
+        let mut unknown = packet_up();
+        unknown.datarate = 99;
+        assert!(decode_packet_up(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_data_rate() {
+        assert_eq!(data_rate(DataRateV1::Sf12bw125), DataRate::lora(12, 125));
+        assert_eq!(data_rate(DataRateV1::Sf7bw250), DataRate::lora(7, 250));
+        assert_eq!(data_rate(DataRateV1::Sf8bw500), DataRate::lora(8, 500));
+        for index in 0..18 {
+            let datarate = DataRateV1::try_from(index).unwrap();
+            assert_eq!(data_rate_v1(data_rate(datarate)), Some(datarate));
+        }
+        assert_eq!(data_rate_v1(DataRate::lora(6, 125)), None);
+    }
+
+    #[test]
+    fn test_packet_down() {
+        use crate::lorawan::channel_plan::ChannelPlan;
+        use crate::lorawan::encoder::FrameBuilder;
+        use base64::Engine;
+
+        // Answer the uplink of `packet_up` with an empty ACK
+        let plan = ChannelPlan::from_config(&Default::default()).unwrap();
+        let (rxpk, _) = decode_packet_up(&packet_up()).unwrap();
+        let frame = FrameBuilder {
+            ack: true,
+            ..FrameBuilder::new_downlink(0x49BE7DF1, 1, 0, Vec::new())
+        }
+        .build()
+        .unwrap();
+        let b64 = base64::engine::general_purpose::STANDARD.encode(&frame);
+        let txpks = ClassATxpks::build(&plan, None, &rxpk, 1, &b64, frame.len() as u16);
+
+        let down = packet_down(&txpks).unwrap();
+        assert_eq!(down.payload, frame);
+        let rx1 = down.rx1.unwrap();
+        assert_eq!(rx1.timestamp, 2_000_000);
+        assert_eq!(rx1.frequency, 924_500_000);
+        assert_eq!(rx1.datarate, DataRateV1::Sf10bw500 as i32);
+        assert!(!rx1.immediate);
+        let rx2 = down.rx2.unwrap();
+        assert_eq!(rx2.timestamp, 3_000_000);
+        assert_eq!(rx2.frequency, 923_300_000);
+        assert_eq!(rx2.datarate, DataRateV1::Sf12bw500 as i32);
+
+        // An FSK downlink has no Packet Router data rate
+        let mut fsk = txpks.clone();
+        fsk.rx1.as_mut().unwrap().datr = DataRate::Fsk { bitrate: 50000 };
+        assert!(packet_down(&fsk).is_err());
+    }
+
+    #[test]
+    fn test_downlink_sender_uses_stream() {
+        use crate::lorawan::channel_plan::{ChannelPlan, Region};
+        use crate::udp::DownlinkSender;
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let stream = PacketRouterStream::new();
+            let (tx, mut rx) = mpsc::channel(1);
+            stream.set(Some(tx));
+            let devices = DeviceRegistry::new();
+            let gateway = "127.0.0.1:9".parse().unwrap();
+            let sender = DownlinkSender::to_gateway("127.0.0.1:0", gateway, Region::US915)
+                .await
+                .unwrap()
+                .with_packet_router(stream, devices.clone());
+
+            // Unconfirmed data down for 49BE7DF1
+            let plan = ChannelPlan::from_config(&Default::default()).unwrap();
+            let txpk = udp::build_txpk(&plan, None, None, "YPF9vkkAAQAAAAAA", 12).unwrap();
+
+            // Heard over GWMP: a PULL_RESP goes to the gateway, not the stream
+            let mut sighting = Sighting {
+                dev_addr: 0x49BE7DF1,
+                fcnt: 1,
+                rssi: -97.0,
+                snr: None,
+                gateway_eui: "00abcd",
+                path: UplinkPath::Gwmp,
+            };
+            devices.record(&sighting);
+            sender.send_downlink(&txpk).await.unwrap();
+            assert!(rx.try_recv().is_err());
+
+            sighting.path = UplinkPath::PacketRouter;
+            devices.record(&sighting);
+            sender.send_downlink(&txpk).await.unwrap();
+            let Some(envelope_up_v1::Data::PacketDown(down)) = rx.recv().await.unwrap().data
+            else {
+                panic!("not a downlink envelope");
+            };
+            assert!(down.rx1.unwrap().immediate);
+            assert_eq!(down.payload.len(), 12);
+        });
+    }
+
+    #[test]
+    fn test_register_signed() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let client = rt.block_on(async {
+            // Channels connect lazily, so no router needs to be listening
+            let channel = Channel::from_static("http://127.0.0.1:1").connect_lazy();
+            PacketRouterClient {
+                grpc: Grpc::new(channel),
+                keypair: Arc::new(keypair::DelegateKeypair::from_secret([3u8; 32])),
+            }
+        });
+        let Some(envelope_up_v1::Data::Register(register)) = client.register().data else {
+            panic!("not a register envelope");
+        };
+        assert_eq!(register.gateway, client.keypair.public_key_bytes());
+        assert!(keypair::verify(&register, &register.gateway));
+    }
+}
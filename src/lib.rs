@@ -0,0 +1,23 @@
+//! LoraUrbit — sovereign LoRaWAN infrastructure powered by Urbit
+//!
+//! The bridge binary (`src/main.rs`) and its tooling share these modules:
+//! - `udp`: Semtech UDP Packet Forwarder server (GWMP)
+//! - `lorawan`: LoRaWAN PHY payload decoder and frame builder
+//! - `urbit`: Airlock client and %lora-agent poke types
+//! - `helium`: Helium Network integration (Phase 4+)
+//! - `health`: liveness/readiness HTTP probes
+//! - `admin`: local HTTP API for sending downlinks directly
+//! - `logging`: log format and file output
+//! - `metrics`: uplink link-quality histograms and drop counters for `/metrics`
+//! - `supervisor`: restarts background tasks that fail
+
+pub mod admin;
+pub mod config;
+pub mod health;
+pub mod helium;
+pub mod logging;
+pub mod lorawan;
+pub mod metrics;
+pub mod supervisor;
+pub mod udp;
+pub mod urbit;
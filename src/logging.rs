@@ -0,0 +1,148 @@
+//! Log output (`[logging]`): line format and an optional rolling file
+//!
+//! The level filter is set up (and swapped on SIGHUP) in `main`; this module
+//! builds the formatting layers stacked on top of it: one for the console
+//! and, with `logging.file`, one appending to a file that is rolled over
+//! daily or hourly. Both use `logging.format`.
+
+use std::path::Path;
+
+use tracing::Subscriber;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::config::{LogFormat, LogRotation, LoggingConfig};
+
+/// A type-erased layer, so the format can be chosen at runtime
+pub type BoxedLayer<S> = Box<dyn Layer<S> + Send + Sync>;
+
+/// A formatting layer writing `format` lines to `writer`
+pub fn fmt_layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+/// The appender for `logging.file` (None if unset)
+///
+/// Rolled files are named `<file>.YYYY-MM-DD` (hourly: `-HH` appended).
+pub fn file_appender(config: &LoggingConfig) -> anyhow::Result<Option<RollingFileAppender>> {
+    let Some(file) = &config.file else {
+        return Ok(None);
+    };
+    let path = Path::new(file);
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("logging.file {:?} has no file name", file))?;
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let rotation = match config.file_rotation {
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    let appender = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(name.to_string_lossy())
+        .build(dir)
+        .map_err(|e| anyhow::anyhow!("Failed to open logging.file {:?}: {}", file, e))?;
+    Ok(Some(appender))
+}
+
+/// The console layer plus, with `logging.file`, the file layer
+pub fn layers<S>(config: &LoggingConfig) -> anyhow::Result<Vec<BoxedLayer<S>>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let mut layers = vec![fmt_layer(config.format, std::io::stdout, true)];
+    if let Some(appender) = file_appender(config)? {
+        layers.push(fmt_layer(config.format, appender, false));
+    }
+    Ok(layers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn logged(format: LogFormat) -> String {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber =
+            tracing_subscriber::registry().with(fmt_layer(format, move || writer.clone(), false));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(dev_addr = "260B1234", "Uplink received");
+        });
+        let bytes = logs.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_formats() {
+        let line: serde_json::Value = serde_json::from_str(&logged(LogFormat::Json)).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "Uplink received");
+        assert_eq!(line["fields"]["dev_addr"], "260B1234");
+
+        let text = logged(LogFormat::Text);
+        assert_eq!(text.lines().count(), 1);
+        assert!(text.contains("INFO") && text.contains("Uplink received dev_addr=\"260B1234\""));
+        assert!(logged(LogFormat::Pretty).lines().count() > 1);
+    }
+
+    #[test]
+    fn test_file_appender() {
+        let dir = std::env::temp_dir().join(format!("lora-urbit-logging-{}", std::process::id()));
+        let path = dir.join("bridge.log");
+        let mut config = LoggingConfig {
+            level: "info".to_string(),
+            packet_log: None,
+            packet_log_max_mb: 0,
+            format: LogFormat::Json,
+            file: None,
+            file_rotation: LogRotation::Never,
+        };
+        assert!(file_appender(&config).unwrap().is_none());
+
+        // Console plus file, both JSON; `Never` writes the path as given
+        config.file = Some(path.display().to_string());
+        let layers = layers(&config).unwrap();
+        assert_eq!(layers.len(), 2);
+        let subscriber = tracing_subscriber::registry().with(layers);
+        tracing::subscriber::with_default(subscriber, || tracing::warn!("Gateway down"));
+        let content = std::fs::read_to_string(&path).unwrap();
+        let line: serde_json::Value = serde_json::from_str(content.trim()).unwrap();
+        assert_eq!(line["fields"]["message"], "Gateway down");
+
+        config.file = Some("/".to_string());
+        assert!(file_appender(&config).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+//! LoRa time on air (Semtech AN1200.13, SX1276 datasheet §4.1.1.6)
+//!
+//!   Tsym      = 2^SF / BW
+//!   Tpreamble = (n_preamble + 4.25) · Tsym
+//!   n_payload = 8 + max(ceil((8PL − 4SF + 28 + 16CRC − 20IH) / (4(SF − 2DE))) · (CR + 4), 0)
+//!   ToA       = Tpreamble + n_payload · Tsym
+//!
+//! where IH is 1 for an implicit header, CR is 1..4 for coding rates
+//! 4/5..4/8 and DE (low data rate optimization) is mandated when a symbol
+//! lasts longer than 16 ms (SF11/SF12 at 125 kHz). LoRaWAN uplinks carry a
+//! payload CRC; downlinks do not.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use super::datarate::DataRate;
+
+/// Time on air of a `size_bytes` payload at `datarate`
+///
+/// `coding_rate` is 1..4 for 4/5..4/8 and `preamble` the number of
+/// programmed preamble symbols (8 for LoRaWAN). `low_dr_optimize` forces DE
+/// on or off; None applies the >16 ms symbol time rule.
+pub fn time_on_air(
+    size_bytes: usize,
+    datarate: DataRate,
+    coding_rate: u8,
+    preamble: u16,
+    explicit_header: bool,
+    low_dr_optimize: Option<bool>,
+    crc: bool,
+) -> Result<Duration> {
+    let DataRate::LoRa { sf, bw_khz } = datarate else {
+        return Err(anyhow!("Not a LoRa data rate: {}", datarate));
+    };
+    if !(1..=4).contains(&coding_rate) {
+        return Err(anyhow!("Coding rate must be 1-4 (4/5-4/8), got {}", coding_rate));
+    }
+
+    let t_sym = 2f64.powi(sf as i32) / (bw_khz as f64 * 1000.0);
+    let de = low_dr_optimize.unwrap_or(t_sym > 0.016) as u8 as f64;
+    let ih = !explicit_header as u8 as f64;
+    let crc = crc as u8 as f64;
+    let (sf, pl, cr) = (sf as f64, size_bytes as f64, coding_rate as f64);
+
+    let payload_bits = 8.0 * pl - 4.0 * sf + 28.0 + 16.0 * crc - 20.0 * ih;
+    let payload_symbols =
+        8.0 + ((payload_bits / (4.0 * (sf - 2.0 * de))).ceil() * (cr + 4.0)).max(0.0);
+    let t_preamble = (preamble as f64 + 4.25) * t_sym;
+    Ok(Duration::from_secs_f64(t_preamble + payload_symbols * t_sym))
+}
+
+/// Time on air of a LoRaWAN downlink (8-symbol preamble, no CRC)
+pub fn downlink_time_on_air(
+    size_bytes: usize,
+    datarate: DataRate,
+    coding_rate: u8,
+) -> Result<Duration> {
+    time_on_air(size_bytes, datarate, coding_rate, 8, true, None, false)
+}
+
+/// The `time_on_air` coding rate (1..4) of a GWMP `codr` ("4/5".."4/8")
+pub fn parse_coding_rate(codr: &str) -> Result<u8> {
+    match codr {
+        "4/5" => Ok(1),
+        "4/6" => Ok(2),
+        "4/7" => Ok(3),
+        "4/8" => Ok(4),
+        _ => Err(anyhow!("Unknown coding rate: {}", codr)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dr(datr: &str) -> DataRate {
+        datr.parse().unwrap()
+    }
+
+    fn ms(size: usize, datr: &str) -> f64 {
+        let toa = time_on_air(size, dr(datr), 1, 8, true, None, true).unwrap();
+        (toa.as_secs_f64() * 1e6).round() / 1e3
+    }
+
+    #[test]
+    fn test_reference_values() {
+        // CR 4/5, 8-symbol preamble, explicit header, CRC on
+        assert_eq!(ms(13, "SF7BW125"), 46.336);
+        assert_eq!(ms(13, "SF10BW125"), 288.768);
+        assert_eq!(ms(13, "SF12BW125"), 1155.072);
+        assert_eq!(ms(51, "SF7BW125"), 102.656);
+        assert_eq!(ms(51, "SF12BW125"), 2465.792);
+        assert_eq!(ms(242, "SF7BW125"), 379.136);
+        assert_eq!(ms(12, "SF8BW500"), 20.608);
+    }
+
+    #[test]
+    fn test_options() {
+        let ms = |toa: Result<Duration>| (toa.unwrap().as_secs_f64() * 1e6).round() / 1e3;
+
+        // DE is on by default at SF12BW125; forcing it off shortens the frame
+        assert_eq!(ms(time_on_air(51, dr("SF12BW125"), 1, 8, true, Some(false), true)), 2138.112);
+        assert_eq!(ms(time_on_air(13, dr("SF7BW125"), 4, 8, true, None, true)), 61.696);
+        assert_eq!(ms(time_on_air(13, dr("SF7BW125"), 1, 8, false, None, true)), 41.216);
+        assert_eq!(ms(downlink_time_on_air(13, dr("SF7BW125"), 1)), 41.216);
+        assert_eq!(ms(downlink_time_on_air(13, dr("SF7BW125"), 4)), 53.504);
+
+        assert_eq!(parse_coding_rate("4/5").unwrap(), 1);
+        assert_eq!(parse_coding_rate("4/8").unwrap(), 4);
+        assert!(parse_coding_rate("4/9").is_err());
+
+        assert!(time_on_air(13, DataRate::Fsk { bitrate: 50000 }, 1, 8, true, None, true).is_err());
+        assert!(time_on_air(13, dr("SF7BW125"), 5, 8, true, None, true).is_err());
+    }
+}
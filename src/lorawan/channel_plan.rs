@@ -0,0 +1,362 @@
+//! Regional channel plans
+//!
+//! Which uplink channels are enabled and how the RX1 downlink channel and
+//! data rate follow from the uplink (LoRaWAN Regional Parameters 1.0.x):
+//!
+//! - US915: 64 × 125 kHz uplinks at 902.3 + 0.2·n MHz (n = 0..63) and
+//!   8 × 500 kHz at 903.0 + 1.6·n MHz (channels 64..71). RX1 is on
+//!   923.3 + 0.6·(ch mod 8) MHz at BW500; RX2 is 923.3 MHz SF12BW500.
+//!   Sub-band k (1..8) enables channels 8(k−1)..8(k−1)+7 plus channel 63+k.
+//! - AU915: as US915, with uplinks at 915.2 + 0.2·n and 915.9 + 1.6·n MHz.
+//! - EU868: 868.1, 868.3 and 868.5 MHz plus any configured extras. RX1 uses
+//!   the uplink frequency and data rate; RX2 is 869.525 MHz SF12BW125.
+//!
+//! A downlink's MACPayload (PHY payload minus MHDR and MIC) is limited per
+//! data rate, using the repeater-compatible maximums (M):
+//!
+//! - US915/AU915 downlinks (BW500): SF12 41, SF11 117, SF10-7 230 bytes.
+//! - EU868: SF12-10 59, SF9 123, SF8/SF7/SF7BW250/FSK 230 bytes.
+//!
+//! Class B ping slots default to 869.525 MHz SF9BW125 in EU868. US915 and
+//! AU915 hop over the eight 923.3 + 0.6·n MHz channels at SF12BW500, picking
+//! (BeaconTime / 128 + DevAddr) mod 8 each beacon period.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use super::datarate::DataRate;
+use crate::config::{ChannelPlanConfig, DownlinkConfig, DownlinkMode, GatewayConfig};
+
+/// LoRaWAN region selected by `channel_plan.region`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Region {
+    #[default]
+    US915,
+    AU915,
+    EU868,
+}
+
+impl Region {
+    /// First 125 kHz and first 500 kHz uplink channel (MHz) of a 72-channel plan
+    fn fixed_plan_base(self) -> Option<(f64, f64)> {
+        match self {
+            Region::US915 => Some((902.3, 903.0)),
+            Region::AU915 => Some((915.2, 915.9)),
+            Region::EU868 => None,
+        }
+    }
+
+    /// Largest downlink MACPayload (bytes) at `datr`, or None if the region
+    /// has no downlink data rate like it
+    pub fn max_mac_payload(self, datr: DataRate) -> Option<usize> {
+        match (self, datr) {
+            (Region::US915 | Region::AU915, DataRate::LoRa { sf, bw_khz: 500 }) => match sf {
+                12 => Some(41),
+                11 => Some(117),
+                7..=10 => Some(230),
+                _ => None,
+            },
+            (Region::US915 | Region::AU915, _) => None,
+            (Region::EU868, DataRate::LoRa { sf, bw_khz: 125 }) => match sf {
+                10..=12 => Some(59),
+                9 => Some(123),
+                7 | 8 => Some(230),
+                _ => None,
+            },
+            (Region::EU868, DataRate::LoRa { sf: 7, bw_khz: 250 }) => Some(230),
+            (Region::EU868, DataRate::Fsk { .. }) => Some(230),
+            (Region::EU868, _) => None,
+        }
+    }
+}
+
+/// Enabled uplink channels and downlink parameters for one region
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelPlan {
+    pub region: Region,
+    /// Enabled uplink frequencies in MHz
+    pub uplink_channels: Vec<f64>,
+    /// RX2 frequency in MHz
+    pub rx2_freq: f64,
+    /// RX2 data rate (e.g. SF12BW500)
+    pub rx2_datr: DataRate,
+    /// Downlink TX power in dBm
+    pub tx_power: u8,
+    /// `tx_power` overrides by lowercase gateway EUI (`[[gateways]]`)
+    pub gateway_tx_power: HashMap<String, u8>,
+    /// Downlink coding rate from `[downlink]`, if set
+    pub downlink_codr: Option<String>,
+    /// Class C data rate from `[downlink]`, if not the RX2 one
+    pub downlink_datr: Option<DataRate>,
+    /// Timing of downlink txpks from `[downlink]`
+    pub downlink_mode: DownlinkMode,
+}
+
+impl ChannelPlan {
+    /// Build from `[channel_plan]`, filling in the region's defaults
+    pub fn from_config(config: &ChannelPlanConfig) -> anyhow::Result<Self> {
+        let region = config.region;
+        let (uplink_channels, rx2_freq, rx2_datr, tx_power) = match region.fixed_plan_base() {
+            Some(_) => {
+                let channels = match config.sub_band {
+                    Some(sub_band) => Self::sub_band_channels(sub_band)?,
+                    None => (0..72).collect(),
+                };
+                if !config.channels.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "channel_plan.channels is only supported for EU868; use sub_band"
+                    ));
+                }
+                let freqs = channels
+                    .into_iter()
+                    .map(|ch| Self::fixed_channel_freq(region, ch))
+                    .collect();
+                (freqs, 923.3, DataRate::lora(12, 500), 27)
+            }
+            None => {
+                if config.sub_band.is_some() {
+                    return Err(anyhow::anyhow!("channel_plan.sub_band is not used in EU868"));
+                }
+                let mut freqs = vec![868.1, 868.3, 868.5];
+                freqs.extend(&config.channels);
+                (freqs, 869.525, DataRate::lora(12, 125), 14)
+            }
+        };
+
+        Ok(Self {
+            region,
+            uplink_channels,
+            rx2_freq: config.rx2_freq.unwrap_or(rx2_freq),
+            rx2_datr: config.rx2_datr.clone().unwrap_or(rx2_datr),
+            tx_power,
+            gateway_tx_power: HashMap::new(),
+            downlink_codr: None,
+            downlink_datr: None,
+            downlink_mode: DownlinkMode::Immediate,
+        })
+    }
+
+    /// Apply the `tx_power` overrides of `[[gateways]]`
+    pub fn with_gateways(mut self, gateways: &[GatewayConfig]) -> Self {
+        for gateway in gateways {
+            if let Some(tx_power) = gateway.tx_power {
+                self.gateway_tx_power
+                    .insert(gateway.eui.to_ascii_lowercase(), tx_power);
+            }
+        }
+        self
+    }
+
+    /// Apply the coding rate and data rate overrides of `[downlink]`
+    pub fn with_downlink(mut self, downlink: &DownlinkConfig) -> Self {
+        self.downlink_codr = downlink.coding_rate.clone();
+        self.downlink_datr = downlink.datr.clone();
+        self.downlink_mode = downlink.mode;
+        self
+    }
+
+    /// Downlink TX power (dBm) for `gateway_eui`, or the region default
+    pub fn tx_power_for(&self, gateway_eui: Option<&str>) -> u8 {
+        gateway_eui
+            .and_then(|eui| self.gateway_tx_power.get(&eui.to_ascii_lowercase()))
+            .copied()
+            .unwrap_or(self.tx_power)
+    }
+
+    /// Channel indices enabled by a US915/AU915 sub-band (1..8)
+    pub fn sub_band_channels(sub_band: u8) -> anyhow::Result<Vec<u8>> {
+        if !(1..=8).contains(&sub_band) {
+            return Err(anyhow::anyhow!("Sub-band must be 1-8, got {}", sub_band));
+        }
+        let first = (sub_band - 1) * 8;
+        let mut channels: Vec<u8> = (first..first + 8).collect();
+        channels.push(63 + sub_band);
+        Ok(channels)
+    }
+
+    /// Uplink frequency (MHz) of channel `ch` in a 72-channel plan
+    fn fixed_channel_freq(region: Region, ch: u8) -> f64 {
+        let (base_125, base_500) = region.fixed_plan_base().unwrap_or_default();
+        let freq = match ch {
+            0..=63 => base_125 + 0.2 * ch as f64,
+            _ => base_500 + 1.6 * (ch - 64) as f64,
+        };
+        round_khz(freq)
+    }
+
+    /// Regional channel index of an uplink frequency, if it is enabled
+    pub fn uplink_channel(&self, freq: f64) -> Option<u8> {
+        let freq = round_khz(freq);
+        if !self.uplink_channels.contains(&freq) {
+            return None;
+        }
+        match self.region.fixed_plan_base() {
+            Some(_) => (0..72).find(|&ch| Self::fixed_channel_freq(self.region, ch) == freq),
+            None => self
+                .uplink_channels
+                .iter()
+                .position(|&f| f == freq)
+                .map(|i| i as u8),
+        }
+    }
+
+    /// Regional data rate index (DR0..) of an uplink data rate
+    pub fn uplink_dr(&self, datr: DataRate) -> Option<u8> {
+        match (self.region, datr) {
+            // DR0-3: SF10-7 BW125, DR4: SF8 BW500
+            (Region::US915, DataRate::LoRa { sf: sf @ 7..=10, bw_khz: 125 }) => Some(10 - sf),
+            (Region::US915, DataRate::LoRa { sf: 8, bw_khz: 500 }) => Some(4),
+            // DR0-5: SF12-7 BW125, DR6: SF8 BW500
+            (Region::AU915, DataRate::LoRa { sf: sf @ 7..=12, bw_khz: 125 }) => Some(12 - sf),
+            (Region::AU915, DataRate::LoRa { sf: 8, bw_khz: 500 }) => Some(6),
+            // DR0-5: SF12-7 BW125, DR6: SF7 BW250, DR7: FSK 50 kbps
+            (Region::EU868, DataRate::LoRa { sf: sf @ 7..=12, bw_khz: 125 }) => Some(12 - sf),
+            (Region::EU868, DataRate::LoRa { sf: 7, bw_khz: 250 }) => Some(6),
+            (Region::EU868, DataRate::Fsk { bitrate: 50_000 }) => Some(7),
+            _ => None,
+        }
+    }
+
+    /// Data rate of downlink data rate index `dr` (e.g. a JoinAccept's RX2DataRate)
+    pub fn downlink_datr(&self, dr: u8) -> Option<DataRate> {
+        match (self.region, dr) {
+            // DR8-13: SF12-7 BW500
+            (Region::US915 | Region::AU915, 8..=13) => Some(DataRate::lora(20 - dr, 500)),
+            (Region::EU868, 0..=5) => Some(DataRate::lora(12 - dr, 125)),
+            (Region::EU868, 6) => Some(DataRate::lora(7, 250)),
+            (Region::EU868, 7) => Some(DataRate::Fsk { bitrate: 50_000 }),
+            _ => None,
+        }
+    }
+
+    /// RX1 downlink frequency (MHz) for an uplink on `uplink_freq`
+    pub fn rx1_freq(&self, uplink_freq: f64) -> Option<f64> {
+        let ch = self.uplink_channel(uplink_freq)?;
+        match self.region {
+            Region::US915 | Region::AU915 => Some(round_khz(923.3 + 0.6 * (ch % 8) as f64)),
+            Region::EU868 => Some(round_khz(uplink_freq)),
+        }
+    }
+
+    /// RX1 data rate for an uplink at `uplink_datr` (RX1DROffset 0)
+    pub fn rx1_datr(&self, uplink_datr: DataRate) -> Option<DataRate> {
+        match self.region {
+            // DR0-3 (SF10-7 BW125) → DR10-13 (same SF, BW500); DR4 (SF8BW500) → DR13
+            Region::US915 | Region::AU915 => match uplink_datr {
+                DataRate::LoRa { sf, bw_khz: 125 } => Some(DataRate::lora(sf, 500)),
+                DataRate::LoRa { sf, bw_khz: 500 } => Some(DataRate::lora((sf - 1).max(7), 500)),
+                _ => None,
+            },
+            Region::EU868 => Some(uplink_datr),
+        }
+    }
+
+    /// Frequency and data rate of a device's ping slots in a beacon period
+    pub fn ping_slot_channel(&self, beacon_time: u32, dev_addr: u32) -> (f64, DataRate) {
+        match self.region {
+            Region::US915 | Region::AU915 => {
+                let ch = (beacon_time as u64 / 128 + dev_addr as u64) % 8;
+                (round_khz(923.3 + 0.6 * ch as f64), DataRate::lora(12, 500))
+            }
+            Region::EU868 => (869.525, DataRate::lora(9, 125)),
+        }
+    }
+}
+
+/// Round a frequency in MHz to whole kHz so channel lookups compare exactly
+fn round_khz(freq: f64) -> f64 {
+    (freq * 1000.0).round() / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn us915_sub_band_2() -> ChannelPlan {
+        ChannelPlan::from_config(&ChannelPlanConfig {
+            sub_band: Some(2),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_us915_sub_band_2() {
+        assert_eq!(
+            ChannelPlan::sub_band_channels(2).unwrap(),
+            vec![8, 9, 10, 11, 12, 13, 14, 15, 65]
+        );
+
+        let plan = us915_sub_band_2();
+        assert_eq!(
+            plan.uplink_channels,
+            vec![903.9, 904.1, 904.3, 904.5, 904.7, 904.9, 905.1, 905.3, 904.6]
+        );
+        assert_eq!(plan.uplink_channel(903.9), Some(8));
+        assert_eq!(plan.uplink_channel(905.3), Some(15));
+        assert_eq!(plan.uplink_channel(904.6), Some(65));
+        // Sub-band 1 channel is not enabled
+        assert_eq!(plan.uplink_channel(902.3), None);
+
+        // RX1: 923.3 + 0.6 × (ch mod 8)
+        assert_eq!(plan.rx1_freq(903.9), Some(923.3));
+        assert_eq!(plan.rx1_freq(904.5), Some(925.1));
+        assert_eq!(plan.rx1_freq(905.3), Some(927.5));
+        assert_eq!(plan.rx1_freq(904.6), Some(923.9));
+        assert_eq!(plan.rx1_datr(DataRate::lora(10, 125)), Some(DataRate::lora(10, 500)));
+        assert_eq!(plan.rx1_datr(DataRate::lora(8, 500)), Some(DataRate::lora(7, 500)));
+        assert_eq!(plan.uplink_dr(DataRate::lora(10, 125)), Some(0));
+        assert_eq!(plan.uplink_dr(DataRate::lora(7, 125)), Some(3));
+        assert_eq!(plan.uplink_dr(DataRate::lora(8, 500)), Some(4));
+        assert_eq!(plan.uplink_dr(DataRate::lora(12, 125)), None);
+
+        assert_eq!(plan.rx2_freq, 923.3);
+        assert_eq!(plan.rx2_datr.to_string(), "SF12BW500");
+        assert!(ChannelPlan::sub_band_channels(9).is_err());
+
+        // Class B ping slots hop with the beacon period
+        let (freq, datr) = plan.ping_slot_channel(1_300_000_000, 0x260B_1234);
+        assert_eq!((freq, datr.to_string().as_str()), (926.9, "SF12BW500"));
+        assert_eq!(plan.ping_slot_channel(1_300_000_128, 0x260B_1234).0, 927.5);
+    }
+
+    #[test]
+    fn test_max_mac_payload() {
+        assert_eq!(Region::US915.max_mac_payload(DataRate::lora(12, 500)), Some(41));
+        assert_eq!(Region::AU915.max_mac_payload(DataRate::lora(9, 500)), Some(230));
+        // BW125 is uplink-only in the 72-channel plans
+        assert_eq!(Region::US915.max_mac_payload(DataRate::lora(10, 125)), None);
+        assert_eq!(Region::EU868.max_mac_payload(DataRate::lora(12, 125)), Some(59));
+        assert_eq!(Region::EU868.max_mac_payload(DataRate::lora(9, 125)), Some(123));
+        assert_eq!(Region::EU868.max_mac_payload(DataRate::Fsk { bitrate: 50000 }), Some(230));
+        assert_eq!(Region::EU868.max_mac_payload(DataRate::lora(12, 500)), None);
+    }
+
+    #[test]
+    fn test_eu868_defaults() {
+        let config: ChannelPlanConfig = toml::from_str(
+            r#"
+            region = "eu868"
+            channels = [867.1, 867.3]
+            "#,
+        )
+        .unwrap();
+        let plan = ChannelPlan::from_config(&config).unwrap();
+        assert_eq!(plan.uplink_channels.len(), 5);
+        assert_eq!(plan.rx1_freq(867.3), Some(867.3));
+        assert_eq!(plan.rx1_datr(DataRate::lora(9, 125)), Some(DataRate::lora(9, 125)));
+        assert_eq!(plan.uplink_dr(DataRate::lora(12, 125)), Some(0));
+        assert_eq!(plan.uplink_dr(DataRate::lora(7, 250)), Some(6));
+        assert_eq!(plan.rx2_freq, 869.525);
+        assert_eq!(plan.ping_slot_channel(0, 0), (869.525, DataRate::lora(9, 125)));
+
+        let bad = ChannelPlanConfig {
+            region: Region::EU868,
+            sub_band: Some(2),
+            ..Default::default()
+        };
+        assert!(ChannelPlan::from_config(&bad).is_err());
+    }
+}
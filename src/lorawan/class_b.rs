@@ -0,0 +1,206 @@
+//! Class B ping slot scheduling (LoRaWAN 1.0.4 §12-13)
+//!
+//! Gateways broadcast a beacon every 128 s of GPS time. After the 2.12 s
+//! beacon-reserved interval, the rest of the period is split into 4096
+//! slots of 30 ms. A Class B device with ping slot periodicity p opens a
+//! receive window every 2^(5+p) slots, starting at a pseudorandom offset
+//! that changes each beacon period:
+//!
+//!   Rand       = aes128_encrypt(16 × 0x00, BeaconTime | DevAddr | pad16)
+//!   pingOffset = (Rand[0] + Rand[1] × 256) mod pingPeriod
+//!
+//! with BeaconTime (GPS seconds of the beacon, mod 2^32) and DevAddr
+//! little-endian. To put a downlink in a slot the gateway needs a `tmst`, so
+//! `ClassBScheduler` keeps a reference pairing the concentrator counter with
+//! GPS time, taken from an uplink that carried both (`rxpk.tmst`/`tmms`).
+//! Every gateway's counter runs on its own, so there is one reference per
+//! gateway and a downlink is timed on the one of the gateway sending it.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+
+/// Beacon period (ms of GPS time)
+pub const BEACON_PERIOD_MS: u64 = 128_000;
+
+/// Time after the beacon start before the first ping slot (ms)
+pub const BEACON_RESERVED_MS: u64 = 2_120;
+
+/// Length of one ping slot (ms)
+pub const SLOT_LEN_MS: u64 = 30;
+
+/// Ping slots per beacon window
+const SLOTS_PER_WINDOW: u32 = 4096;
+
+/// A reference older than this is not trusted (the 32-bit µs `tmst` wraps
+/// after 71 minutes)
+const MAX_REFERENCE_AGE: Duration = Duration::from_secs(30 * 60);
+
+/// Slots between a device's ping slots for periodicity 0-7 (2^(5+p))
+pub fn ping_period(periodicity: u8) -> u32 {
+    SLOTS_PER_WINDOW >> (7 - periodicity.min(7))
+}
+
+/// A device's first ping slot in the beacon period starting at `beacon_time`
+#[cfg(feature = "phase4")]
+pub fn ping_offset(beacon_time: u32, dev_addr: u32, ping_period: u32) -> u32 {
+    let mut block = [0u8; 16];
+    block[..4].copy_from_slice(&beacon_time.to_le_bytes());
+    block[4..8].copy_from_slice(&dev_addr.to_le_bytes());
+    super::crypto::aes_ecb(&[0; 16], &mut block, false);
+    (block[0] as u32 + block[1] as u32 * 256) % ping_period
+}
+
+/// One ping slot of a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingSlot {
+    /// GPS seconds of the beacon that opens the slot's period
+    pub beacon_time: u32,
+    /// Start of the slot (ms of GPS time)
+    pub gps_ms: u64,
+}
+
+/// First ping slot of `dev_addr` starting at or after `earliest_gps_ms`
+pub fn next_ping_slot(dev_addr: u32, periodicity: u8, earliest_gps_ms: u64) -> Result<PingSlot> {
+    #[cfg(feature = "phase4")]
+    {
+        let period = ping_period(periodicity);
+        let mut beacon_ms = earliest_gps_ms - earliest_gps_ms % BEACON_PERIOD_MS;
+        loop {
+            let beacon_time = (beacon_ms / 1000) as u32;
+            let offset = ping_offset(beacon_time, dev_addr, period);
+            let slot = (offset..SLOTS_PER_WINDOW)
+                .step_by(period as usize)
+                .map(|n| beacon_ms + BEACON_RESERVED_MS + n as u64 * SLOT_LEN_MS)
+                .find(|&gps_ms| gps_ms >= earliest_gps_ms);
+            if let Some(gps_ms) = slot {
+                return Ok(PingSlot { beacon_time, gps_ms });
+            }
+            beacon_ms += BEACON_PERIOD_MS;
+        }
+    }
+    #[cfg(not(feature = "phase4"))]
+    {
+        let _ = (dev_addr, periodicity, earliest_gps_ms);
+        Err(anyhow!("Class B scheduling requires the phase4 feature"))
+    }
+}
+
+/// Concentrator timestamp ↔ GPS time reference from a gateway uplink
+#[derive(Debug, Clone, Copy)]
+struct TimeReference {
+    tmst: u32,
+    gps_ms: u64,
+    received: Instant,
+}
+
+/// Maps Class B ping slots to gateway `tmst` values
+#[derive(Debug, Default)]
+pub struct ClassBScheduler {
+    /// By gateway EUI (hex)
+    references: HashMap<String, TimeReference>,
+}
+
+impl ClassBScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that gateway `gateway_eui`'s counter read `tmst` at GPS time `gps_ms`
+    pub fn update_reference(
+        &mut self,
+        gateway_eui: &str,
+        tmst: u32,
+        gps_ms: u64,
+        received: Instant,
+    ) {
+        let reference = TimeReference {
+            tmst,
+            gps_ms,
+            received,
+        };
+        self.references.insert(gateway_eui.to_ascii_lowercase(), reference);
+    }
+
+    /// The next ping slot at least `lead` after `now`, and its `tmst` on
+    /// gateway `gateway_eui`
+    pub fn schedule(
+        &self,
+        gateway_eui: &str,
+        dev_addr: u32,
+        periodicity: u8,
+        now: Instant,
+        lead: Duration,
+    ) -> Result<(PingSlot, u32)> {
+        let reference = self
+            .references
+            .get(&gateway_eui.to_ascii_lowercase())
+            .filter(|r| now.saturating_duration_since(r.received) < MAX_REFERENCE_AGE)
+            .ok_or_else(|| anyhow!("No recent GPS time reference from gateway {}", gateway_eui))?;
+
+        let elapsed = now.saturating_duration_since(reference.received) + lead;
+        let earliest = reference.gps_ms + elapsed.as_millis() as u64;
+        let slot = next_ping_slot(dev_addr, periodicity, earliest)?;
+        let delta_us = (slot.gps_ms - reference.gps_ms) * 1000;
+        Ok((slot, reference.tmst.wrapping_add(delta_us as u32)))
+    }
+}
+
+#[cfg(all(test, feature = "phase4"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ping_offset() {
+        // Rand = AES(0, BeaconTime | DevAddr | 0..) computed independently
+        assert_eq!(ping_offset(1_300_000_000, 0x260B_1234, 4096), 27862 % 4096);
+        assert_eq!(ping_offset(1_300_000_000, 0x260B_1234, ping_period(0)), 22);
+        assert_eq!(ping_offset(1_300_000_000, 0x260B_1234, ping_period(2)), 86);
+        assert_eq!(ping_offset(1_300_000_128, 0x260B_1234, ping_period(7)), 1260);
+        assert_eq!(ping_offset(1_300_000_000, 0x4800_0001, ping_period(7)), 3303);
+        // AES-128 of the all-zero block under the zero key: 66e94bd4...
+        assert_eq!(ping_offset(0, 0, ping_period(7)), 0xE966 % 4096);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let beacon_ms = 1_300_000_000 * 1000;
+        // Periodicity 7: one slot per beacon period, at slot 3286
+        let slot = next_ping_slot(0x260B_1234, 7, beacon_ms).unwrap();
+        assert_eq!(slot.beacon_time, 1_300_000_000);
+        assert_eq!(slot.gps_ms, beacon_ms + BEACON_RESERVED_MS + 3286 * SLOT_LEN_MS);
+        // Too late for this period: the next beacon's offset (1260) applies
+        let slot = next_ping_slot(0x260B_1234, 7, slot.gps_ms + 1).unwrap();
+        assert_eq!(slot.beacon_time, 1_300_000_128);
+        assert_eq!(
+            slot.gps_ms,
+            beacon_ms + BEACON_PERIOD_MS + BEACON_RESERVED_MS + 1260 * SLOT_LEN_MS
+        );
+        // Periodicity 0: a slot every 32 (offset 22)
+        let slot = next_ping_slot(0x260B_1234, 0, beacon_ms + 5_000).unwrap();
+        assert_eq!(slot.gps_ms, beacon_ms + BEACON_RESERVED_MS + (22 + 32 * 3) * SLOT_LEN_MS);
+
+        let mut scheduler = ClassBScheduler::new();
+        let now = Instant::now();
+        let gateway = "aabbccddeeff0011";
+        assert!(scheduler.schedule(gateway, 0x260B_1234, 7, now, Duration::ZERO).is_err());
+        // Counter near wrap-around at the beacon
+        scheduler.update_reference(gateway, u32::MAX - 999, beacon_ms, now);
+        // Another gateway's counter doesn't move this one's reference
+        scheduler.update_reference("0000000000000001", 5_000_000, beacon_ms, now);
+        let (slot, tmst) = scheduler
+            .schedule("AABBCCDDEEFF0011", 0x260B_1234, 7, now, Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(slot.gps_ms, beacon_ms + BEACON_RESERVED_MS + 3286 * SLOT_LEN_MS);
+        let delta_us = (BEACON_RESERVED_MS + 3286 * SLOT_LEN_MS) as u32 * 1000;
+        assert_eq!(tmst, delta_us - 1000);
+        let other = scheduler.schedule("0000000000000001", 0x260B_1234, 7, now, Duration::ZERO);
+        assert_eq!(other.unwrap().1, 5_000_000 + delta_us);
+        let unheard = "0000000000000002";
+        assert!(scheduler.schedule(unheard, 0x260B_1234, 7, now, Duration::ZERO).is_err());
+
+        let stale = now + MAX_REFERENCE_AGE;
+        assert!(scheduler.schedule(gateway, 0x260B_1234, 7, stale, Duration::ZERO).is_err());
+    }
+}
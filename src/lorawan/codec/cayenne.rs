@@ -0,0 +1,218 @@
+//! Cayenne Low Power Payload (LPP) decoder
+//!
+//! An LPP payload is a sequence of `Channel(1) | Type(1) | Data(n)` records,
+//! where the data size and resolution are fixed by the IPSO-derived type:
+//!
+//! | Type | ID  | Size | Resolution            |
+//! |------|-----|------|-----------------------|
+//! | Digital Input  | 0   | 1 | 1                     |
+//! | Digital Output | 1   | 1 | 1                     |
+//! | Analog Input   | 2   | 2 | 0.01 signed           |
+//! | Analog Output  | 3   | 2 | 0.01 signed           |
+//! | Illuminance    | 101 | 2 | 1 lux unsigned        |
+//! | Presence       | 102 | 1 | 1                     |
+//! | Temperature    | 103 | 2 | 0.1 °C signed         |
+//! | Humidity       | 104 | 1 | 0.5 % unsigned        |
+//! | Accelerometer  | 113 | 6 | 0.001 G signed/axis   |
+//! | Barometer      | 115 | 2 | 0.1 hPa unsigned      |
+//! | Gyrometer      | 134 | 6 | 0.01 °/s signed/axis  |
+//! | GPS            | 136 | 9 | 0.0001 ° lat/lon, 0.01 m alt, signed |
+//!
+//! All values are big-endian. An unknown type has no known length, so it and
+//! the rest of the payload are reported as a single `Unknown` hex reading.
+//!
+//! Reference: <https://docs.mydevices.com/docs/lorawan/cayenne-lpp>
+
+use serde::{Deserialize, Serialize};
+
+/// One decoded LPP record
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CayenneReading {
+    pub channel: u8,
+    #[serde(flatten)]
+    pub value: CayenneValue,
+}
+
+/// Decoded LPP value, tagged with its type name
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum CayenneValue {
+    DigitalInput { value: u8 },
+    DigitalOutput { value: u8 },
+    AnalogInput { value: f64 },
+    AnalogOutput { value: f64 },
+    Illuminance { lux: u16 },
+    Presence { value: u8 },
+    Temperature { celsius: f64 },
+    Humidity { percent: f64 },
+    Accelerometer { x: f64, y: f64, z: f64 },
+    Barometer { hpa: f64 },
+    Gyrometer { x: f64, y: f64, z: f64 },
+    Gps { latitude: f64, longitude: f64, altitude: f64 },
+    /// Unrecognized type ID; `hex` holds the remainder of the payload
+    Unknown { type_id: u8, hex: String },
+}
+
+/// Decode a Cayenne LPP payload into its readings
+pub fn decode_cayenne(data: &[u8]) -> anyhow::Result<Vec<CayenneReading>> {
+    let mut readings = Vec::new();
+    let mut rest = data;
+
+    while !rest.is_empty() {
+        if rest.len() < 2 {
+            return Err(anyhow::anyhow!(
+                "Truncated Cayenne LPP record header at byte {}",
+                data.len() - rest.len()
+            ));
+        }
+        let channel = rest[0];
+        let type_id = rest[1];
+        rest = &rest[2..];
+
+        let Some(size) = data_size(type_id) else {
+            readings.push(CayenneReading {
+                channel,
+                value: CayenneValue::Unknown {
+                    type_id,
+                    hex: hex::encode(rest),
+                },
+            });
+            break;
+        };
+        if rest.len() < size {
+            return Err(anyhow::anyhow!(
+                "Cayenne LPP type {} on channel {} needs {} bytes, got {}",
+                type_id,
+                channel,
+                size,
+                rest.len()
+            ));
+        }
+
+        let (value, tail) = rest.split_at(size);
+        readings.push(CayenneReading {
+            channel,
+            value: decode_value(type_id, value),
+        });
+        rest = tail;
+    }
+
+    Ok(readings)
+}
+
+/// Data size in bytes for a known LPP type
+fn data_size(type_id: u8) -> Option<usize> {
+    match type_id {
+        0 | 1 | 102 | 104 => Some(1),
+        2 | 3 | 101 | 103 | 115 => Some(2),
+        113 | 134 => Some(6),
+        136 => Some(9),
+        _ => None,
+    }
+}
+
+/// Decode a known type's data bytes (length already checked)
+fn decode_value(type_id: u8, b: &[u8]) -> CayenneValue {
+    let i16_at = |i: usize| i16::from_be_bytes([b[i], b[i + 1]]) as f64;
+    let u16_at = |i: usize| u16::from_be_bytes([b[i], b[i + 1]]);
+    // 24-bit signed: shift into the top of an i32 and back to sign-extend
+    let i24_at = |i: usize| (i32::from_be_bytes([b[i], b[i + 1], b[i + 2], 0]) >> 8) as f64;
+
+    match type_id {
+        0 => CayenneValue::DigitalInput { value: b[0] },
+        1 => CayenneValue::DigitalOutput { value: b[0] },
+        2 => CayenneValue::AnalogInput { value: i16_at(0) / 100.0 },
+        3 => CayenneValue::AnalogOutput { value: i16_at(0) / 100.0 },
+        101 => CayenneValue::Illuminance { lux: u16_at(0) },
+        102 => CayenneValue::Presence { value: b[0] },
+        103 => CayenneValue::Temperature { celsius: i16_at(0) / 10.0 },
+        104 => CayenneValue::Humidity { percent: b[0] as f64 / 2.0 },
+        113 => CayenneValue::Accelerometer {
+            x: i16_at(0) / 1000.0,
+            y: i16_at(2) / 1000.0,
+            z: i16_at(4) / 1000.0,
+        },
+        115 => CayenneValue::Barometer { hpa: u16_at(0) as f64 / 10.0 },
+        134 => CayenneValue::Gyrometer {
+            x: i16_at(0) / 100.0,
+            y: i16_at(2) / 100.0,
+            z: i16_at(4) / 100.0,
+        },
+        136 => CayenneValue::Gps {
+            latitude: i24_at(0) / 10_000.0,
+            longitude: i24_at(3) / 10_000.0,
+            altitude: i24_at(6) / 100.0,
+        },
+        _ => unreachable!("data_size() only accepts known types"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_temperature() {
+        // LPP spec example: two temperature sensors
+        let data = hex::decode("03670110056700FF").unwrap();
+        let readings = decode_cayenne(&data).unwrap();
+        assert_eq!(
+            readings,
+            vec![
+                CayenneReading {
+                    channel: 3,
+                    value: CayenneValue::Temperature { celsius: 27.2 },
+                },
+                CayenneReading {
+                    channel: 5,
+                    value: CayenneValue::Temperature { celsius: 25.5 },
+                },
+            ]
+        );
+
+        // Negative temperature: 0xFFD7 = -41 → -4.1 °C
+        let readings = decode_cayenne(&hex::decode("0167FFD7").unwrap()).unwrap();
+        assert_eq!(readings[0].value, CayenneValue::Temperature { celsius: -4.1 });
+    }
+
+    #[test]
+    fn test_decode_gps() {
+        // LPP spec example: GPS on channel 1
+        let data = hex::decode("018806765ff2960a0003e8").unwrap();
+        let readings = decode_cayenne(&data).unwrap();
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].channel, 1);
+        assert_eq!(
+            readings[0].value,
+            CayenneValue::Gps {
+                latitude: 42.3519,
+                longitude: -87.9094,
+                altitude: 10.0,
+            }
+        );
+
+        let json = serde_json::to_value(&readings[0]).unwrap();
+        assert_eq!(json["type"], "gps");
+        assert_eq!(json["channel"], 1);
+    }
+
+    #[test]
+    fn test_decode_unknown_and_truncated() {
+        // Humidity, then an unknown type 0x99 swallowing the rest
+        let readings = decode_cayenne(&hex::decode("0268500799AABB").unwrap()).unwrap();
+        assert_eq!(readings[0].value, CayenneValue::Humidity { percent: 40.0 });
+        assert_eq!(
+            readings[1],
+            CayenneReading {
+                channel: 7,
+                value: CayenneValue::Unknown {
+                    type_id: 0x99,
+                    hex: "aabb".to_string(),
+                },
+            }
+        );
+
+        // Temperature with one data byte missing
+        assert!(decode_cayenne(&hex::decode("036701").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+//! Application payload codecs
+//!
+//! Turn a decrypted `FRMPayload` into structured JSON before the packet is
+//! poked to Urbit. A codec is chosen per DevAddr (`lorawan.device_codecs`)
+//! with `lorawan.codec` as the fallback for every other device.
+//!
+//! Codecs implement `PayloadCodec` and are held as trait objects, so a codec
+//! hosted outside the binary (e.g. a WASM module loaded from a path) only
+//! needs a new `CodecKind` variant that builds it.
+
+pub mod cayenne;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::config::LorawanConfig;
+
+pub use cayenne::{decode_cayenne, CayenneReading, CayenneValue};
+
+/// Decodes an application payload into JSON
+pub trait PayloadCodec: Send + Sync {
+    /// Short name for logs
+    fn name(&self) -> &str;
+
+    /// Decode `bytes` received on `f_port`
+    fn decode(&self, f_port: u8, bytes: &[u8]) -> anyhow::Result<serde_json::Value>;
+}
+
+/// Hex string of the raw payload (the same form as `LoRaPacket::payload`)
+pub struct RawHexCodec;
+
+impl PayloadCodec for RawHexCodec {
+    fn name(&self) -> &str {
+        "raw"
+    }
+
+    fn decode(&self, _f_port: u8, bytes: &[u8]) -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::Value::String(hex::encode(bytes)))
+    }
+}
+
+/// Cayenne LPP readings as a JSON array
+pub struct CayenneCodec;
+
+impl PayloadCodec for CayenneCodec {
+    fn name(&self) -> &str {
+        "cayenne"
+    }
+
+    fn decode(&self, _f_port: u8, bytes: &[u8]) -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::to_value(decode_cayenne(bytes)?)?)
+    }
+}
+
+/// Payload codec selected by `lorawan.codec` / `lorawan.device_codecs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodecKind {
+    /// Raw hex payload
+    Raw,
+    /// Cayenne Low Power Payload
+    Cayenne,
+}
+
+impl CodecKind {
+    pub fn build(self) -> Arc<dyn PayloadCodec> {
+        match self {
+            CodecKind::Raw => Arc::new(RawHexCodec),
+            CodecKind::Cayenne => Arc::new(CayenneCodec),
+        }
+    }
+}
+
+/// Codec lookup by DevAddr with a global fallback
+#[derive(Clone, Default)]
+pub struct CodecRegistry {
+    default: Option<Arc<dyn PayloadCodec>>,
+    devices: HashMap<u32, Arc<dyn PayloadCodec>>,
+}
+
+impl CodecRegistry {
+    /// Build from `lorawan.codec` and `lorawan.device_codecs`
+    pub fn from_config(config: &LorawanConfig) -> anyhow::Result<Self> {
+        let mut registry = Self {
+            default: config.codec.map(CodecKind::build),
+            devices: HashMap::new(),
+        };
+        for (dev_addr, kind) in &config.device_codecs {
+            let addr = u32::from_str_radix(dev_addr, 16).map_err(|e| {
+                anyhow::anyhow!("Invalid DevAddr {:?} in lorawan.device_codecs: {}", dev_addr, e)
+            })?;
+            registry.set_device_codec(addr, kind.build());
+        }
+        Ok(registry)
+    }
+
+    /// Use `codec` for every device without a per-device codec
+    pub fn set_default(&mut self, codec: Arc<dyn PayloadCodec>) {
+        self.default = Some(codec);
+    }
+
+    /// Use `codec` for a single device
+    pub fn set_device_codec(&mut self, dev_addr: u32, codec: Arc<dyn PayloadCodec>) {
+        self.devices.insert(dev_addr, codec);
+    }
+
+    /// The codec for `dev_addr`, if any is configured
+    pub fn codec_for(&self, dev_addr: u32) -> Option<&dyn PayloadCodec> {
+        self.devices
+            .get(&dev_addr)
+            .or(self.default.as_ref())
+            .map(|codec| codec.as_ref())
+    }
+
+    /// Decode with the device's codec; None if no codec applies
+    pub fn decode(
+        &self,
+        dev_addr: u32,
+        f_port: u8,
+        bytes: &[u8],
+    ) -> Option<anyhow::Result<serde_json::Value>> {
+        self.codec_for(dev_addr)
+            .map(|codec| codec.decode(f_port, bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec_dispatch() {
+        let config: LorawanConfig = toml::from_str(
+            r#"
+            decrypt_payload = false
+            codec = "cayenne"
+
+            [device_codecs]
+            "260B1234" = "raw"
+            "#,
+        )
+        .unwrap();
+        let registry = CodecRegistry::from_config(&config).unwrap();
+        let payload = hex::decode("03670110").unwrap();
+
+        // Per-device override
+        assert_eq!(registry.codec_for(0x260B1234).unwrap().name(), "raw");
+        let raw = registry.decode(0x260B1234, 1, &payload).unwrap().unwrap();
+        assert_eq!(raw, serde_json::json!("03670110"));
+
+        // Global fallback
+        assert_eq!(registry.codec_for(0x260B5678).unwrap().name(), "cayenne");
+        let lpp = registry.decode(0x260B5678, 1, &payload).unwrap().unwrap();
+        assert_eq!(lpp[0]["type"], "temperature");
+        assert_eq!(lpp[0]["celsius"], 27.2);
+
+        // No codec configured at all
+        assert!(CodecRegistry::default().decode(0x260B5678, 1, &payload).is_none());
+    }
+
+    #[test]
+    fn test_invalid_device_codec_addr() {
+        let config: LorawanConfig = toml::from_str(
+            r#"
+            decrypt_payload = false
+            device_codecs = { "not-hex" = "raw" }
+            "#,
+        )
+        .unwrap();
+        assert!(CodecRegistry::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,296 @@
+//! LoRaWAN 1.0 cryptography, plus the 1.1 uplink MIC (Phase 4)
+//!
+//! Data frame MIC (LoRaWAN 1.0.x §4.4):
+//!
+//!   B0  = 0x49 | 0x00 ×4 | Dir | DevAddr(4, LE) | FCnt(4, LE) | 0x00 | len(msg)
+//!   MIC = aes128_cmac(NwkSKey, B0 | msg)[0..4]
+//!
+//! where `msg` is the PHY payload without the MIC and Dir is 0 for uplink,
+//! 1 for downlink. The MIC is returned as the little-endian `u32` of its four
+//! bytes, matching `LoRaWANFrame::Data::mic`.
+//!
+//! LoRaWAN 1.1 uplinks (1.1 §4.4.2) split the MIC over two keys:
+//!
+//!   B1    = 0x49 | ConfFCnt(2, LE) | TxDr | TxCh | 0x00 | DevAddr | FCntUp | 0x00 | len(msg)
+//!   cmacS = aes128_cmac(SNwkSIntKey, B1 | msg)
+//!   cmacF = aes128_cmac(FNwkSIntKey, B0 | msg)
+//!   MIC   = cmacS[0..2] | cmacF[0..2]
+//!
+//! ConfFCnt is the FCnt of the confirmed downlink the uplink acknowledges
+//! (0 without the ACK bit); TxDr and TxCh are the uplink's data rate and
+//! channel indices.
+//!
+//! FRMPayload encryption (§4.3.3) XORs the payload with the keystream
+//! `aes128_encrypt(key, A_i)`, `A_i = 0x01 | 0x00 ×4 | Dir | DevAddr | FCnt | 0x00 | i`
+//! (i from 1), using the AppSKey for FPort > 0 and the NwkSKey for FPort 0.
+//! The same operation encrypts and decrypts.
+//!
+//! JoinAccept (§6.2.5): the MIC is `aes128_cmac(AppKey, MHDR | fields)[0..4]`
+//! and `fields | MIC` is encrypted with AES-128 *decrypt* in ECB mode, so the
+//! device only needs AES encrypt to read it. Session keys are
+//! `aes128_encrypt(AppKey, 0x01|0x02 | AppNonce | NetID | DevNonce | pad)`.
+
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use cmac::{Cmac, Mac};
+
+use super::keys::B1Params;
+
+/// Frame direction (the Dir byte of the B0 block)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Uplink = 0,
+    Downlink = 1,
+}
+
+/// Compute the MIC of a data frame (`msg` = PHY payload without its MIC)
+pub fn data_mic(key: &[u8; 16], dir: Direction, dev_addr: u32, fcnt: u32, msg: &[u8]) -> u32 {
+    let mut b0 = [0u8; 16];
+    b0[0] = 0x49;
+    b0[5] = dir as u8;
+    b0[6..10].copy_from_slice(&dev_addr.to_le_bytes());
+    b0[10..14].copy_from_slice(&fcnt.to_le_bytes());
+    b0[15] = msg.len() as u8;
+
+    cmac_mic(key, &[&b0, msg])
+}
+
+/// Compute the LoRaWAN 1.1 MIC of an uplink data frame
+pub fn data_mic_v1_1_uplink(
+    f_nwk_s_int_key: &[u8; 16],
+    s_nwk_s_int_key: &[u8; 16],
+    b1: B1Params,
+    dev_addr: u32,
+    fcnt: u32,
+    msg: &[u8],
+) -> u32 {
+    let mut b0 = [0u8; 16];
+    b0[0] = 0x49;
+    b0[6..10].copy_from_slice(&dev_addr.to_le_bytes());
+    b0[10..14].copy_from_slice(&fcnt.to_le_bytes());
+    b0[15] = msg.len() as u8;
+
+    let mut b1_block = b0;
+    b1_block[1..3].copy_from_slice(&b1.conf_fcnt.to_le_bytes());
+    b1_block[3] = b1.tx_dr;
+    b1_block[4] = b1.tx_ch;
+
+    let cmac_s = cmac(s_nwk_s_int_key, &[&b1_block, msg]);
+    let cmac_f = cmac(f_nwk_s_int_key, &[&b0, msg]);
+    u32::from_le_bytes([cmac_s[0], cmac_s[1], cmac_f[0], cmac_f[1]])
+}
+
+/// Verify a LoRaWAN 1.1 uplink's MIC using its full 32-bit FCnt
+pub fn verify_uplink_mic_v1_1(
+    f_nwk_s_int_key: &[u8; 16],
+    s_nwk_s_int_key: &[u8; 16],
+    b1: B1Params,
+    phy: &[u8],
+    fcnt: u32,
+) -> bool {
+    if phy.len() < 12 {
+        return false;
+    }
+    let (msg, mic) = phy.split_at(phy.len() - 4);
+    let dev_addr = u32::from_le_bytes([msg[1], msg[2], msg[3], msg[4]]);
+    let expected =
+        data_mic_v1_1_uplink(f_nwk_s_int_key, s_nwk_s_int_key, b1, dev_addr, fcnt, msg);
+    expected.to_le_bytes() == mic
+}
+
+/// Verify an uplink data frame's MIC using its full 32-bit FCnt
+pub fn verify_uplink_mic(nwk_s_key: &[u8; 16], phy: &[u8], fcnt: u32) -> bool {
+    if phy.len() < 12 {
+        return false;
+    }
+    let (msg, mic) = phy.split_at(phy.len() - 4);
+    let dev_addr = u32::from_le_bytes([msg[1], msg[2], msg[3], msg[4]]);
+    let expected = data_mic(nwk_s_key, Direction::Uplink, dev_addr, fcnt, msg);
+    expected.to_le_bytes() == mic
+}
+
+/// Encrypt or decrypt an FRMPayload (the cipher is its own inverse)
+pub fn frm_payload_cipher(
+    key: &[u8; 16],
+    dir: Direction,
+    dev_addr: u32,
+    fcnt: u32,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut out = payload.to_vec();
+    for (i, chunk) in out.chunks_mut(16).enumerate() {
+        let mut a = [0u8; 16];
+        a[0] = 0x01;
+        a[5] = dir as u8;
+        a[6..10].copy_from_slice(&dev_addr.to_le_bytes());
+        a[10..14].copy_from_slice(&fcnt.to_le_bytes());
+        a[15] = (i + 1) as u8;
+        aes_ecb(key, &mut a, false);
+        for (byte, k) in chunk.iter_mut().zip(a) {
+            *byte ^= k;
+        }
+    }
+    out
+}
+
+/// AES-CMAC over the concatenation of `parts`
+fn cmac(key: &[u8; 16], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = <Cmac<Aes128> as Mac>::new_from_slice(key).expect("AES-128 key is 16 bytes");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// AES-CMAC over the concatenation of `parts`, truncated to the 4-byte MIC
+fn cmac_mic(key: &[u8; 16], parts: &[&[u8]]) -> u32 {
+    let tag = cmac(key, parts);
+    u32::from_le_bytes([tag[0], tag[1], tag[2], tag[3]])
+}
+
+/// Compute the MIC of a JoinAccept (`msg` = MHDR | fields, without MIC)
+pub fn join_mic(app_key: &[u8; 16], msg: &[u8]) -> u32 {
+    cmac_mic(app_key, &[msg])
+}
+
+/// AES-128-ECB over whole blocks; `decrypt` selects the AES direction
+pub fn aes_ecb(key: &[u8; 16], data: &mut [u8], decrypt: bool) {
+    debug_assert!(data.len().is_multiple_of(16), "ECB data must be whole blocks");
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    for block in data.chunks_exact_mut(16) {
+        let block = GenericArray::from_mut_slice(block);
+        if decrypt {
+            cipher.decrypt_block(block);
+        } else {
+            cipher.encrypt_block(block);
+        }
+    }
+}
+
+/// Derive (NwkSKey, AppSKey) from a completed OTAA join
+pub fn derive_session_keys(
+    app_key: &[u8; 16],
+    app_nonce: u32,
+    net_id: u32,
+    dev_nonce: u16,
+) -> ([u8; 16], [u8; 16]) {
+    let derive = |prefix: u8| {
+        let mut block = [0u8; 16];
+        block[0] = prefix;
+        block[1..4].copy_from_slice(&app_nonce.to_le_bytes()[..3]);
+        block[4..7].copy_from_slice(&net_id.to_le_bytes()[..3]);
+        block[7..9].copy_from_slice(&dev_nonce.to_le_bytes());
+        aes_ecb(app_key, &mut block, false);
+        block
+    };
+    (derive(0x01), derive(0x02))
+}
+
+/// Decrypted contents of a JoinAccept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinAcceptPayload {
+    pub app_nonce: u32,
+    pub net_id: u32,
+    pub dev_addr: u32,
+    pub dl_settings: u8,
+    pub rx_delay: u8,
+    pub cf_list: Option<[u8; 16]>,
+}
+
+/// Decrypt a JoinAccept (device side) and verify its MIC
+///
+/// `encrypted_payload` is everything after the MHDR, as in
+/// `LoRaWANFrame::JoinAccept`.
+pub fn decrypt_join_accept(
+    app_key: &[u8; 16],
+    mhdr: u8,
+    encrypted_payload: &[u8],
+) -> anyhow::Result<JoinAcceptPayload> {
+    if encrypted_payload.len() != 16 && encrypted_payload.len() != 32 {
+        return Err(anyhow::anyhow!(
+            "JoinAccept payload must be 16 or 32 bytes, got {}",
+            encrypted_payload.len()
+        ));
+    }
+
+    let mut plain = encrypted_payload.to_vec();
+    aes_ecb(app_key, &mut plain, false);
+    let (fields, mic) = plain.split_at(plain.len() - 4);
+
+    let expected = join_mic(app_key, &[&[mhdr][..], fields].concat());
+    if expected.to_le_bytes() != mic {
+        return Err(anyhow::anyhow!("JoinAccept MIC mismatch"));
+    }
+
+    let u24 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], 0]);
+    Ok(JoinAcceptPayload {
+        app_nonce: u24(&fields[0..3]),
+        net_id: u24(&fields[3..6]),
+        dev_addr: u32::from_le_bytes(fields[6..10].try_into()?),
+        dl_settings: fields[10],
+        rx_delay: fields[11],
+        cf_list: match fields.len() {
+            28 => Some(fields[12..28].try_into()?),
+            _ => None,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lorawan::keys::parse_key;
+
+    #[test]
+    fn test_verify_uplink_mic() {
+        // Unconfirmed uplink, DevAddr 49BE7DF1, FCnt 2, FPort 1 ("test" encrypted)
+        let phy = hex::decode("40F17DBE4900020001954378762B11FF0D").unwrap();
+        let nwk_s_key = parse_key("44024241ed4ce9a68c6a8bc055233fd3").unwrap();
+
+        assert!(verify_uplink_mic(&nwk_s_key, &phy, 2));
+        // Wrong FCnt or key
+        assert!(!verify_uplink_mic(&nwk_s_key, &phy, 0x10002));
+        assert!(!verify_uplink_mic(&[0u8; 16], &phy, 2));
+    }
+
+    #[test]
+    fn test_verify_uplink_mic_v1_1() {
+        // The frame above re-MICed for 1.1 with FNwkSIntKey = the old NwkSKey,
+        // TxDr 3 and TxCh 5 (computed independently). cmacF is the 1.0 MIC.
+        let phy = hex::decode("40F17DBE490002000195437876A4312B11").unwrap();
+        let f_key = parse_key("44024241ed4ce9a68c6a8bc055233fd3").unwrap();
+        let s_key = parse_key("5e3dc1d45a1b7b3d0a8fd3bc1c0f4e12").unwrap();
+        let b1 = B1Params {
+            conf_fcnt: 0,
+            tx_dr: 3,
+            tx_ch: 5,
+        };
+
+        assert!(verify_uplink_mic_v1_1(&f_key, &s_key, b1, &phy, 2));
+        assert!(!verify_uplink_mic_v1_1(&s_key, &f_key, b1, &phy, 2));
+        assert!(!verify_uplink_mic_v1_1(&f_key, &s_key, B1Params { tx_ch: 6, ..b1 }, &phy, 2));
+        // Acknowledging downlink FCnt 7 changes only the cmacS half
+        let mic = data_mic_v1_1_uplink(
+            &f_key,
+            &s_key,
+            B1Params { conf_fcnt: 7, ..b1 },
+            0x49BE7DF1,
+            2,
+            &phy[..phy.len() - 4],
+        );
+        assert_eq!(mic.to_le_bytes(), [0x1c, 0x01, 0x2b, 0x11]);
+    }
+
+    #[test]
+    fn test_frm_payload_cipher() {
+        // Same frame: FRMPayload 95437876 decrypts to "test" with the AppSKey
+        let app_s_key = parse_key("ec925802ae430ca77fd3dd73cb2cc588").unwrap();
+        let encrypted = hex::decode("95437876").unwrap();
+        let plain = frm_payload_cipher(&app_s_key, Direction::Uplink, 0x49BE7DF1, 2, &encrypted);
+        assert_eq!(plain, b"test");
+        assert_eq!(
+            frm_payload_cipher(&app_s_key, Direction::Uplink, 0x49BE7DF1, 2, &plain),
+            encrypted
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Typed LoRa/FSK data rates
+//!
+//! GWMP carries a LoRa data rate as a string like "SF7BW125" and an FSK one
+//! as its bitrate in bits/s, as a JSON number (e.g. 50000). `DataRate`
+//! (de)serializes in exactly that form, so `Rxpk`/`Txpk` stay wire
+//! compatible. Its `Display`/`FromStr` form writes FSK as "FSK50000" so a
+//! bitrate is never mistaken for a LoRa data rate; `as_string` serializes
+//! that form for fields sent to Urbit. A `datr` string a packet forwarder
+//! sends that isn't either (e.g. an LR-FHSS "M0CW137") is kept as `Other`
+//! rather than failing the whole PUSH_DATA.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// LoRa bandwidths (kHz) a gateway can transmit and receive
+const LORA_BANDWIDTHS: [u32; 3] = [125, 250, 500];
+
+/// Modulation and rate of a packet
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DataRate {
+    /// LoRa: spreading factor (6-12) and bandwidth in kHz
+    LoRa { sf: u8, bw_khz: u32 },
+    /// FSK: bitrate in bits/s
+    Fsk { bitrate: u32 },
+    /// A modulation this bridge doesn't handle, as the gateway named it
+    Other(String),
+}
+
+impl DataRate {
+    pub fn lora(sf: u8, bw_khz: u32) -> Self {
+        DataRate::LoRa { sf, bw_khz }
+    }
+
+    /// Spreading factor (LoRa only)
+    pub fn spreading_factor(&self) -> Option<u8> {
+        match self {
+            DataRate::LoRa { sf, .. } => Some(*sf),
+            _ => None,
+        }
+    }
+
+    /// Bandwidth in kHz (LoRa only)
+    pub fn bandwidth_khz(&self) -> Option<u32> {
+        match self {
+            DataRate::LoRa { bw_khz, .. } => Some(*bw_khz),
+            _ => None,
+        }
+    }
+
+    /// Bitrate in bits/s (FSK only)
+    pub fn bitrate(&self) -> Option<u32> {
+        match self {
+            DataRate::Fsk { bitrate } => Some(*bitrate),
+            _ => None,
+        }
+    }
+
+    pub fn is_fsk(&self) -> bool {
+        matches!(self, DataRate::Fsk { .. })
+    }
+}
+
+impl fmt::Display for DataRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataRate::LoRa { sf, bw_khz } => write!(f, "SF{}BW{}", sf, bw_khz),
+            DataRate::Fsk { bitrate } => write!(f, "FSK{}", bitrate),
+            DataRate::Other(datr) => f.write_str(datr),
+        }
+    }
+}
+
+impl FromStr for DataRate {
+    type Err = anyhow::Error;
+
+    /// Parse "SF7BW125", "FSK50000" or a bare FSK bitrate ("50000")
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("Invalid data rate {:?} (expected e.g. SF7BW125)", s);
+
+        if let Some(rest) = s.strip_prefix("SF") {
+            let (sf, bw) = rest.split_once("BW").ok_or_else(invalid)?;
+            let sf: u8 = sf.parse().map_err(|_| invalid())?;
+            let bw_khz: u32 = bw.parse().map_err(|_| invalid())?;
+            if !(6..=12).contains(&sf) || !LORA_BANDWIDTHS.contains(&bw_khz) {
+                return Err(invalid());
+            }
+            return Ok(DataRate::LoRa { sf, bw_khz });
+        }
+
+        let bitrate = s.strip_prefix("FSK").unwrap_or(s);
+        match bitrate.parse::<u32>() {
+            Ok(bitrate) if bitrate > 0 => Ok(DataRate::Fsk { bitrate }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Serialize for DataRate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            DataRate::LoRa { .. } | DataRate::Other(_) => serializer.collect_str(self),
+            DataRate::Fsk { bitrate } => serializer.serialize_u32(*bitrate),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for DataRate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Str(String),
+            Num(u32),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Str(s) => Ok(s.parse().unwrap_or(DataRate::Other(s))),
+            Raw::Num(bitrate) => Ok(DataRate::Fsk { bitrate }),
+        }
+    }
+}
+
+/// Deserialize an optional config `DataRate`, refusing one that isn't LoRa or FSK
+pub fn deserialize_known<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<DataRate>, D::Error> {
+    match Option::<DataRate>::deserialize(deserializer)? {
+        Some(DataRate::Other(datr)) => Err(serde::de::Error::custom(format!(
+            "Invalid data rate {:?} (expected e.g. SF7BW125)",
+            datr
+        ))),
+        rate => Ok(rate),
+    }
+}
+
+/// Serialize a `DataRate` in its display form ("SF7BW125", "FSK50000")
+pub mod as_string {
+    use super::DataRate;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(rate: &DataRate, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(rate)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DataRate, D::Error> {
+        DataRate::deserialize(deserializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regional_data_rates() {
+        // US915 DR0-4 and DR8-13
+        let us915 = [
+            "SF10BW125", "SF9BW125", "SF8BW125", "SF7BW125", "SF8BW500", "SF12BW500",
+            "SF11BW500", "SF10BW500", "SF9BW500", "SF8BW500", "SF7BW500",
+        ];
+        // EU868 DR0-6
+        let eu868 = [
+            "SF12BW125", "SF11BW125", "SF10BW125", "SF9BW125", "SF8BW125", "SF7BW125",
+            "SF7BW250",
+        ];
+        for datr in us915.iter().chain(&eu868) {
+            let rate: DataRate = datr.parse().unwrap();
+            assert_eq!(rate.to_string(), *datr);
+            assert_eq!(serde_json::to_string(&rate).unwrap(), format!("\"{}\"", datr));
+        }
+
+        let rate: DataRate = "SF10BW125".parse().unwrap();
+        assert_eq!(rate.spreading_factor(), Some(10));
+        assert_eq!(rate.bandwidth_khz(), Some(125));
+        assert_eq!(rate.bitrate(), None);
+
+        // EU868 DR7: FSK 50 kbit/s, a number on the wire
+        let fsk: DataRate = serde_json::from_str("50000").unwrap();
+        assert_eq!(fsk, DataRate::Fsk { bitrate: 50000 });
+        assert_eq!(fsk.to_string(), "FSK50000");
+        assert_eq!("FSK50000".parse::<DataRate>().unwrap(), fsk);
+        assert_eq!(serde_json::to_string(&fsk).unwrap(), "50000");
+    }
+
+    #[test]
+    fn test_invalid_data_rate() {
+        for datr in ["", "SF7", "SF13BW125", "SF7BW100", "sf7bw125", "FSK", "LORA"] {
+            let err = datr.parse::<DataRate>().unwrap_err();
+            assert!(err.to_string().contains("Invalid data rate"), "{}", err);
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Config {
+            #[serde(default, deserialize_with = "deserialize_known")]
+            datr: Option<DataRate>,
+        }
+        assert!(serde_json::from_str::<Config>(r#"{"datr":"SF7BW999"}"#).is_err());
+        let config: Config = serde_json::from_str(r#"{"datr":"SF9BW125"}"#).unwrap();
+        assert_eq!(config.datr, Some(DataRate::lora(9, 125)));
+        assert!(serde_json::from_str::<Config>("{}").unwrap().datr.is_none());
+    }
+
+    #[test]
+    fn test_unknown_wire_data_rate() {
+        // LR-FHSS and other modulations a gateway may report are kept as is
+        let rate: DataRate = serde_json::from_str("\"M0CW137\"").unwrap();
+        assert_eq!(rate, DataRate::Other("M0CW137".to_string()));
+        assert_eq!(rate.to_string(), "M0CW137");
+        assert_eq!(serde_json::to_string(&rate).unwrap(), "\"M0CW137\"");
+        assert_eq!(rate.spreading_factor(), None);
+        assert!(!rate.is_fsk());
+    }
+}
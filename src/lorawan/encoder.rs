@@ -5,13 +5,24 @@
 //! GWMP PULL_RESP txpk JSON.
 //!
 //! Frame structure (unconfirmed data down):
-//!   MHDR(1) | DevAddr(4,LE) | FCtrl(1) | FCnt(2,LE) | [FPort(1) | FRMPayload(N)] | MIC(4,LE)
+//!   MHDR(1) | DevAddr(4,LE) | FCtrl(1) | FCnt(2,LE) | FOpts(0-15) | [FPort(1) | FRMPayload(N)] | MIC(4,LE)
 //!
-//! For Phase 3a testing, MIC is set to 0x00000000 (no NwkSKey available).
-//! Phase 4 will add proper MIC computation with CMAC-AES128.
+//! `build` leaves the FRMPayload in plaintext and the MIC as 0x00000000,
+//! which no device accepts; it is kept for tests and for peers without
+//! session keys. `build_with_mic` (`phase4`) produces a valid LoRaWAN 1.0
+//! frame: FRMPayload encrypted with the AppSKey (NwkSKey for FPort 0) and
+//! MIC = CMAC-AES128(NwkSKey, B0 | msg) with the downlink direction.
+//!
+//! JoinAccept (OTAA, `phase4` only):
+//!   MHDR(1) | AppNonce(3,LE) | NetID(3,LE) | DevAddr(4,LE) | DLSettings(1) | RxDelay(1) | [CFList(16)] | MIC(4)
+//! with everything after the MHDR encrypted under the AppKey.
 
+use super::keys::{LorawanVersion, SessionKeys};
 use super::MType;
 
+/// Maximum FOpts length (FCtrl.FOptsLen is a 4-bit field)
+pub const MAX_F_OPTS_LEN: usize = 15;
+
 /// Parameters for building a LoRaWAN data frame
 #[derive(Debug, Clone)]
 pub struct FrameBuilder {
@@ -19,31 +30,74 @@ pub struct FrameBuilder {
     pub mtype: MType,
     /// Device address (32-bit)
     pub dev_addr: u32,
-    /// Frame counter (16-bit, managed by caller)
-    pub fcnt: u16,
+    /// Frame counter (32-bit, managed by caller); the FHDR carries the low 16 bits
+    pub fcnt: u32,
     /// FPort (application port, 1-223 for application data)
     pub f_port: u8,
     /// Application payload (raw bytes, unencrypted for Phase 3)
     pub payload: Vec<u8>,
+    /// Set the FCtrl ACK bit (acknowledges a confirmed uplink)
+    pub ack: bool,
+    /// Set the FCtrl FPending bit (more downlink data is queued)
+    pub f_pending: bool,
+    /// Piggybacked MAC commands (FOpts, max 15 bytes, unencrypted in Phase 3)
+    pub f_opts: Vec<u8>,
 }
 
 impl FrameBuilder {
     /// Create a new frame builder for an unconfirmed downlink
-    pub fn new_downlink(dev_addr: u32, fcnt: u16, f_port: u8, payload: Vec<u8>) -> Self {
+    pub fn new_downlink(dev_addr: u32, fcnt: u32, f_port: u8, payload: Vec<u8>) -> Self {
         Self {
             mtype: MType::UnconfirmedDataDown,
             dev_addr,
             fcnt,
             f_port,
             payload,
+            ack: false,
+            f_pending: false,
+            f_opts: Vec::new(),
+        }
+    }
+
+    /// Create a new frame builder for a confirmed downlink
+    ///
+    /// The device must acknowledge a ConfirmedDataDown by setting the
+    /// ACK bit on its next uplink.
+    pub fn new_confirmed_downlink(dev_addr: u32, fcnt: u32, f_port: u8, payload: Vec<u8>) -> Self {
+        Self {
+            mtype: MType::ConfirmedDataDown,
+            ..Self::new_downlink(dev_addr, fcnt, f_port, payload)
+        }
+    }
+
+    /// FCtrl byte for a downlink: ADR(7) | RFU(6) | ACK(5) | FPending(4) | FOptsLen(3..0)
+    fn fctrl(&self) -> u8 {
+        let mut fctrl = 0x00;
+        if self.ack {
+            fctrl |= 0x20;
+        }
+        if self.f_pending {
+            fctrl |= 0x10;
         }
+        fctrl | (self.f_opts.len() as u8 & 0x0F)
     }
 
-    /// Build the raw LoRaWAN PHY payload bytes
+    /// Build the raw LoRaWAN PHY payload bytes with a zero MIC
     ///
-    /// Returns bytes ready for base64 encoding into txpk.data
-    pub fn build(&self) -> Vec<u8> {
-        let mut frame = Vec::with_capacity(12 + self.payload.len());
+    /// The payload is not encrypted, so devices will drop the frame; use
+    /// `build_with_mic` when the session keys are known. Returns bytes
+    /// ready for base64 encoding into txpk.data, or an error if the FOpts
+    /// exceed the 15 bytes addressable by FOptsLen.
+    pub fn build(&self) -> anyhow::Result<Vec<u8>> {
+        if self.f_opts.len() > MAX_F_OPTS_LEN {
+            return Err(anyhow::anyhow!(
+                "FOpts too long: {} bytes (maximum {})",
+                self.f_opts.len(),
+                MAX_F_OPTS_LEN
+            ));
+        }
+
+        let mut frame = Vec::with_capacity(13 + self.f_opts.len() + self.payload.len());
 
         // MHDR: MType(3 bits) | RFU(3 bits) | Major(2 bits)
         // Major = 0b00 (LoRaWAN R1)
@@ -59,11 +113,14 @@ impl FrameBuilder {
         // DevAddr (4 bytes, little-endian)
         frame.extend_from_slice(&self.dev_addr.to_le_bytes());
 
-        // FCtrl: ADR=0, ACK=0, FPending=0, FOptsLen=0
-        frame.push(0x00);
+        // FCtrl: ADR=0, ACK/FPending from builder, FOptsLen=len(FOpts)
+        frame.push(self.fctrl());
+
+        // FCnt (low 2 bytes, little-endian)
+        frame.extend_from_slice(&(self.fcnt as u16).to_le_bytes());
 
-        // FCnt (2 bytes, little-endian)
-        frame.extend_from_slice(&self.fcnt.to_le_bytes());
+        // FOpts (MAC commands, between FCnt and FPort)
+        frame.extend_from_slice(&self.f_opts);
 
         // FPort (only if payload is present)
         if !self.payload.is_empty() {
@@ -77,10 +134,93 @@ impl FrameBuilder {
         // Phase 4 will compute CMAC-AES128(NwkSKey, B0 | msg)
         frame.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
 
-        frame
+        Ok(frame)
+    }
+
+    /// Build the PHY payload with an encrypted FRMPayload and the real MIC
+    ///
+    /// Only LoRaWAN 1.0 sessions are supported: 1.1 downlinks need the
+    /// NwkSEncKey and the ConfFCnt of the acknowledged uplink.
+    pub fn build_with_mic(&self, keys: &SessionKeys) -> anyhow::Result<Vec<u8>> {
+        if keys.lorawan_version == LorawanVersion::V1_1 {
+            return Err(anyhow::anyhow!(
+                "Downlinks to LoRaWAN 1.1 device {:08X} are not supported",
+                self.dev_addr
+            ));
+        }
+
+        #[cfg(feature = "phase4")]
+        {
+            use super::crypto::{self, Direction};
+
+            let dir = match self.mtype {
+                MType::UnconfirmedDataUp | MType::ConfirmedDataUp => Direction::Uplink,
+                _ => Direction::Downlink,
+            };
+            let mut frame = self.build()?;
+            let mic_start = frame.len() - 4;
+            if !self.payload.is_empty() {
+                let key = if self.f_port == 0 { &keys.nwk_s_key } else { &keys.app_s_key };
+                let payload_start = 9 + self.f_opts.len();
+                let encrypted = crypto::frm_payload_cipher(
+                    key,
+                    dir,
+                    self.dev_addr,
+                    self.fcnt,
+                    &frame[payload_start..mic_start],
+                );
+                frame[payload_start..mic_start].copy_from_slice(&encrypted);
+            }
+            let mic = crypto::data_mic(
+                &keys.nwk_s_key,
+                dir,
+                self.dev_addr,
+                self.fcnt,
+                &frame[..mic_start],
+            );
+            frame[mic_start..].copy_from_slice(&mic.to_le_bytes());
+            Ok(frame)
+        }
+        #[cfg(not(feature = "phase4"))]
+        {
+            Err(anyhow::anyhow!("Downlink MIC computation requires the phase4 feature"))
+        }
     }
 }
 
+/// Build an encrypted JoinAccept PHY payload
+///
+/// Computes the join MIC over the plaintext with the AppKey, then applies
+/// AES-128 ECB decrypt (the inverse of what the device does on receipt).
+#[cfg(feature = "phase4")]
+pub fn build_join_accept(
+    app_key: &[u8; 16],
+    app_nonce: u32,
+    net_id: u32,
+    dev_addr: u32,
+    dl_settings: u8,
+    rx_delay: u8,
+    cf_list: Option<&[u8; 16]>,
+) -> Vec<u8> {
+    use super::crypto;
+
+    // MHDR: JoinAccept, Major = LoRaWAN R1
+    let mut frame = vec![0x20];
+    frame.extend_from_slice(&app_nonce.to_le_bytes()[..3]);
+    frame.extend_from_slice(&net_id.to_le_bytes()[..3]);
+    frame.extend_from_slice(&dev_addr.to_le_bytes());
+    frame.push(dl_settings);
+    frame.push(rx_delay);
+    if let Some(cf_list) = cf_list {
+        frame.extend_from_slice(cf_list);
+    }
+    let mic = crypto::join_mic(app_key, &frame);
+    frame.extend_from_slice(&mic.to_le_bytes());
+
+    crypto::aes_ecb(app_key, &mut frame[1..], true);
+    frame
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -95,7 +235,7 @@ mod tests {
             vec![0x48, 0x65, 0x6C, 0x6C, 0x6F], // "Hello"
         );
 
-        let frame = builder.build();
+        let frame = builder.build().unwrap();
 
         // Verify structure:
         // MHDR(1) + DevAddr(4) + FCtrl(1) + FCnt(2) + FPort(1) + Payload(5) + MIC(4) = 18
@@ -129,9 +269,12 @@ mod tests {
             fcnt: 0,
             f_port: 1,
             payload: vec![],
+            ack: false,
+            f_pending: false,
+            f_opts: vec![],
         };
 
-        let frame = builder.build();
+        let frame = builder.build().unwrap();
 
         // MHDR(1) + DevAddr(4) + FCtrl(1) + FCnt(2) + MIC(4) = 12 (no FPort, no payload)
         assert_eq!(frame.len(), 12);
@@ -146,7 +289,7 @@ mod tests {
             vec![0x01, 0x02, 0x03],
         );
 
-        let encoded = builder.build();
+        let encoded = builder.build().unwrap();
         let decoded = decode_phy_payload(&encoded).expect("should decode successfully");
 
         match decoded {
@@ -178,9 +321,12 @@ mod tests {
             fcnt: 1,
             f_port: 10,
             payload: vec![0xFF],
+            ack: false,
+            f_pending: false,
+            f_opts: vec![],
         };
 
-        let frame = builder.build();
+        let frame = builder.build().unwrap();
         assert_eq!(frame[0], 0xA0); // ConfirmedDataDown MHDR
 
         // Verify it round-trips
@@ -192,4 +338,190 @@ mod tests {
             _ => panic!("Expected Data frame"),
         }
     }
+
+    #[test]
+    fn test_new_confirmed_downlink() {
+        let frame = FrameBuilder::new_confirmed_downlink(0x11223344, 5, 2, vec![0x01]).build().unwrap();
+        assert_eq!(frame[0], 0xA0); // ConfirmedDataDown MHDR
+        assert_eq!(frame[5], 0x00); // no FCtrl bits by default
+    }
+
+    #[test]
+    fn test_ack_bit_roundtrip() {
+        let mut builder = FrameBuilder::new_downlink(0x260B1234, 7, 1, vec![]);
+        builder.ack = true;
+
+        let frame = builder.build().unwrap();
+        assert_eq!(frame[5], 0x20);
+
+        match decode_phy_payload(&frame).expect("should decode") {
+            LoRaWANFrame::Data { fctrl, .. } => {
+                assert!(fctrl.ack);
+                assert!(!fctrl.adr);
+                assert_eq!(fctrl.f_opts_len, 0);
+            }
+            _ => panic!("Expected Data frame"),
+        }
+    }
+
+    #[test]
+    fn test_ack_and_f_pending_bits() {
+        let mut builder = FrameBuilder::new_confirmed_downlink(0x260B1234, 8, 1, vec![0xAA]);
+        builder.ack = true;
+        builder.f_pending = true;
+
+        let frame = builder.build().unwrap();
+        assert_eq!(frame[5], 0x30);
+
+        match decode_phy_payload(&frame).expect("should decode") {
+            LoRaWANFrame::Data { mtype, fctrl, .. } => {
+                assert_eq!(mtype, MType::ConfirmedDataDown);
+                assert!(fctrl.ack);
+                // FPending shares bit 4 with the uplink ClassB flag
+                assert!(fctrl.class_b);
+            }
+            _ => panic!("Expected Data frame"),
+        }
+    }
+
+    #[test]
+    fn test_f_opts_roundtrip() {
+        // LinkCheckAns: CID=0x02, Margin=20 dB, GwCnt=1
+        let mut builder = FrameBuilder::new_downlink(0x260B1234, 3, 1, vec![0xCA, 0xFE]);
+        builder.f_opts = vec![0x02, 0x14, 0x01];
+
+        let frame = builder.build().unwrap();
+        // MHDR(1) + DevAddr(4) + FCtrl(1) + FCnt(2) + FOpts(3) + FPort(1) + Payload(2) + MIC(4)
+        assert_eq!(frame.len(), 18);
+        assert_eq!(frame[5] & 0x0F, 3);
+        assert_eq!(&frame[8..11], &[0x02, 0x14, 0x01]);
+
+        match decode_phy_payload(&frame).expect("should decode") {
+            LoRaWANFrame::Data {
+                fctrl,
+                f_opts,
+                f_port,
+                frm_payload,
+                ..
+            } => {
+                assert_eq!(fctrl.f_opts_len, 3);
+                assert_eq!(f_opts, vec![0x02, 0x14, 0x01]);
+                assert_eq!(f_port, Some(1));
+                assert_eq!(frm_payload, vec![0xCA, 0xFE]);
+            }
+            _ => panic!("Expected Data frame"),
+        }
+    }
+
+    #[test]
+    fn test_f_opts_without_payload() {
+        let mut builder = FrameBuilder::new_downlink(0x260B1234, 4, 1, vec![]);
+        builder.f_opts = vec![0x06];
+
+        let frame = builder.build().unwrap();
+        // No FPort when there is no application payload
+        assert_eq!(frame.len(), 13);
+
+        match decode_phy_payload(&frame).expect("should decode") {
+            LoRaWANFrame::Data { f_opts, f_port, .. } => {
+                assert_eq!(f_opts, vec![0x06]);
+                assert_eq!(f_port, None);
+            }
+            _ => panic!("Expected Data frame"),
+        }
+    }
+
+    #[test]
+    fn test_f_opts_too_long_fails() {
+        let mut builder = FrameBuilder::new_downlink(0x260B1234, 5, 1, vec![]);
+        builder.f_opts = vec![0x00; 16];
+        assert!(builder.build().is_err());
+    }
+
+    #[cfg(feature = "phase4")]
+    #[test]
+    fn test_build_with_mic() {
+        use crate::lorawan::crypto::{self, Direction};
+        use crate::lorawan::keys::{parse_key, DeviceClass};
+
+        let mut keys = SessionKeys {
+            dev_addr: 0x49BE7DF1,
+            nwk_s_key: parse_key("44024241ed4ce9a68c6a8bc055233fd3").unwrap(),
+            app_s_key: parse_key("ec925802ae430ca77fd3dd73cb2cc588").unwrap(),
+            lorawan_version: LorawanVersion::V1_0,
+            s_nwk_s_int_key: None,
+            class: DeviceClass::A,
+            ping_slot_periodicity: 0,
+            rx2_datr: None,
+            rx1_delay: 1,
+        };
+
+        // Computed independently (AES-CTR payload, CMAC over B0 | msg)
+        let frame = FrameBuilder::new_downlink(0x49BE7DF1, 3, 1, b"ping".to_vec())
+            .build_with_mic(&keys)
+            .unwrap();
+        assert_eq!(hex::encode_upper(&frame), "60F17DBE490003000132DD9578EF4BF92F");
+        let mic = u32::from_le_bytes(frame[13..].try_into().unwrap());
+        let dir = Direction::Downlink;
+        assert_eq!(crypto::data_mic(&keys.nwk_s_key, dir, 0x49BE7DF1, 3, &frame[..13]), mic);
+        let plain = crypto::frm_payload_cipher(&keys.app_s_key, dir, 0x49BE7DF1, 3, &frame[9..13]);
+        assert_eq!(plain, b"ping");
+
+        // Empty ACK: only the MIC changes
+        let ack = FrameBuilder {
+            ack: true,
+            ..FrameBuilder::new_downlink(0x49BE7DF1, 4, 0, Vec::new())
+        };
+        let frame = ack.build_with_mic(&keys).unwrap();
+        assert_eq!(hex::encode_upper(frame), "60F17DBE49200400D8EA45F6");
+
+        // Past 65535 the FHDR carries the low 16 bits; B0 and the keystream the full 32
+        let frame = FrameBuilder::new_downlink(0x49BE7DF1, 0x1_0003, 1, b"ping".to_vec())
+            .build_with_mic(&keys)
+            .unwrap();
+        assert_eq!(&frame[6..8], &[0x03, 0x00]);
+        let mic = u32::from_le_bytes(frame[13..].try_into().unwrap());
+        assert_eq!(crypto::data_mic(&keys.nwk_s_key, dir, 0x49BE7DF1, 0x1_0003, &frame[..13]), mic);
+        assert_ne!(crypto::data_mic(&keys.nwk_s_key, dir, 0x49BE7DF1, 3, &frame[..13]), mic);
+        let key = &keys.app_s_key;
+        let plain = crypto::frm_payload_cipher(key, dir, 0x49BE7DF1, 0x1_0003, &frame[9..13]);
+        assert_eq!(plain, b"ping");
+
+        keys.lorawan_version = LorawanVersion::V1_1;
+        assert!(ack.build_with_mic(&keys).is_err());
+    }
+
+    #[cfg(feature = "phase4")]
+    #[test]
+    fn test_join_accept_roundtrip() {
+        use crate::lorawan::crypto::{decrypt_join_accept, JoinAcceptPayload};
+
+        let app_key = crate::lorawan::keys::parse_key("2b7e151628aed2a6abf7158809cf4f3c").unwrap();
+        let cf_list = [0x18, 0x4F, 0x84, 0xE8, 0x56, 0x84, 0xB8, 0x5E, 0x84, 0x88, 0x66, 0x84, 0x58, 0x6E, 0x84, 0x00];
+
+        for cf in [None, Some(&cf_list)] {
+            let frame = build_join_accept(&app_key, 0x123456, 0x000013, 0x260B1234, 0x03, 1, cf);
+            assert_eq!(frame.len(), if cf.is_some() { 33 } else { 17 });
+
+            let encrypted_payload = match decode_phy_payload(&frame).expect("should decode") {
+                LoRaWANFrame::JoinAccept { encrypted_payload } => encrypted_payload,
+                _ => panic!("Expected JoinAccept frame"),
+            };
+            let accept = decrypt_join_accept(&app_key, frame[0], &encrypted_payload).unwrap();
+            assert_eq!(
+                accept,
+                JoinAcceptPayload {
+                    app_nonce: 0x123456,
+                    net_id: 0x000013,
+                    dev_addr: 0x260B1234,
+                    dl_settings: 0x03,
+                    rx_delay: 1,
+                    cf_list: cf.copied(),
+                }
+            );
+
+            // Wrong AppKey fails the MIC
+            assert!(decrypt_join_accept(&[0u8; 16], frame[0], &encrypted_payload).is_err());
+        }
+    }
 }
@@ -0,0 +1,295 @@
+//! Uplink frame counter tracking and replay detection
+//!
+//! Frames carry only the low 16 bits of the device's 32-bit FCntUp. The
+//! tracker remembers the last accepted 32-bit counter per DevAddr and
+//! reconstructs the full counter of each new frame from it, assuming a
+//! rollover of the low 16 bits when they drop by more than `MAX_FCNT_GAP`.
+//!
+//! A frame whose reconstructed counter is not greater than the last accepted
+//! one is a replay, with one exception: an ABP device that reboots starts
+//! counting from 0 again. If the counter is at most `reset_tolerance`, the
+//! device was previously past that point and the frame's MIC verifies, the
+//! frame is accepted as a counter reset.
+//!
+//! For a device with session keys only frames whose MIC verifies move the
+//! counter: a spoofed frame with a far-ahead FCnt would otherwise make the
+//! device's real frames look like replays. Devices without keys are tracked
+//! on their counters alone.
+//!
+//! An accepted counter that skips ahead of the last one means frames were
+//! sent but never heard; the tracker counts those per DevAddr. Only frames
+//! whose MIC verified count, as anyone can send a frame with a made-up FCnt.
+//! The count uses the reconstructed 32-bit counters, so a rollover of the low
+//! 16 bits is not a gap, and a counter reset starts counting afresh.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Largest forward jump of the 16-bit counter treated as lost frames
+/// rather than a rollover (LoRaWAN 1.0 MAX_FCNT_GAP)
+pub const MAX_FCNT_GAP: u32 = 16384;
+
+/// What an uplink's MIC showed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MicStatus {
+    /// Verified against one of the device's sessions
+    Verified,
+    /// The device has a session, but the MIC doesn't match it
+    Failed,
+    /// No session keys (or no crypto) to check it with
+    Unchecked,
+}
+
+/// Outcome of checking an uplink's frame counter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FcntCheck {
+    /// Counter advanced; carries the reconstructed 32-bit FCnt
+    Accepted(u32),
+    /// Counter advanced but the MIC failed, so it was not recorded
+    Unverified(u32),
+    /// Device reset its counter (MIC verified); carries the new FCnt
+    Reset(u32),
+    /// Counter did not advance: replayed or duplicated frame
+    Replay { fcnt: u32, last: u32 },
+}
+
+/// Frames a device sent that never reached the bridge
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MissedFrames {
+    /// Frames skipped just before the last accepted one
+    pub last_gap: u32,
+    /// Frames skipped since the device was first heard
+    pub total: u64,
+}
+
+/// Tracker shared by the UDP server and the outbound task
+pub type SharedFrameCounterTracker = Arc<Mutex<FrameCounterTracker>>;
+
+/// Per-DevAddr last accepted uplink FCnt
+#[derive(Debug, Default)]
+pub struct FrameCounterTracker {
+    last: HashMap<u32, u32>,
+    /// PHYPayload of the last accepted frame, to tell other gateways' copies
+    /// from replays
+    frames: HashMap<u32, Vec<u8>>,
+    missed: HashMap<u32, MissedFrames>,
+    reset_tolerance: u32,
+    replays: u64,
+}
+
+impl FrameCounterTracker {
+    pub fn new(reset_tolerance: u32) -> Self {
+        Self {
+            reset_tolerance,
+            ..Default::default()
+        }
+    }
+
+    /// Last accepted 32-bit FCnt for `dev_addr`
+    pub fn last(&self, dev_addr: u32) -> Option<u32> {
+        self.last.get(&dev_addr).copied()
+    }
+
+    /// Reconstruct the full 32-bit counter of a frame carrying `fcnt`
+    pub fn reconstruct(&self, dev_addr: u32, fcnt: u16) -> u32 {
+        let Some(last) = self.last(dev_addr) else {
+            return fcnt as u32;
+        };
+        let candidate = (last & 0xFFFF_0000) | fcnt as u32;
+        if candidate < last && (last & 0xFFFF) - fcnt as u32 > MAX_FCNT_GAP {
+            candidate.wrapping_add(0x1_0000)
+        } else {
+            candidate
+        }
+    }
+
+    /// FCnt to check the MIC of a frame carrying `fcnt` with
+    ///
+    /// The reconstructed 32-bit counter, except for a frame that can only be
+    /// accepted as a counter reset, whose counter restarted from 0.
+    pub fn mic_fcnt(&self, dev_addr: u32, fcnt: u16) -> u32 {
+        let full = self.reconstruct(dev_addr, fcnt);
+        match self.last(dev_addr) {
+            Some(last) if full <= last && fcnt as u32 <= self.reset_tolerance => fcnt as u32,
+            _ => full,
+        }
+    }
+
+    /// Check an uplink's counter and record it if accepted
+    ///
+    /// `mic` is checked against the device's session using `mic_fcnt`.
+    /// Counter resets need a verified MIC, and a failed one leaves the
+    /// counter where it was.
+    pub fn check(&mut self, dev_addr: u32, fcnt: u16, mic: MicStatus) -> FcntCheck {
+        let full = self.reconstruct(dev_addr, fcnt);
+        match self.last(dev_addr) {
+            Some(last) if full <= last => {
+                let fcnt = fcnt as u32;
+                let verified = mic == MicStatus::Verified;
+                if verified && fcnt <= self.reset_tolerance && last > self.reset_tolerance {
+                    self.last.insert(dev_addr, fcnt);
+                    self.missed.entry(dev_addr).or_default().last_gap = 0;
+                    FcntCheck::Reset(fcnt)
+                } else {
+                    self.replays += 1;
+                    FcntCheck::Replay { fcnt: full, last }
+                }
+            }
+            _ if mic == MicStatus::Failed => FcntCheck::Unverified(full),
+            last => {
+                let gap = match mic {
+                    MicStatus::Verified => last.map_or(0, |last| full - last - 1),
+                    _ => 0,
+                };
+                let missed = self.missed.entry(dev_addr).or_default();
+                missed.last_gap = gap;
+                missed.total += gap as u64;
+                self.last.insert(dev_addr, full);
+                FcntCheck::Accepted(full)
+            }
+        }
+    }
+
+    /// Whether `phy` is the frame last accepted from `dev_addr`: another
+    /// gateway's copy of it rather than a replay
+    pub fn is_copy(&self, dev_addr: u32, phy: &[u8]) -> bool {
+        self.frames.get(&dev_addr).is_some_and(|frame| frame == phy)
+    }
+
+    /// Remember an accepted frame for `is_copy`
+    pub fn record_frame(&mut self, dev_addr: u32, phy: &[u8]) {
+        self.frames.insert(dev_addr, phy.to_vec());
+    }
+
+    /// Forget `dev_addr`'s counter, e.g. for a new session after an OTAA join
+    pub fn reset(&mut self, dev_addr: u32) {
+        self.last.remove(&dev_addr);
+        self.frames.remove(&dev_addr);
+        self.missed.remove(&dev_addr);
+    }
+
+    /// Number of replayed frames detected so far
+    pub fn replays_detected(&self) -> u64 {
+        self.replays
+    }
+
+    /// Frames `dev_addr` sent that were never accepted, judged by FCnt gaps
+    pub fn missed(&self, dev_addr: u32) -> MissedFrames {
+        self.missed.get(&dev_addr).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MicStatus::{Failed, Unchecked, Verified};
+
+    const DEV: u32 = 0x260B1234;
+
+    #[test]
+    fn test_replay_detected() {
+        let mut tracker = FrameCounterTracker::new(16);
+        assert_eq!(tracker.check(DEV, 66, Verified), FcntCheck::Accepted(66));
+        assert_eq!(tracker.check(DEV, 67, Verified), FcntCheck::Accepted(67));
+
+        // Replaying either frame is rejected, even with a valid MIC
+        assert_eq!(tracker.check(DEV, 67, Verified), FcntCheck::Replay { fcnt: 67, last: 67 });
+        assert_eq!(tracker.check(DEV, 66, Verified), FcntCheck::Replay { fcnt: 66, last: 67 });
+        assert_eq!(tracker.replays_detected(), 2);
+
+        // Other devices are tracked independently
+        assert_eq!(tracker.check(0x260B5678, 1, Unchecked), FcntCheck::Accepted(1));
+    }
+
+    #[test]
+    fn test_counter_reset() {
+        let mut tracker = FrameCounterTracker::new(16);
+        assert_eq!(tracker.check(DEV, 500, Verified), FcntCheck::Accepted(500));
+
+        // Device rebooted: a low counter is only accepted if the MIC verifies
+        let replay = FcntCheck::Replay { fcnt: 0, last: 500 };
+        assert_eq!(tracker.check(DEV, 0, Unchecked), replay);
+        assert_eq!(tracker.check(DEV, 0, Failed), replay);
+        assert_eq!(tracker.check(DEV, 0, Verified), FcntCheck::Reset(0));
+        assert_eq!(tracker.check(DEV, 1, Verified), FcntCheck::Accepted(1));
+
+        // Within the tolerance window a replay is still a replay
+        assert_eq!(tracker.check(DEV, 0, Verified), FcntCheck::Replay { fcnt: 0, last: 1 });
+    }
+
+    #[test]
+    fn test_failed_mic_not_recorded() {
+        let mut tracker = FrameCounterTracker::new(16);
+        assert_eq!(tracker.check(DEV, 10, Verified), FcntCheck::Accepted(10));
+
+        // A spoofed frame far ahead doesn't move the counter...
+        assert_eq!(tracker.check(DEV, 16000, Failed), FcntCheck::Unverified(16000));
+        assert_eq!(tracker.last(DEV), Some(10));
+        // ...so the device's next real frame is still accepted
+        assert_eq!(tracker.check(DEV, 11, Verified), FcntCheck::Accepted(11));
+
+        // A copy of the accepted frame is told apart from other frames
+        tracker.record_frame(DEV, &[0x40, 1, 2]);
+        assert!(tracker.is_copy(DEV, &[0x40, 1, 2]));
+        assert!(!tracker.is_copy(DEV, &[0x40, 1, 3]));
+        assert!(!tracker.is_copy(0x260B5678, &[0x40, 1, 2]));
+
+        // A new session (OTAA join) starts from FCnt 0 again
+        tracker.reset(DEV);
+        assert_eq!(tracker.check(DEV, 0, Unchecked), FcntCheck::Accepted(0));
+        assert!(!tracker.is_copy(DEV, &[0x40, 1, 2]));
+    }
+
+    #[test]
+    fn test_rollover() {
+        let mut tracker = FrameCounterTracker::new(0);
+        assert_eq!(tracker.check(DEV, 0xFFFE, Unchecked), FcntCheck::Accepted(0xFFFE));
+        assert_eq!(tracker.check(DEV, 0x0001, Unchecked), FcntCheck::Accepted(0x1_0001));
+        assert_eq!(tracker.reconstruct(DEV, 0x0002), 0x1_0002);
+    }
+
+    #[test]
+    fn test_mic_fcnt() {
+        let mut tracker = FrameCounterTracker::new(16);
+        assert_eq!(tracker.mic_fcnt(DEV, 5), 5);
+        assert_eq!(tracker.check(DEV, 0xFFFF, Verified), FcntCheck::Accepted(0xFFFF));
+        assert_eq!(tracker.check(DEV, 0x0000, Verified), FcntCheck::Accepted(0x1_0000));
+
+        // Past 65535 the MIC is over the full counter...
+        assert_eq!(tracker.mic_fcnt(DEV, 0x0100), 0x1_0100);
+        assert_eq!(tracker.check(DEV, 0x0100, Verified), FcntCheck::Accepted(0x1_0100));
+        // ...except for a low counter that can only be a reset
+        assert_eq!(tracker.mic_fcnt(DEV, 3), 3);
+        assert_eq!(tracker.mic_fcnt(DEV, 17), 0x1_0011);
+    }
+
+    #[test]
+    fn test_missed_frames() {
+        let mut tracker = FrameCounterTracker::new(4);
+        assert_eq!(tracker.check(DEV, 10, Verified), FcntCheck::Accepted(10));
+        assert_eq!(tracker.missed(DEV), MissedFrames::default());
+
+        // 10 -> 15: frames 11 to 14 were lost
+        assert_eq!(tracker.check(DEV, 15, Verified), FcntCheck::Accepted(15));
+        assert_eq!(tracker.missed(DEV), MissedFrames { last_gap: 4, total: 4 });
+        assert_eq!(tracker.check(DEV, 16, Verified), FcntCheck::Accepted(16));
+        assert_eq!(tracker.missed(DEV), MissedFrames { last_gap: 0, total: 4 });
+
+        // Replays and resets are not gaps
+        tracker.check(DEV, 12, Verified);
+        assert_eq!(tracker.check(DEV, 0, Verified), FcntCheck::Reset(0));
+        assert_eq!(tracker.missed(DEV), MissedFrames { last_gap: 0, total: 4 });
+
+        // Nor is a jump by a frame whose MIC wasn't checked
+        assert_eq!(tracker.check(DEV, 20, Unchecked), FcntCheck::Accepted(20));
+        assert_eq!(tracker.missed(DEV), MissedFrames { last_gap: 0, total: 4 });
+
+        // Nor a rollover of the low 16 bits
+        let other = 0x260B5678;
+        tracker.check(other, 0xFFFF, Verified);
+        assert_eq!(tracker.check(other, 0x0000, Verified), FcntCheck::Accepted(0x1_0000));
+        assert_eq!(tracker.missed(other), MissedFrames::default());
+        tracker.check(other, 0x0002, Verified);
+        assert_eq!(tracker.missed(other).total, 1);
+    }
+}
@@ -0,0 +1,404 @@
+//! Offline frame inspection for `lora-urbit decode`
+//!
+//! Decodes a captured PHY payload and, given session keys, verifies its MIC
+//! and decrypts its FRMPayload. Key operations need the `phase4` feature.
+//!
+//! The report prints as text, or as a `DecodedFrame` JSON object for
+//! `--format json`.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use super::{decode_phy_payload, FCtrl, LoRaWANFrame};
+
+/// A decoded frame plus the results of any key-based checks
+#[derive(Debug, Clone)]
+pub struct FrameReport {
+    pub frame: LoRaWANFrame,
+    /// MIC check result; None when no suitable key was given
+    pub mic_valid: Option<bool>,
+    /// Decrypted FRMPayload (data frames) or JoinAccept fields
+    pub decrypted: Option<Vec<u8>>,
+}
+
+impl FrameReport {
+    /// Decode `phy`, checking it with whichever keys are provided
+    ///
+    /// `nwk_key` is the NwkSKey; `app_key` is the AppSKey for data frames
+    /// and the AppKey for JoinRequest/JoinAccept.
+    pub fn new(
+        phy: &[u8],
+        nwk_key: Option<&[u8; 16]>,
+        app_key: Option<&[u8; 16]>,
+    ) -> anyhow::Result<Self> {
+        let frame = decode_phy_payload(phy)?;
+        let mut report = Self {
+            frame,
+            mic_valid: None,
+            decrypted: None,
+        };
+        if nwk_key.is_some() || app_key.is_some() {
+            report.apply_keys(phy, nwk_key, app_key)?;
+        }
+        Ok(report)
+    }
+
+    #[cfg(feature = "phase4")]
+    fn apply_keys(
+        &mut self,
+        phy: &[u8],
+        nwk_key: Option<&[u8; 16]>,
+        app_key: Option<&[u8; 16]>,
+    ) -> anyhow::Result<()> {
+        use super::crypto::{self, Direction};
+        use super::MType;
+
+        match &self.frame {
+            LoRaWANFrame::Data {
+                mtype,
+                dev_addr,
+                fcnt,
+                f_port,
+                frm_payload,
+                ..
+            } => {
+                let dir = match mtype {
+                    MType::UnconfirmedDataUp | MType::ConfirmedDataUp => Direction::Uplink,
+                    _ => Direction::Downlink,
+                };
+                let (msg, mic) = phy.split_at(phy.len() - 4);
+                self.mic_valid = nwk_key.map(|key| {
+                    crypto::data_mic(key, dir, *dev_addr, *fcnt as u32, msg).to_le_bytes() == mic
+                });
+                // FPort 0 carries MAC commands encrypted with the NwkSKey
+                let payload_key = match f_port {
+                    Some(0) => nwk_key,
+                    Some(_) => app_key,
+                    None => None,
+                };
+                self.decrypted = payload_key.map(|key| {
+                    crypto::frm_payload_cipher(key, dir, *dev_addr, *fcnt as u32, frm_payload)
+                });
+            }
+            LoRaWANFrame::JoinRequest { .. } => {
+                let (msg, mic) = phy.split_at(phy.len() - 4);
+                self.mic_valid =
+                    app_key.map(|key| crypto::join_mic(key, msg).to_le_bytes() == mic);
+            }
+            LoRaWANFrame::JoinAccept { encrypted_payload } => {
+                if let Some(key) = app_key {
+                    match crypto::decrypt_join_accept(key, phy[0], encrypted_payload) {
+                        Ok(_) => {
+                            let mut plain = encrypted_payload.clone();
+                            crypto::aes_ecb(key, &mut plain, false);
+                            self.mic_valid = Some(true);
+                            self.decrypted = Some(plain[..plain.len() - 4].to_vec());
+                        }
+                        Err(_) => self.mic_valid = Some(false),
+                    }
+                }
+            }
+            // Rejoin MICs use LoRaWAN 1.1 keys (SNwkSIntKey or JSIntKey)
+            LoRaWANFrame::RejoinRequest { .. } | LoRaWANFrame::Proprietary { .. } => {}
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "phase4"))]
+    fn apply_keys(
+        &mut self,
+        _phy: &[u8],
+        _nwk_key: Option<&[u8; 16]>,
+        _app_key: Option<&[u8; 16]>,
+    ) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!(
+            "MIC verification and decryption require the phase4 feature"
+        ))
+    }
+
+    /// The report as the `--format json` object
+    pub fn to_json(&self) -> DecodedFrame {
+        let mut json = DecodedFrame {
+            mtype: String::new(),
+            major: None,
+            dev_addr: None,
+            fcnt: None,
+            fctrl: None,
+            f_opts: None,
+            f_port: None,
+            payload: None,
+            decrypted: self.decrypted.as_deref().map(hex::encode),
+            mic: None,
+            mic_valid: self.mic_valid,
+            app_eui: None,
+            dev_eui: None,
+            dev_nonce: None,
+        };
+        match &self.frame {
+            LoRaWANFrame::Data {
+                mtype,
+                major,
+                dev_addr,
+                fctrl,
+                fcnt,
+                f_opts,
+                f_port,
+                frm_payload,
+                mic,
+            } => {
+                json.mtype = mtype.to_string();
+                json.major = Some(major.to_string());
+                json.dev_addr = Some(format!("{:08X}", dev_addr));
+                json.fcnt = Some(*fcnt);
+                json.fctrl = Some(FCtrlJson::from(fctrl));
+                json.f_opts = Some(hex::encode(f_opts));
+                json.f_port = *f_port;
+                json.payload = Some(hex::encode(frm_payload));
+                json.mic = Some(format!("{:08X}", mic));
+            }
+            LoRaWANFrame::JoinRequest {
+                app_eui,
+                dev_eui,
+                dev_nonce,
+                mic,
+            } => {
+                json.mtype = "JoinRequest".to_string();
+                json.app_eui = Some(format!("{:016X}", app_eui));
+                json.dev_eui = Some(format!("{:016X}", dev_eui));
+                json.dev_nonce = Some(*dev_nonce);
+                json.mic = Some(format!("{:08X}", mic));
+            }
+            LoRaWANFrame::RejoinRequest {
+                join_eui,
+                dev_eui,
+                mic,
+                ..
+            } => {
+                json.mtype = "RejoinRequest".to_string();
+                json.app_eui = join_eui.map(|eui| format!("{:016X}", eui));
+                json.dev_eui = Some(format!("{:016X}", dev_eui));
+                json.mic = Some(format!("{:08X}", mic));
+            }
+            LoRaWANFrame::JoinAccept { encrypted_payload } => {
+                json.mtype = "JoinAccept".to_string();
+                json.payload = Some(hex::encode(encrypted_payload));
+            }
+            LoRaWANFrame::Proprietary { payload } => {
+                json.mtype = "Proprietary".to_string();
+                json.payload = Some(hex::encode(payload));
+            }
+        }
+        json
+    }
+
+    fn mic_status(&self) -> &'static str {
+        match self.mic_valid {
+            Some(true) => "valid",
+            Some(false) => "INVALID",
+            None => "not checked",
+        }
+    }
+}
+
+/// `lora-urbit decode --format json` output
+///
+/// Every key is always present, null where it doesn't apply to the frame
+/// type; hex fields are uppercase for addresses and EUIs, lowercase for bytes.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DecodedFrame {
+    pub mtype: String,
+    pub major: Option<String>,
+    pub dev_addr: Option<String>,
+    pub fcnt: Option<u16>,
+    pub fctrl: Option<FCtrlJson>,
+    pub f_opts: Option<String>,
+    pub f_port: Option<u8>,
+    /// FRMPayload as sent (encrypted), or a JoinAccept's encrypted body
+    pub payload: Option<String>,
+    /// Decrypted FRMPayload or JoinAccept fields, given the key
+    pub decrypted: Option<String>,
+    pub mic: Option<String>,
+    /// None when no suitable key was given
+    pub mic_valid: Option<bool>,
+    /// AppEUI of a JoinRequest, JoinEUI of a RejoinRequest
+    pub app_eui: Option<String>,
+    pub dev_eui: Option<String>,
+    pub dev_nonce: Option<u16>,
+}
+
+/// FCtrl flags in `DecodedFrame`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FCtrlJson {
+    pub adr: bool,
+    pub adr_ack_req: bool,
+    pub ack: bool,
+    /// FPending in downlinks
+    pub class_b: bool,
+    pub f_opts_len: u8,
+}
+
+impl From<&FCtrl> for FCtrlJson {
+    fn from(fctrl: &FCtrl) -> Self {
+        Self {
+            adr: fctrl.adr,
+            adr_ack_req: fctrl.adr_ack_req,
+            ack: fctrl.ack,
+            class_b: fctrl.class_b,
+            f_opts_len: fctrl.f_opts_len,
+        }
+    }
+}
+
+impl fmt::Display for FrameReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.frame {
+            LoRaWANFrame::Data {
+                mtype,
+                major,
+                dev_addr,
+                fctrl,
+                fcnt,
+                f_opts,
+                f_port,
+                frm_payload,
+                mic,
+            } => {
+                writeln!(f, "MType:     {}", mtype)?;
+                writeln!(f, "Major:     {}", major)?;
+                writeln!(f, "DevAddr:   {:08X}", dev_addr)?;
+                writeln!(f, "FCnt:      {}", fcnt)?;
+                writeln!(
+                    f,
+                    "FPort:     {}",
+                    f_port.map(|p| p.to_string()).unwrap_or("-".to_string())
+                )?;
+                writeln!(
+                    f,
+                    "FCtrl:     ADR={} ACK={} FOptsLen={}",
+                    fctrl.adr, fctrl.ack, fctrl.f_opts_len
+                )?;
+                if !f_opts.is_empty() {
+                    writeln!(f, "FOpts:     {}", hex::encode(f_opts))?;
+                }
+                writeln!(f, "MIC:       {:08X} ({})", mic, self.mic_status())?;
+                writeln!(f, "Payload:   {}", hex::encode(frm_payload))?;
+                if let Some(plain) = &self.decrypted {
+                    write!(f, "Decrypted: {}", hex::encode(plain))?;
+                    match std::str::from_utf8(plain) {
+                        Ok(text) if !text.chars().any(char::is_control) => {
+                            writeln!(f, " ({:?})", text)?
+                        }
+                        _ => writeln!(f)?,
+                    }
+                }
+            }
+            LoRaWANFrame::JoinRequest {
+                app_eui,
+                dev_eui,
+                dev_nonce,
+                mic,
+            } => {
+                writeln!(f, "MType:     JoinRequest")?;
+                writeln!(f, "AppEUI:    {:016X}", app_eui)?;
+                writeln!(f, "DevEUI:    {:016X}", dev_eui)?;
+                writeln!(f, "DevNonce:  {}", dev_nonce)?;
+                writeln!(f, "MIC:       {:08X} ({})", mic, self.mic_status())?;
+            }
+            LoRaWANFrame::RejoinRequest {
+                rejoin_type,
+                net_id,
+                join_eui,
+                dev_eui,
+                rj_count,
+                mic,
+            } => {
+                writeln!(f, "MType:     RejoinRequest (type {})", rejoin_type)?;
+                if let Some(net_id) = net_id {
+                    writeln!(f, "NetID:     {:06X}", net_id)?;
+                }
+                if let Some(join_eui) = join_eui {
+                    writeln!(f, "JoinEUI:   {:016X}", join_eui)?;
+                }
+                writeln!(f, "DevEUI:    {:016X}", dev_eui)?;
+                writeln!(f, "RJcount:   {}", rj_count)?;
+                writeln!(f, "MIC:       {:08X} ({})", mic, self.mic_status())?;
+            }
+            LoRaWANFrame::JoinAccept { encrypted_payload } => {
+                writeln!(f, "MType:     JoinAccept")?;
+                writeln!(f, "Encrypted: {}", hex::encode(encrypted_payload))?;
+                writeln!(f, "MIC:       {}", self.mic_status())?;
+                if let Some(plain) = &self.decrypted {
+                    let u24 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], 0]);
+                    writeln!(f, "AppNonce:  {:06X}", u24(&plain[0..3]))?;
+                    writeln!(f, "NetID:     {:06X}", u24(&plain[3..6]))?;
+                    writeln!(
+                        f,
+                        "DevAddr:   {:08X}",
+                        u32::from_le_bytes([plain[6], plain[7], plain[8], plain[9]])
+                    )?;
+                    writeln!(f, "DLSettings: {:02X}", plain[10])?;
+                    writeln!(f, "RxDelay:   {}", plain[11])?;
+                    if plain.len() > 12 {
+                        writeln!(f, "CFList:    {}", hex::encode(&plain[12..]))?;
+                    }
+                }
+            }
+            LoRaWANFrame::Proprietary { payload } => {
+                writeln!(f, "MType:     Proprietary")?;
+                writeln!(f, "Payload:   {}", hex::encode(payload))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_unconfirmed_data_up() {
+        let phy = hex::decode("40F17DBE4900020001954378762B11FF0D").unwrap();
+        let report = FrameReport::new(&phy, None, None).unwrap();
+        let json = serde_json::to_value(report.to_json()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "mtype": "UnconfirmedDataUp",
+                "major": "LoRaWAN R1",
+                "dev-addr": "49BE7DF1",
+                "fcnt": 2,
+                "fctrl": {
+                    "adr": false,
+                    "adr-ack-req": false,
+                    "ack": false,
+                    "class-b": false,
+                    "f-opts-len": 0
+                },
+                "f-opts": "",
+                "f-port": 1,
+                "payload": "95437876",
+                "decrypted": null,
+                "mic": "0DFF112B",
+                "mic-valid": null,
+                "app-eui": null,
+                "dev-eui": null,
+                "dev-nonce": null
+            })
+        );
+
+        #[cfg(feature = "phase4")]
+        {
+            let key = |k: &str| -> [u8; 16] { hex::decode(k).unwrap().try_into().unwrap() };
+            let nwk_key = key("44024241ed4ce9a68c6a8bc055233fd3");
+            let app_key = key("ec925802ae430ca77fd3dd73cb2cc588");
+            let report = FrameReport::new(&phy, Some(&nwk_key), Some(&app_key)).unwrap();
+            let json = report.to_json();
+            assert_eq!(json.mic_valid, Some(true));
+            assert_eq!(json.decrypted.as_deref(), Some("74657374"));
+        }
+    }
+}
@@ -4,6 +4,50 @@
 //! - NwkSKey for MIC verification and MAC command encryption
 //! - AppSKey for application payload decryption
 //! - DevAddr ↔ session key mapping
+//! - Per-DevAddr downlink frame counters (NFCntDown)
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::AbpDeviceConfig;
+use crate::lorawan::datarate::DataRate;
+
+/// LoRaWAN device class: when the device listens for downlinks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeviceClass {
+    /// Only in the RX1/RX2 windows after an uplink
+    #[default]
+    A,
+    /// Also in ping slots synchronized to network beacons
+    B,
+    /// Continuously
+    C,
+}
+
+/// LoRaWAN version a device implements, which selects the uplink MIC
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LorawanVersion {
+    #[default]
+    #[serde(rename = "1.0")]
+    V1_0,
+    /// Uplink MIC split over FNwkSIntKey and SNwkSIntKey
+    #[serde(rename = "1.1")]
+    V1_1,
+}
+
+/// ConfFCnt, TxDr and TxCh of the LoRaWAN 1.1 uplink MIC's B1 block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct B1Params {
+    /// FCnt of the confirmed downlink acknowledged (0 without FCtrl.ACK)
+    pub conf_fcnt: u16,
+    /// Regional data rate index of the uplink
+    pub tx_dr: u8,
+    /// Regional channel index of the uplink
+    pub tx_ch: u8,
+}
 
 /// Placeholder for session key storage
 /// Will be populated in Phase 4 when we need MIC verification
@@ -11,8 +55,26 @@
 #[derive(Debug, Clone)]
 pub struct SessionKeys {
     pub dev_addr: u32,
+    /// NwkSKey; FNwkSIntKey for LoRaWAN 1.1 devices
     pub nwk_s_key: [u8; 16],
     pub app_s_key: [u8; 16],
+    pub lorawan_version: LorawanVersion,
+    /// SNwkSIntKey (LoRaWAN 1.1 only)
+    pub s_nwk_s_int_key: Option<[u8; 16]>,
+    pub class: DeviceClass,
+    /// Class B ping slot periodicity (0-7: 2^p·0.96 s between slots)
+    pub ping_slot_periodicity: u8,
+    /// RX2 data rate agreed with the device, if not the region default
+    pub rx2_datr: Option<DataRate>,
+    /// RxDelay: seconds to the RX1 window (0 counts as 1)
+    pub rx1_delay: u8,
+}
+
+impl SessionKeys {
+    /// Seconds from the end of an uplink to this device's RX1 window
+    pub fn rx1_delay_secs(&self) -> u64 {
+        self.rx1_delay.max(1) as u64
+    }
 }
 
 /// Session key store — maps DevAddr to session keys
@@ -21,13 +83,86 @@ pub struct SessionKeys {
 #[derive(Debug, Default)]
 pub struct KeyStore {
     pub sessions: Vec<SessionKeys>,
+    /// DevAddrs whose sessions came from `lorawan.devices`
+    abp_addrs: HashSet<u32>,
 }
 
 impl KeyStore {
     pub fn new() -> Self {
-        Self {
-            sessions: Vec::new(),
+        Self::default()
+    }
+
+    /// Build from the ABP devices in `lorawan.devices`
+    pub fn from_config(devices: &[AbpDeviceConfig]) -> anyhow::Result<Self> {
+        let mut store = Self::new();
+        for device in devices {
+            let dev_addr = u32::from_str_radix(&device.dev_addr, 16).map_err(|e| {
+                anyhow::anyhow!("Invalid DevAddr {:?} in lorawan.devices: {}", device.dev_addr, e)
+            })?;
+            let key = |name: &str, value: &str| {
+                parse_key(value).map_err(|e| {
+                    anyhow::anyhow!("Invalid {} for device {}: {}", name, device.dev_addr, e)
+                })
+            };
+            if !(1..=15).contains(&device.rx1_delay) {
+                return Err(anyhow::anyhow!(
+                    "rx1_delay for device {} must be 1-15 seconds, got {}",
+                    device.dev_addr,
+                    device.rx1_delay
+                ));
+            }
+            if device.ping_slot_periodicity > 7 {
+                return Err(anyhow::anyhow!(
+                    "ping_slot_periodicity for device {} must be 0-7, got {}",
+                    device.dev_addr,
+                    device.ping_slot_periodicity
+                ));
+            }
+            let s_nwk_s_int_key = match (device.lorawan_version, &device.s_nwk_s_int_key) {
+                (LorawanVersion::V1_1, Some(k)) => Some(key("s_nwk_s_int_key", k)?),
+                (LorawanVersion::V1_1, None) => {
+                    return Err(anyhow::anyhow!(
+                        "LoRaWAN 1.1 device {} needs s_nwk_s_int_key",
+                        device.dev_addr
+                    ))
+                }
+                (LorawanVersion::V1_0, _) => None,
+            };
+            store.sessions.push(SessionKeys {
+                dev_addr,
+                nwk_s_key: key("nwk_s_key", &device.nwk_s_key)?,
+                app_s_key: key("app_s_key", &device.app_s_key)?,
+                lorawan_version: device.lorawan_version,
+                s_nwk_s_int_key,
+                class: device.class,
+                ping_slot_periodicity: device.ping_slot_periodicity,
+                rx2_datr: device.rx2_datr.clone(),
+                rx1_delay: device.rx1_delay,
+            });
+            store.abp_addrs.insert(dev_addr);
         }
+        Ok(store)
+    }
+
+    /// Replace the ABP sessions with a reloaded `lorawan.devices` list
+    ///
+    /// Sessions from OTAA joins are kept. Nothing changes if the new list
+    /// is invalid.
+    pub fn reload_abp(&mut self, devices: &[AbpDeviceConfig]) -> anyhow::Result<()> {
+        let reloaded = Self::from_config(devices)?;
+        let old_abp = std::mem::take(&mut self.abp_addrs);
+        self.sessions.retain(|s| !old_abp.contains(&s.dev_addr));
+        self.sessions.extend(reloaded.sessions);
+        self.abp_addrs = reloaded.abp_addrs;
+        Ok(())
+    }
+
+    /// Add a session, replacing any earlier session for the same DevAddr
+    ///
+    /// Used when an OTAA join completes: the device discards its old keys.
+    pub fn insert(&mut self, keys: SessionKeys) {
+        self.sessions.retain(|s| s.dev_addr != keys.dev_addr);
+        self.sessions.push(keys);
     }
 
     /// Look up session keys by DevAddr
@@ -39,4 +174,360 @@ impl KeyStore {
             .filter(|s| s.dev_addr == dev_addr)
             .collect()
     }
+
+    /// Ping slot periodicity of `dev_addr` if it is a Class B device
+    pub fn class_b_periodicity(&self, dev_addr: u32) -> Option<u8> {
+        self.lookup(dev_addr)
+            .into_iter()
+            .find(|keys| keys.class == DeviceClass::B)
+            .map(|keys| keys.ping_slot_periodicity)
+    }
+
+    /// Class of `dev_addr`, A unless provisioned otherwise
+    pub fn class(&self, dev_addr: u32) -> DeviceClass {
+        self.lookup(dev_addr).first().map_or(DeviceClass::A, |keys| keys.class)
+    }
+
+    /// Seconds to `dev_addr`'s RX1 window (the default 1 without a session)
+    pub fn rx1_delay_secs(&self, dev_addr: u32) -> u64 {
+        self.lookup(dev_addr).first().map_or(1, |keys| keys.rx1_delay_secs())
+    }
+
+    /// RX2 data rate of `dev_addr` if it differs from the region default
+    pub fn rx2_datr(&self, dev_addr: u32) -> Option<DataRate> {
+        self.lookup(dev_addr).into_iter().find_map(|keys| keys.rx2_datr.clone())
+    }
+
+    /// Whether any session for `dev_addr` verifies this uplink's MIC
+    ///
+    /// Each session uses the MIC of its LoRaWAN version; `b1` is only used
+    /// for 1.1 sessions. Always false without the `phase4` crypto feature.
+    pub fn verify_uplink_mic(&self, dev_addr: u32, phy: &[u8], fcnt: u32, b1: B1Params) -> bool {
+        #[cfg(feature = "phase4")]
+        {
+            use super::crypto;
+            self.lookup(dev_addr).iter().any(|keys| {
+                match (keys.lorawan_version, &keys.s_nwk_s_int_key) {
+                    (LorawanVersion::V1_1, Some(s_key)) => {
+                        crypto::verify_uplink_mic_v1_1(&keys.nwk_s_key, s_key, b1, phy, fcnt)
+                    }
+                    _ => crypto::verify_uplink_mic(&keys.nwk_s_key, phy, fcnt),
+                }
+            })
+        }
+        #[cfg(not(feature = "phase4"))]
+        {
+            let _ = (dev_addr, phy, fcnt, b1);
+            false
+        }
+    }
+
+    /// Decrypt an FPort 0 FRMPayload (MAC commands) with the device's NwkSKey
+    ///
+    /// None without a LoRaWAN 1.0 session for `dev_addr` (1.1 encrypts these
+    /// with NwkSEncKey, which is not kept) or without the `phase4` feature.
+    pub fn decrypt_mac_payload(&self, dev_addr: u32, fcnt: u32, payload: &[u8]) -> Option<Vec<u8>> {
+        #[cfg(feature = "phase4")]
+        {
+            use super::crypto::{frm_payload_cipher, Direction};
+            let keys = self
+                .lookup(dev_addr)
+                .into_iter()
+                .find(|keys| keys.lorawan_version == LorawanVersion::V1_0)?;
+            Some(frm_payload_cipher(&keys.nwk_s_key, Direction::Uplink, dev_addr, fcnt, payload))
+        }
+        #[cfg(not(feature = "phase4"))]
+        {
+            let _ = (dev_addr, fcnt, payload);
+            None
+        }
+    }
+}
+
+/// KeyStore shared between the UDP server and the outbound task
+pub type SharedKeyStore = Arc<RwLock<KeyStore>>;
+
+/// Parse a 32-hex-digit AES-128 key
+pub fn parse_key(key: &str) -> anyhow::Result<[u8; 16]> {
+    let bytes = hex::decode(key).map_err(|e| anyhow::anyhow!("Invalid key hex: {}", e))?;
+    bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| anyhow::anyhow!("Key must be 16 bytes, got {}", b.len()))
+}
+
+/// File name of the persisted downlink counters inside `lorawan.state_dir`
+pub const DOWNLINK_COUNTERS_FILE: &str = "downlink_counters.json";
+
+/// Per-DevAddr downlink frame counters
+///
+/// LoRaWAN devices reject downlinks whose FCnt does not increase, so each
+/// DevAddr gets its own monotonic sequence. Persisted as a JSON object of
+/// DevAddr hex → next FCnt so counters survive restarts.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DownlinkCounters {
+    counters: HashMap<u32, u32>,
+    /// FCnt of the last ConfirmedDataDown to each DevAddr (not persisted)
+    confirmed: HashMap<u32, u32>,
+}
+
+impl DownlinkCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load counters from `path`; a missing file starts every device at 0
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => {
+                return Err(anyhow::anyhow!(
+                    "Failed to read downlink counters {:?}: {}",
+                    path,
+                    e
+                ))
+            }
+        };
+
+        let stored: BTreeMap<String, u32> = serde_json::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Failed to parse downlink counters {:?}: {}", path, e))?;
+        let mut counters = HashMap::with_capacity(stored.len());
+        for (addr, fcnt) in stored {
+            let dev_addr = u32::from_str_radix(&addr, 16)
+                .map_err(|e| anyhow::anyhow!("Invalid DevAddr {:?} in {:?}: {}", addr, path, e))?;
+            counters.insert(dev_addr, fcnt);
+        }
+        Ok(Self {
+            counters,
+            confirmed: HashMap::new(),
+        })
+    }
+
+    /// Write counters to `path` (via a temp file so a crash can't truncate it)
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let stored: BTreeMap<String, u32> = self
+            .counters
+            .iter()
+            .map(|(addr, fcnt)| (format!("{:08X}", addr), *fcnt))
+            .collect();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&stored)?)?;
+        std::fs::rename(&tmp, path)
+            .map_err(|e| anyhow::anyhow!("Failed to save downlink counters {:?}: {}", path, e))
+    }
+
+    /// FCnt to use for the next downlink to `dev_addr`
+    pub fn peek(&self, dev_addr: u32) -> u32 {
+        self.counters.get(&dev_addr).copied().unwrap_or(0)
+    }
+
+    /// Restart `dev_addr` at FCnt 0 (a new session after an OTAA join)
+    pub fn reset(&mut self, dev_addr: u32) {
+        self.counters.remove(&dev_addr);
+        self.confirmed.remove(&dev_addr);
+    }
+
+    /// Consume the current FCnt for `dev_addr` and return it
+    pub fn next(&mut self, dev_addr: u32) -> u32 {
+        let fcnt = self.peek(dev_addr);
+        self.counters.insert(dev_addr, fcnt.wrapping_add(1));
+        fcnt
+    }
+
+    /// `next` for a ConfirmedDataDown, remembered as the one a device's ACK answers
+    pub fn next_confirmed(&mut self, dev_addr: u32) -> u32 {
+        let fcnt = self.next(dev_addr);
+        self.confirmed.insert(dev_addr, fcnt);
+        fcnt
+    }
+
+    /// FCnt of the last ConfirmedDataDown to `dev_addr` since startup
+    pub fn last_confirmed(&self, dev_addr: u32) -> Option<u32> {
+        self.confirmed.get(&dev_addr).copied()
+    }
+}
+
+/// DownlinkCounters shared between the UDP server (auto-ACKs) and the outbound task
+pub type SharedDownlinkCounters = Arc<Mutex<DownlinkCounters>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_store_from_config() {
+        let devices = vec![AbpDeviceConfig {
+            dev_addr: "49BE7DF1".to_string(),
+            nwk_s_key: "44024241ed4ce9a68c6a8bc055233fd3".to_string(),
+            app_s_key: "ec925802ae430ca77fd3dd73cb2cc588".to_string(),
+            lorawan_version: LorawanVersion::V1_0,
+            s_nwk_s_int_key: None,
+            class: DeviceClass::B,
+            ping_slot_periodicity: 3,
+            rx2_datr: None,
+            rx1_delay: 1,
+        }];
+        let store = KeyStore::from_config(&devices).unwrap();
+        assert_eq!(store.lookup(0x49BE7DF1).len(), 1);
+        assert_eq!(store.lookup(0x49BE7DF1)[0].app_s_key[0], 0xEC);
+        assert_eq!(store.class_b_periodicity(0x49BE7DF1), Some(3));
+
+        // A rejoin replaces the device's session
+        let mut store = store;
+        store.insert(SessionKeys {
+            dev_addr: 0x49BE7DF1,
+            nwk_s_key: [1; 16],
+            app_s_key: [2; 16],
+            lorawan_version: LorawanVersion::V1_0,
+            s_nwk_s_int_key: None,
+            class: DeviceClass::A,
+            ping_slot_periodicity: 7,
+            rx2_datr: None,
+            rx1_delay: 1,
+        });
+        assert_eq!(store.lookup(0x49BE7DF1).len(), 1);
+        assert_eq!(store.lookup(0x49BE7DF1)[0].app_s_key, [2; 16]);
+        assert_eq!(store.class_b_periodicity(0x49BE7DF1), None);
+
+        let mut bad = devices.clone();
+        bad[0].nwk_s_key = "0011".to_string();
+        assert!(KeyStore::from_config(&bad).is_err());
+        let mut bad = devices.clone();
+        bad[0].ping_slot_periodicity = 8;
+        assert!(KeyStore::from_config(&bad).is_err());
+        // 1.1 devices need both network integrity keys
+        let mut bad = devices.clone();
+        bad[0].lorawan_version = LorawanVersion::V1_1;
+        assert!(KeyStore::from_config(&bad).is_err());
+    }
+
+    #[cfg(feature = "phase4")]
+    #[test]
+    fn test_verify_uplink_mic_by_version() {
+        let device = AbpDeviceConfig {
+            dev_addr: "49BE7DF1".to_string(),
+            nwk_s_key: "44024241ed4ce9a68c6a8bc055233fd3".to_string(),
+            app_s_key: "ec925802ae430ca77fd3dd73cb2cc588".to_string(),
+            lorawan_version: LorawanVersion::V1_0,
+            s_nwk_s_int_key: None,
+            class: DeviceClass::A,
+            ping_slot_periodicity: 7,
+            rx2_datr: None,
+            rx1_delay: 1,
+        };
+        // The same uplink MICed for 1.0 and for 1.1 (TxDr 3, TxCh 5)
+        let v1_0 = hex::decode("40F17DBE4900020001954378762B11FF0D").unwrap();
+        let v1_1 = hex::decode("40F17DBE490002000195437876A4312B11").unwrap();
+        let b1 = B1Params {
+            conf_fcnt: 0,
+            tx_dr: 3,
+            tx_ch: 5,
+        };
+
+        let store = KeyStore::from_config(std::slice::from_ref(&device)).unwrap();
+        assert!(store.verify_uplink_mic(0x49BE7DF1, &v1_0, 2, b1));
+        assert!(!store.verify_uplink_mic(0x49BE7DF1, &v1_1, 2, b1));
+
+        let device = AbpDeviceConfig {
+            lorawan_version: LorawanVersion::V1_1,
+            s_nwk_s_int_key: Some("5e3dc1d45a1b7b3d0a8fd3bc1c0f4e12".to_string()),
+            ..device
+        };
+        let store = KeyStore::from_config(&[device]).unwrap();
+        assert!(store.verify_uplink_mic(0x49BE7DF1, &v1_1, 2, b1));
+        assert!(!store.verify_uplink_mic(0x49BE7DF1, &v1_0, 2, b1));
+    }
+
+    #[test]
+    fn test_reload_abp_devices() {
+        let device = |dev_addr: &str| AbpDeviceConfig {
+            dev_addr: dev_addr.to_string(),
+            nwk_s_key: "44024241ed4ce9a68c6a8bc055233fd3".to_string(),
+            app_s_key: "ec925802ae430ca77fd3dd73cb2cc588".to_string(),
+            lorawan_version: LorawanVersion::V1_0,
+            s_nwk_s_int_key: None,
+            class: DeviceClass::A,
+            ping_slot_periodicity: 7,
+            rx2_datr: None,
+            rx1_delay: 1,
+        };
+        let keys: SharedKeyStore = Arc::new(RwLock::new(
+            KeyStore::from_config(&[device("260B0001")]).unwrap(),
+        ));
+        // An OTAA session joined at runtime
+        keys.write().unwrap().insert(SessionKeys {
+            dev_addr: 0x260B0099,
+            nwk_s_key: [1; 16],
+            app_s_key: [2; 16],
+            lorawan_version: LorawanVersion::V1_0,
+            s_nwk_s_int_key: None,
+            class: DeviceClass::A,
+            ping_slot_periodicity: 7,
+            rx2_datr: None,
+            rx1_delay: 1,
+        });
+
+        // Simulated SIGHUP: 260B0001 removed, 260B0002 added
+        keys.write().unwrap().reload_abp(&[device("260B0002")]).unwrap();
+        let store = keys.read().unwrap();
+        assert!(store.lookup(0x260B0001).is_empty());
+        assert_eq!(store.lookup(0x260B0002).len(), 1);
+        assert_eq!(store.lookup(0x260B0099).len(), 1);
+        drop(store);
+
+        // An invalid list leaves the store untouched
+        let mut bad = device("260B0003");
+        bad.app_s_key = "zz".to_string();
+        assert!(keys.write().unwrap().reload_abp(&[bad]).is_err());
+        assert_eq!(keys.read().unwrap().lookup(0x260B0002).len(), 1);
+    }
+
+    #[test]
+    fn test_downlink_counters_per_device() {
+        let mut counters = DownlinkCounters::new();
+        assert_eq!(counters.next(0x260B1234), 0);
+        assert_eq!(counters.next(0x260B1234), 1);
+        assert_eq!(counters.next(0x260B5678), 0);
+        assert_eq!(counters.next(0x260B1234), 2);
+        assert_eq!(counters.next(0x260B5678), 1);
+        assert_eq!(counters.peek(0x260B5678), 2);
+
+        counters.reset(0x260B1234);
+        assert_eq!(counters.next(0x260B1234), 0);
+
+        // Confirmed downlinks are remembered past later unconfirmed ones
+        assert_eq!(counters.last_confirmed(0x260B1234), None);
+        assert_eq!(counters.next_confirmed(0x260B1234), 1);
+        assert_eq!(counters.next(0x260B1234), 2);
+        assert_eq!(counters.last_confirmed(0x260B1234), Some(1));
+        counters.reset(0x260B1234);
+        assert_eq!(counters.last_confirmed(0x260B1234), None);
+
+        // The counter keeps counting past the 16 bits of the FHDR
+        counters.counters.insert(0x260B5678, 0xFFFF);
+        assert_eq!(counters.next(0x260B5678), 0xFFFF);
+        assert_eq!(counters.next(0x260B5678), 0x1_0000);
+    }
+
+    #[test]
+    fn test_downlink_counters_persist() {
+        let path = std::env::temp_dir()
+            .join(format!("lora-urbit-counters-{}", std::process::id()))
+            .join(DOWNLINK_COUNTERS_FILE);
+        assert_eq!(DownlinkCounters::load(&path).unwrap().peek(0x260B1234), 0);
+
+        let mut counters = DownlinkCounters::new();
+        counters.next(0x260B1234);
+        counters.next(0x260B1234);
+        counters.next(0x260B5678);
+        counters.save(&path).unwrap();
+
+        let loaded = DownlinkCounters::load(&path).unwrap();
+        assert_eq!(loaded.peek(0x260B1234), 2);
+        assert_eq!(loaded.peek(0x260B5678), 1);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
 }
@@ -0,0 +1,126 @@
+//! Uplink MAC commands (LoRaWAN 1.0.4 §5)
+//!
+//! Devices answer network requests in FOpts (or an FPort 0 payload). Each
+//! command is a CID byte followed by a fixed-size payload; parsing stops at
+//! an unknown CID since its length can't be known. Only the answers with
+//! device-health value are decoded; the rest are kept as raw bytes.
+
+use serde::{Deserialize, Serialize};
+
+/// A MAC command sent by a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UplinkMacCommand {
+    /// DevStatusAns (0x06)
+    DevStatusAns(DevStatus),
+    /// LinkADRAns (0x03)
+    LinkAdrAns(LinkAdrStatus),
+    /// Any other known command and its payload
+    Other { cid: u8, payload: Vec<u8> },
+}
+
+/// Battery and demodulation margin from DevStatusAns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DevStatus {
+    /// 0 = external power, 1-254 = battery level, 255 = unable to measure
+    pub battery: u8,
+    /// SNR of the last DevStatusReq in dB (-32..31)
+    pub margin: i8,
+}
+
+/// Which parts of a LinkADRReq the device accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LinkAdrStatus {
+    pub power_ack: bool,
+    pub data_rate_ack: bool,
+    pub channel_mask_ack: bool,
+}
+
+/// Payload length of an uplink MAC command, if the CID is known
+fn uplink_payload_len(cid: u8) -> Option<usize> {
+    match cid {
+        // LinkCheckReq, DutyCycleAns, RXTimingSetupAns, TxParamSetupAns,
+        // ADRParamSetupAns, DeviceTimeReq, BeaconTimingReq
+        0x02 | 0x04 | 0x08 | 0x09 | 0x0C | 0x0D | 0x12 => Some(0),
+        // ResetInd, LinkADRAns, RXParamSetupAns, NewChannelAns, DlChannelAns,
+        // RekeyInd, RejoinParamSetupAns, PingSlotInfoReq, PingSlotChannelAns,
+        // BeaconFreqAns
+        0x01 | 0x03 | 0x05 | 0x07 | 0x0A | 0x0B | 0x0F | 0x10 | 0x11 | 0x13 => Some(1),
+        // DevStatusAns
+        0x06 => Some(2),
+        _ => None,
+    }
+}
+
+/// Parse the MAC commands in FOpts (or a decrypted FPort 0 payload)
+///
+/// Commands after an unknown CID or a truncated command are dropped.
+pub fn parse_uplink_commands(data: &[u8]) -> Vec<UplinkMacCommand> {
+    let mut commands = Vec::new();
+    let mut rest = data;
+    while let Some((&cid, tail)) = rest.split_first() {
+        let Some(len) = uplink_payload_len(cid).filter(|&len| len <= tail.len()) else {
+            break;
+        };
+        let (payload, tail) = tail.split_at(len);
+        commands.push(match cid {
+            0x06 => UplinkMacCommand::DevStatusAns(DevStatus {
+                battery: payload[0],
+                // 6-bit two's complement
+                margin: ((payload[1] << 2) as i8) >> 2,
+            }),
+            0x03 => UplinkMacCommand::LinkAdrAns(LinkAdrStatus {
+                power_ack: payload[0] & 0x04 != 0,
+                data_rate_ack: payload[0] & 0x02 != 0,
+                channel_mask_ack: payload[0] & 0x01 != 0,
+            }),
+            _ => UplinkMacCommand::Other {
+                cid,
+                payload: payload.to_vec(),
+            },
+        });
+        rest = tail;
+    }
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_uplink_commands() {
+        // LinkCheckReq, LinkADRAns (power and channel mask OK), DevStatusAns
+        // (battery 200, margin -5), then an unknown CID
+        let commands = parse_uplink_commands(&[0x02, 0x03, 0x05, 0x06, 200, 0x3B, 0x7F, 0x00]);
+        assert_eq!(
+            commands,
+            vec![
+                UplinkMacCommand::Other {
+                    cid: 0x02,
+                    payload: vec![]
+                },
+                UplinkMacCommand::LinkAdrAns(LinkAdrStatus {
+                    power_ack: true,
+                    data_rate_ack: false,
+                    channel_mask_ack: true,
+                }),
+                UplinkMacCommand::DevStatusAns(DevStatus {
+                    battery: 200,
+                    margin: -5
+                }),
+            ]
+        );
+
+        // Positive margin; a truncated trailing command is dropped
+        let commands = parse_uplink_commands(&[0x06, 0xFF, 0x14, 0x06, 0x01]);
+        assert_eq!(
+            commands,
+            vec![UplinkMacCommand::DevStatusAns(DevStatus {
+                battery: 255,
+                margin: 20
+            })]
+        );
+        assert!(parse_uplink_commands(&[]).is_empty());
+    }
+}
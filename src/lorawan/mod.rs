@@ -1,7 +1,22 @@
+pub mod airtime;
+pub mod channel_plan;
+pub mod class_b;
+pub mod codec;
+#[cfg(feature = "phase4")]
+pub mod crypto;
+pub mod datarate;
 pub mod encoder;
+pub mod fcnt;
+pub mod inspect;
 pub mod keys;
+pub mod mac;
+#[cfg(feature = "phase4")]
+pub mod selftest;
 
 use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
 
 /// LoRaWAN MAC Header (MHDR) - Message Type
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -17,7 +32,7 @@ pub enum MType {
 }
 
 impl TryFrom<u8> for MType {
-    type Error = anyhow::Error;
+    type Error = DecodeError;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match (value >> 5) & 0x07 {
@@ -29,7 +44,7 @@ impl TryFrom<u8> for MType {
             0b101 => Ok(MType::ConfirmedDataDown),
             0b110 => Ok(MType::RejoinRequest),
             0b111 => Ok(MType::Proprietary),
-            _ => unreachable!(),
+            _ => Err(DecodeError::BadMType(value)),
         }
     }
 }
@@ -50,12 +65,32 @@ impl fmt::Display for MType {
 }
 
 /// LoRaWAN Major version
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Major {
     LoRaWANR1,
+    /// A reserved Major value (1-3)
     Unknown(u8),
 }
 
+impl From<u8> for Major {
+    /// Major from the two low bits of an MHDR byte
+    fn from(mhdr: u8) -> Self {
+        match mhdr & 0x03 {
+            0b00 => Major::LoRaWANR1,
+            major => Major::Unknown(major),
+        }
+    }
+}
+
+impl fmt::Display for Major {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Major::LoRaWANR1 => write!(f, "LoRaWAN R1"),
+            Major::Unknown(major) => write!(f, "RFU ({})", major),
+        }
+    }
+}
+
 /// Frame Control byte (FCtrl) for uplink
 #[derive(Debug, Clone)]
 pub struct FCtrl {
@@ -72,6 +107,7 @@ pub enum LoRaWANFrame {
     /// Data frame (up or down)
     Data {
         mtype: MType,
+        major: Major,
         dev_addr: u32,
         fctrl: FCtrl,
         fcnt: u16,
@@ -87,6 +123,18 @@ pub enum LoRaWANFrame {
         dev_nonce: u16,
         mic: u32,
     },
+    /// Rejoin Request (LoRaWAN 1.1), type 0, 1 or 2
+    RejoinRequest {
+        rejoin_type: u8,
+        /// Types 0 and 2
+        net_id: Option<u32>,
+        /// Type 1
+        join_eui: Option<u64>,
+        dev_eui: u64,
+        /// RJcount0 (types 0 and 2) or RJcount1 (type 1)
+        rj_count: u16,
+        mic: u32,
+    },
     /// Join Accept (encrypted, not decoded further without keys)
     JoinAccept {
         encrypted_payload: Vec<u8>,
@@ -134,6 +182,23 @@ impl fmt::Display for LoRaWANFrame {
                     app_eui, dev_eui, dev_nonce, mic
                 )
             }
+            LoRaWANFrame::RejoinRequest {
+                rejoin_type,
+                net_id,
+                join_eui,
+                dev_eui,
+                rj_count,
+                mic,
+            } => {
+                write!(f, "RejoinRequest Type={}", rejoin_type)?;
+                if let Some(net_id) = net_id {
+                    write!(f, " NetID={:06X}", net_id)?;
+                }
+                if let Some(join_eui) = join_eui {
+                    write!(f, " JoinEUI={:016X}", join_eui)?;
+                }
+                write!(f, " DevEUI={:016X} RJcount={} MIC={:08X}", dev_eui, rj_count, mic)
+            }
             LoRaWANFrame::JoinAccept { encrypted_payload } => {
                 write!(
                     f,
@@ -148,10 +213,152 @@ impl fmt::Display for LoRaWANFrame {
     }
 }
 
+/// NwkID length in bits for DevAddr/NetID types 0-7 (LoRaWAN Backend Interfaces)
+const NWK_ID_BITS: [u32; 8] = [6, 6, 9, 11, 12, 13, 15, 17];
+
+/// A DevAddr split into its address-type prefix, NwkID and NwkAddr
+///
+/// A type N DevAddr starts with N one bits and a zero, followed by the NwkID
+/// of the network that assigned it and the device's NwkAddr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DevAddrParts {
+    /// 0-7: the number of leading one bits
+    pub addr_type: u8,
+    pub nwk_id: u32,
+    pub nwk_addr: u32,
+}
+
+impl DevAddrParts {
+    /// Split `dev_addr`; None for the reserved 0xFF prefix
+    pub fn from_dev_addr(dev_addr: u32) -> Option<Self> {
+        let addr_type = dev_addr.leading_ones();
+        if addr_type > 7 {
+            return None;
+        }
+        let id_bits = NWK_ID_BITS[addr_type as usize];
+        let addr_bits = 32 - (addr_type + 1) - id_bits;
+        Some(Self {
+            addr_type: addr_type as u8,
+            nwk_id: (dev_addr >> addr_bits) & ((1 << id_bits) - 1),
+            nwk_addr: dev_addr & ((1 << addr_bits) - 1),
+        })
+    }
+}
+
+/// A LoRa Alliance NetID: a 3-bit type and a 21-bit ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NetId(u32);
+
+impl NetId {
+    pub fn new(net_id: u32) -> anyhow::Result<Self> {
+        if net_id > 0xFF_FFFF {
+            return Err(anyhow::anyhow!("NetID {:X} is wider than 24 bits", net_id));
+        }
+        Ok(Self(net_id))
+    }
+
+    pub fn net_type(&self) -> u8 {
+        (self.0 >> 21) as u8
+    }
+
+    /// NwkID carried by DevAddrs this network assigns (the ID's low bits)
+    pub fn nwk_id(&self) -> u32 {
+        self.0 & ((1 << NWK_ID_BITS[self.net_type() as usize]) - 1)
+    }
+
+    /// Whether `dev_addr` falls in this network's address range
+    pub fn contains(&self, dev_addr: u32) -> bool {
+        DevAddrParts::from_dev_addr(dev_addr)
+            .is_some_and(|parts| {
+                parts.addr_type == self.net_type() && parts.nwk_id == self.nwk_id()
+            })
+    }
+}
+
+impl fmt::Display for NetId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:06X}", self.0)
+    }
+}
+
+impl FromStr for NetId {
+    type Err = anyhow::Error;
+
+    /// Parse 6 hex digits, e.g. "00003C"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 6 {
+            return Err(anyhow::anyhow!("NetID {:?} must be 6 hex digits (e.g. \"00003C\")", s));
+        }
+        let net_id = u32::from_str_radix(s, 16)
+            .map_err(|_| anyhow::anyhow!("NetID {:?} must be 6 hex digits", s))?;
+        Self::new(net_id)
+    }
+}
+
+impl<'de> Deserialize<'de> for NetId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Why a PHY payload could not be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// No bytes at all
+    Empty,
+    /// Shorter than the frame type's minimum length
+    TooShort { got: usize, min: usize },
+    /// A fixed-size frame (JoinRequest, RejoinRequest) with the wrong length
+    WrongLength { got: usize, expected: usize },
+    /// MHDR byte whose MType can't be decoded
+    BadMType(u8),
+    /// FCtrl.FOptsLen runs into the MIC
+    FOptsOverflow { f_opts_len: u8 },
+    /// A message type this decoder doesn't handle yet
+    Unsupported(MType),
+    /// MHDR Major other than LoRaWAN R1 (strict decoding only)
+    UnsupportedMajor(u8),
+    /// RejoinRequest with a rejoin type other than 0, 1 or 2
+    BadRejoinType(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "Empty PHY payload"),
+            DecodeError::TooShort { got, min } => {
+                write!(f, "Frame too short: {} bytes (minimum {})", got, min)
+            }
+            DecodeError::WrongLength { got, expected } => {
+                write!(f, "Frame must be {} bytes, got {}", expected, got)
+            }
+            DecodeError::BadMType(mhdr) => write!(f, "Invalid MType in MHDR 0x{:02X}", mhdr),
+            DecodeError::FOptsOverflow { f_opts_len } => {
+                write!(f, "FOpts length {} exceeds available data", f_opts_len)
+            }
+            DecodeError::Unsupported(mtype) => write!(f, "{} not yet supported", mtype),
+            DecodeError::UnsupportedMajor(mhdr) => {
+                write!(f, "Unsupported Major {} in MHDR 0x{:02X}", mhdr & 0x03, mhdr)
+            }
+            DecodeError::BadRejoinType(rejoin_type) => {
+                write!(f, "Invalid rejoin type {}", rejoin_type)
+            }
+        }
+    }
+}
+
+/// Also gives `From<DecodeError> for anyhow::Error`, so `?` works in anyhow code
+impl std::error::Error for DecodeError {}
+
 /// Decode a LoRaWAN PHY payload (raw bytes after base64 decode)
-pub fn decode_phy_payload(data: &[u8]) -> anyhow::Result<LoRaWANFrame> {
+///
+/// Data frames record their Major without checking it; see
+/// `decode_phy_payload_strict`.
+pub fn decode_phy_payload(data: &[u8]) -> Result<LoRaWANFrame, DecodeError> {
     if data.is_empty() {
-        return Err(anyhow::anyhow!("Empty PHY payload"));
+        return Err(DecodeError::Empty);
     }
 
     let mhdr = data[0];
@@ -169,23 +376,67 @@ pub fn decode_phy_payload(data: &[u8]) -> anyhow::Result<LoRaWANFrame> {
         MType::Proprietary => Ok(LoRaWANFrame::Proprietary {
             payload: data[1..].to_vec(),
         }),
-        MType::RejoinRequest => Err(anyhow::anyhow!("RejoinRequest not yet supported")),
+        MType::RejoinRequest => decode_rejoin_request(data),
+    }
+}
+
+fn decode_rejoin_request(data: &[u8]) -> Result<LoRaWANFrame, DecodeError> {
+    // Types 0/2: MHDR(1) + type(1) + NetID(3) + DevEUI(8) + RJcount0(2) + MIC(4) = 19
+    // Type 1:    MHDR(1) + type(1) + JoinEUI(8) + DevEUI(8) + RJcount1(2) + MIC(4) = 24
+    let rejoin_type = *data.get(1).ok_or(DecodeError::TooShort {
+        got: data.len(),
+        min: 19,
+    })?;
+    let expected = match rejoin_type {
+        0 | 2 => 19,
+        1 => 24,
+        _ => return Err(DecodeError::BadRejoinType(rejoin_type)),
+    };
+    if data.len() != expected {
+        return Err(DecodeError::WrongLength {
+            got: data.len(),
+            expected,
+        });
+    }
+
+    // The EUI fields start after NetID (3 bytes) or JoinEUI (8 bytes)
+    let (net_id, join_eui, rest) = match rejoin_type {
+        1 => (None, Some(u64::from_le_bytes(data[2..10].try_into().unwrap())), &data[10..]),
+        _ => (Some(u32::from_le_bytes([data[2], data[3], data[4], 0])), None, &data[5..]),
+    };
+    Ok(LoRaWANFrame::RejoinRequest {
+        rejoin_type,
+        net_id,
+        join_eui,
+        dev_eui: u64::from_le_bytes(rest[0..8].try_into().unwrap()),
+        rj_count: u16::from_le_bytes(rest[8..10].try_into().unwrap()),
+        mic: u32::from_le_bytes(rest[10..14].try_into().unwrap()),
+    })
+}
+
+/// Like `decode_phy_payload`, but reject frames whose Major isn't LoRaWAN R1
+pub fn decode_phy_payload_strict(data: &[u8]) -> Result<LoRaWANFrame, DecodeError> {
+    match data.first() {
+        Some(&mhdr) if Major::from(mhdr) != Major::LoRaWANR1 => {
+            Err(DecodeError::UnsupportedMajor(mhdr))
+        }
+        _ => decode_phy_payload(data),
     }
 }
 
-fn decode_join_request(data: &[u8]) -> anyhow::Result<LoRaWANFrame> {
+fn decode_join_request(data: &[u8]) -> Result<LoRaWANFrame, DecodeError> {
     // MHDR(1) + AppEUI(8) + DevEUI(8) + DevNonce(2) + MIC(4) = 23 bytes
     if data.len() != 23 {
-        return Err(anyhow::anyhow!(
-            "JoinRequest must be 23 bytes, got {}",
-            data.len()
-        ));
+        return Err(DecodeError::WrongLength {
+            got: data.len(),
+            expected: 23,
+        });
     }
 
-    let app_eui = u64::from_le_bytes(data[1..9].try_into()?);
-    let dev_eui = u64::from_le_bytes(data[9..17].try_into()?);
-    let dev_nonce = u16::from_le_bytes(data[17..19].try_into()?);
-    let mic = u32::from_le_bytes(data[19..23].try_into()?);
+    let app_eui = u64::from_le_bytes(data[1..9].try_into().unwrap());
+    let dev_eui = u64::from_le_bytes(data[9..17].try_into().unwrap());
+    let dev_nonce = u16::from_le_bytes(data[17..19].try_into().unwrap());
+    let mic = u32::from_le_bytes(data[19..23].try_into().unwrap());
 
     Ok(LoRaWANFrame::JoinRequest {
         app_eui,
@@ -195,17 +446,17 @@ fn decode_join_request(data: &[u8]) -> anyhow::Result<LoRaWANFrame> {
     })
 }
 
-fn decode_data_frame(mtype: MType, data: &[u8]) -> anyhow::Result<LoRaWANFrame> {
+fn decode_data_frame(mtype: MType, data: &[u8]) -> Result<LoRaWANFrame, DecodeError> {
     // Minimum: MHDR(1) + DevAddr(4) + FCtrl(1) + FCnt(2) + MIC(4) = 12 bytes
     if data.len() < 12 {
-        return Err(anyhow::anyhow!(
-            "Data frame too short: {} bytes (minimum 12)",
-            data.len()
-        ));
+        return Err(DecodeError::TooShort {
+            got: data.len(),
+            min: 12,
+        });
     }
 
-    // DevAddr is little-endian
-    let dev_addr = u32::from_le_bytes(data[1..5].try_into()?);
+    // DevAddr is little-endian (lengths are checked above, so slices convert)
+    let dev_addr = u32::from_le_bytes(data[1..5].try_into().unwrap());
 
     // FCtrl
     let fctrl_byte = data[5];
@@ -218,15 +469,14 @@ fn decode_data_frame(mtype: MType, data: &[u8]) -> anyhow::Result<LoRaWANFrame>
     };
 
     // FCnt (16-bit, little-endian)
-    let fcnt = u16::from_le_bytes(data[6..8].try_into()?);
+    let fcnt = u16::from_le_bytes(data[6..8].try_into().unwrap());
 
     // FOpts
     let f_opts_end = 8 + fctrl.f_opts_len as usize;
     if f_opts_end > data.len() - 4 {
-        return Err(anyhow::anyhow!(
-            "FOpts length {} exceeds available data",
-            fctrl.f_opts_len
-        ));
+        return Err(DecodeError::FOptsOverflow {
+            f_opts_len: fctrl.f_opts_len,
+        });
     }
     let f_opts = data[8..f_opts_end].to_vec();
 
@@ -241,10 +491,11 @@ fn decode_data_frame(mtype: MType, data: &[u8]) -> anyhow::Result<LoRaWANFrame>
     };
 
     // MIC (last 4 bytes)
-    let mic = u32::from_le_bytes(data[mic_start..].try_into()?);
+    let mic = u32::from_le_bytes(data[mic_start..].try_into().unwrap());
 
     Ok(LoRaWANFrame::Data {
         mtype,
+        major: Major::from(data[0]),
         dev_addr,
         fctrl,
         fcnt,
@@ -301,6 +552,36 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_major_validation() {
+        // The frame above with Major 01 (RFU) in the MHDR
+        let mut data = vec![
+            0x41, 0x04, 0x03, 0x02, 0x01, 0x00, 0x01, 0x00, 0x01, 0xAA, 0xBB, 0xEF, 0xBE, 0xAD,
+            0xDE,
+        ];
+        match decode_phy_payload(&data).unwrap() {
+            LoRaWANFrame::Data { mtype, major, .. } => {
+                assert_eq!(mtype, MType::UnconfirmedDataUp);
+                assert_eq!(major, Major::Unknown(1));
+            }
+            other => panic!("Expected Data frame, got {:?}", other),
+        }
+        let err = decode_phy_payload_strict(&data).unwrap_err();
+        assert_eq!(err, DecodeError::UnsupportedMajor(0x41));
+        assert_eq!(err.to_string(), "Unsupported Major 1 in MHDR 0x41");
+
+        data[0] = 0x40;
+        match decode_phy_payload_strict(&data).unwrap() {
+            LoRaWANFrame::Data { major, .. } => assert_eq!(major, Major::LoRaWANR1),
+            other => panic!("Expected Data frame, got {:?}", other),
+        }
+        // Strict decoding checks every message type
+        assert_eq!(
+            decode_phy_payload_strict(&[0x03; 23]).unwrap_err(),
+            DecodeError::UnsupportedMajor(0x03)
+        );
+    }
+
     #[test]
     fn test_decode_join_request() {
         // JoinRequest: MHDR=0x00
@@ -325,17 +606,147 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_decode_rejoin_request() {
+        // Type 0: NetID 00003C, DevEUI A8A7A6A5A4A3A2A1, RJcount0 7
+        let mut data = vec![0xC0, 0x00, 0x3C, 0x00, 0x00];
+        data.extend_from_slice(&0xA8A7A6A5A4A3A2A1u64.to_le_bytes());
+        data.extend_from_slice(&[0x07, 0x00, 0xEF, 0xBE, 0xAD, 0xDE]);
+        match decode_phy_payload(&data).unwrap() {
+            LoRaWANFrame::RejoinRequest {
+                rejoin_type,
+                net_id,
+                join_eui,
+                dev_eui,
+                rj_count,
+                mic,
+            } => {
+                assert_eq!(rejoin_type, 0);
+                assert_eq!(net_id, Some(0x00003C));
+                assert_eq!(join_eui, None);
+                assert_eq!(dev_eui, 0xA8A7A6A5A4A3A2A1);
+                assert_eq!(rj_count, 7);
+                assert_eq!(mic, 0xDEADBEEF);
+            }
+            other => panic!("Expected RejoinRequest, got {:?}", other),
+        }
+
+        // Type 2 has the same layout
+        data[1] = 2;
+        let frame = decode_phy_payload(&data).unwrap();
+        assert!(matches!(frame, LoRaWANFrame::RejoinRequest { rejoin_type: 2, .. }));
+        assert_eq!(
+            frame.to_string(),
+            "RejoinRequest Type=2 NetID=00003C DevEUI=A8A7A6A5A4A3A2A1 RJcount=7 MIC=DEADBEEF"
+        );
+        data.push(0);
+        assert_eq!(
+            decode_phy_payload(&data).unwrap_err(),
+            DecodeError::WrongLength { got: 20, expected: 19 }
+        );
+
+        // Type 1: JoinEUI 0807060504030201 instead of NetID
+        let mut data = vec![0xC0, 0x01];
+        data.extend_from_slice(&0x0807060504030201u64.to_le_bytes());
+        data.extend_from_slice(&0xA8A7A6A5A4A3A2A1u64.to_le_bytes());
+        data.extend_from_slice(&[0x00, 0x01, 0xEF, 0xBE, 0xAD, 0xDE]);
+        match decode_phy_payload(&data).unwrap() {
+            LoRaWANFrame::RejoinRequest {
+                net_id,
+                join_eui,
+                dev_eui,
+                rj_count,
+                ..
+            } => {
+                assert_eq!(net_id, None);
+                assert_eq!(join_eui, Some(0x0807060504030201));
+                assert_eq!(dev_eui, 0xA8A7A6A5A4A3A2A1);
+                assert_eq!(rj_count, 256);
+            }
+            other => panic!("Expected RejoinRequest, got {:?}", other),
+        }
+        assert_eq!(
+            decode_phy_payload(&data[..19]).unwrap_err(),
+            DecodeError::WrongLength { got: 19, expected: 24 }
+        );
+        assert_eq!(
+            decode_phy_payload(&[0xC0]).unwrap_err(),
+            DecodeError::TooShort { got: 1, min: 19 }
+        );
+    }
+
     #[test]
     fn test_empty_payload_fails() {
-        let result = decode_phy_payload(&[]);
-        assert!(result.is_err());
+        assert_eq!(decode_phy_payload(&[]).unwrap_err(), DecodeError::Empty);
     }
 
     #[test]
     fn test_too_short_data_frame_fails() {
         // Only 5 bytes — way too short
         let data: Vec<u8> = vec![0x40, 0x01, 0x02, 0x03, 0x04];
-        let result = decode_phy_payload(&data);
-        assert!(result.is_err());
+        assert_eq!(
+            decode_phy_payload(&data).unwrap_err(),
+            DecodeError::TooShort { got: 5, min: 12 }
+        );
+    }
+
+    #[test]
+    fn test_decode_error_variants() {
+        // JoinRequest one byte short
+        assert_eq!(
+            decode_phy_payload(&[0x00; 22]).unwrap_err(),
+            DecodeError::WrongLength { got: 22, expected: 23 }
+        );
+        // FOptsLen 15 with no room before the MIC
+        let data = [0x40, 0x04, 0x03, 0x02, 0x01, 0x0F, 0x01, 0x00, 0, 0, 0, 0];
+        assert_eq!(
+            decode_phy_payload(&data).unwrap_err(),
+            DecodeError::FOptsOverflow { f_opts_len: 15 }
+        );
+        // RejoinRequest (MType 110) of type 0xC0
+        assert_eq!(
+            decode_phy_payload(&[0xC0; 19]).unwrap_err(),
+            DecodeError::BadRejoinType(0xC0)
+        );
+
+        // Still usable with `?` in anyhow code
+        let err: anyhow::Error = decode_phy_payload(&[]).unwrap_err().into();
+        assert_eq!(err.to_string(), "Empty PHY payload");
+        assert_eq!(err.downcast_ref::<DecodeError>(), Some(&DecodeError::Empty));
+    }
+
+    #[test]
+    fn test_dev_addr_net_id() {
+        // Helium (NetID 00003C, type 0): DevAddrs 78000000-79FFFFFF
+        let helium: NetId = "00003C".parse().unwrap();
+        assert_eq!(helium.net_type(), 0);
+        assert_eq!(helium.nwk_id(), 0x3C);
+        let parts = DevAddrParts::from_dev_addr(0x7801_2345).unwrap();
+        assert_eq!(parts.addr_type, 0);
+        assert_eq!(parts.nwk_id, 0x3C);
+        assert_eq!(parts.nwk_addr, 0x0001_2345);
+        assert!(helium.contains(0x7801_2345));
+        assert!(helium.contains(0x79FF_FFFF));
+
+        // TTN (NetID 000013) DevAddrs are out of range
+        assert!(!helium.contains(0x260B_1234));
+        assert!("000013".parse::<NetId>().unwrap().contains(0x260B_1234));
+
+        // Type 1 (prefix 10): NetID 20002A, DevAddrs AA000000-AAFFFFFF
+        let type1: NetId = "20002A".parse().unwrap();
+        assert_eq!(type1.net_type(), 1);
+        let parts = DevAddrParts::from_dev_addr(0xAAAB_CDEF).unwrap();
+        assert_eq!((parts.addr_type, parts.nwk_id, parts.nwk_addr), (1, 0x2A, 0xAB_CDEF));
+        assert!(type1.contains(0xAAAB_CDEF));
+        assert!(!type1.contains(0x5400_0000)); // same NwkID, type 0
+
+        // Type 6 (prefix 1111110): 15-bit NwkID, 10-bit NwkAddr: FC014C00-FC014FFF
+        let type6: NetId = "C00053".parse().unwrap();
+        assert!(type6.contains(0xFC01_4FFF));
+        assert!(!type6.contains(0xFC01_5000));
+
+        assert!(DevAddrParts::from_dev_addr(0xFF00_0000).is_none());
+        assert!("3C".parse::<NetId>().is_err());
+        assert!("00003G".parse::<NetId>().is_err());
     }
 }
@@ -1,154 +1,875 @@
-mod config;
-mod helium;
-mod lorawan;
-mod udp;
-mod urbit;
-
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use lora_urbit::{admin, config, health, helium, logging, udp, urbit};
+#[cfg(feature = "phase2")]
+use lora_urbit::supervisor::supervise;
 use std::path::PathBuf;
-use tracing::{error, info};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
 
+/// Handle for replacing the log filter at runtime
+type LogFilterHandle = tracing_subscriber::reload::Handle<EnvFilter, tracing_subscriber::Registry>;
+
+/// How long to wait for background tasks to drain on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for a gateway's TX_ACK before assuming the downlink went out
+#[cfg(feature = "phase2")]
+const TX_ACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often downlink counters are saved when no outbound task does it
+const COUNTERS_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a ship's DevAddr from the agent's registry is reused
+#[cfg(feature = "phase2")]
+const REGISTRY_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// How long the agent's join allow list (`urbit.authorize_joins`) is reused
+#[cfg(feature = "phase2")]
+const ALLOWED_DEVICES_TTL: Duration = Duration::from_secs(30);
+
+/// How long to wait before reopening a failed Packet Router stream
+#[cfg(feature = "helium-grpc")]
+const PACKET_ROUTER_RETRY: Duration = Duration::from_secs(10);
+
 #[derive(Parser)]
 #[command(name = "lora-urbit")]
 #[command(about = "Sovereign LoRaWAN infrastructure powered by Urbit's Ames protocol")]
 #[command(version)]
 struct Cli {
     /// Path to configuration file
-    #[arg(short, long, default_value = "config.toml")]
+    #[arg(short, long, default_value = "config.toml", global = true)]
     config: PathBuf,
+
+    /// Capture every GWMP datagram to this file (overrides capture.path; `run` only)
+    #[arg(long, global = true)]
+    capture: Option<PathBuf>,
+
+    /// Log pokes and downlinks instead of sending them (general.dry_run)
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+impl Cli {
+    /// `--capture`, which only the bridge itself (`run`) records to
+    fn capture(&self) -> anyhow::Result<Option<&PathBuf>> {
+        match (&self.capture, &self.command) {
+            (Some(_), Some(command)) if !matches!(command, Command::Run) => {
+                Err(anyhow::anyhow!("--capture only applies to `run`"))
+            }
+            (capture, _) => Ok(capture.as_ref()),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the bridge (the default)
+    Run,
+    /// Decode a captured PHY payload without starting the bridge
+    Decode(DecodeArgs),
+    /// Send one test downlink to a gateway and report its TX_ACK
+    SendDownlink(SendDownlinkArgs),
+    /// Re-send the uplinks of a packet log (`logging.packet_log`) as PUSH_DATA
+    Replay(ReplayArgs),
+    /// Check the LoRaWAN crypto against known test vectors
+    Selftest,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DecodeFormat {
+    Text,
+    Json,
+}
+
+#[derive(Args)]
+struct DecodeArgs {
+    /// PHY payload as hex
+    #[arg(long, conflicts_with = "base64", required_unless_present = "base64")]
+    hex: Option<String>,
+    /// PHY payload as base64 (as in rxpk.data)
+    #[arg(long)]
+    base64: Option<String>,
+    /// NwkSKey (hex) to verify the MIC of a data frame
+    #[arg(long)]
+    nwk_key: Option<String>,
+    /// AppSKey (data frames) or AppKey (join frames), hex
+    #[arg(long)]
+    app_key: Option<String>,
+    /// Print the fields as text, or as one JSON object for other tools
+    #[arg(long, value_enum, default_value = "text")]
+    format: DecodeFormat,
+}
+
+#[derive(Args)]
+struct SendDownlinkArgs {
+    /// Gateway address (ip:port) to send the PULL_RESP to
+    #[arg(long)]
+    gateway: std::net::SocketAddr,
+    /// Gateway EUI (hex), to use its `[[gateways]]` tx_power
+    #[arg(long)]
+    gateway_eui: Option<String>,
+    /// Local address to send from (default: the first udp.bind from the config)
+    #[arg(long)]
+    bind: Option<String>,
+    /// Device address (hex)
+    #[arg(long, value_parser = parse_dev_addr)]
+    dev_addr: u32,
+    /// FPort (1-223)
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=223))]
+    port: u8,
+    /// Application payload as hex
+    #[arg(long, default_value = "", value_parser = parse_hex_payload)]
+    hex: HexPayload,
+    /// Downlink frame counter
+    #[arg(long, default_value_t = 0)]
+    fcnt: u32,
+    /// Send a ConfirmedDataDown
+    #[arg(long)]
+    confirmed: bool,
+    /// Concentrator tmst of the uplink to answer, for an rx1/rx2 `downlink.mode`
+    #[arg(long)]
+    reference_tmst: Option<u64>,
+    /// Frequency (MHz) of the uplink to answer, for `downlink.mode = "rx1"`
+    #[arg(long, default_value_t = 0.0)]
+    reference_freq: f64,
+    /// Data rate of the uplink to answer (e.g. SF7BW125), for `downlink.mode = "rx1"`
+    #[arg(long, default_value = "SF12BW125")]
+    reference_datr: lora_urbit::lorawan::datarate::DataRate,
+    /// Seconds to wait for the gateway's TX_ACK
+    #[arg(long, default_value_t = 5)]
+    timeout: u64,
+}
+
+#[derive(Args)]
+struct ReplayArgs {
+    /// JSON-Lines packet log to replay
+    #[arg(long)]
+    file: PathBuf,
+    /// Bridge UDP address to send the PUSH_DATA to
+    #[arg(long, default_value = "127.0.0.1:1680")]
+    target: std::net::SocketAddr,
+    /// Playback speed: 2.0 halves the gaps between uplinks
+    #[arg(long, default_value_t = 1.0, value_parser = parse_speed)]
+    speed: f64,
+}
+
+fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+        _ => Err(format!("speed must be a positive number, got {:?}", s)),
+    }
+}
+
+/// Decoded `--hex` bytes (a newtype so clap treats them as one value)
+#[derive(Clone, Debug)]
+struct HexPayload(Vec<u8>);
+
+fn parse_dev_addr(s: &str) -> Result<u32, String> {
+    if s.len() != 8 {
+        return Err(format!("DevAddr must be 8 hex digits, got {:?}", s));
+    }
+    u32::from_str_radix(s, 16).map_err(|e| format!("invalid DevAddr {:?}: {}", s, e))
+}
+
+fn parse_hex_payload(s: &str) -> Result<HexPayload, String> {
+    hex::decode(s)
+        .map(HexPayload)
+        .map_err(|e| format!("invalid hex payload: {}", e))
+}
+
+impl SendDownlinkArgs {
+    /// Build the PHY payload described by the arguments
+    fn build_frame(&self) -> anyhow::Result<Vec<u8>> {
+        use lora_urbit::lorawan::encoder::FrameBuilder;
+
+        let payload = self.hex.0.clone();
+        let builder = if self.confirmed {
+            FrameBuilder::new_confirmed_downlink(self.dev_addr, self.fcnt, self.port, payload)
+        } else {
+            FrameBuilder::new_downlink(self.dev_addr, self.fcnt, self.port, payload)
+        };
+        builder.build()
+    }
+
+    /// The uplink being answered, if `--reference-tmst` names one
+    fn reference(&self) -> Option<udp::protocol::Rxpk> {
+        Some(udp::protocol::Rxpk {
+            time: None,
+            tmst: Some(self.reference_tmst?),
+            tmms: None,
+            chan: None,
+            rfch: None,
+            freq: self.reference_freq,
+            lsnr: None,
+            rssi: 0.0,
+            modu: None,
+            datr: self.reference_datr.clone(),
+            codr: None,
+            size: 0,
+            data: String::new(),
+        })
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    let capture = cli.capture()?.cloned();
+    if let Some(Command::Decode(args)) = cli.command {
+        return run_decode(args);
+    }
+    if let Some(Command::Replay(args)) = cli.command {
+        return run_replay(args).await;
+    }
+    if let Some(Command::Selftest) = cli.command {
+        return run_selftest();
+    }
 
-    // Load configuration
-    let config = config::Config::load(&cli.config).unwrap_or_else(|e| {
-        eprintln!("Warning: Failed to load config from {:?}: {}", cli.config, e);
-        eprintln!("Using default configuration");
-        config::Config::default()
-    });
+    // Load configuration (env > file > defaults); an invalid file is fatal
+    if !cli.config.exists() {
+        eprintln!("Warning: Config file {:?} not found", cli.config);
+        eprintln!("Using default configuration and LORAURBIT_* environment variables");
+    }
+    let mut config = load_config(&cli.config)?;
+    if let Some(path) = &capture {
+        config.capture = Some(config::CaptureConfig {
+            path: path.display().to_string(),
+        });
+    }
+    if cli.dry_run {
+        config.general.dry_run = true;
+    }
 
-    // Initialize tracing/logging
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| EnvFilter::new(&config.logging.level)),
-        )
+    // Initialize tracing/logging; the filter is swapped on SIGHUP
+    let (log_filter, log_filter_handle) = tracing_subscriber::reload::Layer::new(
+        EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| EnvFilter::new(&config.logging.level)),
+    );
+    tracing_subscriber::registry()
+        .with(log_filter)
+        .with(logging::layers(&config.logging)?)
         .init();
 
+    if let Some(Command::SendDownlink(args)) = cli.command {
+        return run_send_downlink(args, &config).await;
+    }
+
     info!("LoraUrbit v{}", env!("CARGO_PKG_VERSION"));
     info!("===========================================");
     info!("Sovereign LoRaWAN ↔ Urbit Ames Bridge");
     info!("===========================================");
+    if config.general.dry_run {
+        warn!("Dry run: pokes and downlinks are logged, not sent");
+    }
 
-    // Phase 2: Set up Urbit Airlock pipeline
+    // Settings the agent serves replace the file's (urbit.config_path)
+    let file_config = config.clone();
+    let mut remote_source = urbit::remote_config::RemoteConfigSource::from_config(&config);
+    let mut remote_config = None;
+    if let Some(source) = remote_source.as_mut() {
+        let fetched = source.fetch().await;
+        match fetched.and_then(|remote| Ok((remote.merge_over(&config)?, remote))) {
+            Ok((merged, remote)) => {
+                info!("Using settings from urbit.config_path over the config file");
+                config = merged;
+                remote_config = Some(remote);
+            }
+            Err(e) => warn!("urbit.config_path unavailable, using the config file: {}", e),
+        }
+    }
+
+    // Cancelled on Ctrl+C to wind down the UDP server and background tasks
+    let shutdown = CancellationToken::new();
+
+    // Liveness/readiness probes; readiness fills in as the bridge comes up
+    let health = health::Health::new();
+    let health_task = match &config.health {
+        Some(health_config) => {
+            let bind = &health_config.bind;
+            let listener = tokio::net::TcpListener::bind(bind)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to bind health.bind {}: {}", bind, e))?;
+            Some(tokio::spawn(health::serve(listener, health.clone(), shutdown.clone())))
+        }
+        None => None,
+    };
+
+    // Phase 2: Set up Urbit Airlock pipeline, one Airlock task per target
     #[cfg(feature = "phase2")]
-    let (poke_tx, urbit_config_clone) = if let Some(ref urbit_config) = config.urbit {
-        let (tx, rx) = tokio::sync::mpsc::channel::<urbit::types::LoRaPacket>(256);
+    let (pokes, outbox_configs, airlock_targets) = {
+        use urbit::routing::RouteRule;
 
-        // Spawn the Airlock forwarder task (uplink: LoRa → Urbit)
-        let airlock_config = urbit_config.clone();
-        tokio::spawn(async move {
-            if let Err(e) = run_airlock_task(airlock_config, rx).await {
-                error!("Airlock task failed: {}", e);
-            }
-        });
+        let mut pokes = udp::PokeRouter::new();
+        let mut targets = Vec::new();
+        for urbit_config in &config.urbit {
+            let (tx, rx) = tokio::sync::mpsc::channel::<urbit::types::LoRaAction>(256);
+            pokes.add(RouteRule::from_config(urbit_config), tx);
+            targets.push((urbit_config.clone(), rx));
+        }
 
-        info!("Urbit bridge enabled (Phase 2)");
-        (Some(tx), Some(urbit_config.clone()))
-    } else {
-        info!("Urbit bridge not configured (Phase 1 mode)");
-        (None, None)
+        if config.urbit.is_empty() {
+            info!("Urbit bridge not configured (Phase 1 mode)");
+        } else {
+            info!("Urbit bridge enabled (Phase 2), {} target(s)", config.urbit.len());
+        }
+        // Downlinks are polled from every target's outbox
+        (pokes, config.urbit.clone(), targets)
     };
 
     #[cfg(not(feature = "phase2"))]
-    let (poke_tx, urbit_config_clone): (
-        Option<tokio::sync::mpsc::Sender<urbit::types::LoRaPacket>>,
-        Option<config::UrbitConfig>,
+    let (pokes, outbox_configs, airlock_tasks): (
+        udp::PokeRouter,
+        Vec<config::UrbitConfig>,
+        Vec<tokio::task::JoinHandle<()>>,
     ) = {
-        if config.urbit.is_some() {
+        if !config.urbit.is_empty() {
             info!("Urbit config found but phase2 feature not enabled");
         }
         info!("Running in Phase 1 mode (decode only)");
-        (None, None)
+        (udp::PokeRouter::new(), Vec::new(), Vec::new())
     };
 
     // Phase 4: Initialize Helium client
-    if let Some(ref helium_config) = config.helium {
-        let _helium = helium::HeliumClient::new(helium_config.clone());
-        info!("Helium integration enabled (Phase 4)");
-    } else {
-        info!("Helium integration not configured");
-    }
+    let dc_balance_task: Option<tokio::task::JoinHandle<()>> =
+        if let Some(ref helium_config) = config.helium {
+            #[allow(unused_mut)]
+            let mut helium_client = helium::HeliumClient::new(helium_config.clone());
+
+            // A Config Service outage must not take down the packet bridge
+            #[cfg(feature = "helium-grpc")]
+            if let Err(e) = helium_client.connect_config_service().await {
+                warn!("Helium Config Service unavailable: {}", e);
+            }
+
+            info!("Helium integration enabled (Phase 4)");
+
+            #[cfg(feature = "helium-grpc")]
+            {
+                let dc_shutdown = shutdown.clone();
+                Some(tokio::spawn(run_dc_balance_task(helium_client, dc_shutdown)))
+            }
+            #[cfg(not(feature = "helium-grpc"))]
+            {
+                let _ = helium_client;
+                None
+            }
+        } else {
+            info!("Helium integration not configured");
+            None
+        };
+
+    let channel_plan = lora_urbit::lorawan::channel_plan::ChannelPlan::from_config(
+        &config.channel_plan,
+    )?
+    .with_gateways(&config.gateways)
+    .with_downlink(&config.downlink);
+    info!(
+        "Channel plan: {:?}, {} uplink channel(s), RX2 {} MHz {}",
+        channel_plan.region,
+        channel_plan.uplink_channels.len(),
+        channel_plan.rx2_freq,
+        channel_plan.rx2_datr
+    );
 
     // Start the UDP server (Phase 1 core) — returns a DownlinkSender handle
     info!("Starting Semtech UDP Packet Forwarder server...");
-    let downlink_sender = udp::start_server(&config, poke_tx).await?;
+    #[cfg(feature = "helium-grpc")]
+    let router_pokes = pokes.clone();
+    #[allow(unused_mut)]
+    let mut server = udp::start_server(&config, pokes, shutdown.clone()).await?;
+    health.set_udp_bound(server.gateways_seen.clone());
+    health.set_devices(server.devices.clone());
+    health.set_uplink_metrics(server.uplink_metrics.clone());
+
+    // Phase 4: Stream uplinks from the Packet Router alongside GWMP; downlinks
+    // to the devices it delivers go back on the same stream
+    let packet_router_host = config.helium.as_ref().and_then(|h| h.packet_router_host.clone());
+    #[cfg(feature = "helium-grpc")]
+    let packet_router_task = match (packet_router_host, &config.helium) {
+        (Some(host), Some(helium_config)) => {
+            let router = helium::router::RouterContext {
+                pokes: router_pokes,
+                codecs: lora_urbit::lorawan::codec::CodecRegistry::from_config(&config.lorawan)?,
+                devices: server.devices.clone(),
+                keys: server.keys.clone(),
+                stream: helium::router::PacketRouterStream::new(),
+            };
+            server.downlink_sender = server
+                .downlink_sender
+                .clone()
+                .with_packet_router(router.stream.clone(), router.devices.clone());
+            Some(tokio::spawn(run_packet_router_task(
+                host,
+                helium_config.delegate_keypair.clone(),
+                router,
+                shutdown.clone(),
+            )))
+        }
+        _ => None,
+    };
+    #[cfg(not(feature = "helium-grpc"))]
+    let packet_router_task: Option<tokio::task::JoinHandle<()>> = {
+        if packet_router_host.is_some() {
+            warn!("helium.packet_router_host is set but the helium-grpc feature is not enabled");
+        }
+        None
+    };
+
+    // Admin API: downlinks straight to the UDP server's send path (after
+    // the Packet Router, so its devices are answered over it)
+    let admin_task = match &config.admin {
+        Some(admin_config) => {
+            let bind = &admin_config.bind;
+            let listener = tokio::net::TcpListener::bind(bind)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to bind admin.bind {}: {}", bind, e))?;
+            if listener.local_addr().is_ok_and(|addr| !addr.ip().is_loopback()) {
+                warn!("Admin API on {} is reachable from other hosts", bind);
+            }
+            let api = admin::AdminApi {
+                sender: server.downlink_sender.clone(),
+                keys: server.keys.clone(),
+                counters: server.downlink_counters.clone(),
+                channel_plan: channel_plan.clone(),
+                encrypt: config.lorawan.encrypt_downlink,
+                token: admin_config.token.clone(),
+            };
+            Some(tokio::spawn(admin::serve(listener, api, shutdown.clone())))
+        }
+        None => None,
+    };
+
+    // Spawn the Airlock forwarder tasks (uplink: LoRa → Urbit)
+    #[cfg(feature = "phase2")]
+    let airlock_tasks: Vec<_> = airlock_targets
+        .into_iter()
+        .map(|(airlock_config, rx)| {
+            let target = format!("%{} on ~{}", airlock_config.agent, airlock_config.ship);
+            let gateways_seen = server.gateways_seen.clone();
+            let health = health.clone();
+            let dry_run = config.general.dry_run;
+            health.set_airlock_connected(&target, false);
+            // The packet channel outlives restarts of the task draining it
+            let rx = std::sync::Arc::new(tokio::sync::Mutex::new(rx));
+            tokio::spawn(async move {
+                let name = format!("Airlock ({})", target);
+                let result = supervise(&name, || {
+                    let airlock_config = airlock_config.clone();
+                    let (rx, gateways_seen) = (rx.clone(), gateways_seen.clone());
+                    let (health, target) = (health.clone(), target.clone());
+                    async move {
+                        let mut rx = rx.lock().await;
+                        let result = run_airlock_task(
+                            airlock_config,
+                            &mut rx,
+                            gateways_seen,
+                            &health,
+                            &target,
+                            dry_run,
+                        )
+                        .await;
+                        health.set_airlock_connected(&target, false);
+                        result
+                    }
+                })
+                .await;
+                if let Err(e) = result {
+                    error!("Airlock task for {} failed: {}", target, e);
+                }
+            })
+        })
+        .collect();
+
+    // A dry run leaves the saved counters alone
+    let counters_path = config.lorawan.state_dir.as_ref().filter(|_| !config.general.dry_run);
+    let counters_path = counters_path.map(|dir| {
+        PathBuf::from(dir).join(lora_urbit::lorawan::keys::DOWNLINK_COUNTERS_FILE)
+    });
 
     // Phase 3a: Spawn outbound message queue (polls Urbit outbox → sends downlinks)
     #[cfg(feature = "phase2")]
-    if let Some(urbit_cfg) = urbit_config_clone {
-        let dl_sender = downlink_sender.clone();
+    let outbound_task = (!outbox_configs.is_empty()).then(|| {
+        let downlinks = Downlinks {
+            sender: server.downlink_sender.clone(),
+            counters: server.downlink_counters.clone(),
+            keys: server.keys.clone(),
+            channel_plan: channel_plan.clone(),
+            confirmed: config
+                .downlink
+                .confirmed_retries
+                .map(|_| server.confirmed_downlinks.clone()),
+            encrypt: config.lorawan.encrypt_downlink,
+            fcnt_tracker: server.fcnt_tracker.clone(),
+        };
+        let outbound_shutdown = shutdown.clone();
+        let counters_path = counters_path.clone();
+        info!("Outbound message queue enabled (Phase 3a)");
         tokio::spawn(async move {
-            if let Err(e) = run_outbound_task(urbit_cfg, dl_sender).await {
+            let result = supervise("Outbound", || {
+                run_outbound_task(
+                    outbox_configs.clone(),
+                    downlinks.clone(),
+                    counters_path.clone(),
+                    outbound_shutdown.clone(),
+                )
+            })
+            .await;
+            if let Err(e) = result {
                 error!("Outbound task failed: {}", e);
             }
-        });
-        info!("Outbound message queue enabled (Phase 3a)");
-    }
+        })
+    });
+
+    #[cfg(not(feature = "phase2"))]
+    let outbound_task: Option<tokio::task::JoinHandle<()>> = {
+        let _ = outbox_configs;
+        None
+    };
+
+    // Without the outbound task, auto-ACKs, resent confirmed downlinks and
+    // the admin API still use up counters: save them on their own
+    let downlink_counters = server.downlink_counters.clone();
+    let counters_task = match (&outbound_task, &counters_path) {
+        (None, Some(path)) => Some(tokio::spawn(run_counters_task(
+            downlink_counters.clone(),
+            path.clone(),
+            shutdown.clone(),
+        ))),
+        _ => None,
+    };
+
+    // Re-read the config file on SIGHUP
+    #[cfg(unix)]
+    let reload_task = Some(tokio::spawn(run_reload_task(
+        cli.config.clone(),
+        file_config,
+        config,
+        (remote_source, remote_config),
+        server.keys.clone(),
+        log_filter_handle,
+        shutdown.clone(),
+    )));
+    #[cfg(not(unix))]
+    let reload_task: Option<tokio::task::JoinHandle<()>> = {
+        let _ = (log_filter_handle, file_config, remote_source, remote_config);
+        None
+    };
 
     // Keep the main task alive (the UDP server runs in a background task now)
     info!("Bridge running. Press Ctrl+C to stop.");
     tokio::signal::ctrl_c().await?;
     info!("Shutting down...");
 
+    // Stop the UDP server first: it finishes the packet in hand, then drops
+    // the poke channel so the Airlock task drains the queue and disconnects.
+    shutdown.cancel();
+    let mut tasks = vec![("UDP server", Some(server.task))];
+    tasks.extend(airlock_tasks.into_iter().map(|task| ("Airlock", Some(task))));
+    tasks.extend([
+        ("Outbound", outbound_task),
+        ("Counters", counters_task),
+        ("DC balance", dc_balance_task),
+        ("Packet Router", packet_router_task),
+        ("Reload", reload_task),
+        ("Health", health_task),
+        ("Admin", admin_task),
+    ]);
+    for (name, task) in tasks {
+        if let Some(task) = task {
+            match tokio::time::timeout(SHUTDOWN_TIMEOUT, task).await {
+                Ok(Ok(())) => info!("{} task stopped", name),
+                Ok(Err(e)) => error!("{} task panicked: {}", name, e),
+                Err(_) => warn!("{} task did not stop within {:?}", name, SHUTDOWN_TIMEOUT),
+            }
+        }
+    }
+
+    // Every task that sends downlinks has stopped: save the final counters
+    if let Some(path) = &counters_path {
+        let counters = downlink_counters.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        if let Err(e) = counters.save(path) {
+            error!("{}", e);
+        }
+    }
+
+    info!("Shutdown complete");
     Ok(())
 }
 
+/// Save the downlink counters every `COUNTERS_SAVE_INTERVAL` they changed
+///
+/// Only run without the outbound task, which saves them after each poll.
+async fn run_counters_task(
+    counters: lora_urbit::lorawan::keys::SharedDownlinkCounters,
+    path: PathBuf,
+    shutdown: CancellationToken,
+) {
+    let lock_counters = || counters.lock().unwrap_or_else(|e| e.into_inner());
+    let mut saved = lock_counters().clone();
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = tokio::time::sleep(COUNTERS_SAVE_INTERVAL) => {}
+        }
+        save_counters_if_changed(&lock_counters(), &path, &mut saved);
+    }
+}
+
+/// Load `path`, or defaults plus environment variables if it doesn't exist
+fn load_config(path: &std::path::Path) -> anyhow::Result<config::Config> {
+    if path.exists() {
+        config::Config::load(path)
+    } else {
+        config::Config::from_env()
+    }
+}
+
+/// Reload the config on SIGHUP without restarting the bridge
+///
+/// The log level and the ABP device list are applied in place; changes to
+/// anything else are logged as needing a restart. An invalid file is
+/// rejected and the running config kept. Settings from `urbit.config_path`
+/// are scried again every `config_poll_secs` and merged over the file the
+/// same way.
+#[cfg(unix)]
+async fn run_reload_task(
+    path: PathBuf,
+    mut file: config::Config,
+    mut current: config::Config,
+    (mut remote_source, mut remote): (
+        Option<urbit::remote_config::RemoteConfigSource>,
+        Option<config::RemoteConfig>,
+    ),
+    keys: lora_urbit::lorawan::keys::SharedKeyStore,
+    log_filter: LogFilterHandle,
+    shutdown: CancellationToken,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Config reload on SIGHUP unavailable: {}", e);
+            return;
+        }
+    };
+    let poll = remote_source.as_ref().and_then(|source| source.poll);
+    let mut poll_timer = poll.map(|period| {
+        tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+    });
+
+    loop {
+        let remote_due = async {
+            match poll_timer.as_mut() {
+                Some(timer) => timer.tick().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = hangup.recv() => {
+                info!("SIGHUP: reloading {:?}", path);
+                match load_config(&path) {
+                    Ok(new) => file = new,
+                    Err(e) => {
+                        error!("Config reload failed, keeping the running config: {}", e);
+                        continue;
+                    }
+                }
+            }
+            _ = remote_due => {
+                let Some(source) = remote_source.as_mut() else { continue };
+                match source.fetch().await {
+                    Ok(fetched) if remote.as_ref() == Some(&fetched) => continue,
+                    Ok(fetched) => remote = Some(fetched),
+                    Err(e) => {
+                        warn!("urbit.config_path scry failed, keeping its last settings: {}", e);
+                        continue;
+                    }
+                }
+            }
+        }
+
+        let new = match &remote {
+            Some(remote) => match remote.merge_over(&file) {
+                Ok(new) => new,
+                Err(e) => {
+                    error!("Invalid urbit.config_path settings, keeping the running config: {}", e);
+                    continue;
+                }
+            },
+            None => file.clone(),
+        };
+
+        if new.logging.level != current.logging.level {
+            match log_filter.reload(EnvFilter::new(&new.logging.level)) {
+                Ok(()) => info!("Log level now {:?}", new.logging.level),
+                Err(e) => error!("Failed to apply log level: {}", e),
+            }
+        }
+
+        // An invalid list is still recorded as seen below, so the log level
+        // and restart warnings aren't applied again on every reload until
+        // the devices change
+        if new.lorawan.devices != current.lorawan.devices {
+            let result = keys
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .reload_abp(&new.lorawan.devices);
+            match result {
+                Ok(()) => info!("Reloaded {} ABP device(s)", new.lorawan.devices.len()),
+                Err(e) => error!("Invalid lorawan.devices, keeping the previous list: {}", e),
+            }
+        }
+
+        for field in current.restart_required(&new) {
+            warn!("{} changed; restart the bridge to apply it", field);
+        }
+        current = new;
+    }
+}
+
 /// Background task that receives decoded LoRa packets and pokes them to Urbit
 #[cfg(feature = "phase2")]
 async fn run_airlock_task(
     config: config::UrbitConfig,
-    mut rx: tokio::sync::mpsc::Receiver<urbit::types::LoRaPacket>,
+    rx: &mut tokio::sync::mpsc::Receiver<urbit::types::LoRaAction>,
+    gateways_seen: udp::GatewaysSeen,
+    health: &health::Health,
+    target: &str,
+    dry_run: bool,
 ) -> anyhow::Result<()> {
+    use urbit::allowed_devices::{authorize_join, AllowedDevices};
+    use config::PokeEncoding;
+    use urbit::batch::collect_uplinks;
+    use urbit::routing::MarkTable;
     use urbit::types::LoRaAction;
+    use urbit::Recovery;
 
     let agent = config.agent.clone();
+    let typed_marks = config.typed_marks;
+    let encoding = config.encoding;
+    let mark_table = MarkTable::new(&config.mark_routes);
+    let batch_max = config.poke_batch_max.max(1);
+    let batch_window = Duration::from_millis(config.poke_batch_ms);
+    let mut allowed_devices =
+        config.authorize_joins.then(|| AllowedDevices::new(ALLOWED_DEVICES_TTL));
+    let mut heartbeat = (config.heartbeat_secs > 0)
+        .then(|| tokio::time::interval(Duration::from_secs(config.heartbeat_secs)));
+    let started = std::time::Instant::now();
     let mut client = urbit::AirlockClient::new(config);
+    client.set_dry_run(dry_run);
 
     // Connect with retry (up to 5 attempts)
     client.connect_with_retry(5).await?;
+    health.set_airlock_connected(target, true);
     info!("Airlock client connected, waiting for packets...");
+    let mut network_failures = 0u32;
+    // An action that ended the last batch, poked next
+    let mut pending = None;
 
-    while let Some(packet) = rx.recv().await {
-        let dev_addr = packet.dev_addr.clone();
+    loop {
+        // The first tick fires at once, announcing the bridge as soon as it connects
+        let action = match pending.take() {
+            Some(action) => action,
+            None => tokio::select! {
+                action = rx.recv() => match action {
+                    Some(action) => action,
+                    None => break,
+                },
+                _ = async {
+                    match heartbeat.as_mut() {
+                        Some(interval) => interval.tick().await,
+                        None => std::future::pending().await,
+                    }
+                } => LoRaAction::Heartbeat {
+                    bridge_version: env!("CARGO_PKG_VERSION").to_string(),
+                    uptime_secs: started.elapsed().as_secs(),
+                    gateways_seen: gateways_seen.count(),
+                },
+            },
+        };
+        // Uplinks hot on this one's heels share its PUT (`poke_batch_max`)
+        let (actions, next) = collect_uplinks(rx, action, batch_max, batch_window).await;
+        pending = next;
+        let what = match actions.as_slice() {
+            [LoRaAction::Uplink(packet)] => format!("uplink from {}", packet.dev_addr),
+            [LoRaAction::JoinRequest { dev_eui, .. }] => format!("join-request from {}", dev_eui),
+            [LoRaAction::RejoinRequest { dev_eui, .. }] => {
+                format!("rejoin-request from {}", dev_eui)
+            }
+            [LoRaAction::Heartbeat { .. }] => "heartbeat".to_string(),
+            [LoRaAction::GatewayStatus {
+                gateway_eui, up, ..
+            }] => format!("gateway-status ({} {})", gateway_eui, if *up { "up" } else { "down" }),
+            [_] => "action".to_string(),
+            batch => format!("{} uplinks", batch.len()),
+        };
+
+        // Join policy is the agent's: drop joins from devices it doesn't list
+        if let (
+            [LoRaAction::JoinRequest { dev_eui, .. } | LoRaAction::RejoinRequest { dev_eui, .. }],
+            Some(allowed),
+        ) = (actions.as_slice(), allowed_devices.as_mut())
+        {
+            if !authorize_join(&client, allowed, dev_eui).await {
+                info!("Dropped {}: not in %{}'s /allowed-devices", what, agent);
+                continue;
+            }
+        }
 
         // Poke: device-tracking uplink (also handles peer-to-peer via Hoon agent)
-        let action = LoRaAction::Uplink(packet);
-        let json_data = serde_json::to_value(&action)
-            .expect("failed to serialize LoRaAction");
+        let pokes = actions
+            .iter()
+            .map(|action| {
+                let (mark, json_data) = action.poke(typed_marks, encoding);
+                match mark_table.mark_for(action) {
+                    Some(routed) if encoding == PokeEncoding::Json => (routed, json_data),
+                    _ => (mark, json_data),
+                }
+            })
+            .collect();
 
-        match client.poke(&agent, "json", json_data).await {
+        match client.poke_batch(&agent, pokes).await {
+            Ok(()) if matches!(actions.as_slice(), [LoRaAction::Heartbeat { .. }]) => {
+                tracing::debug!("Poked %{} with {}", agent, what);
+            }
             Ok(()) => {
-                info!("Poked %{} with uplink from {}", agent, dev_addr);
+                info!("Poked %{} with {}", agent, what);
             }
             Err(e) => {
-                error!(
-                    "Failed to poke %{} with uplink from {}: {}",
-                    agent, dev_addr, e
-                );
-
-                // Try to reconnect for next packet
-                if !client.is_connected() {
-                    info!("Attempting reconnect for next packet...");
-                    if let Err(re) = client.connect_with_retry(3).await {
-                        error!("Reconnect failed: {}", re);
+                error!("Failed to poke %{} with {}: {}", agent, what, e);
+
+                match e.recovery() {
+                    // Log in again (on a new channel if it was gone) for the next packet
+                    Recovery::Reconnect => {
+                        health.set_airlock_connected(target, false);
+                        info!("Attempting reconnect for next packet...");
+                        if let Err(re) = client.connect_with_retry(3).await {
+                            error!("Reconnect failed: {}", re);
+                        }
+                        health.set_airlock_connected(target, client.is_connected());
+                    }
+                    Recovery::Backoff => {
+                        network_failures += 1;
+                        let delay = urbit::network_backoff(network_failures);
+                        warn!("Ship unreachable, pausing pokes for {:?}", delay);
+                        tokio::time::sleep(delay).await;
+                        continue;
                     }
+                    // The agent refused this poke; the next one may be fine
+                    Recovery::Drop => warn!("Dropped {} refused by %{}", what, agent),
                 }
             }
         }
+        network_failures = 0;
 
         // Note: peer-to-peer message routing is handled in the Hoon
         // agent's %uplink handler. When DevAddr matches a registered peer,
@@ -156,148 +877,717 @@ async fn run_airlock_task(
         // No separate message-received poke needed from the bridge.
     }
 
-    // Channel closed — shutdown
+    // Channel closed — tell the agent, then shut down
     info!("Packet channel closed, disconnecting Airlock client...");
+    let goodbye = LoRaAction::Disconnecting {
+        uptime_secs: started.elapsed().as_secs(),
+    };
+    let (mark, json_data) = goodbye.poke(typed_marks, encoding);
+    if let Err(e) = client.poke(&agent, mark, json_data).await {
+        warn!("Failed to poke %{} with disconnecting: {}", agent, e);
+    }
     client.disconnect().await;
     Ok(())
 }
 
-/// Background task that polls the Urbit agent's outbox and sends downlinks
+/// Background task that periodically checks the OUI's DC escrow balance
+///
+/// Phase 4: Query the Config Service every `helium.dc_check_interval_secs`,
+/// warn below `helium.low_dc_threshold` and log an error at zero, when
+/// Packet Router stops delivering traffic. Failed checks are logged and
+/// retried on the next tick.
+#[cfg(feature = "helium-grpc")]
+async fn run_dc_balance_task(mut client: helium::HeliumClient, shutdown: CancellationToken) {
+    use helium::DcBalanceLevel;
+
+    let threshold = client.config().low_dc_threshold;
+    let interval = Duration::from_secs(client.config().dc_check_interval_secs.max(1));
+    let oui = client.config().oui;
+    info!("DC balance monitor enabled (every {:?}, threshold {} DC)", interval, threshold);
+
+    loop {
+        if !client.is_connected() {
+            if let Err(e) = client.connect_config_service().await {
+                warn!("DC balance check skipped: {}", e);
+            }
+        }
+
+        if client.is_connected() {
+            match client.check_dc_balance().await {
+                Ok(balance) => match DcBalanceLevel::classify(balance, threshold) {
+                    DcBalanceLevel::Ok => info!("OUI {} DC balance: {}", oui, balance),
+                    DcBalanceLevel::Low => warn!(
+                        "OUI {} DC balance low: {} DC (threshold {})",
+                        oui, balance, threshold
+                    ),
+                    DcBalanceLevel::Empty => error!(
+                        "CRITICAL: OUI {} DC balance is zero — Helium traffic will not be delivered",
+                        oui
+                    ),
+                },
+                Err(e) => warn!("DC balance check failed: {}", e),
+            }
+        }
+
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = tokio::time::sleep(interval) => {}
+        }
+    }
+}
+
+/// Phase 4: Register with the Packet Router at `host` and forward the uplinks
+/// it streams to the Urbit targets in `router`, reopening the stream after
+/// `PACKET_ROUTER_RETRY` whenever it fails.
+#[cfg(feature = "helium-grpc")]
+async fn run_packet_router_task(
+    host: String,
+    delegate_keypair: String,
+    router: helium::router::RouterContext,
+    shutdown: CancellationToken,
+) {
+    use helium::keypair::DelegateKeypair;
+    use helium::router::PacketRouterClient;
+
+    loop {
+        let result = async {
+            let keypair = DelegateKeypair::from_file(std::path::Path::new(&delegate_keypair))?;
+            let client = PacketRouterClient::connect(&host, keypair).await?;
+            client.run(&router, &shutdown).await
+        }
+        .await;
+        if let Err(e) = result {
+            warn!("Packet Router stream failed: {}", e);
+        }
+
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = tokio::time::sleep(PACKET_ROUTER_RETRY) => {}
+        }
+    }
+}
+
+/// Downlink state shared by every agent's outbox
+#[cfg(feature = "phase2")]
+#[derive(Clone)]
+struct Downlinks {
+    sender: udp::DownlinkSender,
+    counters: lora_urbit::lorawan::keys::SharedDownlinkCounters,
+    keys: lora_urbit::lorawan::keys::SharedKeyStore,
+    channel_plan: lora_urbit::lorawan::channel_plan::ChannelPlan,
+    /// Set with `downlink.confirmed_retries`: confirmed downlinks await the device's ACK
+    confirmed: Option<udp::confirmed::ConfirmedDownlinks>,
+    /// `lorawan.encrypt_downlink`
+    encrypt: bool,
+    /// Uplink FCnt per DevAddr, restarted for a device's new session
+    fcnt_tracker: lora_urbit::lorawan::fcnt::SharedFrameCounterTracker,
+}
+
+/// Background task that polls the Urbit agents' outboxes and sends downlinks
+///
+/// Phase 3a: Scry every configured agent's outbox every `outbox_poll_ms`
+/// (2 seconds by default, plus any jitter), convert pending messages to
+/// LoRaWAN frames, send as PULL_RESP to the gateway, and poke
+/// tx-ack/tx-fail back to the agent that queued the message. Agents with
+/// `outbox_subscribe_path` also push messages the moment they are queued.
 ///
-/// Phase 3a: Scry the outbox every 2 seconds, convert pending messages to
-/// LoRaWAN frames, send as PULL_RESP to the gateway, and poke tx-ack/tx-fail.
+/// Each agent keeps its own Airlock connection; an agent that can't be
+/// reached is retried on the next poll without holding up the others.
+///
+/// Each DevAddr gets its own downlink FCnt sequence, shared across agents and
+/// with the UDP server's auto-ACKs, and saved to `counters_path` (if set)
+/// whenever it changed so counters survive restarts.
+///
+/// Timing and collision errors in the gateway's TX_ACK, and having no
+/// gateway to send to yet, leave the message in the outbox for another
+/// attempt after a backoff (up to `max_tx_attempts`); any other error pokes
+/// tx-fail.
+///
+/// With `downlink.confirmed_retries` set, a confirmed downlink's tx-ack
+/// waits until its device ACKs it; the outcome is poked on the next poll.
 #[cfg(feature = "phase2")]
 async fn run_outbound_task(
-    config: config::UrbitConfig,
-    downlink_sender: udp::DownlinkSender,
+    configs: Vec<config::UrbitConfig>,
+    downlinks: Downlinks,
+    counters_path: Option<PathBuf>,
+    shutdown: CancellationToken,
 ) -> anyhow::Result<()> {
-    use base64::Engine;
-    use urbit::types::{OutboundMessage, TxAck};
-    use lorawan::encoder::FrameBuilder;
-    use udp::build_txpk;
+    use urbit::outbox::{next_poll, poll_all, run_subscription, OutboxPush, OutboxSource};
 
-    let agent = config.agent.clone();
-    let mut client = urbit::AirlockClient::new(config);
+    let dry_run = downlinks.sender.is_dry_run();
+    let mut sources: Vec<OutboxSource> = configs
+        .into_iter()
+        .map(|config| {
+            let mut source = OutboxSource::new(config, REGISTRY_CACHE_TTL);
+            source.client.set_dry_run(dry_run);
+            source
+        })
+        .collect();
 
-    // Connect with retry
-    client.connect_with_retry(5).await?;
-    info!("Outbound task connected, polling outbox every 2s...");
+    // Connect with retry; agents that stay unreachable are retried when polled
+    for source in &mut sources {
+        if let Err(e) = source.client.connect_with_retry(5).await {
+            error!("Outbound task could not connect to {}: {}", source.target(), e);
+        }
+    }
+    info!("Outbound task polling {} outbox(es)...", sources.len());
+
+    // Agents with outbox_subscribe_path also push messages as they are queued
+    let (push_tx, mut pushes) = tokio::sync::mpsc::channel(64);
+    let mut subscriptions = Vec::new();
+    for (index, source) in sources.iter().enumerate() {
+        let config = source.client.config();
+        if let Some(path) = config.outbox_subscribe_path.clone() {
+            subscriptions.push(tokio::spawn(run_subscription(
+                index,
+                config.clone(),
+                path,
+                push_tx.clone(),
+                source.subscribed.clone(),
+                shutdown.clone(),
+            )));
+        }
+    }
+    drop(push_tx);
 
-    let mut fcnt: u16 = 0; // Frame counter for downlinks (simple incrementing)
+    let lock_counters = || downlinks.counters.lock().unwrap_or_else(|e| e.into_inner());
+    let mut counters_saved = lock_counters().clone();
 
     loop {
-        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+        let wake = next_poll(&sources).unwrap_or_else(std::time::Instant::now);
+        let push = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = tokio::time::sleep_until(wake.into()) => None,
+            Some(push) = pushes.recv() => Some(push),
+        };
+        match push {
+            Some(OutboxPush::Queued(index, msg)) if sources[index].queue.take_pushed(&msg) => {
+                send_outbound_message(&mut sources[index], index, &msg, &downlinks).await;
+            }
+            Some(OutboxPush::Lost(index)) => sources[index].next_poll = std::time::Instant::now(),
+            Some(OutboxPush::Queued(..)) | None => {}
+        }
 
-        // Scry the outbox
-        let outbox = match client.scry(&agent, "/outbox").await {
-            Ok(val) => val,
-            Err(e) => {
-                tracing::warn!("Failed to scry outbox: {}", e);
+        // Persist counters consumed since the last poll (downlinks and auto-ACKs)
+        if let Some(path) = &counters_path {
+            save_counters_if_changed(&lock_counters(), path, &mut counters_saved);
+        }
 
-                // If auth expired, try to reconnect
-                if !client.is_connected() {
-                    info!("Outbound task: attempting reconnect...");
-                    if let Err(re) = client.connect_with_retry(3).await {
-                        error!("Outbound task reconnect failed: {}", re);
-                    }
+        // Confirmed downlinks their devices ACKed, or that were given up on
+        if let Some(confirmed) = &downlinks.confirmed {
+            for resolved in confirmed.take_resolved(std::time::Instant::now()) {
+                poke_confirmed_outcome(&mut sources[resolved.target], &resolved).await;
+            }
+        }
+
+        // Messages already sent whose tx-ack hasn't reached the agent are skipped
+        for (index, msg) in poll_all(&mut sources, std::time::Instant::now()).await {
+            send_outbound_message(&mut sources[index], index, &msg, &downlinks).await;
+        }
+    }
+
+    if let Some(path) = &counters_path {
+        save_counters_if_changed(&lock_counters(), path, &mut counters_saved);
+    }
+    for subscription in subscriptions {
+        let _ = subscription.await;
+    }
+    for source in &mut sources {
+        source.client.disconnect().await;
+    }
+    Ok(())
+}
+
+/// Send one outbox message as a downlink and poke the result to its agent
+///
+/// `index` is the source's place in the outbound task's list, under which a
+/// confirmed downlink awaits its device's ACK.
+#[cfg(feature = "phase2")]
+async fn send_outbound_message(
+    source: &mut urbit::outbox::OutboxSource,
+    index: usize,
+    msg: &urbit::types::OutboundMessage,
+    downlinks: &Downlinks,
+) {
+    use udp::{build_device_frame, TxResult};
+    use urbit::registry::resolve_dest_addr;
+    use urbit::types::TxAck;
+
+    let Downlinks {
+        sender: downlink_sender,
+        counters,
+        keys,
+        channel_plan,
+        confirmed,
+        encrypt,
+        fcnt_tracker,
+    } = downlinks;
+    let lock_counters = || counters.lock().unwrap_or_else(|e| e.into_inner());
+    let agent = source.agent().to_string();
+
+    info!(
+        "Processing outbound msg #{}: dest={} ({}) payload={}",
+        msg.id, msg.dest_ship, msg.dest_addr, msg.payload
+    );
+
+    // The recipient's DevAddr picks its RX2 data rate and, for Class B, its
+    // ping slot. A JoinAccept answers its JoinRequest instead, timed from it.
+    // A confirmed data frame is ACKed by the device its DevAddr names.
+    let (frame_bytes, recipient, acked_by, join_nonce) = if let Some(accept) = &msg.join_accept {
+        // OTAA: answer the join and install the new session keys
+        match build_join_accept_frame(accept, keys, channel_plan) {
+            Ok((bytes, dev_addr)) => {
+                info!("JoinAccept for DevAddr {:08X} (msg #{})", dev_addr, msg.id);
+                // The new session counts both ways from 0
+                lock_counters().reset(dev_addr);
+                fcnt_tracker.lock().unwrap_or_else(|e| e.into_inner()).reset(dev_addr);
+                (bytes, None, None, Some(accept.dev_nonce))
+            }
+            Err(e) => {
+                error!("Failed to build JoinAccept for msg #{}: {}", msg.id, e);
+                let _ = source.poke_tx_ack(TxAck::failure(msg.id)).await;
+                return;
+            }
+        }
+    } else {
+        // Messages addressed only by ship: look the DevAddr up in the agent's registry
+        let dest_addr = if msg.dest_addr.is_empty() {
+            match resolve_dest_addr(&source.client, &mut source.addr_cache, &msg.dest_ship).await {
+                Ok(Some(addr)) => addr,
+                Ok(None) => {
+                    error!("No DevAddr registered for {} (msg #{})", msg.dest_ship, msg.id);
+                    let _ = source.poke_tx_ack(TxAck::failure(msg.id)).await;
+                    return;
+                }
+                Err(e) => {
+                    warn!("Failed to resolve {} for msg #{}: {}", msg.dest_ship, msg.id, e);
+                    source.queue.release(msg.id);
+                    return;
                 }
-                continue;
             }
+        } else {
+            msg.dest_addr.clone()
         };
 
-        // Parse the outbox JSON array
-        let messages: Vec<OutboundMessage> = match serde_json::from_value(outbox.clone()) {
-            Ok(msgs) => msgs,
-            Err(_) => {
-                // The scry might return nested JSON — try unwrapping common patterns
-                if let Some(arr) = outbox.as_array() {
-                    match serde_json::from_value(serde_json::Value::Array(arr.clone())) {
-                        Ok(msgs) => msgs,
-                        Err(e) => {
-                            tracing::debug!("No parseable outbox messages: {}", e);
-                            continue;
-                        }
-                    }
-                } else {
-                    tracing::debug!("Outbox is not an array: {}", outbox);
-                    continue;
-                }
+        // Use the SENDER's DevAddr in the LoRaWAN frame header.
+        // This way, the receiving bridge identifies the source of the message.
+        // Fall back to dest_addr if src_addr is not set.
+        let addr_hex = if !msg.src_addr.is_empty() { &msg.src_addr } else { &dest_addr };
+        let dev_addr = match u32::from_str_radix(addr_hex, 16) {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("Invalid addr '{}': {}", addr_hex, e);
+                let _ = source.poke_tx_ack(TxAck::failure(msg.id)).await;
+                return;
             }
         };
 
-        if messages.is_empty() {
-            continue;
-        }
+        // Decode the hex payload
+        let payload_bytes = match hex::decode(&msg.payload) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("Invalid hex payload '{}': {}", msg.payload, e);
+                let _ = source.poke_tx_ack(TxAck::failure(msg.id)).await;
+                return;
+            }
+        };
 
-        info!("Outbox has {} pending message(s)", messages.len());
+        // Build the LoRaWAN frame
+        let frame_bytes = match build_device_frame(
+            keys,
+            counters,
+            dev_addr,
+            1,
+            payload_bytes,
+            msg.confirmed,
+            *encrypt,
+        ) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("Failed to build frame for msg #{}: {}", msg.id, e);
+                let _ = source.poke_tx_ack(TxAck::failure(msg.id)).await;
+                return;
+            }
+        };
+        let acked_by = msg.confirmed.then_some(dev_addr);
+        (frame_bytes, u32::from_str_radix(&dest_addr, 16).ok(), acked_by, None)
+    };
 
-        for msg in &messages {
-            info!(
-                "Processing outbound msg #{}: dest={} ({}) payload={}",
-                msg.id, msg.dest_ship, msg.dest_addr, msg.payload
+    let sent = match join_nonce {
+        Some(dev_nonce) => {
+            downlink_sender
+                .send_join_accept(channel_plan, &frame_bytes, dev_nonce, TX_ACK_TIMEOUT)
+                .await
+        }
+        None => downlink_sender
+            .send_frame(channel_plan, keys, &frame_bytes, recipient, TX_ACK_TIMEOUT)
+            .await
+            .map(|(_, result)| result),
+    };
+    let failure = match sent {
+        Ok(TxResult::Success) => None,
+        Ok(TxResult::NoAck) => {
+            tracing::debug!("No TX_ACK for msg #{}, assuming sent", msg.id);
+            None
+        }
+        Ok(TxResult::Error(err))
+            if err.is_transient() && source.queue.attempts(msg.id) < source.max_tx_attempts =>
+        {
+            let attempts = source.queue.attempts(msg.id);
+            let delay = source.queue.retry_later(msg.id, std::time::Instant::now());
+            warn!(
+                "Msg #{} not sent ({}), retrying in {:?} (attempt {}/{})",
+                msg.id, err, delay, attempts, source.max_tx_attempts
             );
+            return;
+        }
+        Ok(TxResult::Error(err)) => Some(format!("TX failed: {}", err)),
+        Err(e) => Some(e.to_string()),
+    };
 
-            // Use the SENDER's DevAddr in the LoRaWAN frame header.
-            // This way, the receiving bridge identifies the source of the message.
-            // Fall back to dest_addr if src_addr is not set.
-            let addr_hex = if !msg.src_addr.is_empty() { &msg.src_addr } else { &msg.dest_addr };
-            let dev_addr = match u32::from_str_radix(addr_hex, 16) {
-                Ok(addr) => addr,
-                Err(e) => {
-                    error!("Invalid addr '{}': {}", addr_hex, e);
-                    let _ = client.poke(&agent, "json", TxAck::failure(msg.id)).await;
-                    continue;
+    match failure {
+        None => {
+            if let (Some(confirmed), Some(dev_addr)) = (confirmed, acked_by) {
+                let now = std::time::Instant::now();
+                confirmed.track(dev_addr, index, msg.id, frame_bytes, now);
+                info!("Downlink sent for msg #{}, awaiting ACK from {:08X}", msg.id, dev_addr);
+                return;
+            }
+            info!("Downlink sent for msg #{}", msg.id);
+            // Poke tx-ack
+            match source.poke_tx_ack(TxAck::success(msg.id)).await {
+                Ok(()) => {
+                    info!("Poked %{} with tx-ack for msg #{}", agent, msg.id);
                 }
-            };
-
-            // Decode the hex payload
-            let payload_bytes = match hex::decode(&msg.payload) {
-                Ok(bytes) => bytes,
                 Err(e) => {
-                    error!("Invalid hex payload '{}': {}", msg.payload, e);
-                    let _ = client.poke(&agent, "json", TxAck::failure(msg.id)).await;
-                    continue;
+                    error!("Failed to poke tx-ack for msg #{}: {}", msg.id, e);
                 }
-            };
+            }
+        }
+        Some(e) => {
+            error!("Failed to send downlink for msg #{}: {}", msg.id, e);
+            // Poke tx-fail
+            let fail = TxAck::failure_with_reason(msg.id, &e);
+            match source.poke_tx_ack(fail).await {
+                Ok(()) => {
+                    info!("Poked %{} with tx-fail for msg #{}", agent, msg.id);
+                }
+                Err(e2) => {
+                    error!("Failed to poke tx-fail for msg #{}: {}", msg.id, e2);
+                }
+            }
+        }
+    }
+}
 
-            // Build the LoRaWAN frame
-            let frame = FrameBuilder::new_downlink(dev_addr, fcnt, 1, payload_bytes);
-            let frame_bytes = frame.build();
-            fcnt = fcnt.wrapping_add(1);
+/// Poke tx-ack once a confirmed downlink's device ACKed it, or tx-fail
+#[cfg(feature = "phase2")]
+async fn poke_confirmed_outcome(
+    source: &mut urbit::outbox::OutboxSource,
+    resolved: &udp::confirmed::Resolved,
+) {
+    use urbit::types::TxAck;
 
-            // Base64 encode for txpk
-            let payload_b64 = base64::engine::general_purpose::STANDARD.encode(&frame_bytes);
-            let size = frame_bytes.len() as u16;
+    let msg_id = resolved.msg_id;
+    let (poke, what) = match &resolved.failure {
+        None => {
+            info!("Confirmed downlink for msg #{} ACKed by the device", msg_id);
+            (TxAck::success(msg_id), "tx-ack")
+        }
+        Some(reason) => {
+            warn!("Confirmed downlink for msg #{} {}", msg_id, reason);
+            (TxAck::failure_with_reason(msg_id, reason), "tx-fail")
+        }
+    };
+    match source.poke_tx_ack(poke).await {
+        Ok(()) => info!("Poked %{} with {} for msg #{}", source.agent(), what, msg_id),
+        Err(e) => error!("Failed to poke {} for msg #{}: {}", what, msg_id, e),
+    }
+}
 
-            // Build txpk and send PULL_RESP
-            let txpk = build_txpk(&payload_b64, size);
+/// Save `counters` to `path` unless they match the last saved copy
+fn save_counters_if_changed(
+    counters: &lora_urbit::lorawan::keys::DownlinkCounters,
+    path: &std::path::Path,
+    saved: &mut lora_urbit::lorawan::keys::DownlinkCounters,
+) {
+    if counters == saved {
+        return;
+    }
+    match counters.save(path) {
+        Ok(()) => *saved = counters.clone(),
+        Err(e) => error!("{}", e),
+    }
+}
 
-            match downlink_sender.send_downlink(&txpk).await {
-                Ok(()) => {
-                    info!("Downlink sent for msg #{}", msg.id);
-                    // Poke tx-ack
-                    match client.poke(&agent, "json", TxAck::success(msg.id)).await {
-                        Ok(()) => {
-                            info!("Poked %{} with tx-ack for msg #{}", agent, msg.id);
-                        }
-                        Err(e) => {
-                            error!("Failed to poke tx-ack for msg #{}: {}", msg.id, e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to send downlink for msg #{}: {}", msg.id, e);
-                    // Poke tx-fail
-                    match client.poke(&agent, "json", TxAck::failure(msg.id)).await {
-                        Ok(()) => {
-                            info!("Poked %{} with tx-fail for msg #{}", agent, msg.id);
-                        }
-                        Err(e2) => {
-                            error!("Failed to poke tx-fail for msg #{}: {}", msg.id, e2);
-                        }
-                    }
-                }
+/// Build the JoinAccept requested by an outbox message and install its keys
+///
+/// Returns the PHY bytes and the DevAddr assigned to the device. The derived
+/// session keys are added to `keys` so the device's uplinks verify, along
+/// with the RX2 data rate from DLSettings if it is not `plan`'s default.
+#[cfg(feature = "phase2")]
+fn build_join_accept_frame(
+    accept: &urbit::types::JoinAcceptRequest,
+    keys: &lora_urbit::lorawan::keys::SharedKeyStore,
+    plan: &lora_urbit::lorawan::channel_plan::ChannelPlan,
+) -> anyhow::Result<(Vec<u8>, u32)> {
+    #[cfg(feature = "phase4")]
+    {
+        use lora_urbit::lorawan::keys::{parse_key, SessionKeys};
+        use lora_urbit::lorawan::{crypto, encoder};
+
+        let app_key = parse_key(&accept.app_key)?;
+        let dev_addr = u32::from_str_radix(&accept.dev_addr, 16)
+            .map_err(|e| anyhow::anyhow!("Invalid DevAddr {:?}: {}", accept.dev_addr, e))?;
+        let cf_list = accept.cf_list.as_deref().map(parse_key).transpose()?;
+
+        let frame = encoder::build_join_accept(
+            &app_key,
+            accept.app_nonce,
+            accept.net_id,
+            dev_addr,
+            accept.dl_settings,
+            accept.rx_delay,
+            cf_list.as_ref(),
+        );
+        let (nwk_s_key, app_s_key) =
+            crypto::derive_session_keys(&app_key, accept.app_nonce, accept.net_id, accept.dev_nonce);
+        keys.write().unwrap_or_else(|e| e.into_inner()).insert(SessionKeys {
+            dev_addr,
+            nwk_s_key,
+            app_s_key,
+            lorawan_version: Default::default(),
+            s_nwk_s_int_key: None,
+            class: Default::default(),
+            ping_slot_periodicity: 7,
+            rx2_datr: plan
+                .downlink_datr(accept.dl_settings & 0x0F)
+                .filter(|datr| *datr != plan.rx2_datr),
+            rx1_delay: accept.rx_delay & 0x0F,
+        });
+        Ok((frame, dev_addr))
+    }
+    #[cfg(not(feature = "phase4"))]
+    {
+        let _ = (accept, keys, plan);
+        Err(anyhow::anyhow!("JoinAccept requires the phase4 feature"))
+    }
+}
+
+/// `lora-urbit decode`: print a captured frame's fields
+fn run_decode(args: DecodeArgs) -> anyhow::Result<()> {
+    use base64::Engine;
+    use lora_urbit::lorawan::inspect::FrameReport;
+    use lora_urbit::lorawan::keys::parse_key;
+
+    let phy = match (&args.hex, &args.base64) {
+        (Some(h), _) => hex::decode(h.trim())
+            .map_err(|e| anyhow::anyhow!("Invalid hex payload: {}", e))?,
+        (None, Some(b)) => base64::engine::general_purpose::STANDARD
+            .decode(b.trim())
+            .map_err(|e| anyhow::anyhow!("Invalid base64 payload: {}", e))?,
+        (None, None) => unreachable!("clap requires --hex or --base64"),
+    };
+    let nwk_key = args.nwk_key.as_deref().map(parse_key).transpose()?;
+    let app_key = args.app_key.as_deref().map(parse_key).transpose()?;
+
+    let report = FrameReport::new(&phy, nwk_key.as_ref(), app_key.as_ref())?;
+    match args.format {
+        DecodeFormat::Text => print!("{}", report),
+        DecodeFormat::Json => println!("{}", serde_json::to_string(&report.to_json())?),
+    }
+    Ok(())
+}
+
+/// `lora-urbit selftest`: run the crypto known-answer checks, failing on a mismatch
+#[cfg(feature = "phase4")]
+fn run_selftest() -> anyhow::Result<()> {
+    let results = lora_urbit::lorawan::selftest::run();
+    for result in &results {
+        match &result.failure {
+            None => println!("PASS  {}", result.name),
+            Some(failure) => println!("FAIL  {}: {}", result.name, failure),
+        }
+    }
+    let failed = results.iter().filter(|result| !result.passed()).count();
+    if failed > 0 {
+        anyhow::bail!("{} of {} self-test checks failed", failed, results.len());
+    }
+    println!("All {} checks passed", results.len());
+    Ok(())
+}
+
+#[cfg(not(feature = "phase4"))]
+fn run_selftest() -> anyhow::Result<()> {
+    anyhow::bail!("selftest checks the LoRaWAN crypto, which needs the phase4 feature")
+}
+
+/// `lora-urbit replay`: re-send logged uplinks at their original pace
+///
+/// Each entry is sent at its offset from the first one's `received-at`,
+/// divided by `--speed`. Entries without an 8-byte gateway EUI (Helium
+/// gRPC uplinks) are skipped.
+async fn run_replay(args: ReplayArgs) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(&args.file)
+        .map_err(|e| anyhow::anyhow!("Failed to read {:?}: {}", args.file, e))?;
+    let entries = udp::packet_log::read_entries(&text)?;
+    let Some(first) = entries.first() else {
+        println!("{:?} has no packets", args.file);
+        return Ok(());
+    };
+    let bind = if args.target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = tokio::net::UdpSocket::bind(bind).await?;
+
+    let start = tokio::time::Instant::now();
+    let mut sent = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        let frame = match entry.to_push_data(i as u16) {
+            Ok(frame) => frame,
+            Err(e) => {
+                let packet = &entry.packet;
+                eprintln!("Skipping DevAddr {} FCnt {}: {}", packet.dev_addr, packet.fcnt, e);
+                continue;
             }
+        };
+        let offset = (entry.packet.received_at - first.packet.received_at)
+            .to_std()
+            .unwrap_or_default();
+        tokio::time::sleep_until(start + offset.div_f64(args.speed)).await;
+        socket.send_to(&frame, args.target).await?;
+        sent += 1;
+        println!(
+            "+{:.3}s DevAddr {} FCnt {} via {}",
+            offset.as_secs_f64(),
+            entry.packet.dev_addr,
+            entry.packet.fcnt,
+            entry.packet.gateway_eui
+        );
+    }
+    println!("Replayed {} of {} packets to {}", sent, entries.len(), args.target);
+    Ok(())
+}
+
+/// `lora-urbit send-downlink`: one PULL_RESP, then wait for its TX_ACK
+async fn run_send_downlink(args: SendDownlinkArgs, config: &config::Config) -> anyhow::Result<()> {
+    use base64::Engine;
+    use lora_urbit::lorawan::channel_plan::ChannelPlan;
+    use udp::TxResult;
+
+    let frame = args.build_frame()?;
+    let plan = ChannelPlan::from_config(&config.channel_plan)?
+        .with_gateways(&config.gateways)
+        .with_downlink(&config.downlink);
+    let payload_b64 = base64::engine::general_purpose::STANDARD.encode(&frame);
+    let gateway_eui = args.gateway_eui.as_deref();
+    let reference = args.reference();
+    let size = frame.len() as u16;
+    let txpk = udp::build_txpk(&plan, gateway_eui, reference.as_ref(), &payload_b64, size)?;
+
+    let bind = args.bind.as_deref().unwrap_or(&config.udp.bind[0]);
+    let sender = udp::DownlinkSender::to_gateway(bind, args.gateway, plan.region).await?;
+    println!(
+        "Sending {} byte frame to DevAddr {:08X} via {} ({} MHz {})",
+        frame.len(),
+        args.dev_addr,
+        args.gateway,
+        txpk.freq,
+        txpk.datr
+    );
+
+    match sender
+        .send_downlink_acked(&txpk, Duration::from_secs(args.timeout))
+        .await?
+    {
+        TxResult::Success => println!("TX_ACK: OK"),
+        TxResult::NoAck => println!(
+            "No TX_ACK within {}s (GWMP v1 gateways never send one)",
+            args.timeout
+        ),
+        TxResult::Error(err) => return Err(anyhow::anyhow!("TX_ACK: gateway reported {}", err)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_args(args: &[&str]) -> Result<SendDownlinkArgs, clap::Error> {
+        let argv = ["lora-urbit", "send-downlink", "--gateway", "127.0.0.1:1700"];
+        match Cli::try_parse_from(argv.iter().chain(args))?.command {
+            Some(Command::SendDownlink(args)) => Ok(args),
+            _ => panic!("expected send-downlink"),
+        }
+    }
+
+    #[test]
+    fn test_send_downlink_args() {
+        let args =
+            send_args(&["--dev-addr", "260B1234", "--port", "2", "--hex", "48656c6c6f"]).unwrap();
+        assert_eq!(args.dev_addr, 0x260B1234);
+        assert_eq!(args.port, 2);
+        assert!(!args.confirmed);
+
+        let frame = args.build_frame().unwrap();
+        assert_eq!(frame[0], 0x60);
+        assert_eq!(&frame[1..5], &0x260B1234u32.to_le_bytes());
+        assert_eq!(frame[8], 2);
+        assert_eq!(&frame[9..14], b"Hello");
+
+        let args = send_args(&["--dev-addr", "260B1234", "--confirmed"]).unwrap();
+        assert_eq!(args.build_frame().unwrap()[0], 0xA0);
+
+        // Invalid DevAddr, payload and FPort are rejected at parse time
+        assert!(send_args(&["--dev-addr", "260B12"]).is_err());
+        assert!(send_args(&["--dev-addr", "XYZ01234"]).is_err());
+        assert!(send_args(&["--dev-addr", "260B1234", "--hex", "abc"]).is_err());
+        assert!(send_args(&["--dev-addr", "260B1234", "--port", "0"]).is_err());
+        assert!(send_args(&["--hex", "00"]).is_err());
+    }
+
+    fn replay_args(args: &[&str]) -> Result<ReplayArgs, clap::Error> {
+        let argv = ["lora-urbit", "replay", "--file", "packets.jsonl"];
+        match Cli::try_parse_from(argv.iter().chain(args))?.command {
+            Some(Command::Replay(args)) => Ok(args),
+            _ => panic!("expected replay"),
         }
     }
+
+    #[test]
+    fn test_replay_args() {
+        let args = replay_args(&["--target", "127.0.0.1:1700", "--speed", "2.5"]).unwrap();
+        assert_eq!(args.target, "127.0.0.1:1700".parse().unwrap());
+        assert_eq!(args.speed, 2.5);
+        assert_eq!(replay_args(&[]).unwrap().speed, 1.0);
+        assert!(replay_args(&["--speed", "0"]).is_err());
+        assert!(replay_args(&["--speed", "fast"]).is_err());
+    }
+
+    #[test]
+    fn test_capture_after_subcommand() {
+        let cli = Cli::try_parse_from(["lora-urbit", "run", "--capture", "gwmp.jsonl"]).unwrap();
+        assert_eq!(cli.capture().unwrap(), Some(&PathBuf::from("gwmp.jsonl")));
+        assert!(matches!(cli.command, Some(Command::Run)));
+        let cli = Cli::try_parse_from(["lora-urbit", "--capture", "gwmp.jsonl"]).unwrap();
+        assert_eq!(cli.capture().unwrap(), Some(&PathBuf::from("gwmp.jsonl")));
+
+        // Only the bridge records datagrams; other subcommands refuse the flag
+        let cli = Cli::try_parse_from(["lora-urbit", "selftest", "--capture", "x"]).unwrap();
+        assert!(cli.capture().is_err());
+        let argv = ["lora-urbit", "send-downlink", "--gateway", "127.0.0.1:1700"];
+        let args = ["--dev-addr", "260B1234", "--capture", "gwmp.jsonl"];
+        let cli = Cli::try_parse_from(argv.iter().chain(&args)).unwrap();
+        assert!(cli.capture().is_err());
+        let cli = Cli::try_parse_from(["lora-urbit", "selftest"]).unwrap();
+        assert_eq!(cli.capture().unwrap(), None);
+    }
+
+    #[test]
+    fn test_dry_run_after_subcommand() {
+        let cli = Cli::try_parse_from(["lora-urbit", "run", "--dry-run"]).unwrap();
+        assert!(cli.dry_run);
+        assert!(matches!(cli.command, Some(Command::Run)));
+        assert!(Cli::try_parse_from(["lora-urbit", "--dry-run"]).unwrap().dry_run);
+        assert!(!Cli::try_parse_from(["lora-urbit", "run"]).unwrap().dry_run);
+    }
 }
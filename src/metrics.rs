@@ -0,0 +1,347 @@
+//! Link-quality metrics in OpenMetrics text format (`GET /metrics`)
+//!
+//! Histograms of the RSSI and SNR of every accepted uplink, to spot
+//! coverage gaps across the fleet. Buckets are cumulative, as OpenMetrics
+//! requires: `le="-100.0"` counts every uplink at or below -100 dBm.
+//!
+//! Alongside them, a counter per `DropReason` of the datagrams and frames
+//! that never reached Urbit, and one per gateway of the uplinks it heard,
+//! labelled with the gateway's configured name or its EUI.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+/// Upper bounds of the RSSI buckets (dBm)
+pub const RSSI_BUCKETS: [f64; 10] =
+    [-120.0, -110.0, -100.0, -90.0, -80.0, -70.0, -60.0, -50.0, -40.0, -30.0];
+
+/// Upper bounds of the SNR buckets (dB)
+pub const SNR_BUCKETS: [f64; 11] =
+    [-20.0, -15.0, -10.0, -7.5, -5.0, -2.5, 0.0, 2.5, 5.0, 7.5, 10.0];
+
+/// A fixed-bucket histogram
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    /// Observations per bucket (not cumulative), plus one for +Inf
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Cumulative count of each bucket, ending with +Inf
+    pub fn cumulative(&self) -> Vec<u64> {
+        self.counts
+            .iter()
+            .scan(0, |total, count| {
+                *total += count;
+                Some(*total)
+            })
+            .collect()
+    }
+
+    /// OpenMetrics lines for this histogram under `name`
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let cumulative = self.cumulative();
+        for (bound, count) in self.bounds.iter().zip(&cumulative) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{:?}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count());
+        let _ = writeln!(out, "{}_sum {:?}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count());
+    }
+}
+
+/// Why a datagram or frame was not forwarded to Urbit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DropReason {
+    /// Not a valid GWMP datagram, or unparseable PUSH_DATA JSON
+    ParseError,
+    /// JSON payload over `udp.max_json_len`
+    Oversized,
+    /// Gateway not allowed (`udp.allowed_gateways`/`denied_gateways`)
+    GatewayDenied,
+    /// Another gateway's copy of the same uplink (merged into the best one)
+    DuplicateReception,
+    /// rxpk data that is not valid base64
+    BadPayload,
+    /// PHYPayload that does not decode as a LoRaWAN frame
+    Undecodable,
+    /// Frame type the bridge does not handle (e.g. proprietary)
+    Unsupported,
+    /// DevAddr outside `lorawan.accept_net_ids`
+    ForeignNetId,
+    /// Frame counter not above the last accepted one
+    Replay,
+    /// No `[[urbit]]` target's routing rules match
+    NoRoute,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 10] = [
+        DropReason::ParseError,
+        DropReason::Oversized,
+        DropReason::GatewayDenied,
+        DropReason::DuplicateReception,
+        DropReason::BadPayload,
+        DropReason::Undecodable,
+        DropReason::Unsupported,
+        DropReason::ForeignNetId,
+        DropReason::Replay,
+        DropReason::NoRoute,
+    ];
+
+    /// Label value in `/metrics`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DropReason::ParseError => "parse_error",
+            DropReason::Oversized => "oversized",
+            DropReason::GatewayDenied => "gateway_denied",
+            DropReason::DuplicateReception => "duplicate_reception",
+            DropReason::BadPayload => "bad_payload",
+            DropReason::Undecodable => "undecodable",
+            DropReason::Unsupported => "unsupported",
+            DropReason::ForeignNetId => "foreign_net_id",
+            DropReason::Replay => "replay",
+            DropReason::NoRoute => "no_route",
+        }
+    }
+}
+
+impl std::fmt::Display for DropReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug)]
+struct UplinkHistograms {
+    rssi: Histogram,
+    snr: Histogram,
+    drops: BTreeMap<DropReason, u64>,
+    gateway_uplinks: BTreeMap<String, u64>,
+    missed_frames: BTreeMap<String, u64>,
+}
+
+/// Uplink histograms and drop counters, shared between the UDP server and `/metrics`
+#[derive(Debug, Clone)]
+pub struct UplinkMetrics {
+    inner: Arc<Mutex<UplinkHistograms>>,
+}
+
+impl Default for UplinkMetrics {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(UplinkHistograms {
+                rssi: Histogram::new(&RSSI_BUCKETS),
+                snr: Histogram::new(&SNR_BUCKETS),
+                drops: BTreeMap::new(),
+                gateway_uplinks: BTreeMap::new(),
+                missed_frames: BTreeMap::new(),
+            })),
+        }
+    }
+}
+
+impl UplinkMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record an uplink's RSSI and, if the gateway reported one, its SNR
+    pub fn observe(&self, rssi: f64, snr: Option<f64>) {
+        let mut histograms = self.lock();
+        histograms.rssi.observe(rssi);
+        if let Some(snr) = snr {
+            histograms.snr.observe(snr);
+        }
+    }
+
+    /// Count a datagram or frame dropped for `reason`
+    pub fn record_drop(&self, reason: DropReason) {
+        *self.lock().drops.entry(reason).or_default() += 1;
+    }
+
+    pub fn drops(&self, reason: DropReason) -> u64 {
+        self.lock().drops.get(&reason).copied().unwrap_or(0)
+    }
+
+    /// Count an accepted uplink heard by `gateway` (its name or EUI hex)
+    pub fn record_gateway_uplink(&self, gateway: &str) {
+        let mut histograms = self.lock();
+        match histograms.gateway_uplinks.get_mut(gateway) {
+            Some(count) => *count += 1,
+            None => {
+                histograms.gateway_uplinks.insert(gateway.to_string(), 1);
+            }
+        }
+    }
+
+    pub fn gateway_uplinks(&self, gateway: &str) -> u64 {
+        self.lock().gateway_uplinks.get(gateway).copied().unwrap_or(0)
+    }
+
+    /// Count `count` frames from `dev_addr` (8 hex digits) lost to an FCnt gap
+    pub fn record_missed_frames(&self, dev_addr: &str, count: u64) {
+        let mut histograms = self.lock();
+        match histograms.missed_frames.get_mut(dev_addr) {
+            Some(total) => *total += count,
+            None => {
+                histograms.missed_frames.insert(dev_addr.to_string(), count);
+            }
+        }
+    }
+
+    pub fn missed_frames(&self, dev_addr: &str) -> u64 {
+        self.lock().missed_frames.get(dev_addr).copied().unwrap_or(0)
+    }
+
+    pub fn rssi(&self) -> Histogram {
+        self.lock().rssi.clone()
+    }
+
+    pub fn snr(&self) -> Histogram {
+        self.lock().snr.clone()
+    }
+
+    /// The OpenMetrics exposition, ending with `# EOF`
+    pub fn render(&self) -> String {
+        let histograms = self.lock();
+        let mut out = String::new();
+        histograms.rssi.render(&mut out, "lora_uplink_rssi_dbm", "RSSI of accepted uplinks");
+        histograms.snr.render(&mut out, "lora_uplink_snr_db", "SNR of accepted uplinks");
+        out.push_str("# TYPE lora_dropped_packets counter\n");
+        out.push_str("# HELP lora_dropped_packets Uplinks not forwarded to Urbit, by reason\n");
+        for reason in DropReason::ALL {
+            let count = histograms.drops.get(&reason).copied().unwrap_or(0);
+            let _ = writeln!(out, "lora_dropped_packets_total{{reason=\"{}\"}} {}", reason, count);
+        }
+        out.push_str("# TYPE lora_gateway_uplinks counter\n");
+        out.push_str("# HELP lora_gateway_uplinks Accepted uplinks, by receiving gateway\n");
+        for (gateway, count) in &histograms.gateway_uplinks {
+            let label = escape_label(gateway);
+            let _ = writeln!(out, "lora_gateway_uplinks_total{{gateway=\"{}\"}} {}", label, count);
+        }
+        out.push_str("# TYPE lora_device_missed_frames counter\n");
+        out.push_str("# HELP lora_device_missed_frames Uplinks lost, from gaps in each FCnt\n");
+        for (dev_addr, count) in &histograms.missed_frames {
+            let metric = "lora_device_missed_frames_total";
+            let _ = writeln!(out, "{}{{dev_addr=\"{}\"}} {}", metric, dev_addr, count);
+        }
+        out.push_str("# EOF\n");
+        out
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, UplinkHistograms> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Escape a label value: backslash, double quote and newline
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_counts() {
+        let metrics = UplinkMetrics::new();
+        metrics.observe(-125.0, Some(-22.0));
+        metrics.observe(-110.0, Some(-7.5));
+        metrics.observe(-95.5, None);
+        metrics.observe(-42.0, Some(9.0));
+        metrics.observe(-20.0, Some(12.0));
+
+        // Boundaries are inclusive; values past the last bound land in +Inf
+        let rssi = metrics.rssi();
+        assert_eq!(rssi.cumulative(), vec![1, 2, 2, 3, 3, 3, 3, 3, 4, 4, 5]);
+        assert_eq!(rssi.count(), 5);
+        let snr = metrics.snr();
+        assert_eq!(snr.cumulative(), vec![1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 3, 4]);
+        assert_eq!(snr.count(), 4);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE lora_uplink_rssi_dbm histogram\n"));
+        assert!(text.contains("lora_uplink_rssi_dbm_bucket{le=\"-110.0\"} 2\n"));
+        assert!(text.contains("lora_uplink_rssi_dbm_bucket{le=\"+Inf\"} 5\n"));
+        assert!(text.contains("lora_uplink_rssi_dbm_sum -392.5\n"));
+        assert!(text.contains("lora_uplink_snr_db_bucket{le=\"-7.5\"} 2\n"));
+        assert!(text.contains("lora_uplink_snr_db_count 4\n"));
+        assert!(text.contains("lora_dropped_packets_total{reason=\"replay\"} 0\n"));
+        assert!(text.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn test_drop_counters() {
+        let metrics = UplinkMetrics::new();
+        metrics.record_drop(DropReason::Replay);
+        metrics.record_drop(DropReason::Replay);
+        metrics.record_drop(DropReason::NoRoute);
+        assert_eq!(metrics.drops(DropReason::Replay), 2);
+        assert_eq!(metrics.drops(DropReason::ParseError), 0);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE lora_dropped_packets counter\n"));
+        assert!(text.contains("lora_dropped_packets_total{reason=\"replay\"} 2\n"));
+        assert!(text.contains("lora_dropped_packets_total{reason=\"no_route\"} 1\n"));
+        assert!(text.contains("lora_dropped_packets_total{reason=\"parse_error\"} 0\n"));
+    }
+
+    #[test]
+    fn test_gateway_uplinks() {
+        let metrics = UplinkMetrics::new();
+        metrics.record_gateway_uplink("roof-north");
+        metrics.record_gateway_uplink("roof-north");
+        metrics.record_gateway_uplink("0016c001ff10a235");
+        metrics.record_gateway_uplink("shed \"B\"");
+        assert_eq!(metrics.gateway_uplinks("roof-north"), 2);
+        assert_eq!(metrics.gateway_uplinks("basement"), 0);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE lora_gateway_uplinks counter\n"));
+        assert!(text.contains("lora_gateway_uplinks_total{gateway=\"roof-north\"} 2\n"));
+        assert!(text.contains("lora_gateway_uplinks_total{gateway=\"0016c001ff10a235\"} 1\n"));
+        assert!(text.contains("lora_gateway_uplinks_total{gateway=\"shed \\\"B\\\"\"} 1\n"));
+    }
+
+    #[test]
+    fn test_missed_frames() {
+        let metrics = UplinkMetrics::new();
+        metrics.record_missed_frames("260B1234", 4);
+        metrics.record_missed_frames("260B1234", 1);
+        assert_eq!(metrics.missed_frames("260B1234"), 5);
+        assert_eq!(metrics.missed_frames("260B5678"), 0);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE lora_device_missed_frames counter\n"));
+        assert!(text.contains("lora_device_missed_frames_total{dev_addr=\"260B1234\"} 5\n"));
+    }
+}
@@ -0,0 +1,127 @@
+//! Restart background tasks that fail
+//!
+//! `supervise` runs a task made by a factory and, when it returns an error,
+//! makes and runs a fresh one after a backoff. The backoff doubles with each
+//! failure in a row and resets once a run has stayed up for a while. A task
+//! that keeps failing right after starting is given up on, so a broken
+//! config doesn't spin forever. A task that returns Ok is done.
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use tracing::{error, warn};
+
+/// Restart timing for `Supervisor::run`
+#[derive(Debug, Clone)]
+pub struct Supervisor {
+    /// Wait before the first restart
+    pub initial_backoff: Duration,
+    /// Longest wait between restarts
+    pub max_backoff: Duration,
+    /// A run that lasted this long resets the backoff and the failure count
+    pub healthy_after: Duration,
+    /// Failures in a row, each before `healthy_after`, before giving up
+    pub max_rapid_failures: u32,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            healthy_after: Duration::from_secs(60),
+            max_rapid_failures: 5,
+        }
+    }
+}
+
+impl Supervisor {
+    /// Run `factory`'s task until it returns Ok, restarting it on errors
+    ///
+    /// Returns the last error once the task failed `max_rapid_failures`
+    /// times in a row without staying up for `healthy_after`.
+    pub async fn run<F, Fut>(&self, name: &str, mut factory: F) -> anyhow::Result<()>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let mut failures = 0u32;
+        let mut backoff = self.initial_backoff;
+        loop {
+            let started = Instant::now();
+            let e = match factory().await {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            if started.elapsed() >= self.healthy_after {
+                failures = 0;
+                backoff = self.initial_backoff;
+            }
+            failures += 1;
+            if failures >= self.max_rapid_failures {
+                error!("{} task failed {} times in a row, giving up: {}", name, failures, e);
+                return Err(e);
+            }
+            warn!("{} task failed: {}; restarting in {:?}", name, e, backoff);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+}
+
+/// Run `factory`'s task under the default `Supervisor`
+pub async fn supervise<F, Fut>(name: &str, factory: F) -> anyhow::Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    Supervisor::default().run(name, factory).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quick() -> Supervisor {
+        Supervisor {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            healthy_after: Duration::from_secs(60),
+            max_rapid_failures: 3,
+        }
+    }
+
+    #[test]
+    fn test_supervise() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            // Fails once, then succeeds: restarted once
+            let mut runs = 0;
+            let result = quick()
+                .run("test", || {
+                    runs += 1;
+                    let run = runs;
+                    async move {
+                        match run {
+                            1 => Err(anyhow::anyhow!("ship unreachable")),
+                            _ => Ok(()),
+                        }
+                    }
+                })
+                .await;
+            assert!(result.is_ok());
+            assert_eq!(runs, 2);
+
+            // Never comes up: given up on after max_rapid_failures
+            let mut runs = 0;
+            let result = quick()
+                .run("test", || {
+                    runs += 1;
+                    async { Err(anyhow::anyhow!("bad config")) }
+                })
+                .await;
+            assert_eq!(result.unwrap_err().to_string(), "bad config");
+            assert_eq!(runs, 3);
+        });
+    }
+}
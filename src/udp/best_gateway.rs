@@ -0,0 +1,88 @@
+//! Which gateway heard each device best, for routing its downlinks
+//!
+//! With several gateways in range, copies of an uplink reach the bridge from
+//! each of them, and most are dropped as grouped copies or replays. Every
+//! copy still counts here: the gateway with the strongest RSSI on a
+//! device's latest uplink is its hint, and immediate downlinks to that
+//! device go to the hinted gateway (at its TX power) instead of whichever
+//! sent the latest PULL_DATA. Timed downlinks are scheduled on one gateway's
+//! counter: Class A windows go to the gateway whose uplink timed them, and
+//! Class B ping slots stay on the tracked gateway.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone)]
+struct Hint {
+    /// FCnt of the uplink the hint comes from
+    fcnt: u32,
+    gateway_eui: String,
+    rssi: f64,
+}
+
+/// Best-heard gateway per DevAddr, shared by the UDP server and its senders
+#[derive(Debug, Clone, Default)]
+pub struct BestGateways {
+    inner: Arc<Mutex<HashMap<u32, Hint>>>,
+}
+
+impl BestGateways {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An accepted uplink: its gateway is the hint until a stronger copy arrives
+    pub fn record_uplink(&self, dev_addr: u32, fcnt: u32, gateway_eui: &str, rssi: f64) {
+        let hint = Hint {
+            fcnt,
+            gateway_eui: gateway_eui.to_string(),
+            rssi,
+        };
+        self.lock().insert(dev_addr, hint);
+    }
+
+    /// Another gateway's copy of an uplink; it takes over if stronger
+    ///
+    /// Copies of any uplink but the latest accepted one are ignored.
+    pub fn record_copy(&self, dev_addr: u32, fcnt: u32, gateway_eui: &str, rssi: f64) {
+        let mut hints = self.lock();
+        if let Some(hint) = hints.get_mut(&dev_addr) {
+            if hint.fcnt == fcnt && rssi > hint.rssi {
+                hint.gateway_eui = gateway_eui.to_string();
+                hint.rssi = rssi;
+            }
+        }
+    }
+
+    /// EUI (hex) of the gateway that heard `dev_addr` best
+    pub fn get(&self, dev_addr: u32) -> Option<String> {
+        self.lock().get(&dev_addr).map(|hint| hint.gateway_eui.clone())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u32, Hint>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strongest_copy_wins() {
+        let hints = BestGateways::new();
+        hints.record_copy(0x260B_1234, 5, "aaaaaaaaaaaaaaaa", -50.0);
+        assert_eq!(hints.get(0x260B_1234), None);
+
+        hints.record_uplink(0x260B_1234, 5, "aaaaaaaaaaaaaaaa", -100.0);
+        hints.record_copy(0x260B_1234, 5, "bbbbbbbbbbbbbbbb", -60.0);
+        hints.record_copy(0x260B_1234, 5, "cccccccccccccccc", -80.0);
+        assert_eq!(hints.get(0x260B_1234).as_deref(), Some("bbbbbbbbbbbbbbbb"));
+
+        // Late copies of older uplinks don't count; a new uplink starts over
+        hints.record_uplink(0x260B_1234, 6, "aaaaaaaaaaaaaaaa", -100.0);
+        hints.record_copy(0x260B_1234, 5, "bbbbbbbbbbbbbbbb", -40.0);
+        assert_eq!(hints.get(0x260B_1234).as_deref(), Some("aaaaaaaaaaaaaaaa"));
+        assert_eq!(hints.get(0x260B_5678), None);
+    }
+}
@@ -0,0 +1,176 @@
+//! Raw GWMP datagram capture (`[capture]` / `--capture`)
+//!
+//! Every datagram received on or sent from the server socket is appended to
+//! a length-prefixed binary log, so a flaky gateway's traffic can be replayed
+//! byte for byte. The file starts with the magic `GWMPCAP1`, followed by one
+//! record per datagram:
+//!
+//!   timestamp (u64 BE, µs since the Unix epoch) | direction (u8: 0 received, 1 sent)
+//!   | peer address length (u8) | peer address (UTF-8, e.g. "192.0.2.1:1700")
+//!   | datagram length (u32 BE) | datagram
+//!
+//! As with the packet log, the UDP loop only queues records and a writer
+//! task does the I/O. With capture off nothing is copied or queued.
+
+use std::net::SocketAddr;
+use std::path::Path;
+
+use bytes::{Buf, BufMut};
+use chrono::{DateTime, Utc};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::config::CaptureConfig;
+
+/// First bytes of a capture file
+pub const CAPTURE_MAGIC: &[u8; 8] = b"GWMPCAP1";
+
+/// Records buffered between the UDP loop and the writer task
+const QUEUE_DEPTH: usize = 4096;
+
+/// Which way a datagram crossed the socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Received = 0,
+    Sent = 1,
+}
+
+/// One captured datagram
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedDatagram {
+    pub timestamp: DateTime<Utc>,
+    pub direction: Direction,
+    /// Gateway (or other peer) address
+    pub peer: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+impl CapturedDatagram {
+    fn encode(&self) -> Vec<u8> {
+        let peer = self.peer.to_string();
+        let mut buf = Vec::with_capacity(14 + peer.len() + self.data.len());
+        buf.put_u64(self.timestamp.timestamp_micros() as u64);
+        buf.put_u8(self.direction as u8);
+        buf.put_u8(peer.len() as u8);
+        buf.put_slice(peer.as_bytes());
+        buf.put_u32(self.data.len() as u32);
+        buf.put_slice(&self.data);
+        buf
+    }
+
+    /// Decode the record at the front of `buf`, advancing past it
+    fn decode(buf: &mut &[u8]) -> anyhow::Result<Self> {
+        let truncated = || anyhow::anyhow!("Truncated capture record");
+        if buf.remaining() < 10 {
+            return Err(truncated());
+        }
+        let micros = buf.get_u64() as i64;
+        let direction = match buf.get_u8() {
+            0 => Direction::Received,
+            1 => Direction::Sent,
+            other => return Err(anyhow::anyhow!("Invalid capture direction {}", other)),
+        };
+        let peer_len = buf.get_u8() as usize;
+        if buf.remaining() < peer_len + 4 {
+            return Err(truncated());
+        }
+        let peer = std::str::from_utf8(&buf[..peer_len])?.parse()?;
+        buf.advance(peer_len);
+        let len = buf.get_u32() as usize;
+        if buf.remaining() < len {
+            return Err(truncated());
+        }
+        let data = buf[..len].to_vec();
+        buf.advance(len);
+
+        Ok(Self {
+            timestamp: DateTime::from_timestamp_micros(micros)
+                .ok_or_else(|| anyhow::anyhow!("Invalid capture timestamp {}", micros))?,
+            direction,
+            peer,
+            data,
+        })
+    }
+}
+
+/// Handle for queueing datagrams to the capture writer task
+#[derive(Debug, Clone)]
+pub struct Capture {
+    tx: mpsc::Sender<CapturedDatagram>,
+}
+
+impl Capture {
+    /// Open (append to) `path` and spawn the writer task
+    ///
+    /// The task exits once every `Capture` handle has been dropped.
+    pub async fn open(path: &Path) -> anyhow::Result<(Self, JoinHandle<()>)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to open capture file {:?}: {}", path, e))?;
+        let is_new = file.metadata().await?.len() == 0;
+        let mut file = BufWriter::new(file);
+        if is_new {
+            file.write_all(CAPTURE_MAGIC).await?;
+        }
+
+        let (tx, mut rx) = mpsc::channel::<CapturedDatagram>(QUEUE_DEPTH);
+        let path = path.to_path_buf();
+        let task = tokio::spawn(async move {
+            while let Some(datagram) = rx.recv().await {
+                let mut result = file.write_all(&datagram.encode()).await;
+                if result.is_ok() && rx.is_empty() {
+                    result = file.flush().await;
+                }
+                if let Err(e) = result {
+                    warn!("Failed to write capture file {:?}: {}", path, e);
+                }
+            }
+            let _ = file.flush().await;
+        });
+        Ok((Self { tx }, task))
+    }
+
+    /// Open `capture.path`, if capture is configured
+    pub async fn from_config(
+        config: Option<&CaptureConfig>,
+    ) -> anyhow::Result<Option<(Self, JoinHandle<()>)>> {
+        match config {
+            Some(config) => Ok(Some(Self::open(Path::new(&config.path)).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Queue a datagram without waiting
+    pub fn record(&self, direction: Direction, peer: SocketAddr, data: &[u8]) {
+        let datagram = CapturedDatagram {
+            timestamp: Utc::now(),
+            direction,
+            peer,
+            data: data.to_vec(),
+        };
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(datagram) {
+            warn!("Capture queue full; dropping datagram");
+        }
+    }
+}
+
+/// Read every datagram from a capture file
+pub fn read_capture(path: &Path) -> anyhow::Result<Vec<CapturedDatagram>> {
+    let content = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read capture file {:?}: {}", path, e))?;
+    let mut buf = content
+        .strip_prefix(CAPTURE_MAGIC.as_slice())
+        .ok_or_else(|| anyhow::anyhow!("{:?} is not a GWMP capture file", path))?;
+
+    let mut datagrams = Vec::new();
+    while !buf.is_empty() {
+        datagrams.push(CapturedDatagram::decode(&mut buf)?);
+    }
+    Ok(datagrams)
+}
@@ -0,0 +1,192 @@
+//! Confirmed downlinks awaiting the device's ACK (`downlink.confirmed_retries`)
+//!
+//! A device acknowledges a ConfirmedDataDown by setting FCtrl.ACK on its
+//! next uplink. After the outbound task sends one it records the frame here
+//! under the device's DevAddr, instead of poking tx-ack at once. The UDP
+//! server then checks each uplink from that device: an ACK resolves the
+//! downlink as delivered, anything else gets the same frame sent again in
+//! the uplink's receive windows, up to `confirmed_retries` times before the
+//! downlink is given up on. The outbound task collects the outcomes and
+//! pokes tx-ack or tx-fail to the agent that queued each message.
+//!
+//! A confirmed uplink the UDP server auto-ACKs has its windows taken by the
+//! ACK, so it is skipped and leaves the retransmission count untouched.
+//! Only uplinks whose MIC verified are checked; since the MIC needs the
+//! device's session keys, so does delivery confirmation.
+//!
+//! A device has at most one confirmed downlink outstanding: sending another
+//! gives up on the earlier one. A device that goes quiet for
+//! `CONFIRMED_ACK_TIMEOUT` has its downlink given up on too.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a confirmed downlink waits for an uplink from its device
+pub const CONFIRMED_ACK_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// A confirmed downlink the device hasn't acknowledged yet
+#[derive(Debug, Clone)]
+struct Pending {
+    /// Which outbox the message came from (the outbound task's source index)
+    target: usize,
+    msg_id: u64,
+    /// PHYPayload, resent unchanged (same FCnt) on retransmission
+    frame: Vec<u8>,
+    retransmissions: u32,
+    sent_at: Instant,
+}
+
+/// How an outstanding confirmed downlink ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolved {
+    pub target: usize,
+    pub msg_id: u64,
+    /// Why the device never acknowledged it; None once it did
+    pub failure: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    pending: HashMap<u32, Pending>,
+    resolved: Vec<Resolved>,
+}
+
+/// Outstanding confirmed downlinks per DevAddr, shared by the UDP server
+/// and the outbound task
+#[derive(Debug, Clone, Default)]
+pub struct ConfirmedDownlinks {
+    max_retransmissions: u32,
+    state: Arc<Mutex<State>>,
+}
+
+impl ConfirmedDownlinks {
+    pub fn new(max_retransmissions: u32) -> Self {
+        Self {
+            max_retransmissions,
+            ..Default::default()
+        }
+    }
+
+    /// Wait for `dev_addr` to acknowledge `frame`, sent for outbox message `msg_id`
+    pub fn track(&self, dev_addr: u32, target: usize, msg_id: u64, frame: Vec<u8>, now: Instant) {
+        let pending = Pending {
+            target,
+            msg_id,
+            frame,
+            retransmissions: 0,
+            sent_at: now,
+        };
+        let mut state = self.lock();
+        if let Some(earlier) = state.pending.insert(dev_addr, pending) {
+            let failure = format!("superseded by msg #{} before the device ACKed", msg_id);
+            state.resolved.push(resolve(earlier, Some(failure)));
+        }
+    }
+
+    /// Note an uplink from `dev_addr`; returns the frame to send again, if any
+    ///
+    /// `ack` is the uplink's FCtrl.ACK bit.
+    pub fn on_uplink(&self, dev_addr: u32, ack: bool, now: Instant) -> Option<Vec<u8>> {
+        let mut state = self.lock();
+        let pending = state.pending.get_mut(&dev_addr)?;
+        if !ack && pending.retransmissions < self.max_retransmissions {
+            pending.retransmissions += 1;
+            pending.sent_at = now;
+            return Some(pending.frame.clone());
+        }
+        let pending = state.pending.remove(&dev_addr)?;
+        let failure = (!ack).then(|| {
+            format!("not ACKed after {} retransmissions", pending.retransmissions)
+        });
+        state.resolved.push(resolve(pending, failure));
+        None
+    }
+
+    /// Outbox message id awaiting an ACK from `dev_addr`
+    pub fn pending(&self, dev_addr: u32) -> Option<u64> {
+        self.lock().pending.get(&dev_addr).map(|pending| pending.msg_id)
+    }
+
+    /// Downlinks resolved since the last call, giving up on any that timed out
+    pub fn take_resolved(&self, now: Instant) -> Vec<Resolved> {
+        let mut state = self.lock();
+        let expired: Vec<u32> = state
+            .pending
+            .iter()
+            .filter(|(_, pending)| {
+                now.saturating_duration_since(pending.sent_at) >= CONFIRMED_ACK_TIMEOUT
+            })
+            .map(|(dev_addr, _)| *dev_addr)
+            .collect();
+        for dev_addr in expired {
+            if let Some(pending) = state.pending.remove(&dev_addr) {
+                let failure = "no uplink from the device to ACK it".to_string();
+                state.resolved.push(resolve(pending, Some(failure)));
+            }
+        }
+        std::mem::take(&mut state.resolved)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn resolve(pending: Pending, failure: Option<String>) -> Resolved {
+    Resolved {
+        target: pending.target,
+        msg_id: pending.msg_id,
+        failure,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEV: u32 = 0x260B1234;
+
+    #[test]
+    fn test_ack_clears_pending() {
+        let confirmed = ConfirmedDownlinks::new(2);
+        let now = Instant::now();
+        confirmed.track(DEV, 1, 7, vec![0xA0, 1, 2], now);
+        assert_eq!(confirmed.pending(DEV), Some(7));
+
+        // Uplinks from other devices, and unACKed ones, leave it pending
+        assert_eq!(confirmed.on_uplink(0x260B5678, true, now), None);
+        assert_eq!(confirmed.on_uplink(DEV, false, now), Some(vec![0xA0, 1, 2]));
+        assert_eq!(confirmed.pending(DEV), Some(7));
+        assert!(confirmed.take_resolved(now).is_empty());
+
+        // The ACK resolves it as delivered
+        assert_eq!(confirmed.on_uplink(DEV, true, now), None);
+        assert_eq!(confirmed.pending(DEV), None);
+        let resolved = confirmed.take_resolved(now);
+        assert_eq!(resolved, vec![Resolved { target: 1, msg_id: 7, failure: None }]);
+        assert!(confirmed.take_resolved(now).is_empty());
+    }
+
+    #[test]
+    fn test_retransmissions_exhausted() {
+        let confirmed = ConfirmedDownlinks::new(1);
+        let now = Instant::now();
+        confirmed.track(DEV, 0, 7, vec![0xA0], now);
+        assert!(confirmed.on_uplink(DEV, false, now).is_some());
+        assert_eq!(confirmed.on_uplink(DEV, false, now), None);
+        assert_eq!(confirmed.pending(DEV), None);
+        let failure = confirmed.take_resolved(now).remove(0).failure;
+        assert_eq!(failure.as_deref(), Some("not ACKed after 1 retransmissions"));
+
+        // A newer downlink replaces an older one; a silent device times out
+        confirmed.track(DEV, 0, 8, vec![0xA0], now);
+        confirmed.track(DEV, 0, 9, vec![0xA0], now);
+        let resolved = confirmed.take_resolved(now);
+        assert_eq!((resolved.len(), resolved[0].msg_id), (1, 8));
+        let later = now + CONFIRMED_ACK_TIMEOUT;
+        let resolved = confirmed.take_resolved(later);
+        assert_eq!((resolved.len(), resolved[0].msg_id), (1, 9));
+        assert_eq!(confirmed.pending(DEV), None);
+    }
+}
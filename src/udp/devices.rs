@@ -0,0 +1,157 @@
+//! Bridge-side view of the devices heard from
+//!
+//! The agent keeps the authoritative device state, but operators (and the
+//! health endpoint's `/devices`) can see when each DevAddr was last heard,
+//! how often and how well without scrying the ship. Only uplinks that pass
+//! the NetID and replay checks are counted.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// What the bridge has seen of one device
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DeviceStats {
+    pub last_seen: DateTime<Utc>,
+    pub packet_count: u64,
+    pub last_rssi: f64,
+    pub last_snr: Option<f64>,
+    pub last_fcnt: u32,
+    /// Gateway EUI (hex) of the last uplink
+    pub last_gateway: String,
+    /// How the last uplink reached the bridge; downlinks go back the same way
+    pub last_path: UplinkPath,
+}
+
+/// Transport an uplink arrived over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UplinkPath {
+    /// Semtech UDP, from a local gateway or Helium's GWMP mode
+    #[default]
+    Gwmp,
+    /// The Helium Packet Router's gRPC stream
+    PacketRouter,
+}
+
+/// One accepted uplink, as recorded in the registry
+#[derive(Debug, Clone)]
+pub struct Sighting<'a> {
+    pub dev_addr: u32,
+    pub fcnt: u32,
+    pub rssi: f64,
+    pub snr: Option<f64>,
+    pub gateway_eui: &'a str,
+    pub path: UplinkPath,
+}
+
+/// Per-DevAddr last-seen and reception statistics, shared between tasks
+#[derive(Debug, Clone, Default)]
+pub struct DeviceRegistry {
+    inner: Arc<Mutex<BTreeMap<u32, DeviceStats>>>,
+}
+
+impl DeviceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count an uplink received now
+    pub fn record(&self, sighting: &Sighting<'_>) {
+        self.record_at(sighting, Utc::now());
+    }
+
+    pub fn record_at(&self, sighting: &Sighting<'_>, now: DateTime<Utc>) {
+        let mut devices = self.lock();
+        let stats = devices.entry(sighting.dev_addr).or_insert_with(|| DeviceStats {
+            last_seen: now,
+            packet_count: 0,
+            last_rssi: sighting.rssi,
+            last_snr: None,
+            last_fcnt: 0,
+            last_gateway: String::new(),
+            last_path: UplinkPath::Gwmp,
+        });
+        stats.last_seen = now;
+        stats.packet_count += 1;
+        stats.last_rssi = sighting.rssi;
+        stats.last_snr = sighting.snr;
+        stats.last_fcnt = sighting.fcnt;
+        stats.last_gateway = sighting.gateway_eui.to_string();
+        stats.last_path = sighting.path;
+    }
+
+    pub fn get(&self, dev_addr: u32) -> Option<DeviceStats> {
+        self.lock().get(&dev_addr).cloned()
+    }
+
+    pub fn count(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Every device keyed by DevAddr hex, for `/devices`
+    pub fn to_json(&self) -> serde_json::Value {
+        let devices: BTreeMap<String, DeviceStats> = self
+            .lock()
+            .iter()
+            .map(|(dev_addr, stats)| (format!("{:08X}", dev_addr), stats.clone()))
+            .collect();
+        serde_json::to_value(devices).unwrap_or_default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<u32, DeviceStats>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sighting(dev_addr: u32, fcnt: u32, rssi: f64, gateway_eui: &str) -> Sighting<'_> {
+        Sighting {
+            dev_addr,
+            fcnt,
+            rssi,
+            snr: Some(7.5),
+            gateway_eui,
+            path: UplinkPath::Gwmp,
+        }
+    }
+
+    #[test]
+    fn test_count_and_last_seen() {
+        let registry = DeviceRegistry::new();
+        let t0 = Utc::now();
+        assert_eq!(registry.get(0x260B_1234), None);
+
+        registry.record_at(&sighting(0x260B_1234, 1, -40.0, "aabbccddeeff0011"), t0);
+        let later = t0 + chrono::Duration::seconds(30);
+        let other_gateway = "0000000000000001";
+        registry.record_at(&sighting(0x260B_1234, 2, -95.0, other_gateway), later);
+        registry.record_at(&sighting(0x01AB_5678, 7, -60.0, "aabbccddeeff0011"), t0);
+
+        // Counts accumulate; the rest reflects the latest uplink
+        let stats = registry.get(0x260B_1234).unwrap();
+        assert_eq!(stats.packet_count, 2);
+        assert_eq!(stats.last_seen, later);
+        assert_eq!(stats.last_fcnt, 2);
+        assert_eq!(stats.last_rssi, -95.0);
+        assert_eq!(stats.last_gateway, other_gateway);
+        assert_eq!(registry.count(), 2);
+
+        // Clones share the same registry (the UDP server and health endpoint)
+        let shared = registry.clone();
+        shared.record(&sighting(0x01AB_5678, 8, -61.0, "aabbccddeeff0011"));
+        assert_eq!(registry.get(0x01AB_5678).unwrap().packet_count, 2);
+
+        let json = registry.to_json();
+        assert_eq!(json["260B1234"]["packet-count"], 2);
+        assert_eq!(json["01AB5678"]["last-fcnt"], 8);
+        assert_eq!(json["01AB5678"]["last-snr"], 7.5);
+        assert_eq!(json["01AB5678"]["last-path"], "gwmp");
+    }
+}
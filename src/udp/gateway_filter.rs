@@ -0,0 +1,117 @@
+//! Gateway allow/deny lists (`udp.allowed_gateways`, `udp.denied_gateways`)
+//!
+//! On a shared port, datagrams from gateways we don't operate are dropped
+//! before they are acknowledged or decoded. Such a gateway is never recorded
+//! as the downlink destination either. A denied EUI is dropped even if it
+//! is also allowed; an empty allow list allows every gateway not denied.
+//!
+//! ```toml
+//! [udp]
+//! allowed_gateways = ["aabbccddeeff0011", "0016C001FF10A235"]
+//! denied_gateways = ["0000000000000001"]
+//! ```
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::protocol::GatewayEui;
+
+/// Which gateways' datagrams are handled
+#[derive(Debug, Default)]
+pub struct GatewayFilter {
+    allowed: HashSet<GatewayEui>,
+    denied: HashSet<GatewayEui>,
+    dropped: AtomicU64,
+}
+
+impl GatewayFilter {
+    /// Build from the configured lists of 16-hex-digit EUIs
+    pub fn new(allowed: &[String], denied: &[String]) -> anyhow::Result<Self> {
+        Ok(Self {
+            allowed: parse_euis("allowed_gateways", allowed)?,
+            denied: parse_euis("denied_gateways", denied)?,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Whether datagrams from `eui` are handled
+    pub fn permits(&self, eui: &GatewayEui) -> bool {
+        !self.denied.contains(eui) && (self.allowed.is_empty() || self.allowed.contains(eui))
+    }
+
+    /// Like `permits`, counting a rejected datagram; returns the new drop count
+    pub fn check(&self, eui: &GatewayEui) -> Result<(), u64> {
+        match self.permits(eui) {
+            true => Ok(()),
+            false => Err(self.dropped.fetch_add(1, Ordering::Relaxed) + 1),
+        }
+    }
+
+    /// Datagrams dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn parse_euis(field: &str, entries: &[String]) -> anyhow::Result<HashSet<GatewayEui>> {
+    entries
+        .iter()
+        .map(|entry| {
+            hex::decode(entry.trim())
+                .ok()
+                .and_then(|bytes| GatewayEui::try_from(bytes).ok())
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Invalid udp.{} entry {:?} (expected 16 hex digits)",
+                        field,
+                        entry
+                    )
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GW_A: GatewayEui = [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF, 0x00, 0x11];
+    const GW_B: GatewayEui = [0, 0, 0, 0, 0, 0, 0, 1];
+    const GW_C: GatewayEui = [0, 0, 0, 0, 0, 0, 0, 2];
+
+    fn filter(allowed: &[&str], denied: &[&str]) -> GatewayFilter {
+        let strings = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        GatewayFilter::new(&strings(allowed), &strings(denied)).unwrap()
+    }
+
+    #[test]
+    fn test_allow_deny_precedence() {
+        // No lists: everything is handled
+        let open = filter(&[], &[]);
+        assert!(open.permits(&GW_A) && open.permits(&GW_B));
+
+        // Deny list only
+        let deny = filter(&[], &["0000000000000001"]);
+        assert!(deny.permits(&GW_A));
+        assert!(!deny.permits(&GW_B));
+
+        // Allow list only; EUIs are case-insensitive
+        let allow = filter(&["AABBCCDDEEFF0011"], &[]);
+        assert!(allow.permits(&GW_A));
+        assert!(!allow.permits(&GW_B));
+
+        // Deny wins over allow
+        let both = filter(&["aabbccddeeff0011", "0000000000000001"], &["0000000000000001"]);
+        assert!(both.permits(&GW_A));
+        assert!(!both.permits(&GW_B));
+        assert!(!both.permits(&GW_C));
+
+        assert_eq!(both.check(&GW_A), Ok(()));
+        assert_eq!(both.check(&GW_B), Err(1));
+        assert_eq!(both.check(&GW_C), Err(2));
+        assert_eq!(both.dropped(), 2);
+
+        assert!(GatewayFilter::new(&["aabb".to_string()], &[]).is_err());
+        assert!(GatewayFilter::new(&[], &["not hex at all!".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+//! Friendly gateway names for logs and metrics (`[[gateways]] name`)
+//!
+//! Log lines and the per-gateway `/metrics` labels show a gateway's
+//! configured name instead of its EUI; gateways without one keep the hex.
+//!
+//! ```toml
+//! [[gateways]]
+//! eui = "aabbccddeeff0011"
+//! name = "roof-north"
+//! ```
+
+use std::collections::HashMap;
+
+use crate::config::GatewayConfig;
+
+/// Configured names by lowercase EUI hex
+#[derive(Debug, Clone, Default)]
+pub struct GatewayNames {
+    names: HashMap<String, String>,
+}
+
+impl GatewayNames {
+    pub fn from_config(gateways: &[GatewayConfig]) -> Self {
+        let names = gateways
+            .iter()
+            .filter_map(|gateway| {
+                let name = gateway.name.as_deref()?.trim();
+                (!name.is_empty()).then(|| (gateway.eui.to_ascii_lowercase(), name.to_string()))
+            })
+            .collect();
+        Self { names }
+    }
+
+    /// The configured name for `eui_hex`, if any
+    pub fn get(&self, eui_hex: &str) -> Option<&str> {
+        self.names.get(&eui_hex.to_ascii_lowercase()).map(String::as_str)
+    }
+
+    /// The name to show for `eui_hex`: its configured name, or the hex itself
+    pub fn display<'a>(&'a self, eui_hex: &'a str) -> &'a str {
+        self.get(eui_hex).unwrap_or(eui_hex)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gateway(eui: &str, name: Option<&str>) -> GatewayConfig {
+        GatewayConfig {
+            eui: eui.to_string(),
+            tx_power: None,
+            name: name.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_lookup_and_fallback() {
+        let names = GatewayNames::from_config(&[
+            gateway("AABBCCDDEEFF0011", Some("roof-north")),
+            gateway("0016c001ff10a235", None),
+            gateway("0000000000000001", Some("  ")),
+        ]);
+        assert_eq!(names.display("aabbccddeeff0011"), "roof-north");
+        assert_eq!(names.get("AABBCCDDEEFF0011"), Some("roof-north"));
+        // No name, a blank name, or not configured at all: the EUI hex
+        assert_eq!(names.display("0016c001ff10a235"), "0016c001ff10a235");
+        assert_eq!(names.display("0000000000000001"), "0000000000000001");
+        assert_eq!(names.display("1122334455667788"), "1122334455667788");
+    }
+}
@@ -0,0 +1,93 @@
+//! JoinRequests awaiting the agent's JoinAccept
+//!
+//! A JoinAccept goes out JOIN_ACCEPT_DELAY1 (5 s) after its JoinRequest,
+//! or JOIN_ACCEPT_DELAY2 (6 s) in the second window, timed on the `tmst`
+//! counter of the gateway that heard the request. The UDP server notes each
+//! JoinRequest here under its DevNonce (the agent's JoinAccept echoes it)
+//! and the outbound task looks it up to time the answer. Requests older
+//! than the second window are of no use and are forgotten.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::protocol::Rxpk;
+
+/// Seconds from a JoinRequest to the device's first JoinAccept window
+pub const JOIN_ACCEPT_DELAY1_SECS: u64 = 5;
+
+/// How long a JoinRequest can still be answered (JOIN_ACCEPT_DELAY2)
+pub const JOIN_ACCEPT_WINDOW: Duration = Duration::from_secs(JOIN_ACCEPT_DELAY1_SECS + 1);
+
+/// A JoinRequest as it was received
+#[derive(Debug, Clone)]
+pub struct HeardJoin {
+    pub rxpk: Rxpk,
+    /// EUI (hex) of the gateway that heard it
+    pub gateway_eui: String,
+    heard_at: Instant,
+}
+
+/// Recent JoinRequests by DevNonce, shared by the UDP server and its senders
+#[derive(Debug, Clone, Default)]
+pub struct PendingJoins {
+    inner: Arc<Mutex<HashMap<u16, HeardJoin>>>,
+}
+
+impl PendingJoins {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Note a JoinRequest carrying `dev_nonce`, heard by `gateway_eui`
+    ///
+    /// Of several gateways' copies, the strongest is answered through.
+    pub fn record(&self, dev_nonce: u16, rxpk: &Rxpk, gateway_eui: &str, now: Instant) {
+        let mut joins = self.lock();
+        joins.retain(|_, join| now.saturating_duration_since(join.heard_at) < JOIN_ACCEPT_WINDOW);
+        if joins.get(&dev_nonce).is_some_and(|join| join.rxpk.rssi >= rxpk.rssi) {
+            return;
+        }
+        let join = HeardJoin {
+            rxpk: rxpk.clone(),
+            gateway_eui: gateway_eui.to_string(),
+            heard_at: now,
+        };
+        joins.insert(dev_nonce, join);
+    }
+
+    /// The JoinRequest with `dev_nonce`, if it can still be answered
+    pub fn get(&self, dev_nonce: u16, now: Instant) -> Option<HeardJoin> {
+        let join = self.lock().get(&dev_nonce)?.clone();
+        (now.saturating_duration_since(join.heard_at) < JOIN_ACCEPT_WINDOW).then_some(join)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u16, HeardJoin>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_expires_after_second_window() {
+        let rxpk: Rxpk = serde_json::from_str(
+            r#"{"tmst":1000000,"freq":902.3,"rssi":-40,"datr":"SF10BW125","size":23,"data":""}"#,
+        )
+        .unwrap();
+        let joins = PendingJoins::new();
+        let t0 = Instant::now();
+        joins.record(0x1234, &rxpk, "aabbccddeeff0011", t0);
+        // A weaker copy from another gateway doesn't take over
+        let weaker = Rxpk { rssi: -90.0, ..rxpk.clone() };
+        joins.record(0x1234, &weaker, "0000000000000001", t0);
+
+        let join = joins.get(0x1234, t0 + Duration::from_secs(5)).unwrap();
+        assert_eq!(join.gateway_eui, "aabbccddeeff0011");
+        assert_eq!(join.rxpk.tmst, Some(1000000));
+        assert!(joins.get(0x4321, t0).is_none());
+        assert!(joins.get(0x1234, t0 + JOIN_ACCEPT_WINDOW).is_none());
+    }
+}
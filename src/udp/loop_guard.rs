@@ -0,0 +1,139 @@
+//! PULL_RESP handling on the server socket
+//!
+//! The bridge sends PULL_RESPs and never acts on one it receives. In a
+//! pair or relay topology a misconfigured peer can loop our own downlinks
+//! back to us, so the tokens of recently sent PULL_RESPs are remembered and
+//! a received PULL_RESP carrying one is reported as an echo. Either way the
+//! datagram is dropped, with at most one warning per `WARN_INTERVAL`.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a sent token is remembered
+pub const TOKEN_TTL: Duration = Duration::from_secs(30);
+
+/// Most sent tokens remembered at once
+const MAX_TOKENS: usize = 256;
+
+/// Shortest gap between warnings about received PULL_RESPs
+pub const WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What a received PULL_RESP is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PullRespOrigin {
+    /// Carries the token of a PULL_RESP we sent recently
+    Echo,
+    /// From some other downlink source
+    Foreign,
+}
+
+/// Whether to warn about a dropped PULL_RESP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warning {
+    /// Warn, mentioning how many drops went unreported since the last warning
+    Due { suppressed: u64 },
+    /// Within `WARN_INTERVAL` of the last warning
+    Suppressed,
+}
+
+#[derive(Debug, Default)]
+struct GuardState {
+    sent: VecDeque<(u16, Instant)>,
+    last_warning: Option<Instant>,
+    suppressed: u64,
+    dropped: u64,
+    echoes: u64,
+}
+
+/// Recently sent PULL_RESP tokens and received PULL_RESP counts, shared
+/// between the DownlinkSender and the receive loops
+#[derive(Debug, Clone, Default)]
+pub struct LoopGuard {
+    inner: Arc<Mutex<GuardState>>,
+}
+
+impl LoopGuard {
+    /// Remember `token` as sent at `now`
+    pub fn record_sent(&self, token: u16, now: Instant) {
+        let mut state = self.lock();
+        prune(&mut state.sent, now);
+        if state.sent.len() >= MAX_TOKENS {
+            state.sent.pop_front();
+        }
+        state.sent.push_back((token, now));
+    }
+
+    /// Count a received PULL_RESP carrying `token` as dropped
+    pub fn check(&self, token: u16, now: Instant) -> (PullRespOrigin, Warning) {
+        let mut state = self.lock();
+        prune(&mut state.sent, now);
+        state.dropped += 1;
+        let origin = match state.sent.iter().any(|(sent, _)| *sent == token) {
+            true => {
+                state.echoes += 1;
+                PullRespOrigin::Echo
+            }
+            false => PullRespOrigin::Foreign,
+        };
+        let due = state
+            .last_warning
+            .is_none_or(|last| now.duration_since(last) >= WARN_INTERVAL);
+        let warning = match due {
+            true => {
+                state.last_warning = Some(now);
+                Warning::Due {
+                    suppressed: std::mem::take(&mut state.suppressed),
+                }
+            }
+            false => {
+                state.suppressed += 1;
+                Warning::Suppressed
+            }
+        };
+        (origin, warning)
+    }
+
+    /// PULL_RESPs received and dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.lock().dropped
+    }
+
+    /// Of those, the ones that were our own downlinks looped back
+    pub fn echoes(&self) -> u64 {
+        self.lock().echoes
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, GuardState> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn prune(sent: &mut VecDeque<(u16, Instant)>, now: Instant) {
+    while sent.front().is_some_and(|(_, at)| now.duration_since(*at) > TOKEN_TTL) {
+        sent.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_echo_and_rate_limit() {
+        let guard = LoopGuard::default();
+        let start = Instant::now();
+        guard.record_sent(0x1234, start);
+
+        let first = guard.check(0x1234, start);
+        assert_eq!(first, (PullRespOrigin::Echo, Warning::Due { suppressed: 0 }));
+        let second = guard.check(0x9999, start + Duration::from_secs(1));
+        assert_eq!(second, (PullRespOrigin::Foreign, Warning::Suppressed));
+
+        // Past the TTL the token is forgotten; past the interval, warn again
+        let later = start + WARN_INTERVAL;
+        let third = guard.check(0x1234, later);
+        assert_eq!(third, (PullRespOrigin::Foreign, Warning::Due { suppressed: 1 }));
+        assert_eq!((guard.dropped(), guard.echoes()), (3, 1));
+    }
+}
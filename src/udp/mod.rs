@@ -1,45 +1,274 @@
+pub mod best_gateway;
+pub mod capture;
+pub mod confirmed;
+pub mod devices;
+pub mod gateway_filter;
+pub mod gateway_names;
+pub mod joins;
+pub mod loop_guard;
+pub mod packet_log;
 pub mod protocol;
+pub mod receptions;
+pub mod source;
+pub mod watchdog;
 
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, oneshot, RwLock};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
-use crate::config::Config;
-use crate::lorawan::{self, LoRaWANFrame};
-use crate::urbit::types::{LoRaPacket, PacketSource};
-use protocol::{GwmpPacket, PushDataPayload, Rxpk, Txpk, PullRespPayload};
+use crate::config::{Config, DownlinkMode, DutyCycleBand, DutyCycleConfig};
+#[cfg(feature = "helium-grpc")]
+use crate::helium::router::PacketRouterStream;
+use crate::lorawan::airtime::{downlink_time_on_air, parse_coding_rate};
+use crate::lorawan::channel_plan::{ChannelPlan, Region};
+use crate::lorawan::datarate::DataRate;
+use crate::lorawan::class_b::ClassBScheduler;
+use crate::lorawan::codec::CodecRegistry;
+use crate::lorawan::fcnt::{FcntCheck, FrameCounterTracker, MicStatus, SharedFrameCounterTracker};
+use crate::lorawan::encoder::FrameBuilder;
+use crate::lorawan::keys::{
+    B1Params, DownlinkCounters, KeyStore, SessionKeys, SharedDownlinkCounters, SharedKeyStore,
+    DOWNLINK_COUNTERS_FILE,
+};
+use crate::lorawan::{self, mac, LoRaWANFrame, MType, NetId};
+use crate::metrics::{DropReason, UplinkMetrics};
+use crate::urbit::routing::Router;
+use crate::urbit::types::{AltReception, LoRaAction, LoRaPacket, MacStatus, PacketSource};
+use best_gateway::BestGateways;
+use capture::{Capture, Direction};
+use confirmed::ConfirmedDownlinks;
+use devices::{Sighting, UplinkPath};
+use gateway_filter::GatewayFilter;
+use gateway_names::GatewayNames;
+use joins::{PendingJoins, JOIN_ACCEPT_DELAY1_SECS};
+use loop_guard::{LoopGuard, PullRespOrigin, Warning};
+use packet_log::{PacketLog, PacketLogEntry};
+use protocol::{GwmpPacket, PushDataPayload, Rxpk, Txpk, TxpkAck, PullRespPayload};
+use protocol::{PayloadTooLong, PROTOCOL_VERSION, PROTOCOL_VERSION_1};
+use receptions::ReceptionWindow;
+use source::SourceClassifier;
+
+pub use devices::DeviceRegistry;
+
+/// How far ahead of a Class B ping slot a downlink must reach the gateway
+const CLASS_B_LEAD_TIME: Duration = Duration::from_secs(1);
+
+/// RX1 delay of ABP devices (LoRaWAN default RECEIVE_DELAY1)
+const RX1_DELAY_SECS: u64 = 1;
+
+/// How long an auto-ACK waits for the gateway's TX_ACK before assuming it was sent
+const ACK_TX_ACK_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// Shared state for tracking the gateway's address (learned from PULL_DATA keepalives)
 ///
 /// The gateway sends periodic PULL_DATA packets. The source address from those
 /// packets tells us where to send PULL_RESP (downlink) packets.
+///
+/// Behind NAT (e.g. cellular backhaul) that address can change between
+/// keepalives, so the latest keepalive's address always wins, and a gateway
+/// reappearing at a new address is logged as a possible NAT rebinding. A
+/// downlink sent when the last keepalive is older than the keepalive window
+/// is logged too: the NAT mapping it relies on may already be gone.
 #[derive(Debug, Clone)]
 pub struct GatewayTracker {
-    inner: Arc<RwLock<Option<SocketAddr>>>,
+    inner: Arc<RwLock<Option<TrackedGateway>>>,
+    /// Age past which the last keepalive's address is stale (zero: never)
+    keepalive_window: Duration,
+    /// Times the tracked gateway's address changed under the same EUI
+    rebinds: Arc<AtomicU64>,
+    /// Every gateway that reported its EUI, by EUI (hex), for `pinned`
+    by_eui: Arc<RwLock<HashMap<String, TrackedGateway>>>,
+}
+
+impl Default for GatewayTracker {
+    fn default() -> Self {
+        Self::with_keepalive_window(Duration::ZERO)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TrackedGateway {
+    addr: SocketAddr,
+    /// Known once the gateway has sent a PULL_DATA
+    eui: Option<String>,
+    /// GWMP version of its PULL_DATA, used for PULL_RESP framing
+    version: u8,
+    /// Server socket its PULL_DATA arrived on (None: the sender's own)
+    socket: Option<Arc<UdpSocket>>,
+    /// When its last PULL_DATA arrived (None: set by hand)
+    pulled_at: Option<Instant>,
 }
 
 impl GatewayTracker {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A tracker whose addresses go stale `window` after the last keepalive
+    pub fn with_keepalive_window(window: Duration) -> Self {
         Self {
-            inner: Arc::new(RwLock::new(None)),
+            inner: Default::default(),
+            keepalive_window: window,
+            rebinds: Default::default(),
+            by_eui: Default::default(),
         }
     }
 
     /// Update the tracked gateway address
     pub async fn set(&self, addr: SocketAddr) {
+        self.set_gateway(addr, None).await;
+    }
+
+    /// Update the tracked gateway address and the EUI (hex) it reported
+    pub async fn set_gateway(&self, addr: SocketAddr, eui: Option<&str>) {
+        self.track(TrackedGateway {
+            addr,
+            eui: eui.map(str::to_string),
+            version: PROTOCOL_VERSION,
+            socket: None,
+            pulled_at: None,
+        })
+        .await;
+    }
+
+    /// Track the gateway whose GWMP `version` PULL_DATA arrived on `socket`
+    pub async fn set_pulled(
+        &self,
+        socket: &Arc<UdpSocket>,
+        addr: SocketAddr,
+        eui: &str,
+        version: u8,
+    ) {
+        self.set_pulled_at(socket, addr, eui, version, Instant::now()).await;
+    }
+
+    async fn set_pulled_at(
+        &self,
+        socket: &Arc<UdpSocket>,
+        addr: SocketAddr,
+        eui: &str,
+        version: u8,
+        now: Instant,
+    ) {
+        self.track(TrackedGateway {
+            addr,
+            eui: Some(eui.to_string()),
+            version,
+            socket: Some(socket.clone()),
+            pulled_at: Some(now),
+        })
+        .await;
+    }
+
+    async fn track(&self, gateway: TrackedGateway) {
+        if let Some(eui) = &gateway.eui {
+            self.by_eui.write().await.insert(eui.clone(), gateway.clone());
+        }
         let mut guard = self.inner.write().await;
-        let changed = *guard != Some(addr);
-        *guard = Some(addr);
-        if changed {
-            info!("Gateway address updated: {}", addr);
+        let addr = gateway.addr;
+        let eui = gateway.eui.clone();
+        match guard.replace(gateway) {
+            Some(previous) if previous.addr == addr => {}
+            Some(previous) if eui.is_some() && previous.eui == eui => {
+                self.rebinds.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Gateway {} moved from {} to {} (NAT rebinding?)",
+                    eui.unwrap_or_default(),
+                    previous.addr,
+                    addr
+                );
+            }
+            _ => info!("Gateway address updated: {}", addr),
         }
     }
 
+    /// How long ago the last keepalive arrived, if that is past the keepalive window
+    pub async fn stale_keepalive(&self, now: Instant) -> Option<Duration> {
+        let pulled_at = self.inner.read().await.as_ref()?.pulled_at?;
+        let age = now.saturating_duration_since(pulled_at);
+        (!self.keepalive_window.is_zero() && age > self.keepalive_window).then_some(age)
+    }
+
+    /// Address changes of the tracked gateway seen so far
+    pub fn rebinds(&self) -> u64 {
+        self.rebinds.load(Ordering::Relaxed)
+    }
+
     /// Get the tracked gateway address (None if no PULL_DATA received yet)
     pub async fn get(&self) -> Option<SocketAddr> {
-        *self.inner.read().await
+        self.inner.read().await.as_ref().map(|gateway| gateway.addr)
+    }
+
+    /// EUI of the tracked gateway (None if unknown)
+    pub async fn eui(&self) -> Option<String> {
+        self.inner.read().await.as_ref().and_then(|gateway| gateway.eui.clone())
+    }
+
+    /// Socket the tracked gateway is reached on, if learned from a PULL_DATA
+    pub async fn socket(&self) -> Option<Arc<UdpSocket>> {
+        self.inner.read().await.as_ref().and_then(|gateway| gateway.socket.clone())
+    }
+
+    /// A tracker fixed on gateway `eui`, if it has been tracked before
+    pub async fn pinned(&self, eui: &str) -> Option<GatewayTracker> {
+        let gateway = self.by_eui.read().await.get(eui)?.clone();
+        Some(Self {
+            inner: Arc::new(RwLock::new(Some(gateway))),
+            ..Self::with_keepalive_window(self.keepalive_window)
+        })
+    }
+
+    /// GWMP version the tracked gateway speaks (2 until one is tracked)
+    pub async fn protocol_version(&self) -> u8 {
+        let guard = self.inner.read().await;
+        guard.as_ref().map_or(PROTOCOL_VERSION, |gateway| gateway.version)
+    }
+}
+
+/// Gateway EUIs the server has heard from and when each was last heard
+///
+/// The count goes into heartbeats; the times feed the gateway watchdog.
+#[derive(Debug, Clone, Default)]
+pub struct GatewaysSeen {
+    inner: Arc<std::sync::Mutex<HashMap<String, Instant>>>,
+}
+
+impl GatewaysSeen {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Note a PUSH_DATA or PULL_DATA from `gateway_eui` (hex)
+    pub fn record(&self, gateway_eui: &str) {
+        self.record_at(gateway_eui, Instant::now());
+    }
+
+    pub fn record_at(&self, gateway_eui: &str, now: Instant) {
+        let mut seen = self.inner.lock().unwrap();
+        match seen.get_mut(gateway_eui) {
+            Some(last) => *last = now,
+            None => {
+                seen.insert(gateway_eui.to_string(), now);
+            }
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+
+    /// Each gateway's EUI and when it was last heard
+    pub fn last_seen(&self) -> Vec<(String, Instant)> {
+        let seen = self.inner.lock().unwrap();
+        seen.iter().map(|(eui, last)| (eui.clone(), *last)).collect()
     }
 }
 
@@ -51,35 +280,551 @@ impl GatewayTracker {
 pub struct DownlinkSender {
     socket: Arc<UdpSocket>,
     gateway: GatewayTracker,
+    tx_acks: PendingTxAcks,
+    /// Tokens of the PULL_RESPs sent, to spot them looping back
+    loop_guard: LoopGuard,
+    /// Airtime budget (None when duty-cycle limits are off)
+    duty_cycle: Option<Arc<std::sync::Mutex<DutyCycleLimiter>>>,
+    /// Raw datagram capture (`[capture]`)
+    capture: Option<Capture>,
+    /// Gateway GPS time reference for Class B ping slots
+    class_b: Arc<std::sync::Mutex<ClassBScheduler>>,
+    /// Log downlinks instead of sending them (`general.dry_run`)
+    dry_run: bool,
+    /// Region whose payload size limits apply
+    region: Region,
+    /// Gateway that heard each device best, for immediate downlinks
+    best_gateways: BestGateways,
+    /// Recent JoinRequests, to time JoinAccepts from
+    joins: PendingJoins,
+    /// Packet Router stream for devices last heard over it, and the registry that says so
+    #[cfg(feature = "helium-grpc")]
+    packet_router: Option<(PacketRouterStream, DeviceRegistry)>,
 }
 
 impl DownlinkSender {
-    /// Send a PULL_RESP downlink to the tracked gateway
+    /// Standalone sender to a fixed gateway address, without a running server
+    ///
+    /// Binds `bind` and listens on it only for TX_ACKs, so
+    /// `send_downlink_acked` reports the gateway's result. Used by the
+    /// `send-downlink` subcommand.
+    pub async fn to_gateway(
+        bind: &str,
+        gateway: SocketAddr,
+        region: Region,
+    ) -> anyhow::Result<Self> {
+        let socket = Arc::new(bind_socket(bind).await?);
+        let tracker = GatewayTracker::new();
+        tracker.set(gateway).await;
+        let tx_acks = PendingTxAcks::default();
+
+        let (ack_socket, acks) = (socket.clone(), tx_acks.clone());
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 65535];
+            while let Ok((len, _)) = ack_socket.recv_from(&mut buf).await {
+                if let Ok(GwmpPacket::TxAck {
+                    random_token,
+                    json_payload,
+                    ..
+                }) = GwmpPacket::parse(&buf[..len])
+                {
+                    acks.resolve(random_token, TxResult::from_tx_ack(json_payload.as_deref()));
+                }
+            }
+        });
+
+        Ok(Self {
+            socket,
+            gateway: tracker,
+            tx_acks,
+            loop_guard: LoopGuard::default(),
+            duty_cycle: None,
+            capture: None,
+            class_b: Default::default(),
+            dry_run: false,
+            region,
+            best_gateways: BestGateways::new(),
+            joins: PendingJoins::new(),
+            #[cfg(feature = "helium-grpc")]
+            packet_router: None,
+        })
+    }
+
+    /// Send downlinks for devices whose last uplink came over the Packet
+    /// Router stream back on it rather than as a GWMP PULL_RESP
+    #[cfg(feature = "helium-grpc")]
+    pub fn with_packet_router(self, stream: PacketRouterStream, devices: DeviceRegistry) -> Self {
+        Self {
+            packet_router: Some((stream, devices)),
+            ..self
+        }
+    }
+
+    /// Retime `txpk` to the next ping slot of Class B device `dev_addr`, on
+    /// the counter of gateway `gateway_eui`
+    ///
+    /// Fails until that gateway has reported an uplink with GPS time.
+    pub fn schedule_ping_slot(
+        &self,
+        plan: &ChannelPlan,
+        txpk: &mut Txpk,
+        gateway_eui: &str,
+        dev_addr: u32,
+        periodicity: u8,
+    ) -> anyhow::Result<()> {
+        let (slot, tmst) = self.class_b.lock().unwrap_or_else(|e| e.into_inner()).schedule(
+            gateway_eui,
+            dev_addr,
+            periodicity,
+            Instant::now(),
+            CLASS_B_LEAD_TIME,
+        )?;
+        let (freq, datr) = plan.ping_slot_channel(slot.beacon_time, dev_addr);
+        txpk.imme = Some(false);
+        txpk.tmst = Some(tmst as u64);
+        txpk.freq = freq;
+        txpk.datr = datr;
+        debug!(
+            "Class B downlink for {:08X} in ping slot at GPS {} ms (tmst {})",
+            dev_addr, slot.gps_ms, tmst
+        );
+        Ok(())
+    }
+
+    /// This sender fixed on gateway `eui`, for downlinks timed on its counter
+    ///
+    /// Keeps the tracked gateway if `eui` has never sent a PULL_DATA.
+    pub async fn pinned(mut self, eui: &str) -> Self {
+        if let Some(gateway) = self.gateway.pinned(eui).await {
+            self.gateway = gateway;
+        }
+        self
+    }
+
+    /// Whether downlinks are only logged (`general.dry_run`)
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// EUI of the gateway downlinks currently go to, if known
+    pub async fn gateway_eui(&self) -> Option<String> {
+        self.gateway.eui().await
+    }
+
+    /// EUI of the gateway an immediate downlink to `dev_addr` goes to
     ///
-    /// Returns Ok(()) if sent, Err if no gateway address is known.
+    /// The one that heard its latest uplink best, if known, else the tracked one.
+    pub async fn downlink_gateway_eui(&self, dev_addr: u32) -> Option<String> {
+        match self.best_gateways.get(dev_addr) {
+            Some(eui) if self.gateway.pinned(&eui).await.is_some() => Some(eui),
+            _ => self.gateway_eui().await,
+        }
+    }
+
+    /// Send a PULL_RESP downlink to the tracked (or pinned) gateway
+    ///
+    /// Returns Ok(()) if sent, Err if no gateway address is known
+    /// (`TxError::NoGateway`), the frame is too large for its data rate
+    /// (`TxError::PayloadTooLarge`) or the duty-cycle budget is exhausted
+    /// (`TxError::DutyCycleExceeded`).
     pub async fn send_downlink(&self, txpk: &Txpk) -> anyhow::Result<()> {
-        let gw_addr = self.gateway.get().await
-            .ok_or_else(|| anyhow::anyhow!("no gateway address known (no PULL_DATA received yet)"))?;
+        #[cfg(feature = "helium-grpc")]
+        if let Some(stream) = self.packet_router_for(txpk) {
+            return self.send_packet_down(stream, &ClassATxpks::single(txpk)).await;
+        }
+        self.send_pull_resp(txpk, rand_token()).await
+    }
+
+    /// Send a Class A downlink in RX1, falling back to RX2 if RX1 is too late
+    ///
+    /// RX2 is only tried if the RX1 TX_ACK came back before the RX2 window
+    /// (its `tmst`) was due; the wait for it is capped at that gap.
+    pub async fn send_class_a(
+        &self,
+        txpks: &ClassATxpks,
+        timeout: Duration,
+    ) -> anyhow::Result<TxResult> {
+        let (rx1, rx2) = match (&txpks.rx1, &txpks.rx2) {
+            (Some(rx1), Some(rx2)) => (rx1, rx2),
+            (Some(only), None) | (None, Some(only)) => {
+                return self.send_downlink_acked(only, timeout).await;
+            }
+            (None, None) => return Err(anyhow::anyhow!("No RX1 or RX2 window to send in")),
+        };
+        // The router picks the window itself and reports no TX_ACK
+        #[cfg(feature = "helium-grpc")]
+        if let Some(stream) = self.packet_router_for(rx1) {
+            return self.send_packet_down(stream, txpks).await.map(|()| TxResult::NoAck);
+        }
+        let gap = match (rx1.tmst, rx2.tmst) {
+            (Some(t1), Some(t2)) => {
+                Duration::from_micros((t2 as u32).wrapping_sub(t1 as u32).into())
+            }
+            _ => Duration::ZERO,
+        };
+        let started = Instant::now();
+        let result = self.send_downlink_acked(rx1, timeout.min(gap)).await?;
+        if result != TxResult::Error(TxError::TooLate) {
+            return Ok(result);
+        }
+        if started.elapsed() >= gap {
+            debug!("RX1 too late and RX2 already passed");
+            return Ok(result);
+        }
+        info!("RX1 too late, retrying in RX2 ({} MHz {})", rx2.freq, rx2.datr);
+        self.send_downlink_acked(rx2, timeout).await
+    }
+
+    /// Send a PULL_RESP and wait up to `timeout` for the gateway's TX_ACK
+    ///
+    /// Err means the PULL_RESP could not be sent at all; otherwise the
+    /// TX_ACK outcome is returned, or `TxResult::NoAck` on timeout (and at
+    /// once in dry-run mode). Airtime the gateway reports it didn't use is
+    /// given back to the duty-cycle budget.
+    pub async fn send_downlink_acked(
+        &self,
+        txpk: &Txpk,
+        timeout: Duration,
+    ) -> anyhow::Result<TxResult> {
+        #[cfg(feature = "helium-grpc")]
+        if let Some(stream) = self.packet_router_for(txpk) {
+            let txpks = ClassATxpks::single(txpk);
+            return self.send_packet_down(stream, &txpks).await.map(|()| TxResult::NoAck);
+        }
+        let token = rand_token();
+        if self.dry_run {
+            self.send_pull_resp(txpk, token).await?;
+            return Ok(TxResult::NoAck);
+        }
+        let ack = self.tx_acks.register(token);
+        if let Err(e) = self.send_pull_resp(txpk, token).await {
+            self.tx_acks.cancel(token);
+            return match e.downcast::<TxError>() {
+                Ok(tx_error) => Ok(TxResult::Error(tx_error)),
+                Err(e) => Err(e),
+            };
+        }
+        match tokio::time::timeout(timeout, ack).await {
+            Ok(Ok(result)) => {
+                // The gateway didn't transmit it, so it used no airtime
+                if let (TxResult::Error(_), Some(limiter)) = (&result, &self.duty_cycle) {
+                    refund_airtime(limiter, txpk);
+                }
+                Ok(result)
+            }
+            _ => {
+                self.tx_acks.cancel(token);
+                Ok(TxResult::NoAck)
+            }
+        }
+    }
 
-        let payload = PullRespPayload { txpk: txpk.clone() };
-        let json = serde_json::to_string(&payload)?;
+    /// Send a built `frame` and wait up to `timeout` for its TX_ACK
+    ///
+    /// With a `recipient` DevAddr the txpk uses that device's RX2 data rate
+    /// and goes to the gateway that heard it best, at that gateway's TX
+    /// power; a Class B device's goes in its next ping slot through the
+    /// tracked gateway instead. There is no uplink to
+    /// time it from, so an rx1/rx2 `downlink.mode` falls back to immediate
+    /// (with a warning, except for Class B, whose ping slot sets the timing).
+    /// Returns the txpk sent alongside the outcome of `send_downlink_acked`.
+    pub async fn send_frame(
+        &self,
+        plan: &ChannelPlan,
+        keys: &SharedKeyStore,
+        frame: &[u8],
+        recipient: Option<u32>,
+        timeout: Duration,
+    ) -> anyhow::Result<(Txpk, TxResult)> {
+        use base64::Engine;
 
-        // Use a random token for the PULL_RESP
-        let token: u16 = rand_token();
-        let packet = GwmpPacket::pull_resp(token, &json);
+        let payload_b64 = base64::engine::general_purpose::STANDARD.encode(frame);
+        let size = frame.len() as u16;
+        let periodicity = |dev_addr| {
+            keys.read().unwrap_or_else(|e| e.into_inner()).class_b_periodicity(dev_addr)
+        };
+        let ping_slot = recipient.and_then(|dev_addr| Some((dev_addr, periodicity(dev_addr)?)));
+        let immediate;
+        let plan = match plan.downlink_mode {
+            DownlinkMode::Immediate => plan,
+            mode => {
+                if ping_slot.is_none() {
+                    warn!("No uplink to time a {:?} downlink from; sending it immediately", mode);
+                }
+                immediate = ChannelPlan {
+                    downlink_mode: DownlinkMode::Immediate,
+                    ..plan.clone()
+                };
+                &immediate
+            }
+        };
+        // The gateway comes first: its TX power goes into the txpk
+        let gateway_eui = match recipient {
+            Some(dev_addr) if ping_slot.is_none() => self.downlink_gateway_eui(dev_addr).await,
+            _ => self.gateway_eui().await,
+        };
+        let sender = match &gateway_eui {
+            Some(eui) => self.clone().pinned(eui).await,
+            None => self.clone(),
+        };
+        let gateway_eui = gateway_eui.as_deref();
+        let mut txpk = match recipient {
+            Some(dev_addr) => {
+                let keys = keys.read().unwrap_or_else(|e| e.into_inner());
+                build_device_txpk(plan, &keys, dev_addr, gateway_eui, None, &payload_b64, size)?
+            }
+            None => build_txpk(plan, gateway_eui, None, &payload_b64, size)?,
+        };
+        if let Some((dev_addr, periodicity)) = ping_slot {
+            let gateway_eui = gateway_eui
+                .ok_or_else(|| anyhow::anyhow!("No Class B ping slot: no gateway connected"))?;
+            self.schedule_ping_slot(plan, &mut txpk, gateway_eui, dev_addr, periodicity)
+                .map_err(|e| anyhow::anyhow!("No Class B ping slot: {}", e))?;
+        }
+        let result = sender.send_downlink_acked(&txpk, timeout).await?;
+        Ok((txpk, result))
+    }
 
-        self.socket.send_to(&packet, gw_addr).await?;
-        info!(
-            "Sent PULL_RESP to gateway {} (token=0x{:04x}, {} bytes)",
-            gw_addr,
-            token,
-            json.len()
+    /// Send JoinAccept `frame` in the windows of the JoinRequest with `dev_nonce`
+    ///
+    /// JOIN_ACCEPT_DELAY1 (5 s) after the request, or DELAY2 (6 s) if that
+    /// is too late, on the gateway that heard it. Fails if no such request
+    /// was heard within the last 6 s.
+    pub async fn send_join_accept(
+        &self,
+        plan: &ChannelPlan,
+        frame: &[u8],
+        dev_nonce: u16,
+        timeout: Duration,
+    ) -> anyhow::Result<TxResult> {
+        use base64::Engine;
+
+        let join = self.joins.get(dev_nonce, Instant::now()).ok_or_else(|| {
+            anyhow::anyhow!("JoinRequest with DevNonce {} not heard in time to answer", dev_nonce)
+        })?;
+        let payload_b64 = base64::engine::general_purpose::STANDARD.encode(frame);
+        let txpks = ClassATxpks::build(
+            plan,
+            Some(&join.gateway_eui),
+            &join.rxpk,
+            JOIN_ACCEPT_DELAY1_SECS,
+            &payload_b64,
+            frame.len() as u16,
         );
+        // The windows are timed on the receiving gateway's counter
+        let sender = self.clone().pinned(&join.gateway_eui).await;
+        sender.send_class_a(&txpks, timeout).await
+    }
+
+    /// The open Packet Router stream, if `txpk` is for a device last heard over it
+    #[cfg(feature = "helium-grpc")]
+    fn packet_router_for(&self, txpk: &Txpk) -> Option<&PacketRouterStream> {
+        let (stream, devices) = self.packet_router.as_ref()?;
+        let dev_addr = downlink_dev_addr(txpk)?;
+        let via_router = devices.get(dev_addr)?.last_path == UplinkPath::PacketRouter;
+        (via_router && stream.is_open()).then_some(stream)
+    }
+
+    /// Send a downlink as `packet_router_packet_down_v1` on the Packet Router stream
+    #[cfg(feature = "helium-grpc")]
+    async fn send_packet_down(
+        &self,
+        stream: &PacketRouterStream,
+        txpks: &ClassATxpks,
+    ) -> anyhow::Result<()> {
+        for txpk in txpks.rx1.iter().chain(&txpks.rx2) {
+            check_payload_size(self.region, txpk)?;
+        }
+        let packet = crate::helium::router::packet_down(txpks)?;
+        if self.dry_run {
+            info!("[dry run] Would send Packet Router downlink: {:?}", packet);
+            return Ok(());
+        }
+        let size = packet.payload.len();
+        stream.send(packet).await?;
+        info!("Sent downlink over the Packet Router stream ({} bytes)", size);
+        Ok(())
+    }
 
+    async fn send_pull_resp(&self, txpk: &Txpk, token: u16) -> anyhow::Result<()> {
+        check_payload_size(self.region, txpk)?;
+        send_pull_resp(
+            &self.socket,
+            &self.gateway,
+            self.duty_cycle.as_deref(),
+            self.capture.as_ref(),
+            txpk,
+            token,
+            self.dry_run,
+        )
+        .await?;
+        if !self.dry_run {
+            self.loop_guard.record_sent(token, Instant::now());
+        }
         Ok(())
     }
 }
 
+/// DevAddr of the data downlink a txpk carries (None for a JoinAccept)
+#[cfg(feature = "helium-grpc")]
+fn downlink_dev_addr(txpk: &Txpk) -> Option<u32> {
+    let phy = base64_decode(&txpk.data).ok()?;
+    let mtype = phy.first()? >> 5;
+    // Unconfirmed (0b011) or confirmed (0b101) data down
+    if mtype != 0b011 && mtype != 0b101 {
+        return None;
+    }
+    Some(u32::from_le_bytes(phy.get(1..5)?.try_into().ok()?))
+}
+
+/// Refuse a `txpk` whose MACPayload (size less MHDR and MIC) is over the
+/// region's maximum for its data rate
+pub fn check_payload_size(region: Region, txpk: &Txpk) -> Result<(), TxError> {
+    let size = (txpk.size as usize).saturating_sub(5);
+    match region.max_mac_payload(txpk.datr.clone()) {
+        Some(max) if size > max => Err(TxError::PayloadTooLarge {
+            size,
+            max,
+            datr: txpk.datr.clone(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Send `txpk` as a PULL_RESP to the tracked gateway, within the duty-cycle budget
+///
+/// The airtime is given back if the datagram can't be sent. With `dry_run`
+/// the PULL_RESP is only logged.
+async fn send_pull_resp(
+    socket: &UdpSocket,
+    gateway: &GatewayTracker,
+    duty_cycle: Option<&std::sync::Mutex<DutyCycleLimiter>>,
+    capture: Option<&Capture>,
+    txpk: &Txpk,
+    token: u16,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    if dry_run {
+        let json = serde_json::to_string(&PullRespPayload { txpk: txpk.clone() })?;
+        info!("[dry run] Would send PULL_RESP (token=0x{:04x}): {}", token, json);
+        return Ok(());
+    }
+
+    let gw_addr = gateway.get().await.ok_or(TxError::NoGateway)?;
+    if let Some(age) = gateway.stale_keepalive(Instant::now()).await {
+        warn!(
+            "Last PULL_DATA from gateway {} was {:?} ago; its NAT mapping may have expired",
+            gw_addr, age
+        );
+    }
+    let tracked_socket = gateway.socket().await;
+    let socket = tracked_socket.as_deref().unwrap_or(socket);
+
+    let gw_addr = match_socket_family(socket.local_addr()?, gw_addr)?;
+
+    if let Some(limiter) = duty_cycle {
+        limiter
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .try_reserve(txpk.freq, downlink_airtime(txpk), Instant::now())?;
+    }
+
+    let payload = PullRespPayload { txpk: txpk.clone() };
+    let json = serde_json::to_string(&payload)?;
+    // A v1 forwarder drops v2 packets, and never sends a TX_ACK for this one
+    let packet = match gateway.protocol_version().await {
+        PROTOCOL_VERSION_1 => {
+            GwmpPacket::with_version(GwmpPacket::pull_resp(0, &json), PROTOCOL_VERSION_1)
+        }
+        _ => GwmpPacket::pull_resp(token, &json),
+    };
+
+    if let Err(e) = socket.send_to(&packet, gw_addr).await {
+        if let Some(limiter) = duty_cycle {
+            refund_airtime(limiter, txpk);
+        }
+        return Err(e.into());
+    }
+    if let Some(capture) = capture {
+        capture.record(Direction::Sent, gw_addr, &packet);
+    }
+    info!(
+        "Sent PULL_RESP to gateway {} (token=0x{:04x}, {} bytes)",
+        gw_addr,
+        token,
+        json.len()
+    );
+
+    Ok(())
+}
+
+/// Time on air `txpk` is charged to the duty-cycle budget, at its `codr` (4/5 if unset)
+fn downlink_airtime(txpk: &Txpk) -> Duration {
+    let coding_rate = txpk.codr.as_deref().map_or(Ok(1), parse_coding_rate);
+    coding_rate
+        .and_then(|cr| downlink_time_on_air(txpk.size as usize, txpk.datr.clone(), cr))
+        .unwrap_or_default()
+}
+
+/// Give back the airtime charged for a `txpk` that was never transmitted
+fn refund_airtime(limiter: &std::sync::Mutex<DutyCycleLimiter>, txpk: &Txpk) {
+    limiter
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .refund(txpk.freq, downlink_airtime(txpk));
+}
+
+/// Bind the server's UDP socket
+///
+/// Accepts IPv4 (`0.0.0.0:1680`), IPv6 (`[::]:1680`) or a resolvable
+/// `host:port`. Binding the IPv6 any-address turns off IPV6_V6ONLY so the
+/// one socket also receives IPv4 gateways as v4-mapped addresses.
+pub async fn bind_socket(bind: &str) -> anyhow::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let addr = tokio::net::lookup_host(bind)
+        .await
+        .map_err(|e| anyhow::anyhow!("Invalid udp.bind address {:?}: {}", bind, e))?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("udp.bind address {:?} did not resolve", bind))?;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .map_err(|e| anyhow::anyhow!("Failed to bind UDP socket to {}: {}", addr, e))?;
+
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Express `dest` in the address family of a socket bound to `local`
+///
+/// An IPv4 destination is reachable from a dual-stack IPv6 socket as a
+/// v4-mapped address and a v4-mapped destination from an IPv4 socket as
+/// plain IPv4; a true IPv6 destination cannot be reached from IPv4.
+fn match_socket_family(local: SocketAddr, dest: SocketAddr) -> anyhow::Result<SocketAddr> {
+    match (local, dest) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => Ok(SocketAddr::new(
+            v4.ip().to_ipv6_mapped().into(),
+            v4.port(),
+        )),
+        (SocketAddr::V4(_), SocketAddr::V6(v6)) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => Ok(SocketAddr::new(v4.into(), v6.port())),
+            None => Err(anyhow::anyhow!(
+                "gateway {} is IPv6 but the UDP server is bound to IPv4 {}; \
+                 bind to [::]:{} to serve both",
+                dest,
+                local,
+                local.port()
+            )),
+        },
+        _ => Ok(dest),
+    }
+}
+
 /// Generate a pseudo-random 16-bit token
 fn rand_token() -> u16 {
     use std::time::SystemTime;
@@ -91,344 +836,3471 @@ fn rand_token() -> u16 {
 }
 
 /// TX result reported back from the UDP server when we receive TX_ACK
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TxResult {
     /// Gateway confirmed transmission (TX_ACK with no error)
     Success,
     /// Gateway reported a TX error
-    Error(String),
+    Error(TxError),
+    /// No TX_ACK within the timeout (e.g. a GWMP v1 forwarder never sends one)
+    NoAck,
 }
 
-/// Run the Semtech UDP Packet Forwarder server
-///
-/// Decoded LoRaWAN packets are sent to `poke_tx` for forwarding to Urbit.
-/// If `poke_tx` is `None`, packets are decoded and logged but not forwarded
-/// (Phase 1 mode).
-///
-/// Returns a `DownlinkSender` handle that the outbound task can use to
-/// send PULL_RESP packets to the gateway.
-pub async fn run_server(
-    config: &Config,
-    poke_tx: Option<mpsc::Sender<LoRaPacket>>,
-) -> anyhow::Result<()> {
-    let socket = Arc::new(UdpSocket::bind(&config.udp.bind).await?);
-    info!("UDP server listening on {}", config.udp.bind);
+impl TxResult {
+    /// Interpret a TX_ACK's JSON payload
+    ///
+    /// A missing payload, a payload without `txpk_ack` and an `error` of
+    /// `"NONE"` all mean success.
+    pub fn from_tx_ack(json_payload: Option<&str>) -> Self {
+        Self::from(&TxpkAck::parse(json_payload))
+    }
+}
 
-    let gateway = GatewayTracker::new();
+impl From<&TxpkAck> for TxResult {
+    fn from(ack: &TxpkAck) -> Self {
+        match ack.error() {
+            None => TxResult::Success,
+            Some(err) => TxResult::Error(TxError::parse(err)),
+        }
+    }
+}
 
-    let mut buf = vec![0u8; 65535];
+/// Gateway TX error from a TX_ACK's `txpk_ack.error`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxError {
+    /// Packet arrived at the gateway after its scheduled time
+    TooLate,
+    /// Packet was scheduled too far in advance
+    TooEarly,
+    /// Another packet is already scheduled at that time
+    CollisionPacket,
+    /// A beacon is scheduled at that time
+    CollisionBeacon,
+    /// Frequency not supported by the radio
+    TxFreq,
+    /// Requested power not supported (the gateway may have used another)
+    TxPower,
+    /// Timestamped TX requested but the gateway has no GPS lock
+    GpsUnlocked,
+    /// Refused locally: the sub-band's airtime budget is used up
+    DutyCycleExceeded,
+    /// Refused locally: no gateway has sent a PULL_DATA yet
+    NoGateway,
+    /// Refused locally: the MACPayload exceeds the region's maximum at the data rate
+    PayloadTooLarge {
+        size: usize,
+        max: usize,
+        datr: DataRate,
+    },
+    /// Any other error string
+    Other(String),
+}
 
-    loop {
-        let (len, src) = socket.recv_from(&mut buf).await?;
-        debug!("Received {} bytes from {}", len, src);
+impl TxError {
+    /// Map a GWMP error string (e.g. `"TOO_LATE"`)
+    pub fn parse(error: &str) -> Self {
+        match error {
+            "TOO_LATE" => TxError::TooLate,
+            "TOO_EARLY" => TxError::TooEarly,
+            "COLLISION_PACKET" => TxError::CollisionPacket,
+            "COLLISION_BEACON" => TxError::CollisionBeacon,
+            "TX_FREQ" => TxError::TxFreq,
+            "TX_POWER" => TxError::TxPower,
+            "GPS_UNLOCKED" => TxError::GpsUnlocked,
+            other => TxError::Other(other.to_string()),
+        }
+    }
 
-        match GwmpPacket::parse(&buf[..len]) {
-            Ok(packet) => {
-                handle_packet(&socket, src, packet, &poke_tx, &gateway).await;
-            }
-            Err(e) => {
-                warn!("Failed to parse GWMP packet from {}: {}", src, e);
+    /// Whether sending the same downlink again later may succeed
+    ///
+    /// Timing and collision errors depend on when the packet is sent, and a
+    /// gateway may connect at any moment; frequency, power and GPS errors
+    /// will recur on every attempt.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            TxError::TooLate
+                | TxError::TooEarly
+                | TxError::CollisionPacket
+                | TxError::CollisionBeacon
+                | TxError::DutyCycleExceeded
+                | TxError::NoGateway
+        )
+    }
+}
+
+impl std::fmt::Display for TxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            TxError::TooLate => "TOO_LATE",
+            TxError::TooEarly => "TOO_EARLY",
+            TxError::CollisionPacket => "COLLISION_PACKET",
+            TxError::CollisionBeacon => "COLLISION_BEACON",
+            TxError::TxFreq => "TX_FREQ",
+            TxError::TxPower => "TX_POWER",
+            TxError::GpsUnlocked => "GPS_UNLOCKED",
+            TxError::DutyCycleExceeded => "DUTY_CYCLE_EXCEEDED",
+            TxError::NoGateway => "NO_GATEWAY",
+            TxError::PayloadTooLarge { size, max, datr } => {
+                return write!(
+                    f,
+                    "PAYLOAD_TOO_LARGE ({}-byte MACPayload, {} allows {})",
+                    size, datr, max
+                );
             }
-        }
+            TxError::Other(other) => other,
+        };
+        f.write_str(s)
     }
 }
 
-/// Start the UDP server and return a DownlinkSender handle
+impl std::error::Error for TxError {}
+
+/// Sliding-window airtime budget per sub-band
 ///
-/// Unlike `run_server` which blocks, this spawns the server as a background
-/// task and returns immediately with the handle for sending downlinks.
-pub async fn start_server(
-    config: &Config,
-    poke_tx: Option<mpsc::Sender<LoRaPacket>>,
-) -> anyhow::Result<DownlinkSender> {
-    let socket = Arc::new(UdpSocket::bind(&config.udp.bind).await?);
-    info!("UDP server listening on {}", config.udp.bind);
-
-    let gateway = GatewayTracker::new();
-    let downlink_sender = DownlinkSender {
-        socket: socket.clone(),
-        gateway: gateway.clone(),
-    };
+/// Each transmission's time on air is charged to the sub-band containing its
+/// frequency; a downlink that would push the sub-band over its share of the
+/// window is refused. Frequencies outside every band are not limited.
+#[derive(Debug)]
+pub struct DutyCycleLimiter {
+    bands: Vec<DutyCycleBand>,
+    window: Duration,
+    /// (sent at, band index, airtime), oldest first
+    sent: VecDeque<(Instant, usize, Duration)>,
+}
 
-    // Spawn the receive loop as a background task
-    tokio::spawn(async move {
-        let mut buf = vec![0u8; 65535];
-        loop {
-            match socket.recv_from(&mut buf).await {
-                Ok((len, src)) => {
-                    debug!("Received {} bytes from {}", len, src);
-                    match GwmpPacket::parse(&buf[..len]) {
-                        Ok(packet) => {
-                            handle_packet(&socket, src, packet, &poke_tx, &gateway).await;
-                        }
-                        Err(e) => {
-                            warn!("Failed to parse GWMP packet from {}: {}", src, e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("UDP recv error: {}", e);
-                }
-            }
+impl DutyCycleLimiter {
+    pub fn new(bands: Vec<DutyCycleBand>, window: Duration) -> Self {
+        Self {
+            bands,
+            window,
+            sent: VecDeque::new(),
         }
-    });
+    }
 
-    Ok(downlink_sender)
-}
+    /// Build from `[duty_cycle]`; None when limits are disabled
+    ///
+    /// Unless set explicitly, limits apply only in EU868 (ETSI sub-bands).
+    pub fn from_config(config: &DutyCycleConfig, region: Region) -> Option<Self> {
+        if !config.enabled.unwrap_or(region == Region::EU868) {
+            return None;
+        }
+        let bands = if config.bands.is_empty() {
+            Self::region_bands(region)
+        } else {
+            config.bands.clone()
+        };
+        Some(Self::new(bands, Duration::from_secs(config.window_secs)))
+    }
 
-async fn handle_packet(
-    socket: &UdpSocket,
-    src: SocketAddr,
-    packet: GwmpPacket,
-    poke_tx: &Option<mpsc::Sender<LoRaPacket>>,
-    gateway: &GatewayTracker,
-) {
-    match packet {
-        GwmpPacket::PushData {
-            random_token,
-            gateway_eui,
-            json_payload,
-        } => {
-            let gw_eui_hex = hex::encode(gateway_eui);
-            info!(
-                "PUSH_DATA from gateway {} (token: 0x{:04x})",
-                gw_eui_hex, random_token
-            );
+    /// Default sub-band limits for a region
+    pub fn region_bands(region: Region) -> Vec<DutyCycleBand> {
+        let band = |min_freq, max_freq, percent| DutyCycleBand {
+            min_freq,
+            max_freq,
+            percent,
+        };
+        match region {
+            // ETSI EN 300 220: g/g1 1%, g2 0.1%, g3 10% (RX2), g4 1%
+            Region::EU868 => vec![
+                band(863.0, 868.6, 1.0),
+                band(868.7, 869.2, 0.1),
+                band(869.4, 869.65, 10.0),
+                band(869.7, 870.0, 1.0),
+            ],
+            // FCC rules limit dwell time, not duty cycle
+            Region::US915 | Region::AU915 => Vec::new(),
+        }
+    }
 
-            // Send ACK immediately
-            let ack = GwmpPacket::push_ack(random_token);
-            if let Err(e) = socket.send_to(&ack, src).await {
-                error!("Failed to send PUSH_ACK to {}: {}", src, e);
-            }
-
-            // Parse the JSON payload
-            match serde_json::from_str::<PushDataPayload>(&json_payload) {
-                Ok(payload) => {
-                    if let Some(rxpks) = payload.rxpk {
-                        for rxpk in rxpks {
-                            info!(
-                                "  rxpk: freq={} MHz, rssi={} dBm, datr={}, size={} bytes",
-                                rxpk.freq, rxpk.rssi, rxpk.datr, rxpk.size
-                            );
-
-                            // Decode the LoRaWAN PHY payload
-                            match base64_decode(&rxpk.data) {
-                                Ok(phy_payload) => {
-                                    match lorawan::decode_phy_payload(&phy_payload) {
-                                        Ok(frame) => {
-                                            info!("  LoRaWAN: {}", frame);
-
-                                            // Forward to Urbit via mpsc channel
-                                            if let Some(tx) = poke_tx {
-                                                if let Some(lora_pkt) = frame_to_lora_packet(
-                                                    &frame,
-                                                    &rxpk,
-                                                    &gw_eui_hex,
-                                                ) {
-                                                    if let Err(e) = tx.send(lora_pkt).await {
-                                                        error!(
-                                                            "Failed to forward packet to Airlock task: {}",
-                                                            e
-                                                        );
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        Err(e) => {
-                                            warn!("  Failed to decode LoRaWAN frame: {}", e);
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    warn!("  Failed to base64 decode rxpk data: {}", e);
-                                }
-                            }
-                        }
-                    }
+    /// Charge `airtime` on `freq` at `now`, or refuse if over budget
+    pub fn try_reserve(
+        &mut self,
+        freq: f64,
+        airtime: Duration,
+        now: Instant,
+    ) -> Result<(), TxError> {
+        let Some(band) = self
+            .bands
+            .iter()
+            .position(|b| freq >= b.min_freq && freq <= b.max_freq)
+        else {
+            return Ok(());
+        };
 
-                    if let Some(stat) = payload.stat {
-                        debug!("  Gateway status: {}", stat);
-                    }
-                }
-                Err(e) => {
-                    warn!("Failed to parse PUSH_DATA JSON: {}", e);
-                    debug!("  Raw JSON: {}", json_payload);
-                }
+        while let Some(&(at, _, _)) = self.sent.front() {
+            if now.duration_since(at) < self.window {
+                break;
             }
+            self.sent.pop_front();
         }
-        GwmpPacket::PullData {
-            random_token,
-            gateway_eui,
-        } => {
-            let gw_eui_hex = hex::encode(gateway_eui);
-            debug!(
-                "PULL_DATA from gateway {} (token: 0x{:04x})",
-                gw_eui_hex, random_token
-            );
 
-            // Track the gateway address for downlink delivery
-            gateway.set(src).await;
-
-            let ack = GwmpPacket::pull_ack(random_token);
-            if let Err(e) = socket.send_to(&ack, src).await {
-                error!("Failed to send PULL_ACK to {}: {}", src, e);
-            }
+        let budget = self.window.mul_f64(self.bands[band].percent / 100.0);
+        if self.used(band) + airtime > budget {
+            return Err(TxError::DutyCycleExceeded);
         }
-        GwmpPacket::TxAck {
-            random_token,
-            gateway_eui,
-            json_payload,
-        } => {
-            let gw_eui_hex = hex::encode(gateway_eui);
+        self.sent.push_back((now, band, airtime));
+        Ok(())
+    }
 
-            // Check for TX errors in the payload
-            if let Some(ref json) = json_payload {
-                if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(json) {
-                    if let Some(txpk_ack) = parsed.get("txpk_ack") {
-                        let error = txpk_ack.get("error").and_then(|e| e.as_str());
-                        match error {
-                            None | Some("NONE") => {
-                                info!(
-                                    "TX_ACK from gateway {} (token: 0x{:04x}): SUCCESS",
-                                    gw_eui_hex, random_token
-                                );
-                            }
-                            Some(err) => {
-                                warn!(
-                                    "TX_ACK from gateway {} (token: 0x{:04x}): ERROR: {}",
-                                    gw_eui_hex, random_token, err
-                                );
-                            }
-                        }
-                    } else {
-                        // TX_ACK with no txpk_ack field — treat as success
-                        info!(
-                            "TX_ACK from gateway {} (token: 0x{:04x}): OK (no txpk_ack)",
-                            gw_eui_hex, random_token
-                        );
-                    }
+    /// Take back the latest charge of `airtime` on `freq`
+    pub fn refund(&mut self, freq: f64, airtime: Duration) {
+        let Some(band) = self
+            .bands
+            .iter()
+            .position(|b| freq >= b.min_freq && freq <= b.max_freq)
+        else {
+            return;
+        };
+        if let Some(i) = self.sent.iter().rposition(|&(_, b, a)| b == band && a == airtime) {
+            self.sent.remove(i);
+        }
+    }
+
+    /// Airtime charged to band `band` within the current window
+    fn used(&self, band: usize) -> Duration {
+        self.sent
+            .iter()
+            .filter(|(_, b, _)| *b == band)
+            .map(|(_, _, airtime)| *airtime)
+            .sum()
+    }
+}
+
+/// PULL_RESP tokens waiting for their TX_ACK
+///
+/// Shared between the `DownlinkSender` (which registers a token before
+/// sending) and the receive loop (which resolves it when TX_ACK arrives).
+#[derive(Debug, Clone, Default)]
+pub struct PendingTxAcks {
+    waiters: Arc<std::sync::Mutex<HashMap<u16, oneshot::Sender<TxResult>>>>,
+}
+
+impl PendingTxAcks {
+    /// Wait for the TX_ACK carrying `token`
+    pub fn register(&self, token: u16) -> oneshot::Receiver<TxResult> {
+        let (tx, rx) = oneshot::channel();
+        self.lock().insert(token, tx);
+        rx
+    }
+
+    /// Deliver a TX_ACK; false if nobody was waiting for `token`
+    pub fn resolve(&self, token: u16, result: TxResult) -> bool {
+        match self.lock().remove(&token) {
+            Some(tx) => tx.send(result).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Stop waiting for `token` (send failed or timed out)
+    pub fn cancel(&self, token: u16) {
+        self.lock().remove(&token);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u16, oneshot::Sender<TxResult>>> {
+        self.waiters.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Poke channels to the Airlock task of each Urbit target, with their routes
+pub type PokeRouter = Router<mpsc::Sender<LoRaAction>>;
+
+/// Run the Semtech UDP Packet Forwarder server
+///
+/// Decoded LoRaWAN packets are sent to each matching channel in `pokes` for
+/// forwarding to Urbit. If `pokes` is empty, packets are decoded and logged
+/// but not forwarded (Phase 1 mode).
+///
+/// Runs until the process exits; use `start_server` to also send downlinks.
+pub async fn run_server(
+    config: &Config,
+    pokes: PokeRouter,
+) -> anyhow::Result<()> {
+    let server = start_server(config, pokes, CancellationToken::new()).await?;
+    server.task.await?;
+    Ok(())
+}
+
+/// Handle to a running UDP server started with `start_server`
+pub struct ServerHandle {
+    /// Address the first UDP socket is bound to (the real port if `udp.bind` used port 0)
+    pub local_addr: SocketAddr,
+    /// Addresses of every socket, in `udp.bind` order
+    pub local_addrs: Vec<SocketAddr>,
+    /// Sender for PULL_RESP downlinks through the server's socket
+    pub downlink_sender: DownlinkSender,
+    /// Session keys used for uplink MIC checks; OTAA joins add to it
+    pub keys: SharedKeyStore,
+    /// Per-device downlink FCnt, loaded from `lorawan.state_dir`; auto-ACKs consume it too
+    pub downlink_counters: SharedDownlinkCounters,
+    /// Confirmed downlinks awaiting the device's ACK; uplinks resolve or resend them
+    pub confirmed_downlinks: ConfirmedDownlinks,
+    /// Uplink FCnt per DevAddr; an OTAA join restarts its device's
+    pub fcnt_tracker: SharedFrameCounterTracker,
+    /// Gateways heard from so far
+    pub gateways_seen: GatewaysSeen,
+    /// Devices heard from so far, with reception statistics
+    pub devices: DeviceRegistry,
+    /// RSSI/SNR histograms of accepted uplinks
+    pub uplink_metrics: UplinkMetrics,
+    /// The receive loops' task; completes after the shutdown token is cancelled
+    pub task: JoinHandle<()>,
+}
+
+/// Start the UDP server and return a handle with its DownlinkSender
+///
+/// Unlike `run_server` which blocks, this spawns the server as a background
+/// task and returns immediately with the handle for sending downlinks.
+/// With port 0 in `udp.bind` the OS picks a free port, reported in
+/// `ServerHandle::local_addrs`.
+///
+/// Each `udp.bind` address gets its own socket and receive loop, all
+/// feeding the same pipeline. Downlinks go out on the socket the gateway's
+/// last PULL_DATA arrived on.
+///
+/// The receive loops stop when `shutdown` is cancelled. A packet that is
+/// already being handled is finished first, then `pokes` is dropped so the
+/// Airlock task sees the channel close and can drain and disconnect. The
+/// gateway watchdog, if enabled, stops on the same token.
+pub async fn start_server(
+    config: &Config,
+    pokes: PokeRouter,
+    shutdown: CancellationToken,
+) -> anyhow::Result<ServerHandle> {
+    let gateway = GatewayTracker::with_keepalive_window(Duration::from_secs(
+        config.udp.keepalive_window_secs,
+    ));
+    let (packet_log, packet_log_task) = PacketLog::from_config(&config.logging).await?.unzip();
+    if let Some(path) = &config.logging.packet_log {
+        info!("Logging decoded uplinks to {}", path);
+    }
+    let (capture, capture_task) = Capture::from_config(config.capture.as_ref()).await?.unzip();
+    if let Some(capture) = &config.capture {
+        info!("Capturing GWMP datagrams to {}", capture.path);
+    }
+    let status_pokes =
+        (config.udp.gateway_status_pokes && !config.general.dry_run).then(|| pokes.clone());
+    let ctx = Arc::new(PacketContext::new(config, pokes, gateway, packet_log, capture)?);
+    let mut sockets = Vec::new();
+    for bind in &config.udp.bind {
+        let socket = Arc::new(bind_socket(bind).await?);
+        info!("UDP server listening on {}", socket.local_addr()?);
+        sockets.push(socket);
+    }
+    let local_addrs = sockets
+        .iter()
+        .map(|socket| socket.local_addr())
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let local_addr = *local_addrs
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("udp.bind has no addresses"))?;
+
+    let watchdog_task = (config.udp.gateway_timeout_secs > 0).then(|| {
+        tokio::spawn(watchdog::run_watchdog(
+            ctx.gateways_seen.clone(),
+            Duration::from_secs(config.udp.gateway_timeout_secs),
+            status_pokes,
+            shutdown.clone(),
+        ))
+    });
+
+    if ctx.duty_cycle.is_some() {
+        info!("Downlink duty-cycle limits enabled");
+    }
+    let downlink_sender = ctx.downlink_sender(sockets[0].clone());
+    let keys = ctx.keys.clone();
+    let downlink_counters = ctx.downlink_counters.clone();
+    let confirmed_downlinks = ctx.confirmed.clone();
+    let fcnt_tracker = ctx.fcnt_tracker.clone();
+    let gateways_seen = ctx.gateways_seen.clone();
+    let devices = ctx.devices.clone();
+    let uplink_metrics = ctx.uplink_metrics.clone();
+
+    // Spawn a receive loop per socket, and a task that outlives them all
+    let mut loops = tokio::task::JoinSet::new();
+    for socket in sockets {
+        loops.spawn(receive_loop(socket, ctx.clone(), shutdown.clone()));
+    }
+    let task = tokio::spawn(async move {
+        while loops.join_next().await.is_some() {}
+        info!("UDP server shut down");
+
+        // Close the packet channel so the Airlock task drains and exits
+        drop(ctx);
+        for task in [packet_log_task, capture_task, watchdog_task].into_iter().flatten() {
+            let _ = task.await;
+        }
+    });
+
+    Ok(ServerHandle {
+        local_addr,
+        local_addrs,
+        downlink_sender,
+        keys,
+        downlink_counters,
+        confirmed_downlinks,
+        fcnt_tracker,
+        gateways_seen,
+        devices,
+        uplink_metrics,
+        task,
+    })
+}
+
+/// Receive and handle datagrams on `socket` until `shutdown`
+async fn receive_loop(
+    socket: Arc<UdpSocket>,
+    ctx: Arc<PacketContext>,
+    shutdown: CancellationToken,
+) {
+    let mut buf = vec![0u8; 65535];
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            result = socket.recv_from(&mut buf) => match result {
+                Ok((len, src)) => {
+                    handle_datagram(&socket, src, &buf[..len], &ctx).await;
+                }
+                Err(e) => {
+                    error!("UDP recv error: {}", e);
+                }
+            },
+        }
+    }
+}
+
+/// Server state shared by every packet the receive loops handle
+struct PacketContext {
+    /// Channels to the Airlock task of each Urbit target (empty in Phase 1 mode)
+    pokes: PokeRouter,
+    /// Gateway address learned from PULL_DATA, shared with the DownlinkSender
+    gateway: GatewayTracker,
+    /// Gateway EUIs heard from, shared with the Airlock tasks' heartbeats
+    gateways_seen: GatewaysSeen,
+    /// Per-device statistics, shared with the health endpoint
+    devices: DeviceRegistry,
+    /// Link-quality histograms, shared with the health endpoint
+    uplink_metrics: UplinkMetrics,
+    /// Downlinks awaiting TX_ACK, shared with the DownlinkSender
+    tx_acks: PendingTxAcks,
+    /// Tokens of sent PULL_RESPs, shared with the DownlinkSender
+    loop_guard: LoopGuard,
+    /// Gateways whose datagrams are handled (`udp.allowed_gateways`/`denied_gateways`)
+    gateway_filter: GatewayFilter,
+    /// Names shown for gateways in logs and metrics (`[[gateways]] name`)
+    gateway_names: GatewayNames,
+    /// Local vs Helium origin rules (`udp.helium_sources`)
+    classifier: SourceClassifier,
+    /// Payload codecs (`lorawan.codec`, `lorawan.device_codecs`)
+    codecs: CodecRegistry,
+    /// Session keys: ABP devices (`lorawan.devices`) plus OTAA joins
+    keys: SharedKeyStore,
+    /// Last accepted uplink FCnt per DevAddr, shared with the outbound task
+    fcnt_tracker: SharedFrameCounterTracker,
+    /// NetIDs whose DevAddrs are processed (`lorawan.accept_net_ids`; empty = all)
+    accept_net_ids: Vec<NetId>,
+    /// JSON-Lines uplink log (`logging.packet_log`)
+    packet_log: Option<PacketLog>,
+    /// Raw datagram capture (`[capture]`), shared with the DownlinkSender
+    capture: Option<Capture>,
+    /// GPS time reference from uplinks, shared with the DownlinkSender
+    class_b: Arc<std::sync::Mutex<ClassBScheduler>>,
+    /// Airtime budget, shared with the DownlinkSender (None when limits are off)
+    duty_cycle: Option<Arc<std::sync::Mutex<DutyCycleLimiter>>>,
+    /// Region plan for RX1 auto-ACKs
+    channel_plan: ChannelPlan,
+    /// Answer confirmed uplinks with an empty ACK (`lorawan.auto_ack_confirmed`)
+    auto_ack_confirmed: bool,
+    /// Drop rather than just log non-R1 Majors (`lorawan.reject_unknown_major`)
+    reject_unknown_major: bool,
+    /// Parse GWMP v1 datagrams too (`udp.accept_protocol_v1`)
+    accept_protocol_v1: bool,
+    /// Longest JSON payload parsed (`udp.max_json_len`)
+    max_json_len: usize,
+    /// Downlink FCnt per DevAddr, shared with the outbound task
+    downlink_counters: SharedDownlinkCounters,
+    /// Confirmed downlinks awaiting an ACK, shared with the outbound task
+    confirmed: ConfirmedDownlinks,
+    /// Best-heard gateway per DevAddr, shared with the DownlinkSender
+    best_gateways: BestGateways,
+    /// JoinRequests awaiting their JoinAccept, shared with the DownlinkSender
+    joins: PendingJoins,
+    /// Log pokes and downlinks instead of sending them (`general.dry_run`)
+    dry_run: bool,
+    /// Groups copies of an uplink from several gateways (`udp.dedup_window_ms`)
+    receptions: Option<ReceptionWindow>,
+}
+
+impl PacketContext {
+    fn new(
+        config: &Config,
+        pokes: PokeRouter,
+        gateway: GatewayTracker,
+        packet_log: Option<PacketLog>,
+        capture: Option<Capture>,
+    ) -> anyhow::Result<Self> {
+        let downlink_counters = match &config.lorawan.state_dir {
+            Some(dir) => DownlinkCounters::load(&Path::new(dir).join(DOWNLINK_COUNTERS_FILE))?,
+            None => DownlinkCounters::new(),
+        };
+        let duty_cycle =
+            DutyCycleLimiter::from_config(&config.duty_cycle, config.channel_plan.region);
+        Ok(Self {
+            pokes,
+            gateway,
+            gateways_seen: GatewaysSeen::new(),
+            devices: DeviceRegistry::new(),
+            uplink_metrics: UplinkMetrics::new(),
+            tx_acks: PendingTxAcks::default(),
+            loop_guard: LoopGuard::default(),
+            gateway_filter: GatewayFilter::new(
+                &config.udp.allowed_gateways,
+                &config.udp.denied_gateways,
+            )?,
+            gateway_names: GatewayNames::from_config(&config.gateways),
+            classifier: SourceClassifier::new(&config.udp.helium_sources)?,
+            codecs: CodecRegistry::from_config(&config.lorawan)?,
+            keys: Arc::new(std::sync::RwLock::new(KeyStore::from_config(
+                &config.lorawan.devices,
+            )?)),
+            fcnt_tracker: Arc::new(std::sync::Mutex::new(FrameCounterTracker::new(
+                config.lorawan.fcnt_reset_tolerance,
+            ))),
+            accept_net_ids: config.lorawan.accept_net_ids.clone(),
+            packet_log,
+            capture,
+            class_b: Default::default(),
+            duty_cycle: duty_cycle.map(|limiter| Arc::new(std::sync::Mutex::new(limiter))),
+            channel_plan: ChannelPlan::from_config(&config.channel_plan)?
+                .with_gateways(&config.gateways)
+                .with_downlink(&config.downlink),
+            auto_ack_confirmed: config.lorawan.auto_ack_confirmed,
+            reject_unknown_major: config.lorawan.reject_unknown_major,
+            accept_protocol_v1: config.udp.accept_protocol_v1,
+            max_json_len: config.udp.max_json_len,
+            downlink_counters: Arc::new(std::sync::Mutex::new(downlink_counters)),
+            confirmed: ConfirmedDownlinks::new(config.downlink.confirmed_retries.unwrap_or(0)),
+            best_gateways: BestGateways::new(),
+            joins: PendingJoins::new(),
+            dry_run: config.general.dry_run,
+            receptions: (config.udp.dedup_window_ms > 0).then(|| {
+                ReceptionWindow::new(Duration::from_millis(config.udp.dedup_window_ms))
+            }),
+        })
+    }
+
+    /// Send an action to the Airlock task of every Urbit target it routes to
+    async fn forward(&self, action: LoRaAction, gateway_eui: &str) {
+        forward_action(&self.pokes, &self.uplink_metrics, self.dry_run, action, gateway_eui).await;
+    }
+
+    /// Forward `packet` once its reception window closes, with every
+    /// gateway's reception of `phy_payload`
+    fn forward_grouped(
+        &self,
+        window: &ReceptionWindow,
+        mut packet: LoRaPacket,
+        phy_payload: &[u8],
+        gateway_eui: &str,
+    ) {
+        let window = window.clone();
+        let pokes = self.pokes.clone();
+        let metrics = self.uplink_metrics.clone();
+        let dry_run = self.dry_run;
+        let phy_payload = phy_payload.to_vec();
+        let gateway_eui = gateway_eui.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(window.window()).await;
+            packet.receptions = window.close(&phy_payload);
+            if packet.receptions.len() > 1 {
+                debug!(
+                    "  DevAddr {} FCnt {} heard by {} gateways",
+                    packet.dev_addr,
+                    packet.fcnt,
+                    packet.receptions.len()
+                );
+            }
+            let action = LoRaAction::Uplink(packet);
+            forward_action(&pokes, &metrics, dry_run, action, &gateway_eui).await;
+        });
+    }
+
+    /// MAC status from an FPort 0 uplink's FRMPayload, decrypted with its NwkSKey
+    fn port0_mac(&self, frame: &LoRaWANFrame) -> Option<MacStatus> {
+        let LoRaWANFrame::Data {
+            dev_addr,
+            fcnt,
+            f_port: Some(0),
+            frm_payload,
+            ..
+        } = frame
+        else {
+            return None;
+        };
+        let fcnt = self
+            .fcnt_tracker
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .reconstruct(*dev_addr, *fcnt);
+        let Some(plain) = self
+            .keys
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .decrypt_mac_payload(*dev_addr, fcnt, frm_payload)
+        else {
+            debug!("  FPort 0 MAC commands from {:08X}: no NwkSKey to decrypt", dev_addr);
+            return None;
+        };
+        let commands = mac::parse_uplink_commands(&plain);
+        info!("  FPort 0 MAC commands from {:08X}: {:?}", dev_addr, commands);
+        MacStatus::from_commands(&commands)
+    }
+
+    /// Whether `frame` is another gateway's copy of an uplink already being grouped
+    fn is_grouped_copy(
+        &self,
+        frame: &LoRaWANFrame,
+        phy_payload: &[u8],
+        rxpk: &Rxpk,
+        gateway_eui: &str,
+    ) -> bool {
+        let Some(window) = &self.receptions else {
+            return false;
+        };
+        if !matches!(
+            frame,
+            LoRaWANFrame::Data {
+                mtype: MType::UnconfirmedDataUp | MType::ConfirmedDataUp,
+                ..
+            }
+        ) {
+            return false;
+        }
+        let reception = receptions::reception(rxpk, gateway_eui);
+        !window.add(phy_payload, reception, Instant::now())
+    }
+
+    /// Count a datagram or frame that will not reach Urbit
+    fn record_drop(&self, reason: DropReason) {
+        self.uplink_metrics.record_drop(reason);
+        debug!("  Dropped: {}", reason);
+    }
+
+    /// Send a datagram from the server socket, capturing it if enabled
+    async fn send_to(
+        &self,
+        socket: &UdpSocket,
+        data: &[u8],
+        dest: SocketAddr,
+    ) -> std::io::Result<()> {
+        socket.send_to(data, dest).await?;
+        if let Some(capture) = &self.capture {
+            capture.record(Direction::Sent, dest, data);
+        }
+        Ok(())
+    }
+
+    /// Queue a decoded data frame for the packet log
+    fn log_uplink(
+        &self,
+        log: &PacketLog,
+        frame: &LoRaWANFrame,
+        rxpk: &Rxpk,
+        gateway_eui: &str,
+        source: PacketSource,
+        phy_payload: &[u8],
+    ) {
+        let packet = frame_to_lora_packet(
+            frame,
+            rxpk,
+            gateway_eui,
+            source,
+            &self.codecs,
+            &self.keys,
+        );
+        let Some(packet) = packet else {
+            return;
+        };
+        log.record(PacketLogEntry {
+            packet,
+            phy: hex::encode(phy_payload),
+        });
+    }
+
+    /// True if a data frame's DevAddr belongs to a NetID we don't accept
+    fn is_foreign(&self, frame: &LoRaWANFrame) -> bool {
+        let LoRaWANFrame::Data { dev_addr, .. } = frame else {
+            return false;
+        };
+        if self.accept_net_ids.is_empty()
+            || self.accept_net_ids.iter().any(|net_id| net_id.contains(*dev_addr))
+        {
+            return false;
+        }
+        debug!("  DevAddr {:08X} is outside lorawan.accept_net_ids; dropped", dev_addr);
+        true
+    }
+
+    /// Answer a confirmed uplink with an empty ACK downlink in its RX1 window
+    ///
+    /// Application downlinks leave the outbox as soon as they are polled
+    /// rather than waiting for an uplink, so the ACK never has a payload to
+    /// piggyback on.
+    ///
+    /// RX1 falls back to RX2 on a TOO_LATE TX_ACK; that exchange runs in its
+    /// own task so the receive loop (which resolves the TX_ACK) keeps going.
+    /// Only uplinks whose MIC verified are ACKed, so spoofed frames can't
+    /// spend the device's downlink FCnts or airtime.
+    /// Returns whether an ACK went out (or was handed to that task).
+    async fn auto_ack(
+        &self,
+        socket: &Arc<UdpSocket>,
+        frame: &LoRaWANFrame,
+        rxpk: &Rxpk,
+        gateway_eui: &str,
+        mic: MicStatus,
+    ) -> bool {
+        let LoRaWANFrame::Data {
+            mtype: MType::ConfirmedDataUp,
+            dev_addr,
+            ..
+        } = frame
+        else {
+            return false;
+        };
+        if !self.auto_ack_confirmed {
+            return false;
+        }
+        if mic != MicStatus::Verified {
+            debug!("  MIC of {:08X}'s uplink not verified; not ACKing", dev_addr);
+            return false;
+        }
+        // Without a session the ACK's MIC would be zeros, which no device accepts
+        let session = {
+            let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+            keys.lookup(*dev_addr).first().map(|session| (*session).clone())
+        };
+        let Some(session) = session else {
+            debug!("  No session keys for {:08X}; not ACKing", dev_addr);
+            return false;
+        };
+
+        // Consumed even if sending fails: a skipped FCnt is harmless, a reused one is not
+        let fcnt = self
+            .downlink_counters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .next(*dev_addr);
+        let plan = &self.channel_plan;
+        let txpks = match build_ack_txpks(plan, Some(gateway_eui), rxpk, &session, fcnt) {
+            Ok(txpks) => txpks,
+            Err(e) => {
+                warn!("  Could not build an ACK to {:08X}: {}", dev_addr, e);
+                return false;
+            }
+        };
+        // RX1 and RX2 are timed on the receiving gateway's counter
+        let sender = self.downlink_sender(socket.clone()).pinned(gateway_eui).await;
+        let dev_addr = *dev_addr;
+        let send = async move {
+            match sender.send_class_a(&txpks, ACK_TX_ACK_TIMEOUT).await {
+                Ok(_) if sender.dry_run => {}
+                Ok(TxResult::Error(e)) => {
+                    warn!("  Failed to ACK confirmed uplink from {:08X}: {}", dev_addr, e)
+                }
+                Ok(_) => info!("  ACK sent to {:08X} (FCnt {})", dev_addr, fcnt),
+                Err(e) => warn!("  Failed to ACK confirmed uplink from {:08X}: {}", dev_addr, e),
+            }
+        };
+        // Dry runs return at once; logging inline keeps their output in order
+        match self.dry_run {
+            true => send.await,
+            false => {
+                tokio::spawn(send);
+            }
+        }
+        true
+    }
+
+    /// Resend a confirmed downlink the uplink's sender hasn't ACKed yet
+    ///
+    /// The same frame goes out in the uplink's RX1 window, or RX2 if RX1 is
+    /// too late, through the gateway that heard the uplink. A confirmed uplink
+    /// whose auto-ACK was sent (`ack_sent`) leaves it for the next uplink:
+    /// the device stops listening once the ACK arrives in RX1. An uplink
+    /// whose MIC failed is ignored, and only a verified one's ACK bit counts:
+    /// anyone can send a frame with the DevAddr and ACK set.
+    async fn retransmit_confirmed(
+        &self,
+        socket: &Arc<UdpSocket>,
+        frame: &LoRaWANFrame,
+        rxpk: &Rxpk,
+        gateway_eui: &str,
+        mic: MicStatus,
+        ack_sent: bool,
+    ) {
+        use base64::Engine;
+
+        let LoRaWANFrame::Data {
+            mtype: MType::UnconfirmedDataUp | MType::ConfirmedDataUp,
+            dev_addr,
+            fctrl,
+            ..
+        } = frame
+        else {
+            return;
+        };
+        let dev_addr = *dev_addr;
+        if mic == MicStatus::Failed {
+            return;
+        }
+        let ack = fctrl.ack && mic == MicStatus::Verified;
+        if ack_sent && !ack {
+            if let Some(msg_id) = self.confirmed.pending(dev_addr) {
+                debug!("  Confirmed msg #{} to {:08X} waits for the next uplink", msg_id, dev_addr);
+            }
+            return;
+        }
+        let Some(resend) = self.confirmed.on_uplink(dev_addr, ack, Instant::now()) else {
+            if ack {
+                debug!("  DevAddr {:08X} ACKed", dev_addr);
+            }
+            return;
+        };
+        let payload_b64 = base64::engine::general_purpose::STANDARD.encode(&resend);
+        let size = resend.len() as u16;
+        let delay = self.keys.read().unwrap_or_else(|e| e.into_inner()).rx1_delay_secs(dev_addr);
+        let txpks = ClassATxpks::build(
+            &self.channel_plan,
+            Some(gateway_eui),
+            rxpk,
+            delay,
+            &payload_b64,
+            size,
+        );
+        let sender = self.downlink_sender(socket.clone()).pinned(gateway_eui).await;
+        let send = async move {
+            match sender.send_class_a(&txpks, ACK_TX_ACK_TIMEOUT).await {
+                Ok(TxResult::Error(e)) => {
+                    warn!("  Failed to resend confirmed downlink to {:08X}: {}", dev_addr, e)
+                }
+                Ok(_) => info!("  Confirmed downlink resent to {:08X} (no ACK)", dev_addr),
+                Err(e) => warn!("  Failed to resend confirmed downlink to {:08X}: {}", dev_addr, e),
+            }
+        };
+        match self.dry_run {
+            true => send.await,
+            false => {
+                tokio::spawn(send);
+            }
+        }
+    }
+
+    /// Let a dropped copy of a data uplink improve its device's gateway hint
+    fn record_copy(&self, frame: &LoRaWANFrame, rxpk: &Rxpk, gateway_eui: &str) {
+        if let LoRaWANFrame::Data { dev_addr, fcnt, .. } = frame {
+            self.best_gateways.record_copy(*dev_addr, *fcnt as u32, gateway_eui, rxpk.rssi);
+        }
+    }
+
+    /// A sender sharing this context's gateway, TX_ACKs, budget and capture
+    fn downlink_sender(&self, socket: Arc<UdpSocket>) -> DownlinkSender {
+        DownlinkSender {
+            socket,
+            gateway: self.gateway.clone(),
+            tx_acks: self.tx_acks.clone(),
+            loop_guard: self.loop_guard.clone(),
+            duty_cycle: self.duty_cycle.clone(),
+            capture: self.capture.clone(),
+            class_b: self.class_b.clone(),
+            dry_run: self.dry_run,
+            region: self.channel_plan.region,
+            best_gateways: self.best_gateways.clone(),
+            joins: self.joins.clone(),
+            #[cfg(feature = "helium-grpc")]
+            packet_router: None,
+        }
+    }
+
+    /// B1 block inputs of a LoRaWAN 1.1 uplink MIC
+    ///
+    /// An ACK acknowledges the last confirmed downlink sent to the device
+    /// (0 if none was since startup); B1 carries the low 16 bits of its FCnt.
+    fn b1_params(&self, dev_addr: u32, ack: bool, rxpk: &Rxpk) -> B1Params {
+        let conf_fcnt = match ack {
+            true => {
+                let counters = self.downlink_counters.lock().unwrap_or_else(|e| e.into_inner());
+                counters.last_confirmed(dev_addr).unwrap_or(0) as u16
+            }
+            false => 0,
+        };
+        B1Params {
+            conf_fcnt,
+            tx_dr: self.channel_plan.uplink_dr(rxpk.datr.clone()).unwrap_or(0),
+            tx_ch: self.channel_plan.uplink_channel(rxpk.freq).unwrap_or(0),
+        }
+    }
+
+    /// Whether `frame` is another gateway's copy of the device's last accepted
+    /// uplink, which arrived too late (or without `udp.dedup_window_ms`) to be grouped
+    fn is_late_copy(&self, frame: &LoRaWANFrame, phy_payload: &[u8]) -> bool {
+        let LoRaWANFrame::Data { dev_addr, .. } = frame else {
+            return false;
+        };
+        let tracker = self.fcnt_tracker.lock().unwrap_or_else(|e| e.into_inner());
+        tracker.is_copy(*dev_addr, phy_payload)
+    }
+
+    /// Frames lost just before `frame`, if its FCnt skipped ahead
+    fn missed_frames(&self, frame: &LoRaWANFrame) -> Option<u32> {
+        let LoRaWANFrame::Data { dev_addr, .. } = frame else {
+            return None;
+        };
+        let tracker = self.fcnt_tracker.lock().unwrap_or_else(|e| e.into_inner());
+        Some(tracker.missed(*dev_addr).last_gap).filter(|gap| *gap > 0)
+    }
+
+    /// Check an uplink's MIC and frame counter
+    ///
+    /// Returns what the MIC showed (`Unchecked` for frames other than data
+    /// uplinks), or None if the frame must be dropped as a replay.
+    fn check_uplink(
+        &self,
+        frame: &LoRaWANFrame,
+        phy_payload: &[u8],
+        rxpk: &Rxpk,
+    ) -> Option<MicStatus> {
+        let LoRaWANFrame::Data {
+            mtype: MType::UnconfirmedDataUp | MType::ConfirmedDataUp,
+            dev_addr,
+            fctrl,
+            fcnt,
+            ..
+        } = frame
+        else {
+            return Some(MicStatus::Unchecked);
+        };
+
+        let b1 = self.b1_params(*dev_addr, fctrl.ack, rxpk);
+        let mic_fcnt = self
+            .fcnt_tracker
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .mic_fcnt(*dev_addr, *fcnt);
+        let mic = {
+            let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+            if !cfg!(feature = "phase4") || keys.lookup(*dev_addr).is_empty() {
+                MicStatus::Unchecked
+            } else if keys.verify_uplink_mic(*dev_addr, phy_payload, mic_fcnt, b1) {
+                MicStatus::Verified
+            } else {
+                MicStatus::Failed
+            }
+        };
+        let mut tracker = self.fcnt_tracker.lock().unwrap_or_else(|e| e.into_inner());
+        match tracker.check(*dev_addr, *fcnt, mic) {
+            FcntCheck::Accepted(full) => {
+                tracker.record_frame(*dev_addr, phy_payload);
+                let gap = tracker.missed(*dev_addr).last_gap;
+                if gap > 0 {
+                    debug!("  DevAddr {:08X} FCnt {}: {} frames missed", dev_addr, full, gap);
+                    let dev_addr = format!("{:08X}", dev_addr);
+                    self.uplink_metrics.record_missed_frames(&dev_addr, gap as u64);
+                }
+                Some(mic)
+            }
+            FcntCheck::Unverified(full) => {
+                debug!("  DevAddr {:08X} FCnt {}: MIC failed, not recorded", dev_addr, full);
+                Some(mic)
+            }
+            FcntCheck::Reset(fcnt) => {
+                tracker.record_frame(*dev_addr, phy_payload);
+                info!("  DevAddr {:08X} reset its frame counter to {}", dev_addr, fcnt);
+                Some(mic)
+            }
+            FcntCheck::Replay { fcnt, last } => {
+                warn!(
+                    "  replay_detected: DevAddr {:08X} FCnt {} <= last accepted {} (total {})",
+                    dev_addr,
+                    fcnt,
+                    last,
+                    tracker.replays_detected()
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Send an action to the Airlock task of every Urbit target it routes to
+async fn forward_action(
+    pokes: &PokeRouter,
+    metrics: &UplinkMetrics,
+    dry_run: bool,
+    action: LoRaAction,
+    gateway_eui: &str,
+) {
+    let targets: Vec<_> = pokes.route_for(&action, gateway_eui).collect();
+    if targets.is_empty() {
+        debug!("  No Urbit target matches; not forwarded");
+        metrics.record_drop(DropReason::NoRoute);
+    } else if dry_run {
+        let json = serde_json::to_string(&action).unwrap_or_default();
+        info!("  [dry run] Would poke {} target(s): {}", targets.len(), json);
+        return;
+    }
+    for tx in targets {
+        if let Err(e) = tx.send(action.clone()).await {
+            error!("Failed to forward packet to Airlock task: {}", e);
+        }
+    }
+}
+
+/// Capture and parse one received datagram, then handle it
+async fn handle_datagram(
+    socket: &Arc<UdpSocket>,
+    src: SocketAddr,
+    data: &[u8],
+    ctx: &PacketContext,
+) {
+    debug!("Received {} bytes from {}", data.len(), src);
+    if let Some(capture) = &ctx.capture {
+        capture.record(Direction::Received, src, data);
+    }
+    match GwmpPacket::parse_versioned(data, ctx.accept_protocol_v1, ctx.max_json_len) {
+        Ok((version, packet)) => handle_packet(socket, src, version, packet, ctx).await,
+        Err(e) if e.is::<PayloadTooLong>() => {
+            warn!("Dropped datagram from {}: {}", src, e);
+            ctx.record_drop(DropReason::Oversized);
+        }
+        Err(e) => {
+            warn!("Failed to parse GWMP packet from {}: {}", src, e);
+            ctx.record_drop(DropReason::ParseError);
+        }
+    }
+}
+
+/// Handle a parsed datagram; replies use the sender's GWMP `version`
+async fn handle_packet(
+    socket: &Arc<UdpSocket>,
+    src: SocketAddr,
+    version: u8,
+    packet: GwmpPacket,
+    ctx: &PacketContext,
+) {
+    // Untrusted gateways are neither acknowledged nor used for downlinks
+    if let GwmpPacket::PushData { gateway_eui, .. }
+    | GwmpPacket::PullData { gateway_eui, .. }
+    | GwmpPacket::TxAck { gateway_eui, .. } = &packet
+    {
+        if let Err(dropped) = ctx.gateway_filter.check(gateway_eui) {
+            warn!(
+                "Dropped datagram from gateway {} at {}: not allowed ({} dropped so far)",
+                ctx.gateway_names.display(&hex::encode(gateway_eui)),
+                src,
+                dropped
+            );
+            ctx.record_drop(DropReason::GatewayDenied);
+            return;
+        }
+    }
+
+    match packet {
+        GwmpPacket::PushData {
+            random_token,
+            gateway_eui,
+            json_payload,
+        } => {
+            let gw_eui_hex = hex::encode(gateway_eui);
+            handle_push_data(socket, src, version, random_token, &gw_eui_hex, &json_payload, ctx)
+                .await;
+        }
+        GwmpPacket::PullData {
+            random_token,
+            gateway_eui,
+        } => {
+            let gw_eui_hex = hex::encode(gateway_eui);
+            debug!(
+                "PULL_DATA from gateway {} (token: 0x{:04x})",
+                ctx.gateway_names.display(&gw_eui_hex),
+                random_token
+            );
+
+            // Track the gateway address for downlink delivery
+            ctx.gateway.set_pulled(socket, src, &gw_eui_hex, version).await;
+            ctx.gateways_seen.record(&gw_eui_hex);
+
+            let ack = GwmpPacket::with_version(GwmpPacket::pull_ack(random_token), version);
+            if let Err(e) = ctx.send_to(socket, &ack, src).await {
+                error!("Failed to send PULL_ACK to {}: {}", src, e);
+            }
+        }
+        GwmpPacket::TxAck {
+            random_token,
+            gateway_eui,
+            json_payload,
+        } => {
+            let gw_eui_hex = hex::encode(gateway_eui);
+            let gw_name = ctx.gateway_names.display(&gw_eui_hex);
+
+            let ack = TxpkAck::parse(json_payload.as_deref());
+            let result = TxResult::from(&ack);
+            let value = ack.value.as_ref().map(|v| format!(" (value {})", v)).unwrap_or_default();
+            match &result {
+                TxResult::Error(err) => warn!(
+                    "TX_ACK from gateway {} (token: 0x{:04x}): ERROR: {}{}",
+                    gw_name, random_token, err, value
+                ),
+                _ => info!(
+                    "TX_ACK from gateway {} (token: 0x{:04x}): SUCCESS",
+                    gw_name, random_token
+                ),
+            }
+            if let Some(warning) = &ack.warn {
+                warn!(
+                    "TX_ACK from gateway {} (token: 0x{:04x}): WARNING: {}{}",
+                    gw_name, random_token, warning, value
+                );
+            }
+            if !ctx.tx_acks.resolve(random_token, result) {
+                debug!("TX_ACK token 0x{:04x} has no pending downlink", random_token);
+            }
+        }
+        GwmpPacket::PushAck { random_token } => {
+            debug!("PUSH_ACK (token: 0x{:04x})", random_token);
+        }
+        GwmpPacket::PullAck { random_token } => {
+            debug!("PULL_ACK (token: 0x{:04x})", random_token);
+        }
+        GwmpPacket::PullResp {
+            random_token,
+            json_payload,
+        } => {
+            // Downlinks only ever leave the bridge; never act on one sent to it
+            let (origin, warning) = ctx.loop_guard.check(random_token, Instant::now());
+            let why = match origin {
+                PullRespOrigin::Echo => "our own downlink looped back",
+                PullRespOrigin::Foreign => "the bridge does not accept downlinks",
+            };
+            match warning {
+                Warning::Due { suppressed } => warn!(
+                    "Dropped PULL_RESP from {} (token: 0x{:04x}, {} bytes): {} \
+                     ({} more dropped since the last warning)",
+                    src,
+                    random_token,
+                    json_payload.len(),
+                    why,
+                    suppressed
+                ),
+                Warning::Suppressed => debug!(
+                    "Dropped PULL_RESP from {} (token: 0x{:04x}): {}",
+                    src, random_token, why
+                ),
+            }
+        }
+    }
+}
+
+/// ACK a PUSH_DATA from gateway `gw_eui_hex` and handle each frame it carries
+async fn handle_push_data(
+    socket: &Arc<UdpSocket>,
+    src: SocketAddr,
+    version: u8,
+    random_token: u16,
+    gw_eui_hex: &str,
+    json_payload: &str,
+    ctx: &PacketContext,
+) {
+    ctx.gateways_seen.record(gw_eui_hex);
+    let source = ctx.classifier.classify_source(gw_eui_hex, src);
+    info!(
+        "PUSH_DATA from gateway {} (token: 0x{:04x}, source: {:?})",
+        ctx.gateway_names.display(gw_eui_hex),
+        random_token,
+        source
+    );
+
+    // Send ACK immediately
+    let ack = GwmpPacket::with_version(GwmpPacket::push_ack(random_token), version);
+    if let Err(e) = ctx.send_to(socket, &ack, src).await {
+        error!("Failed to send PUSH_ACK to {}: {}", src, e);
+    }
+
+    // Some gateways send no JSON at all between status reports
+    if json_payload.trim().is_empty() {
+        debug!("  Empty PUSH_DATA payload (keepalive)");
+        return;
+    }
+
+    // Parse the JSON payload, salvaging what rxpks it can if that fails
+    let parsed = serde_json::from_str::<PushDataPayload>(json_payload)
+        .or_else(|e| salvage_push_data(json_payload, e));
+    let payload = match parsed {
+        Ok(payload) => payload,
+        Err(e) => {
+            warn!("Failed to parse PUSH_DATA JSON: {}", e);
+            ctx.record_drop(DropReason::ParseError);
+            debug!("  Raw JSON: {}", json_payload);
+            return;
+        }
+    };
+    for (rxpk, duplicates) in group_receptions(payload.rxpk.unwrap_or_default()) {
+        handle_rxpk(socket, &rxpk, &duplicates, gw_eui_hex, &source, ctx).await;
+    }
+    if let Some(stat) = payload.stat {
+        debug!("  Gateway status: {}", stat);
+    }
+}
+
+/// Decode one received frame (`rxpk`, the strongest of its `duplicates`),
+/// forward it to Urbit and answer it
+async fn handle_rxpk(
+    socket: &Arc<UdpSocket>,
+    rxpk: &Rxpk,
+    duplicates: &[Rxpk],
+    gw_eui_hex: &str,
+    source: &PacketSource,
+    ctx: &PacketContext,
+) {
+    if rxpk.is_fsk() {
+        info!(
+            "  rxpk: FSK freq={} MHz, rssi={} dBm, bitrate={} bps, size={} bytes",
+            rxpk.freq, rxpk.rssi, rxpk.datr, rxpk.size
+        );
+    } else {
+        info!(
+            "  rxpk: freq={} MHz, rssi={} dBm, datr={}, size={} bytes",
+            rxpk.freq, rxpk.rssi, rxpk.datr, rxpk.size
+        );
+    }
+    for dup in duplicates {
+        debug!("  duplicate reception: freq={} MHz, rssi={} dBm (merged)", dup.freq, dup.rssi);
+        ctx.record_drop(DropReason::DuplicateReception);
+    }
+
+    // GPS-synchronized gateways let us time Class B ping slots
+    if let (Some(tmst), Some(tmms)) = (rxpk.tmst, rxpk.tmms) {
+        let mut class_b = ctx.class_b.lock().unwrap_or_else(|e| e.into_inner());
+        class_b.update_reference(gw_eui_hex, tmst as u32, tmms, Instant::now());
+    }
+
+    // Decode the LoRaWAN PHY payload
+    let phy_payload = match base64_decode(&rxpk.data) {
+        Ok(phy_payload) => phy_payload,
+        Err(e) => {
+            warn!("  Failed to base64 decode rxpk data: {}", e);
+            ctx.record_drop(DropReason::BadPayload);
+            return;
+        }
+    };
+    let decoded = match ctx.reject_unknown_major {
+        true => lorawan::decode_phy_payload_strict(&phy_payload),
+        false => lorawan::decode_phy_payload(&phy_payload),
+    };
+    let frame = match decoded {
+        Ok(frame) => frame,
+        Err(e @ lorawan::DecodeError::Unsupported(_)) => {
+            info!("  Skipping frame: {}", e);
+            ctx.record_drop(DropReason::Unsupported);
+            return;
+        }
+        Err(e) => {
+            warn!("  Failed to decode LoRaWAN frame: {}", e);
+            ctx.record_drop(DropReason::Undecodable);
+            return;
+        }
+    };
+    info!("  LoRaWAN: {}", frame);
+    if let LoRaWANFrame::Data {
+        major: major @ lorawan::Major::Unknown(_),
+        dev_addr,
+        ..
+    } = &frame
+    {
+        warn!("  DevAddr {:08X} sent reserved Major {}", dev_addr, major);
+    }
+
+    if ctx.is_foreign(&frame) {
+        ctx.record_drop(DropReason::ForeignNetId);
+        return;
+    }
+    if ctx.is_grouped_copy(&frame, &phy_payload, rxpk, gw_eui_hex) {
+        debug!("  Copy from another gateway (grouped)");
+        ctx.record_copy(&frame, rxpk, gw_eui_hex);
+        ctx.record_drop(DropReason::DuplicateReception);
+        return;
+    }
+    if ctx.is_late_copy(&frame, &phy_payload) {
+        debug!("  Copy from another gateway");
+        ctx.record_copy(&frame, rxpk, gw_eui_hex);
+        ctx.record_drop(DropReason::DuplicateReception);
+        return;
+    }
+    let Some(mic) = ctx.check_uplink(&frame, &phy_payload, rxpk) else {
+        ctx.record_copy(&frame, rxpk, gw_eui_hex);
+        ctx.record_drop(DropReason::Replay);
+        return;
+    };
+
+    if let LoRaWANFrame::Data { dev_addr, fcnt, .. } = &frame {
+        ctx.best_gateways.record_uplink(*dev_addr, *fcnt as u32, gw_eui_hex, rxpk.rssi);
+        ctx.devices.record(&Sighting {
+            dev_addr: *dev_addr,
+            fcnt: *fcnt as u32,
+            rssi: rxpk.rssi,
+            snr: rxpk.lsnr,
+            gateway_eui: gw_eui_hex,
+            path: UplinkPath::Gwmp,
+        });
+        ctx.uplink_metrics.observe(rxpk.rssi, rxpk.lsnr);
+        ctx.uplink_metrics.record_gateway_uplink(ctx.gateway_names.display(gw_eui_hex));
+    }
+
+    if let LoRaWANFrame::JoinRequest { dev_nonce, .. } = &frame {
+        ctx.joins.record(*dev_nonce, rxpk, gw_eui_hex, Instant::now());
+    }
+
+    if let Some(log) = &ctx.packet_log {
+        ctx.log_uplink(log, &frame, rxpk, gw_eui_hex, source.clone(), &phy_payload);
+    }
+
+    // Forward to the matching Urbit targets
+    if !ctx.pokes.is_empty() {
+        let action =
+            frame_to_action(&frame, rxpk, gw_eui_hex, source.clone(), &ctx.codecs, &ctx.keys);
+        if let Some(mut action) = action {
+            if let LoRaAction::Uplink(packet) = &mut action {
+                if packet.f_port == Some(0) {
+                    packet.mac = ctx.port0_mac(&frame);
+                }
+                packet.missed_frames = ctx.missed_frames(&frame);
+                packet.alt_receptions = duplicates
+                    .iter()
+                    .map(|dup| AltReception {
+                        rssi: dup.rssi,
+                        snr: dup.lsnr,
+                        freq: dup.freq,
+                    })
+                    .collect();
+            }
+            match (&ctx.receptions, action) {
+                (Some(window), LoRaAction::Uplink(packet)) => {
+                    ctx.forward_grouped(window, packet, &phy_payload, gw_eui_hex)
+                }
+                (_, action) => ctx.forward(action, gw_eui_hex).await,
+            }
+        }
+    }
+
+    let ack_sent = ctx.auto_ack(socket, &frame, rxpk, gw_eui_hex, mic).await;
+    ctx.retransmit_confirmed(socket, &frame, rxpk, gw_eui_hex, mic, ack_sent).await;
+}
+
+/// The rxpks recovered from PUSH_DATA JSON that failed with `err`, or `err` if none
+fn salvage_push_data(
+    json: &str,
+    err: serde_json::Error,
+) -> Result<PushDataPayload, serde_json::Error> {
+    let salvaged = protocol::salvage_rxpks(json);
+    if salvaged.rxpks.is_empty() {
+        return Err(err);
+    }
+    warn!(
+        "Invalid PUSH_DATA JSON ({}); salvaged {} rxpk(s), first bad entry at byte {}",
+        err,
+        salvaged.rxpks.len(),
+        salvaged.failed_at.map_or("-".to_string(), |at| at.to_string())
+    );
+    Ok(PushDataPayload {
+        rxpk: Some(salvaged.rxpks),
+        stat: None,
+    })
+}
+
+/// Group a PUSH_DATA's rxpks carrying the same PHY payload
+///
+/// A gateway with several antennas or overlapping channels may report one
+/// frame more than once. Each group is the strongest reception (by RSSI)
+/// and the other copies, in order of first appearance.
+fn group_receptions(rxpks: Vec<Rxpk>) -> Vec<(Rxpk, Vec<Rxpk>)> {
+    let mut groups: Vec<(Rxpk, Vec<Rxpk>)> = Vec::new();
+    for rxpk in rxpks {
+        match groups.iter_mut().find(|(best, _)| best.data == rxpk.data) {
+            Some((best, duplicates)) if rxpk.rssi > best.rssi => {
+                duplicates.push(std::mem::replace(best, rxpk));
+            }
+            Some((_, duplicates)) => duplicates.push(rxpk),
+            None => groups.push((rxpk, Vec::new())),
+        }
+    }
+    groups
+}
+
+/// Convert a decoded LoRaWAN frame into the poke to send to Urbit
+///
+/// Data frames become `uplink` pokes; JoinRequests become `join-request`
+/// pokes so the agent can decide whether to accept the device, and
+/// RejoinRequests `rejoin-request` pokes.
+pub(crate) fn frame_to_action(
+    frame: &LoRaWANFrame,
+    rxpk: &Rxpk,
+    gateway_eui: &str,
+    source: PacketSource,
+    codecs: &CodecRegistry,
+    keys: &SharedKeyStore,
+) -> Option<LoRaAction> {
+    match frame {
+        LoRaWANFrame::JoinRequest {
+            app_eui,
+            dev_eui,
+            dev_nonce,
+            ..
+        } => Some(LoRaAction::JoinRequest {
+            app_eui: format!("{:016X}", app_eui),
+            dev_eui: format!("{:016X}", dev_eui),
+            dev_nonce: *dev_nonce,
+        }),
+        LoRaWANFrame::RejoinRequest {
+            rejoin_type,
+            net_id,
+            join_eui,
+            dev_eui,
+            rj_count,
+            ..
+        } => Some(LoRaAction::RejoinRequest {
+            rejoin_type: *rejoin_type,
+            net_id: net_id.map(|id| format!("{:06X}", id)),
+            join_eui: join_eui.map(|eui| format!("{:016X}", eui)),
+            dev_eui: format!("{:016X}", dev_eui),
+            rj_count: *rj_count,
+        }),
+        _ => frame_to_lora_packet(frame, rxpk, gateway_eui, source, codecs, keys)
+            .map(LoRaAction::Uplink),
+    }
+}
+
+/// Convert a decoded LoRaWAN frame + rxpk metadata into a LoRaPacket for Urbit
+///
+/// An FPort 0 FRMPayload holds encrypted MAC commands, not application data,
+/// so it is not forwarded as `payload`; `PacketContext::port0_mac` decrypts
+/// it into `mac` where keys allow. `class` is the device's as provisioned in
+/// `keys`.
+pub(crate) fn frame_to_lora_packet(
+    frame: &LoRaWANFrame,
+    rxpk: &Rxpk,
+    gateway_eui: &str,
+    source: PacketSource,
+    codecs: &CodecRegistry,
+    keys: &SharedKeyStore,
+) -> Option<LoRaPacket> {
+    match frame {
+        LoRaWANFrame::Data {
+            mtype,
+            dev_addr,
+            fcnt,
+            f_opts,
+            f_port,
+            frm_payload,
+            ..
+        } => Some(LoRaPacket {
+            dev_addr: format!("{:08X}", dev_addr),
+            fcnt: *fcnt,
+            f_port: *f_port,
+            payload: match f_port {
+                Some(0) => String::new(),
+                _ => hex::encode(frm_payload),
+            },
+            rssi: rxpk.rssi,
+            snr: rxpk.lsnr,
+            freq: rxpk.freq,
+            data_rate: rxpk.datr.clone(),
+            gateway_eui: gateway_eui.to_string(),
+            received_at: chrono::Utc::now(),
+            mtype: mtype.to_string(),
+            source,
+            decoded: decode_payload(codecs, *dev_addr, *f_port, frm_payload),
+            alt_receptions: Vec::new(),
+            mac: MacStatus::from_commands(&mac::parse_uplink_commands(f_opts)),
+            tmst: rxpk.tmst,
+            tmms: rxpk.tmms,
+            chan: rxpk.chan,
+            receptions: Vec::new(),
+            missed_frames: None,
+            class: keys.read().unwrap_or_else(|e| e.into_inner()).class(*dev_addr),
+        }),
+        // JoinAccept, Proprietary — skip for now
+        _ => {
+            debug!("Skipping non-data frame for Urbit forwarding");
+            None
+        }
+    }
+}
+
+/// Run the device's payload codec over an application (FPort > 0) payload
+///
+/// Decode failures are logged and leave `decoded` empty; the raw hex payload
+/// is always forwarded.
+fn decode_payload(
+    codecs: &CodecRegistry,
+    dev_addr: u32,
+    f_port: Option<u8>,
+    frm_payload: &[u8],
+) -> Option<serde_json::Value> {
+    let f_port = f_port.filter(|&port| port > 0)?;
+    match codecs.decode(dev_addr, f_port, frm_payload)? {
+        Ok(value) => Some(value),
+        Err(e) => {
+            let codec = codecs.codec_for(dev_addr).map(|c| c.name()).unwrap_or("-");
+            warn!("  Payload codec {} failed for {:08X}: {}", codec, dev_addr, e);
+            None
+        }
+    }
+}
+
+fn base64_decode(input: &str) -> anyhow::Result<Vec<u8>> {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD
+        .decode(input)
+        .map_err(|e| anyhow::anyhow!("Base64 decode error: {}", e))
+}
+
+/// Build a Txpk for a downlink transmission
+///
+/// Sent on the channel plan's RX2 frequency and data rate (US915 default:
+/// 923.3 MHz, SF12BW500, 27 dBm), at the `[[gateways]]` TX power of
+/// `gateway_eui` if it has one, timed by `downlink.mode`:
+///
+/// - immediate (Class C): `imme` set, no `tmst`; the `[downlink]` data rate
+///   replaces the RX2 one
+/// - rx1: one second after the `reference` uplink being answered, on the RX1
+///   frequency and data rate derived from it
+/// - rx2: two seconds after the `reference` uplink, on the RX2 channel
+///
+/// rx1/rx2 fail without a reference uplink (or one without a `tmst`). The
+/// `[downlink]` coding rate replaces 4/5 in every mode.
+pub fn build_txpk(
+    plan: &ChannelPlan,
+    gateway_eui: Option<&str>,
+    reference: Option<&Rxpk>,
+    payload_b64: &str,
+    payload_size: u16,
+) -> anyhow::Result<Txpk> {
+    build_delayed_txpk(plan, gateway_eui, reference, RX1_DELAY_SECS, payload_b64, payload_size)
+}
+
+/// `build_txpk` with rx1/rx2 opening `rx1_delay_secs` after the reference uplink
+fn build_delayed_txpk(
+    plan: &ChannelPlan,
+    gateway_eui: Option<&str>,
+    reference: Option<&Rxpk>,
+    rx1_delay_secs: u64,
+    payload_b64: &str,
+    payload_size: u16,
+) -> anyhow::Result<Txpk> {
+    if plan.downlink_mode == DownlinkMode::Immediate {
+        return Ok(immediate_txpk(plan, gateway_eui, payload_b64, payload_size));
+    }
+    let reference = reference
+        .ok_or_else(|| anyhow::anyhow!("rx1/rx2 downlink.mode needs an uplink to time from"))?;
+    let build_window = match plan.downlink_mode {
+        DownlinkMode::Rx1 => build_rx1_txpk,
+        _ => build_rx2_txpk,
+    };
+    let txpk =
+        build_window(plan, gateway_eui, reference, rx1_delay_secs, payload_b64, payload_size);
+    txpk.ok_or_else(|| {
+        anyhow::anyhow!(
+            "Reference uplink at {} MHz {} has no tmst or RX1 channel to answer in",
+            reference.freq,
+            reference.datr
+        )
+    })
+}
+
+/// The `downlink.mode = "immediate"` txpk of `build_txpk`
+fn immediate_txpk(
+    plan: &ChannelPlan,
+    gateway_eui: Option<&str>,
+    payload_b64: &str,
+    payload_size: u16,
+) -> Txpk {
+    Txpk {
+        imme: Some(true),          // Immediate TX (Class C)
+        tmst: None,                // No timestamp (immediate mode)
+        freq: plan.rx2_freq,       // Region's RX2 frequency
+        rfch: Some(0),             // RF chain 0
+        powe: Some(plan.tx_power_for(gateway_eui)),
+        modu: Some("LORA".to_string()),
+        datr: plan.downlink_datr.clone().unwrap_or_else(|| plan.rx2_datr.clone()),
+        codr: Some(plan.downlink_codr.clone().unwrap_or_else(|| "4/5".to_string())),
+        ipol: Some(true),          // Inverted polarity for downlink
+        size: payload_size,
+        data: payload_b64.to_string(),
+        ncrc: Some(true),          // No CRC for downlink
+    }
+}
+
+/// `build_txpk` for `dev_addr`, at the RX2 data rate its session agreed if any
+///
+/// Devices joined with a non-default RX2DataRate (or configured with
+/// `rx2_datr`) only listen at that rate in RX2. rx1/rx2 windows open after
+/// the device's own RX1 delay.
+pub fn build_device_txpk(
+    plan: &ChannelPlan,
+    keys: &KeyStore,
+    dev_addr: u32,
+    gateway_eui: Option<&str>,
+    reference: Option<&Rxpk>,
+    payload_b64: &str,
+    payload_size: u16,
+) -> anyhow::Result<Txpk> {
+    let delay_secs = keys.rx1_delay_secs(dev_addr);
+    let txpk =
+        build_delayed_txpk(plan, gateway_eui, reference, delay_secs, payload_b64, payload_size)?;
+    Ok(match keys.rx2_datr(dev_addr) {
+        Some(datr) => Txpk { datr, ..txpk },
+        None => txpk,
+    })
+}
+
+/// Build a data downlink to `dev_addr` on its next downlink FCnt
+///
+/// Encrypted and signed with the device's session keys if `encrypt`
+/// (`lorawan.encrypt_downlink`), otherwise, or when there are none, sent in
+/// plaintext with a zero MIC. The FCnt is reserved before building, so
+/// concurrent senders never share one; a failed build just skips it.
+pub fn build_device_frame(
+    keys: &SharedKeyStore,
+    counters: &SharedDownlinkCounters,
+    dev_addr: u32,
+    f_port: u8,
+    payload: Vec<u8>,
+    confirmed: bool,
+    encrypt: bool,
+) -> anyhow::Result<Vec<u8>> {
+    let frame = {
+        let mut counters = counters.lock().unwrap_or_else(|e| e.into_inner());
+        if confirmed {
+            let fcnt = counters.next_confirmed(dev_addr);
+            FrameBuilder::new_confirmed_downlink(dev_addr, fcnt, f_port, payload)
+        } else {
+            FrameBuilder::new_downlink(dev_addr, counters.next(dev_addr), f_port, payload)
+        }
+    };
+    let session = if encrypt {
+        let keys = keys.read().unwrap_or_else(|e| e.into_inner());
+        keys.lookup(dev_addr).first().map(|session| (*session).clone())
+    } else {
+        None
+    };
+    let bytes = match &session {
+        Some(session) => frame.build_with_mic(session)?,
+        None if !encrypt => {
+            debug!("Downlink encryption off; plaintext to {:08X} with zero MIC", dev_addr);
+            frame.build()?
+        }
+        None => {
+            debug!("No session keys for {:08X}; zero MIC", dev_addr);
+            frame.build()?
+        }
+    };
+    Ok(bytes)
+}
+
+/// Build a Class A RX1 txpk answering the uplink `rxpk`
+///
+/// Scheduled `rx_delay_secs` after the uplink's concentrator timestamp, on
+/// the RX1 frequency and data rate the channel plan derives from the uplink.
+/// None if the uplink has no `tmst` or is not on an enabled channel.
+pub fn build_rx1_txpk(
+    plan: &ChannelPlan,
+    gateway_eui: Option<&str>,
+    rxpk: &Rxpk,
+    rx_delay_secs: u64,
+    payload_b64: &str,
+    payload_size: u16,
+) -> Option<Txpk> {
+    Some(Txpk {
+        imme: Some(false),
+        tmst: Some((rxpk.tmst? + rx_delay_secs * 1_000_000) & 0xFFFF_FFFF),
+        freq: plan.rx1_freq(rxpk.freq)?,
+        datr: plan.rx1_datr(rxpk.datr.clone())?,
+        codr: plan
+            .downlink_codr
+            .clone()
+            .or_else(|| rxpk.codr.clone())
+            .or_else(|| Some("4/5".to_string())),
+        ..immediate_txpk(plan, gateway_eui, payload_b64, payload_size)
+    })
+}
+
+/// Build a Class A RX2 txpk answering the uplink `rxpk`
+///
+/// Scheduled one second after RX1, on the channel plan's RX2 frequency and
+/// data rate. None if the uplink has no `tmst`.
+pub fn build_rx2_txpk(
+    plan: &ChannelPlan,
+    gateway_eui: Option<&str>,
+    rxpk: &Rxpk,
+    rx_delay_secs: u64,
+    payload_b64: &str,
+    payload_size: u16,
+) -> Option<Txpk> {
+    Some(Txpk {
+        imme: Some(false),
+        tmst: Some((rxpk.tmst? + (rx_delay_secs + 1) * 1_000_000) & 0xFFFF_FFFF),
+        datr: plan.rx2_datr.clone(),
+        ..immediate_txpk(plan, gateway_eui, payload_b64, payload_size)
+    })
+}
+
+/// RX1 and RX2 candidates for one Class A downlink, tried in that order
+#[derive(Debug, Clone)]
+pub struct ClassATxpks {
+    pub rx1: Option<Txpk>,
+    pub rx2: Option<Txpk>,
+}
+
+impl ClassATxpks {
+    /// A downlink with only the one window `txpk` describes
+    pub fn single(txpk: &Txpk) -> Self {
+        Self {
+            rx1: Some(txpk.clone()),
+            rx2: None,
+        }
+    }
+
+    /// Both windows answering the uplink `rxpk`
+    pub fn build(
+        plan: &ChannelPlan,
+        gateway_eui: Option<&str>,
+        rxpk: &Rxpk,
+        rx_delay_secs: u64,
+        payload_b64: &str,
+        payload_size: u16,
+    ) -> Self {
+        Self {
+            rx1: build_rx1_txpk(plan, gateway_eui, rxpk, rx_delay_secs, payload_b64, payload_size),
+            rx2: build_rx2_txpk(plan, gateway_eui, rxpk, rx_delay_secs, payload_b64, payload_size),
+        }
+    }
+}
+
+/// Build RX1/RX2 txpks carrying an empty ACK (FCtrl.ACK, no FPort) to the
+/// device of session `keys`
+///
+/// The windows open the device's RX1 delay after the uplink `rxpk`. Fails
+/// if the MIC can't be computed (LoRaWAN 1.1, or no `phase4` feature) or
+/// the uplink has no `tmst` to time the windows from.
+pub fn build_ack_txpks(
+    plan: &ChannelPlan,
+    gateway_eui: Option<&str>,
+    rxpk: &Rxpk,
+    keys: &SessionKeys,
+    fcnt: u32,
+) -> anyhow::Result<ClassATxpks> {
+    use base64::Engine;
+    let frame = FrameBuilder {
+        ack: true,
+        ..FrameBuilder::new_downlink(keys.dev_addr, fcnt, 0, Vec::new())
+    }
+    .build_with_mic(keys)?;
+    let payload_b64 = base64::engine::general_purpose::STANDARD.encode(&frame);
+    let size = frame.len() as u16;
+    let delay = keys.rx1_delay_secs();
+    let txpks = ClassATxpks::build(plan, gateway_eui, rxpk, delay, &payload_b64, size);
+    if txpks.rx1.is_none() && txpks.rx2.is_none() {
+        return Err(anyhow::anyhow!("No RX1 or RX2 window to answer the uplink in"));
+    }
+    Ok(txpks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::urbit::routing::RouteRule;
+
+    #[test]
+    fn test_gateway_tracker() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let tracker = GatewayTracker::new();
+            assert!(tracker.get().await.is_none());
+
+            let addr: SocketAddr = "127.0.0.1:1700".parse().unwrap();
+            tracker.set(addr).await;
+            assert_eq!(tracker.get().await, Some(addr));
+
+            // Update with new address
+            let addr2: SocketAddr = "127.0.0.1:1701".parse().unwrap();
+            tracker.set(addr2).await;
+            assert_eq!(tracker.get().await, Some(addr2));
+        });
+    }
+
+    #[test]
+    fn test_gateway_rebinding() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let tracker = GatewayTracker::with_keepalive_window(Duration::from_secs(60));
+            let t0 = Instant::now();
+            let before: SocketAddr = "203.0.113.7:40001".parse().unwrap();
+            let after: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+
+            // The carrier NAT maps the gateway to a new port between keepalives
+            tracker.set_pulled_at(&socket, before, "aabbccddeeff0011", 2, t0).await;
+            let t1 = t0 + Duration::from_secs(30);
+            tracker.set_pulled_at(&socket, after, "aabbccddeeff0011", 2, t1).await;
+            assert_eq!(tracker.get().await, Some(after));
+            assert_eq!(tracker.rebinds(), 1);
+
+            // Same address again, or another gateway: not a rebinding
+            tracker.set_pulled_at(&socket, after, "aabbccddeeff0011", 2, t1).await;
+            let other: SocketAddr = "198.51.100.1:1700".parse().unwrap();
+            tracker.set_pulled_at(&socket, other, "0000000000000002", 2, t1).await;
+            assert_eq!(tracker.rebinds(), 1);
+
+            // Fresh within the window, stale past it
+            assert_eq!(tracker.stale_keepalive(t1 + Duration::from_secs(60)).await, None);
+            let late = t1 + Duration::from_secs(61);
+            assert_eq!(tracker.stale_keepalive(late).await, Some(Duration::from_secs(61)));
+            assert_eq!(GatewayTracker::new().stale_keepalive(late).await, None);
+        });
+    }
+
+    #[test]
+    fn test_build_txpk() {
+        let plan = ChannelPlan::from_config(&Default::default()).unwrap();
+        let txpk = build_txpk(&plan, None, None, "AQIDBA==", 4).unwrap();
+        assert_eq!(txpk.freq, 923.3);
+        assert_eq!(txpk.imme, Some(true));
+        assert_eq!(txpk.ipol, Some(true));
+        assert_eq!(txpk.powe, Some(27));
+        assert_eq!(txpk.datr.to_string(), "SF12BW500");
+        assert_eq!(txpk.data, "AQIDBA==");
+        assert_eq!(txpk.size, 4);
+    }
+
+    #[test]
+    fn test_downlink_modes() {
+        let plan_in = |mode| {
+            let downlink = crate::config::DownlinkConfig {
+                datr: Some(DataRate::lora(10, 500)),
+                mode,
+                ..Default::default()
+            };
+            ChannelPlan::from_config(&Default::default()).unwrap().with_downlink(&downlink)
+        };
+
+        let uplink = |tmst| {
+            let rxpk: Rxpk = serde_json::from_str(
+                r#"{"freq":902.5,"rssi":-40,"datr":"SF7BW125","size":4,"data":"AQIDBA=="}"#,
+            )
+            .unwrap();
+            Rxpk { tmst, ..rxpk }
+        };
+
+        // Immediate: as before, whatever the reference
+        let plan = plan_in(DownlinkMode::Immediate);
+        let txpk = build_txpk(&plan, None, Some(&uplink(Some(1_000_000))), "AQIDBA==", 4).unwrap();
+        assert_eq!((txpk.imme, txpk.tmst), (Some(true), None));
+        assert_eq!(txpk.datr, DataRate::lora(10, 500));
+
+        // RX1: timed from the reference, on the RX1 channel of its uplink channel
+        let plan = plan_in(DownlinkMode::Rx1);
+        let txpk = build_txpk(&plan, None, Some(&uplink(Some(1_000_000))), "AQIDBA==", 4).unwrap();
+        assert_eq!((txpk.imme, txpk.tmst), (Some(false), Some(2_000_000)));
+        assert_eq!((txpk.freq, txpk.datr), (923.9, DataRate::lora(7, 500)));
+        assert!(build_txpk(&plan, None, None, "AQIDBA==", 4).is_err());
+        assert!(build_txpk(&plan, None, Some(&uplink(None)), "AQIDBA==", 4).is_err());
+
+        // RX2: a second later, on the RX2 channel
+        let plan = plan_in(DownlinkMode::Rx2);
+        let wrapping = uplink(Some(0xFFFF_0000));
+        let txpk = build_txpk(&plan, None, Some(&wrapping), "AQIDBA==", 4).unwrap();
+        assert_eq!((txpk.imme, txpk.tmst), (Some(false), Some(0x001D_8480)));
+        assert_eq!((txpk.freq, txpk.datr), (923.3, DataRate::lora(12, 500)));
+        assert!(build_txpk(&plan, None, None, "AQIDBA==", 4).is_err());
+    }
+
+    #[test]
+    fn test_gateway_tx_power() {
+        let gateways = [
+            crate::config::GatewayConfig {
+                eui: "AABBCCDDEEFF0011".to_string(),
+                tx_power: Some(20),
+                name: None,
+            },
+            crate::config::GatewayConfig {
+                eui: "0000000000000002".to_string(),
+                tx_power: None,
+                name: None,
+            },
+        ];
+        let plan = ChannelPlan::from_config(&Default::default())
+            .unwrap()
+            .with_gateways(&gateways);
+
+        // The override applies to its gateway, whatever the EUI's case
+        let txpk = build_txpk(&plan, Some("aabbccddeeff0011"), None, "AQIDBA==", 4).unwrap();
+        assert_eq!(txpk.powe, Some(20));
+        // Region default for other, unconfigured and unknown gateways
+        for eui in [Some("0000000000000002"), Some("0000000000000003"), None] {
+            assert_eq!(build_txpk(&plan, eui, None, "AQIDBA==", 4).unwrap().powe, Some(27));
+        }
+
+        let rxpk: Rxpk = serde_json::from_str(
+            r#"{"tmst":1000000,"freq":902.3,"rssi":-40,"datr":"SF10BW125","size":4,"data":"AQIDBA=="}"#,
+        )
+        .unwrap();
+        let txpks = ClassATxpks::build(&plan, Some("aabbccddeeff0011"), &rxpk, 1, "AQIDBA==", 4);
+        assert_eq!(txpks.rx1.unwrap().powe, Some(20));
+    }
+
+    #[test]
+    fn test_downlink_overrides() {
+        let downlink = crate::config::DownlinkConfig {
+            coding_rate: Some("4/8".to_string()),
+            datr: Some(DataRate::lora(10, 500)),
+            mode: DownlinkMode::Immediate,
+            confirmed_retries: None,
+        };
+        let plan = ChannelPlan::from_config(&Default::default())
+            .unwrap()
+            .with_downlink(&downlink);
+
+        let txpk = build_txpk(&plan, None, None, "AQIDBA==", 4).unwrap();
+        assert_eq!(txpk.codr.as_deref(), Some("4/8"));
+        assert_eq!(txpk.datr, DataRate::lora(10, 500));
+
+        // Class A keeps the plan's RX1/RX2 data rates but takes the coding rate
+        let rxpk: Rxpk = serde_json::from_str(
+            r#"{"tmst":1000000,"freq":902.3,"rssi":-40,"datr":"SF10BW125","codr":"4/5","size":4,"data":"AQIDBA=="}"#,
+        )
+        .unwrap();
+        let rx1 = build_rx1_txpk(&plan, None, &rxpk, 1, "AQIDBA==", 4).unwrap();
+        assert_eq!((rx1.codr.as_deref(), rx1.datr), (Some("4/8"), DataRate::lora(10, 500)));
+        let rx2 = build_rx2_txpk(&plan, None, &rxpk, 1, "AQIDBA==", 4).unwrap();
+        assert_eq!((rx2.codr.as_deref(), rx2.datr), (Some("4/8"), DataRate::lora(12, 500)));
+    }
+
+    #[test]
+    fn test_fsk_uplink_packet() {
+        let rxpk: Rxpk = serde_json::from_str(
+            r#"{"freq":868.8,"modu":"FSK","datr":50000,"rssi":-75,"size":17,
+                "data":"QPF9vkkAAgABlUN4disR/w0="}"#,
+        )
+        .unwrap();
+        let phy = base64_decode(&rxpk.data).unwrap();
+        let frame = lorawan::decode_phy_payload(&phy).unwrap();
+        let keys = SharedKeyStore::default();
+        let packet = frame_to_lora_packet(
+            &frame,
+            &rxpk,
+            "aabbccddeeff0011",
+            PacketSource::Local,
+            &CodecRegistry::default(),
+            &keys,
+        )
+        .unwrap();
+        assert_eq!(packet.dev_addr, "49BE7DF1");
+        assert_eq!(packet.data_rate.to_string(), "FSK50000");
+    }
+
+    #[test]
+    fn test_uplink_packet_class() {
+        use crate::lorawan::keys::DeviceClass;
+
+        let rxpk: Rxpk = serde_json::from_str(
+            r#"{"freq":868.1,"datr":"SF7BW125","rssi":-75,"size":17,"data":"QPF9vkkAAgABlUN4disR/w0="}"#,
+        )
+        .unwrap();
+        let phy = base64_decode(&rxpk.data).unwrap();
+        let frame = lorawan::decode_phy_payload(&phy).unwrap();
+        let (source, codecs) = (PacketSource::Local, CodecRegistry::default());
+        let keys = SharedKeyStore::default();
+
+        // Class A for devices the bridge has no session for
+        let packet =
+            frame_to_lora_packet(&frame, &rxpk, "aabbccddeeff0011", source.clone(), &codecs, &keys);
+        assert_eq!(packet.unwrap().class, DeviceClass::A);
+
+        // Otherwise the class its session was provisioned with
+        let device = crate::config::AbpDeviceConfig {
+            dev_addr: "49BE7DF1".to_string(),
+            nwk_s_key: "44024241ed4ce9a68c6a8bc055233fd3".to_string(),
+            app_s_key: "ec925802ae430ca77fd3dd73cb2cc588".to_string(),
+            lorawan_version: Default::default(),
+            s_nwk_s_int_key: None,
+            class: DeviceClass::C,
+            ping_slot_periodicity: 7,
+            rx2_datr: None,
+            rx1_delay: 1,
+        };
+        *keys.write().unwrap() = KeyStore::from_config(&[device]).unwrap();
+        let packet =
+            frame_to_lora_packet(&frame, &rxpk, "aabbccddeeff0011", source, &codecs, &keys);
+        assert_eq!(packet.unwrap().class, DeviceClass::C);
+    }
+
+    #[test]
+    fn test_build_device_txpk() {
+        let plan = ChannelPlan::from_config(&Default::default()).unwrap();
+        let device = |dev_addr: &str, rx2_datr, rx1_delay| crate::config::AbpDeviceConfig {
+            dev_addr: dev_addr.to_string(),
+            nwk_s_key: "44024241ed4ce9a68c6a8bc055233fd3".to_string(),
+            app_s_key: "ec925802ae430ca77fd3dd73cb2cc588".to_string(),
+            lorawan_version: Default::default(),
+            s_nwk_s_int_key: None,
+            class: crate::lorawan::keys::DeviceClass::C,
+            ping_slot_periodicity: 7,
+            rx2_datr,
+            rx1_delay,
+        };
+        let keys = KeyStore::from_config(&[
+            device("260B1234", Some(DataRate::lora(9, 500)), 1),
+            device("260B5678", None, 5),
+        ])
+        .unwrap();
+
+        let txpk = build_device_txpk(&plan, &keys, 0x260B_1234, None, None, "AQIDBA==", 4).unwrap();
+        assert_eq!(txpk.datr.to_string(), "SF9BW500");
+        assert_eq!(txpk.freq, plan.rx2_freq);
+        let txpk = build_device_txpk(&plan, &keys, 0x260B_5678, None, None, "AQIDBA==", 4).unwrap();
+        assert_eq!(txpk.datr, plan.rx2_datr);
+
+        // rx1/rx2 windows open after the device's RxDelay, not the default
+        let downlink = crate::config::DownlinkConfig {
+            mode: DownlinkMode::Rx2,
+            ..Default::default()
+        };
+        let plan = plan.with_downlink(&downlink);
+        let uplink: Rxpk = serde_json::from_str(
+            r#"{"tmst":1000000,"freq":902.5,"rssi":-40,"datr":"SF7BW125","size":4,"data":"AQIDBA=="}"#,
+        )
+        .unwrap();
+        let reference = Some(&uplink);
+        let txpk = build_device_txpk(&plan, &keys, 0x260B_5678, None, reference, "AQIDBA==", 4);
+        assert_eq!(txpk.unwrap().tmst, Some(7_000_000));
+        let txpk = build_device_txpk(&plan, &keys, 0x260B_1234, None, reference, "AQIDBA==", 4);
+        assert_eq!(txpk.unwrap().tmst, Some(3_000_000));
+
+        // A JoinAccept's RX2DataRate index maps to the same rate
+        assert_eq!(plan.downlink_datr(11), Some(DataRate::lora(9, 500)));
+        assert_eq!(plan.downlink_datr(2), None);
+    }
+
+    #[test]
+    fn test_downlink_encryption_toggle() {
+        let device = crate::config::AbpDeviceConfig {
+            dev_addr: "260B1234".to_string(),
+            nwk_s_key: "44024241ed4ce9a68c6a8bc055233fd3".to_string(),
+            app_s_key: "ec925802ae430ca77fd3dd73cb2cc588".to_string(),
+            lorawan_version: Default::default(),
+            s_nwk_s_int_key: None,
+            class: Default::default(),
+            ping_slot_periodicity: 7,
+            rx2_datr: None,
+            rx1_delay: 1,
+        };
+        let keys = KeyStore::from_config(&[device]).unwrap();
+        let keys = Arc::new(std::sync::RwLock::new(keys));
+        let counters = Arc::new(std::sync::Mutex::new(DownlinkCounters::new()));
+        let build = |encrypt| {
+            build_device_frame(&keys, &counters, 0x260B_1234, 1, b"test".to_vec(), false, encrypt)
+        };
+
+        // Off: plaintext FRMPayload and a zero MIC, even with session keys
+        let plain = build(false).unwrap();
+        let expected = FrameBuilder::new_downlink(0x260B_1234, 0, 1, b"test".to_vec());
+        assert_eq!(plain, expected.build().unwrap());
+
+        // On: encrypted and signed with FCnt 1
+        let encrypted = build(true);
+        #[cfg(feature = "phase4")]
+        {
+            let encrypted = encrypted.unwrap();
+            let expected = FrameBuilder::new_downlink(0x260B_1234, 1, 1, b"test".to_vec());
+            assert_eq!(encrypted[..6], expected.build().unwrap()[..6]);
+            assert_ne!(encrypted[9..13], *b"test");
+            assert_ne!(encrypted[13..], [0, 0, 0, 0]);
+        }
+        #[cfg(not(feature = "phase4"))]
+        assert!(encrypted.is_err());
+    }
+
+    #[test]
+    fn test_build_rx1_txpk() {
+        let plan = ChannelPlan::from_config(&crate::config::ChannelPlanConfig {
+            sub_band: Some(2),
+            ..Default::default()
+        })
+        .unwrap();
+        let rxpk: Rxpk = serde_json::from_str(
+            r#"{"tmst":4294000000,"freq":904.5,"rssi":-40,"datr":"SF9BW125","codr":"4/5","size":4,"data":"AQIDBA=="}"#,
+        )
+        .unwrap();
+
+        let txpk = build_rx1_txpk(&plan, None, &rxpk, 1, "AQIDBA==", 4).unwrap();
+        assert_eq!(txpk.imme, Some(false));
+        // Concentrator counter wraps at 2^32
+        assert_eq!(txpk.tmst, Some(32_704));
+        assert_eq!(txpk.freq, 925.1);
+        assert_eq!(txpk.datr.to_string(), "SF9BW500");
+
+        // Not an enabled channel
+        let rxpk = Rxpk { freq: 902.3, ..rxpk };
+        assert!(build_rx1_txpk(&plan, None, &rxpk, 1, "AQIDBA==", 4).is_none());
+    }
+
+    #[test]
+    fn test_build_ack_txpk() {
+        let plan = ChannelPlan::from_config(&Default::default()).unwrap();
+        let rxpk: Rxpk = serde_json::from_str(
+            r#"{"tmst":1000000,"freq":902.3,"rssi":-40,"datr":"SF10BW125","size":4,"data":"AQIDBA=="}"#,
+        )
+        .unwrap();
+
+        let device = crate::config::AbpDeviceConfig {
+            dev_addr: "260B1234".to_string(),
+            nwk_s_key: "44024241ed4ce9a68c6a8bc055233fd3".to_string(),
+            app_s_key: "ec925802ae430ca77fd3dd73cb2cc588".to_string(),
+            lorawan_version: Default::default(),
+            s_nwk_s_int_key: None,
+            class: Default::default(),
+            ping_slot_periodicity: 7,
+            rx2_datr: None,
+            rx1_delay: 2,
+        };
+        let keys = KeyStore::from_config(&[device]).unwrap();
+        let session = keys.lookup(0x260B_1234)[0];
+
+        let txpks = build_ack_txpks(&plan, None, &rxpk, session, 7);
+        // The MIC needs the crypto feature; an ACK without it is not sent
+        #[cfg(not(feature = "phase4"))]
+        assert!(txpks.is_err());
+        #[cfg(feature = "phase4")]
+        {
+            // Timed from the device's RX1 delay (2 s), not the 1 s default
+            let txpks = txpks.unwrap();
+            let rx2 = txpks.rx2.unwrap();
+            assert_eq!((rx2.tmst, rx2.freq, rx2.datr), (Some(4_000_000), 923.3, plan.rx2_datr));
+            assert_eq!(rx2.data, txpks.rx1.as_ref().unwrap().data);
+            let txpk = txpks.rx1.unwrap();
+            assert_eq!(txpk.tmst, Some(3_000_000));
+            assert_eq!(txpk.size, 12);
+            let phy = base64_decode(&txpk.data).unwrap();
+            match lorawan::decode_phy_payload(&phy).unwrap() {
+                LoRaWANFrame::Data {
+                    mtype,
+                    dev_addr,
+                    fctrl,
+                    fcnt,
+                    f_port,
+                    ..
+                } => {
+                    assert_eq!(mtype, MType::UnconfirmedDataDown);
+                    assert_eq!(dev_addr, 0x260B_1234);
+                    assert!(fctrl.ack);
+                    assert_eq!(phy[5], 0x20);
+                    assert_eq!(fcnt, 7);
+                    assert_eq!(f_port, None);
+                }
+                other => panic!("expected a data frame, got {:?}", other),
+            }
+            assert_ne!(phy[8..], [0, 0, 0, 0], "the MIC is computed");
+        }
+    }
+
+    #[test]
+    fn test_duplicate_rxpks_merged() {
+        // The same frame heard twice, plus a different one
+        let rxpk = |rssi: i32, lsnr: f64, data: &str| {
+            format!(
+                r#"{{"freq":902.3,"rssi":{},"lsnr":{},"datr":"SF7BW125","size":17,"data":"{}"}}"#,
+                rssi, lsnr, data
+            )
+        };
+        let json = format!(
+            r#"{{"rxpk":[{},{},{}]}}"#,
+            rxpk(-95, 1.5, "QPF9vkkAAgABlUN4disR/w0="),
+            rxpk(-60, 9.0, "QPF9vkkAAgABlUN4disR/w0="),
+            rxpk(-70, 5.0, "QDQSCyYAAQABAAAAAA=="),
+        );
+        let push_data = GwmpPacket::push_data(0x1234, &[0xAA; 8], &json);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (tx, mut rx) = mpsc::channel(8);
+            let mut pokes = PokeRouter::new();
+            pokes.add(crate::urbit::routing::RouteRule::default(), tx);
+            let config = Config::default();
+            let ctx =
+                PacketContext::new(&config, pokes, GatewayTracker::new(), None, None).unwrap();
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let gateway_addr = gateway.local_addr().unwrap();
+            handle_datagram(&socket, gateway_addr, &push_data, &ctx).await;
+            drop(ctx);
+
+            let mut packets = Vec::new();
+            while let Some(LoRaAction::Uplink(packet)) = rx.recv().await {
+                packets.push(packet);
+            }
+            assert_eq!(packets.len(), 2);
+            // One poke for the duplicated frame, from the strongest reception
+            assert_eq!(packets[0].dev_addr, "49BE7DF1");
+            assert_eq!(packets[0].rssi, -60.0);
+            assert_eq!(
+                packets[0].alt_receptions,
+                vec![AltReception {
+                    rssi: -95.0,
+                    snr: Some(1.5),
+                    freq: 902.3
+                }]
+            );
+            assert!(packets[1].alt_receptions.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_receptions_from_two_gateways() {
+        let rxpk = |tmst: u64, rssi: i32| {
+            format!(
+                r#"{{"rxpk":[{{"tmst":{},"freq":902.3,"rssi":{},"lsnr":7.5,"datr":"SF7BW125","size":17,"data":"QPF9vkkAAgABlUN4disR/w0="}}]}}"#,
+                tmst, rssi
+            )
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (tx, mut rx) = mpsc::channel(8);
+            let mut pokes = PokeRouter::new();
+            pokes.add(crate::urbit::routing::RouteRule::default(), tx);
+            let mut config = Config::default();
+            config.udp.dedup_window_ms = 50;
+            let ctx =
+                PacketContext::new(&config, pokes, GatewayTracker::new(), None, None).unwrap();
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let gateway_addr = gateway.local_addr().unwrap();
+
+            let first = GwmpPacket::push_data(1, &[0xAA; 8], &rxpk(1_000_000, -70));
+            handle_datagram(&socket, gateway_addr, &first, &ctx).await;
+            let second = GwmpPacket::push_data(2, &[0xBB; 8], &rxpk(1_000_412, -95));
+            handle_datagram(&socket, gateway_addr, &second, &ctx).await;
+            drop(ctx);
+
+            // One poke, from the first gateway, carrying both receptions
+            let Some(LoRaAction::Uplink(packet)) = rx.recv().await else {
+                panic!("expected an uplink");
+            };
+            assert!(rx.recv().await.is_none());
+            assert_eq!(packet.gateway_eui, "aaaaaaaaaaaaaaaa");
+            let heard: Vec<_> = packet
+                .receptions
+                .iter()
+                .map(|r| (r.gateway_eui.as_str(), r.rssi, r.tmst))
+                .collect();
+            assert_eq!(
+                heard,
+                vec![
+                    ("aaaaaaaaaaaaaaaa", -70.0, Some(1_000_000)),
+                    ("bbbbbbbbbbbbbbbb", -95.0, Some(1_000_412)),
+                ]
+            );
+        });
+    }
+
+    #[cfg(feature = "phase4")]
+    #[test]
+    fn test_port0_mac_commands() {
+        use crate::lorawan::crypto::{data_mic, frm_payload_cipher, Direction};
+        use crate::lorawan::keys::parse_key;
+        use crate::lorawan::mac::LinkAdrStatus;
+
+        let nwk_s_key = "44024241ed4ce9a68c6a8bc055233fd3";
+        let key = parse_key(nwk_s_key).unwrap();
+        // UnconfirmedDataUp from 260B1234, FCnt 5, FPort 0: LinkADRAns (all acked)
+        let mut phy = vec![0x40, 0x34, 0x12, 0x0B, 0x26, 0x00, 0x05, 0x00, 0x00];
+        phy.extend(frm_payload_cipher(&key, Direction::Uplink, 0x260B_1234, 5, &[0x03, 0x07]));
+        let mic = data_mic(&key, Direction::Uplink, 0x260B_1234, 5, &phy);
+        phy.extend(mic.to_le_bytes());
+        let json = format!(
+            r#"{{"rxpk":[{{"freq":902.3,"rssi":-60,"datr":"SF7BW125","size":{},"data":"{}"}}]}}"#,
+            phy.len(),
+            base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &phy)
+        );
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (tx, mut rx) = mpsc::channel(8);
+            let mut pokes = PokeRouter::new();
+            pokes.add(crate::urbit::routing::RouteRule::default(), tx);
+            let mut config = Config::default();
+            config.lorawan.devices = vec![crate::config::AbpDeviceConfig {
+                dev_addr: "260B1234".to_string(),
+                nwk_s_key: nwk_s_key.to_string(),
+                app_s_key: "ec925802ae430ca77fd3dd73cb2cc588".to_string(),
+                lorawan_version: Default::default(),
+                s_nwk_s_int_key: None,
+                class: Default::default(),
+                ping_slot_periodicity: 0,
+                rx2_datr: None,
+                rx1_delay: 1,
+            }];
+            let ctx =
+                PacketContext::new(&config, pokes, GatewayTracker::new(), None, None).unwrap();
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let push_data = GwmpPacket::push_data(1, &[0xAA; 8], &json);
+            handle_datagram(&socket, gateway.local_addr().unwrap(), &push_data, &ctx).await;
+            drop(ctx);
+
+            // The MAC answer is surfaced; nothing reaches the application payload
+            let Some(LoRaAction::Uplink(packet)) = rx.recv().await else {
+                panic!("expected an uplink");
+            };
+            assert_eq!(packet.f_port, Some(0));
+            assert_eq!(packet.payload, "");
+            assert_eq!(packet.decoded, None);
+            assert_eq!(
+                packet.mac.unwrap().link_adr_ans,
+                Some(LinkAdrStatus {
+                    power_ack: true,
+                    data_rate_ack: true,
+                    channel_mask_ack: true,
+                })
+            );
+        });
+    }
+
+    #[test]
+    fn test_denied_gateway_dropped() {
+        let json = r#"{"rxpk":[{"freq":902.3,"rssi":-60,"datr":"SF7BW125","size":17,"data":"QPF9vkkAAgABlUN4disR/w0="}]}"#;
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (tx, mut rx) = mpsc::channel(8);
+            let mut pokes = PokeRouter::new();
+            pokes.add(crate::urbit::routing::RouteRule::default(), tx);
+            let mut config = Config::default();
+            config.udp.allowed_gateways = vec!["aaaaaaaaaaaaaaaa".to_string()];
+            let ctx =
+                PacketContext::new(&config, pokes, GatewayTracker::new(), None, None).unwrap();
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let gateway_addr = gateway.local_addr().unwrap();
+
+            let denied = GwmpPacket::push_data(1, &[0xBB; 8], json);
+            handle_datagram(&socket, gateway_addr, &denied, &ctx).await;
+            let pull = GwmpPacket::pull_data(2, &[0xBB; 8]);
+            handle_datagram(&socket, gateway_addr, &pull, &ctx).await;
+            assert_eq!(ctx.gateways_seen.count(), 0);
+            assert_eq!(ctx.gateway.get().await, None);
+            assert_eq!(ctx.gateway_filter.dropped(), 2);
+
+            let allowed = GwmpPacket::push_data(3, &[0xAA; 8], json);
+            handle_datagram(&socket, gateway_addr, &allowed, &ctx).await;
+            drop(ctx);
+            assert!(matches!(rx.recv().await, Some(LoRaAction::Uplink(_))));
+            assert!(rx.recv().await.is_none());
+
+            // Only the allowed gateway's PUSH_DATA was acknowledged
+            let mut buf = [0u8; 16];
+            let (len, _) = gateway.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], GwmpPacket::push_ack(3).as_slice());
+        });
+    }
+
+    #[test]
+    fn test_keepalive_push_data() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (tx, mut rx) = mpsc::channel(8);
+            let mut pokes = PokeRouter::new();
+            pokes.add(crate::urbit::routing::RouteRule::default(), tx);
+            let config = Config::default();
+            let ctx =
+                PacketContext::new(&config, pokes, GatewayTracker::new(), None, None).unwrap();
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let gateway_addr = gateway.local_addr().unwrap();
+
+            // Each is acknowledged, and none is a parse error or an uplink
+            let mut buf = [0u8; 16];
+            for (token, json) in [(1, "{}"), (2, r#"{"stat":{}}"#), (3, ""), (4, " \r\n")] {
+                let push = GwmpPacket::push_data(token, &[0xAA; 8], json);
+                handle_datagram(&socket, gateway_addr, &push, &ctx).await;
+                let (len, _) = gateway.recv_from(&mut buf).await.unwrap();
+                assert_eq!(&buf[..len], GwmpPacket::push_ack(token).as_slice());
+            }
+            assert_eq!(ctx.uplink_metrics.drops(DropReason::ParseError), 0);
+            assert_eq!(ctx.gateways_seen.count(), 1);
+            drop(ctx);
+            assert!(rx.recv().await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_downlink_to_strongest_gateway() {
+        use base64::Engine;
+
+        let uplink = |rssi| {
+            format!(
+                r#"{{"rxpk":[{{"freq":902.3,"rssi":{},"datr":"SF7BW125","size":17,"data":"{}"}}]}}"#,
+                rssi, "QPF9vkkAAgABlUN4disR/w0="
+            )
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let config = Config::default();
+            let ctx =
+                PacketContext::new(&config, PokeRouter::new(), GatewayTracker::new(), None, None)
+                    .unwrap();
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let near = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let far = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let (near_addr, far_addr) = (near.local_addr().unwrap(), far.local_addr().unwrap());
+            let mut buf = [0u8; 512];
+
+            // The far gateway relays the uplink first; the near one's copy is stronger
+            let push = GwmpPacket::push_data(1, &[0xFA; 8], &uplink(-110));
+            handle_datagram(&socket, far_addr, &push, &ctx).await;
+            let push = GwmpPacket::push_data(2, &[0x0E; 8], &uplink(-45));
+            handle_datagram(&socket, near_addr, &push, &ctx).await;
+            assert_eq!(ctx.best_gateways.get(0x49BE_7DF1).as_deref(), Some("0e0e0e0e0e0e0e0e"));
+            // The late copy is a duplicate, not a replay
+            assert_eq!(ctx.uplink_metrics.drops(DropReason::DuplicateReception), 1);
+            assert_eq!(ctx.uplink_metrics.drops(DropReason::Replay), 0);
+
+            // Both pull; the far gateway's keepalive is the latest
+            for (token, eui, addr) in [(3, [0x0E; 8], near_addr), (4, [0xFA; 8], far_addr)] {
+                handle_datagram(&socket, addr, &GwmpPacket::pull_data(token, &eui), &ctx).await;
+            }
+            // Skip each gateway's PUSH_ACK and PULL_ACK
+            for gateway in [&near, &near, &far, &far] {
+                gateway.recv_from(&mut buf).await.unwrap();
+            }
+            assert_eq!(ctx.gateway.get().await, Some(far_addr));
+
+            // The device's downlink goes to the near gateway anyway
+            let sender = ctx.downlink_sender(socket.clone());
+            let frame = FrameBuilder::new_downlink(0x49BE_7DF1, 0, 1, vec![1]).build().unwrap();
+            let gateways = [("0e0e0e0e0e0e0e0e", 20), ("fafafafafafafafa", 14)].map(|(eui, power)| {
+                crate::config::GatewayConfig {
+                    eui: eui.to_string(),
+                    tx_power: Some(power),
+                    name: None,
+                }
+            });
+            let plan = ChannelPlan::from_config(&Default::default())
+                .unwrap()
+                .with_gateways(&gateways);
+            let keys = ctx.keys.clone();
+            let timeout = Duration::from_millis(20);
+            let sent = sender.send_frame(&plan, &keys, &frame, Some(0x49BE_7DF1), timeout);
+            let (txpk, result) = sent.await.unwrap();
+            assert_eq!(result, TxResult::NoAck);
+            // ...at its own TX power
+            assert_eq!(txpk.powe, Some(20));
+            let (len, _) = near.recv_from(&mut buf).await.unwrap();
+            let expected = base64::engine::general_purpose::STANDARD.encode(&frame);
+            match GwmpPacket::parse(&buf[..len]).unwrap() {
+                GwmpPacket::PullResp { json_payload, .. } => {
+                    assert!(json_payload.contains(&expected))
+                }
+                other => panic!("expected PULL_RESP, got {:?}", other),
+            }
+
+            // Other devices' downlinks follow the latest keepalive
+            let frame = FrameBuilder::new_downlink(0x260B_1234, 0, 1, vec![1]).build().unwrap();
+            let sent = sender.send_frame(&plan, &keys, &frame, Some(0x260B_1234), timeout);
+            let (txpk, result) = sent.await.unwrap();
+            assert_eq!((result, txpk.powe), (TxResult::NoAck, Some(14)));
+            let (len, _) = far.recv_from(&mut buf).await.unwrap();
+            assert!(matches!(GwmpPacket::parse(&buf[..len]), Ok(GwmpPacket::PullResp { .. })));
+        });
+    }
+
+    #[test]
+    fn test_join_accept_timed_from_join_request() {
+        use base64::Engine;
+
+        // MHDR | AppEUI | DevEUI | DevNonce 0x1234 | MIC
+        let mut phy = vec![0x00];
+        phy.extend_from_slice(&[0x01; 16]);
+        phy.extend_from_slice(&0x1234u16.to_le_bytes());
+        phy.extend_from_slice(&[0; 4]);
+        let json = format!(
+            r#"{{"rxpk":[{{"tmst":1000000,"freq":902.3,"rssi":-60,"datr":"SF10BW125","size":23,"data":"{}"}}]}}"#,
+            base64::engine::general_purpose::STANDARD.encode(&phy)
+        );
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let config = Config::default();
+            let ctx =
+                PacketContext::new(&config, PokeRouter::new(), GatewayTracker::new(), None, None)
+                    .unwrap();
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let heard = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let heard_addr = heard.local_addr().unwrap();
+            let other_addr = other.local_addr().unwrap();
+            let mut buf = [0u8; 512];
+
+            // One gateway hears the JoinRequest; another pulled last
+            let pull = GwmpPacket::pull_data(1, &[0x0E; 8]);
+            handle_datagram(&socket, heard_addr, &pull, &ctx).await;
+            let push = GwmpPacket::push_data(2, &[0x0E; 8], &json);
+            handle_datagram(&socket, heard_addr, &push, &ctx).await;
+            handle_datagram(&socket, other_addr, &GwmpPacket::pull_data(3, &[0xFA; 8]), &ctx).await;
+            for gateway in [&heard, &heard, &other] {
+                gateway.recv_from(&mut buf).await.unwrap();
+            }
+
+            let plan = ChannelPlan::from_config(&Default::default()).unwrap();
+            let sender = ctx.downlink_sender(socket.clone());
+            let timeout = Duration::from_millis(20);
+            let sent = sender.send_join_accept(&plan, &[0x20; 17], 0x1234, timeout).await;
+            assert_eq!(sent.unwrap(), TxResult::NoAck);
+            let (len, _) = heard.recv_from(&mut buf).await.unwrap();
+            match GwmpPacket::parse(&buf[..len]).unwrap() {
+                GwmpPacket::PullResp { json_payload, .. } => {
+                    let resp: PullRespPayload = serde_json::from_str(&json_payload).unwrap();
+                    assert_eq!((resp.txpk.imme, resp.txpk.tmst), (Some(false), Some(6_000_000)));
+                }
+                other => panic!("expected PULL_RESP, got {:?}", other),
+            }
+
+            // An unknown DevNonce can't be answered
+            assert!(sender.send_join_accept(&plan, &[0x20; 17], 0x4321, timeout).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_echoed_pull_resp_dropped() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let ctx = PacketContext::new(
+                &Config::default(),
+                PokeRouter::new(),
+                GatewayTracker::new(),
+                None,
+                None,
+            )
+            .unwrap();
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let peer_addr = peer.local_addr().unwrap();
+            ctx.gateway.set(peer_addr).await;
+
+            // A relay peer bounces our downlink straight back
+            let plan = ChannelPlan::from_config(&Default::default()).unwrap();
+            let txpk = build_txpk(&plan, None, None, "AQIDBA==", 4).unwrap();
+            ctx.downlink_sender(socket.clone()).send_downlink(&txpk).await.unwrap();
+            let mut buf = [0u8; 1024];
+            let (len, _) = peer.recv_from(&mut buf).await.unwrap();
+            handle_datagram(&socket, peer_addr, &buf[..len], &ctx).await;
+            assert_eq!((ctx.loop_guard.dropped(), ctx.loop_guard.echoes()), (1, 1));
+
+            // A PULL_RESP we never sent is dropped too, but is no echo
+            let foreign = GwmpPacket::pull_resp(0x0001, r#"{"txpk":{}}"#);
+            handle_datagram(&socket, peer_addr, &foreign, &ctx).await;
+            assert_eq!((ctx.loop_guard.dropped(), ctx.loop_guard.echoes()), (2, 1));
+
+            // Neither was answered or sent on
+            let answer = tokio::time::timeout(
+                Duration::from_millis(50),
+                peer.recv_from(&mut buf),
+            );
+            assert!(answer.await.is_err());
+        });
+    }
+
+    #[cfg(feature = "phase4")]
+    #[test]
+    fn test_confirmed_downlink_resent_until_acked() {
+        use base64::Engine;
+
+        let device = crate::config::AbpDeviceConfig {
+            dev_addr: "260B1234".to_string(),
+            nwk_s_key: "44024241ed4ce9a68c6a8bc055233fd3".to_string(),
+            app_s_key: "ec925802ae430ca77fd3dd73cb2cc588".to_string(),
+            lorawan_version: Default::default(),
+            s_nwk_s_int_key: None,
+            class: Default::default(),
+            ping_slot_periodicity: 0,
+            rx2_datr: None,
+            rx1_delay: 3,
+        };
+        let session = KeyStore::from_config(std::slice::from_ref(&device)).unwrap();
+        let session = session.lookup(0x260B_1234)[0].clone();
+        // `signed: false` leaves the MIC zeroed, as a forger without the keys would
+        let uplink = |fcnt, ack, signed| {
+            let builder = FrameBuilder {
+                mtype: MType::UnconfirmedDataUp,
+                ack,
+                ..FrameBuilder::new_downlink(0x260B_1234, fcnt, 1, vec![0x01])
+            };
+            let phy = match signed {
+                true => builder.build_with_mic(&session),
+                false => builder.build(),
+            }
+            .unwrap();
+            let json = format!(
+                r#"{{"rxpk":[{{"tmst":1000000,"freq":902.3,"rssi":-60,"datr":"SF7BW125","size":{},"data":"{}"}}]}}"#,
+                phy.len(),
+                base64::engine::general_purpose::STANDARD.encode(&phy)
+            );
+            GwmpPacket::push_data(fcnt as u16, &[0xAA; 8], &json)
+        };
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut config = Config::default();
+            config.downlink.confirmed_retries = Some(2);
+            config.lorawan.devices = vec![device.clone()];
+            let tracker = GatewayTracker::new();
+            let ctx = PacketContext::new(&config, PokeRouter::new(), tracker, None, None).unwrap();
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let gateway_addr = gateway.local_addr().unwrap();
+            let mut buf = [0u8; 1024];
+            let pull = GwmpPacket::pull_data(1, &[0xAA; 8]);
+            handle_datagram(&socket, gateway_addr, &pull, &ctx).await;
+            gateway.recv_from(&mut buf).await.unwrap();
+
+            let frame = FrameBuilder::new_confirmed_downlink(0x260B_1234, 3, 1, vec![0x42])
+                .build_with_mic(&session)
+                .unwrap();
+            ctx.confirmed.track(0x260B_1234, 0, 7, frame.clone(), Instant::now());
+
+            // No ACK: the same frame goes out again in the device's RX1 window
+            handle_datagram(&socket, gateway_addr, &uplink(5, false, true), &ctx).await;
+            loop {
+                let (len, _) = gateway.recv_from(&mut buf).await.unwrap();
+                let packet = GwmpPacket::parse(&buf[..len]);
+                if let Ok(GwmpPacket::PullResp { json_payload, .. }) = packet {
+                    let resp: PullRespPayload = serde_json::from_str(&json_payload).unwrap();
+                    let data = base64::engine::general_purpose::STANDARD
+                        .decode(&resp.txpk.data)
+                        .unwrap();
+                    assert_eq!(data, frame);
+                    assert_eq!(resp.txpk.tmst, Some(4_000_000));
+                    break;
+                }
+            }
+            assert_eq!(ctx.confirmed.pending(0x260B_1234), Some(7));
+
+            // A forged ACK neither clears it nor uses up a retry
+            handle_datagram(&socket, gateway_addr, &uplink(6, true, false), &ctx).await;
+            assert_eq!(ctx.confirmed.pending(0x260B_1234), Some(7));
+            assert!(ctx.confirmed.take_resolved(Instant::now()).is_empty());
+
+            // The device's ACK clears it, ready for the outbound task to poke tx-ack
+            handle_datagram(&socket, gateway_addr, &uplink(6, true, true), &ctx).await;
+            assert_eq!(ctx.confirmed.pending(0x260B_1234), None);
+            let resolved = ctx.confirmed.take_resolved(Instant::now());
+            assert_eq!((resolved[0].msg_id, resolved[0].failure.clone()), (7, None));
+        });
+    }
+
+    #[cfg(feature = "phase4")]
+    #[test]
+    fn test_confirmed_uplink_acked_before_resend() {
+        use base64::Engine;
+
+        let device = crate::config::AbpDeviceConfig {
+            dev_addr: "260B1234".to_string(),
+            nwk_s_key: "44024241ed4ce9a68c6a8bc055233fd3".to_string(),
+            app_s_key: "ec925802ae430ca77fd3dd73cb2cc588".to_string(),
+            lorawan_version: Default::default(),
+            s_nwk_s_int_key: None,
+            class: Default::default(),
+            ping_slot_periodicity: 0,
+            rx2_datr: None,
+            rx1_delay: 1,
+        };
+        let session = KeyStore::from_config(std::slice::from_ref(&device)).unwrap();
+        let session = session.lookup(0x260B_1234)[0].clone();
+        let uplink = |mtype, fcnt| {
+            let phy = FrameBuilder {
+                mtype,
+                ..FrameBuilder::new_downlink(0x260B_1234, fcnt, 1, vec![0x01])
+            }
+            .build_with_mic(&session)
+            .unwrap();
+            let json = format!(
+                r#"{{"rxpk":[{{"tmst":1000000,"freq":902.3,"rssi":-60,"datr":"SF7BW125","size":{},"data":"{}"}}]}}"#,
+                phy.len(),
+                base64::engine::general_purpose::STANDARD.encode(&phy)
+            );
+            GwmpPacket::push_data(fcnt as u16, &[0xAA; 8], &json)
+        };
+        async fn next_downlink(gateway: &UdpSocket) -> Vec<u8> {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (len, _) = gateway.recv_from(&mut buf).await.unwrap();
+                let packet = GwmpPacket::parse(&buf[..len]);
+                if let Ok(GwmpPacket::PullResp { json_payload, .. }) = packet {
+                    let resp: PullRespPayload = serde_json::from_str(&json_payload).unwrap();
+                    let data = base64::engine::general_purpose::STANDARD.decode(&resp.txpk.data);
+                    return data.unwrap();
                 }
-            } else {
-                // TX_ACK with no JSON payload — treat as success
-                info!(
-                    "TX_ACK from gateway {} (token: 0x{:04x}): OK",
-                    gw_eui_hex, random_token
-                );
             }
         }
-        GwmpPacket::PushAck { random_token } => {
-            debug!("PUSH_ACK (token: 0x{:04x})", random_token);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut config = Config::default();
+            config.downlink.confirmed_retries = Some(1);
+            config.lorawan.devices = vec![device.clone()];
+            let tracker = GatewayTracker::new();
+            let ctx = PacketContext::new(&config, PokeRouter::new(), tracker, None, None).unwrap();
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let gateway_addr = gateway.local_addr().unwrap();
+            let mut buf = [0u8; 1024];
+            let pull = GwmpPacket::pull_data(1, &[0xAA; 8]);
+            handle_datagram(&socket, gateway_addr, &pull, &ctx).await;
+            gateway.recv_from(&mut buf).await.unwrap();
+
+            let frame = FrameBuilder::new_confirmed_downlink(0x260B_1234, 3, 1, vec![0x42])
+                .build_with_mic(&session)
+                .unwrap();
+            ctx.confirmed.track(0x260B_1234, 0, 7, frame.clone(), Instant::now());
+
+            // A confirmed uplink gets its ACK in RX1; the resend waits
+            handle_datagram(&socket, gateway_addr, &uplink(MType::ConfirmedDataUp, 5), &ctx).await;
+            let ack = next_downlink(&gateway).await;
+            assert_eq!(ack[5] & 0x20, 0x20);
+            assert_ne!(ack, frame);
+            let no_more = tokio::time::timeout(Duration::from_millis(100), next_downlink(&gateway));
+            assert!(no_more.await.is_err());
+            assert_eq!(ctx.confirmed.pending(0x260B_1234), Some(7));
+
+            // The next unconfirmed uplink still has the one retransmission left
+            handle_datagram(&socket, gateway_addr, &uplink(MType::UnconfirmedDataUp, 6), &ctx)
+                .await;
+            assert_eq!(next_downlink(&gateway).await, frame);
+            assert_eq!(ctx.confirmed.pending(0x260B_1234), Some(7));
+        });
+    }
+
+    #[test]
+    fn test_b1_conf_fcnt() {
+        let ctx = PacketContext::new(
+            &Config::default(),
+            PokeRouter::new(),
+            GatewayTracker::new(),
+            None,
+            None,
+        )
+        .unwrap();
+        let rxpk: Rxpk = serde_json::from_str(
+            r#"{"freq":902.3,"rssi":-60,"datr":"SF7BW125","size":4,"data":"AQIDBA=="}"#,
+        )
+        .unwrap();
+        let (keys, counters) = (&ctx.keys, &ctx.downlink_counters);
+        for _ in 0..5 {
+            build_device_frame(keys, counters, 0x260B_1234, 1, vec![0x01], false, false).unwrap();
         }
-        GwmpPacket::PullAck { random_token } => {
-            debug!("PULL_ACK (token: 0x{:04x})", random_token);
+        assert_eq!(ctx.b1_params(0x260B_1234, true, &rxpk).conf_fcnt, 0);
+
+        // An ACK answers the confirmed downlink, not the auto-ACK sent after it
+        build_device_frame(keys, counters, 0x260B_1234, 1, vec![0x01], true, false).unwrap();
+        counters.lock().unwrap().next(0x260B_1234);
+        assert_eq!(ctx.b1_params(0x260B_1234, true, &rxpk).conf_fcnt, 5);
+        assert_eq!(ctx.b1_params(0x260B_1234, false, &rxpk).conf_fcnt, 0);
+    }
+
+    #[cfg(feature = "phase4")]
+    #[test]
+    fn test_mic_past_16_bit_fcnt() {
+        let device = crate::config::AbpDeviceConfig {
+            dev_addr: "260B1234".to_string(),
+            nwk_s_key: "44024241ed4ce9a68c6a8bc055233fd3".to_string(),
+            app_s_key: "ec925802ae430ca77fd3dd73cb2cc588".to_string(),
+            lorawan_version: Default::default(),
+            s_nwk_s_int_key: None,
+            class: Default::default(),
+            ping_slot_periodicity: 0,
+            rx2_datr: None,
+            rx1_delay: 1,
+        };
+        let mut config = Config::default();
+        config.lorawan.devices = vec![device.clone()];
+        let ctx =
+            PacketContext::new(&config, PokeRouter::new(), GatewayTracker::new(), None, None)
+                .unwrap();
+        let session = KeyStore::from_config(&[device]).unwrap().lookup(0x260B_1234)[0].clone();
+        let rxpk: Rxpk = serde_json::from_str(
+            r#"{"tmst":1000000,"freq":902.3,"rssi":-60,"datr":"SF7BW125","size":0,"data":""}"#,
+        )
+        .unwrap();
+
+        // The frames carry 0xFFFF, 0x0000 and 0x0001; the MIC covers all 32 bits
+        for fcnt in [0xFFFF, 0x1_0000, 0x1_0001] {
+            let phy = FrameBuilder {
+                mtype: MType::UnconfirmedDataUp,
+                ..FrameBuilder::new_downlink(0x260B_1234, fcnt, 1, vec![0x01])
+            }
+            .build_with_mic(&session)
+            .unwrap();
+            let frame = lorawan::decode_phy_payload(&phy).unwrap();
+            assert_eq!(ctx.check_uplink(&frame, &phy, &rxpk), Some(MicStatus::Verified));
+            let tracker = ctx.fcnt_tracker.lock().unwrap();
+            assert_eq!(tracker.last(0x260B_1234), Some(fcnt));
         }
-        GwmpPacket::PullResp {
-            random_token,
-            json_payload,
-        } => {
-            debug!(
-                "PULL_RESP (token: 0x{:04x}): {} bytes",
-                random_token,
-                json_payload.len()
-            );
+    }
+
+    #[cfg(feature = "phase4")]
+    #[test]
+    fn test_no_auto_ack_without_verified_mic() {
+        use base64::Engine;
+
+        let device = crate::config::AbpDeviceConfig {
+            dev_addr: "260B1234".to_string(),
+            nwk_s_key: "44024241ed4ce9a68c6a8bc055233fd3".to_string(),
+            app_s_key: "ec925802ae430ca77fd3dd73cb2cc588".to_string(),
+            lorawan_version: Default::default(),
+            s_nwk_s_int_key: None,
+            class: Default::default(),
+            ping_slot_periodicity: 0,
+            rx2_datr: None,
+            rx1_delay: 1,
+        };
+        // A confirmed uplink with the device's DevAddr but a zeroed MIC
+        let phy = FrameBuilder {
+            mtype: MType::ConfirmedDataUp,
+            ..FrameBuilder::new_downlink(0x260B_1234, 5, 1, vec![0x01])
         }
+        .build()
+        .unwrap();
+        let json = format!(
+            r#"{{"rxpk":[{{"tmst":1000000,"freq":902.3,"rssi":-60,"datr":"SF7BW125","size":{},"data":"{}"}}]}}"#,
+            phy.len(),
+            base64::engine::general_purpose::STANDARD.encode(&phy)
+        );
+        let forged = GwmpPacket::push_data(2, &[0xAA; 8], &json);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut config = Config::default();
+            config.lorawan.devices = vec![device];
+            let tracker = GatewayTracker::new();
+            let ctx = PacketContext::new(&config, PokeRouter::new(), tracker, None, None).unwrap();
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let gateway_addr = gateway.local_addr().unwrap();
+            let mut buf = [0u8; 1024];
+            let pull = GwmpPacket::pull_data(1, &[0xAA; 8]);
+            handle_datagram(&socket, gateway_addr, &pull, &ctx).await;
+            gateway.recv_from(&mut buf).await.unwrap();
+
+            handle_datagram(&socket, gateway_addr, &forged, &ctx).await;
+            // Only the PUSH_ACK comes back, no ACK downlink
+            let (len, _) = gateway.recv_from(&mut buf).await.unwrap();
+            assert!(matches!(GwmpPacket::parse(&buf[..len]), Ok(GwmpPacket::PushAck { .. })));
+            let more =
+                tokio::time::timeout(Duration::from_millis(100), gateway.recv_from(&mut buf));
+            assert!(more.await.is_err());
+            let counters = ctx.downlink_counters.lock().unwrap();
+            assert_eq!(counters.peek(0x260B_1234), 0);
+        });
     }
-}
 
-/// Convert a decoded LoRaWAN frame + rxpk metadata into a LoRaPacket for Urbit
-fn frame_to_lora_packet(
-    frame: &LoRaWANFrame,
-    rxpk: &Rxpk,
-    gateway_eui: &str,
-) -> Option<LoRaPacket> {
-    match frame {
-        LoRaWANFrame::Data {
-            mtype,
-            dev_addr,
-            fcnt,
-            f_port,
-            frm_payload,
-            ..
-        } => Some(LoRaPacket {
-            dev_addr: format!("{:08X}", dev_addr),
-            fcnt: *fcnt,
-            f_port: *f_port,
-            payload: hex::encode(frm_payload),
-            rssi: rxpk.rssi,
-            snr: rxpk.lsnr,
-            freq: rxpk.freq,
-            data_rate: rxpk.datr.clone(),
-            gateway_eui: gateway_eui.to_string(),
-            received_at: chrono::Utc::now(),
-            mtype: mtype.to_string(),
-            source: PacketSource::Local,
-        }),
-        // JoinRequest, JoinAccept, Proprietary — skip for now
-        _ => {
-            debug!("Skipping non-data frame for Urbit forwarding");
-            None
+    /// Log lines written while the test runs
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
         }
     }
-}
 
-fn base64_decode(input: &str) -> anyhow::Result<Vec<u8>> {
-    use base64::Engine;
-    base64::engine::general_purpose::STANDARD
-        .decode(input)
-        .map_err(|e| anyhow::anyhow!("Base64 decode error: {}", e))
-}
+    #[test]
+    fn test_dry_run() {
+        use base64::Engine;
 
-/// Build a Txpk for a downlink transmission
-///
-/// Uses US915 Class C defaults: RX2 frequency 923.3 MHz, SF12BW500, 27 dBm.
-/// Immediate mode (imme=true) for Class C devices.
-pub fn build_txpk(payload_b64: &str, payload_size: u16) -> Txpk {
-    Txpk {
-        imme: Some(true),          // Immediate TX (Class C)
-        tmst: None,                // No timestamp (immediate mode)
-        freq: 923.3,               // US915 RX2 frequency
-        rfch: Some(0),             // RF chain 0
-        powe: Some(27),            // 27 dBm (US915 max EIRP)
-        modu: Some("LORA".to_string()),
-        datr: "SF12BW500".to_string(), // US915 RX2 default
-        codr: Some("4/5".to_string()),
-        ipol: Some(true),          // Inverted polarity for downlink
-        size: payload_size,
-        data: payload_b64.to_string(),
-        ncrc: Some(true),          // No CRC for downlink
+        // Only devices with a session are ACKed
+        let device = crate::config::AbpDeviceConfig {
+            dev_addr: "260B1234".to_string(),
+            nwk_s_key: "44024241ed4ce9a68c6a8bc055233fd3".to_string(),
+            app_s_key: "ec925802ae430ca77fd3dd73cb2cc588".to_string(),
+            lorawan_version: Default::default(),
+            s_nwk_s_int_key: None,
+            class: Default::default(),
+            ping_slot_periodicity: 7,
+            rx2_datr: None,
+            rx1_delay: 1,
+        };
+        // A confirmed uplink, which would normally be ACKed in RX1
+        let builder = FrameBuilder {
+            mtype: MType::ConfirmedDataUp,
+            ..FrameBuilder::new_downlink(0x260B_1234, 5, 1, vec![0x01, 0x02])
+        };
+        #[cfg(feature = "phase4")]
+        let phy = {
+            let keys = KeyStore::from_config(std::slice::from_ref(&device)).unwrap();
+            builder.build_with_mic(keys.lookup(0x260B_1234)[0]).unwrap()
+        };
+        #[cfg(not(feature = "phase4"))]
+        let phy = builder.build().unwrap();
+        let json = format!(
+            r#"{{"rxpk":[{{"tmst":1000000,"freq":902.3,"rssi":-60,"datr":"SF7BW125","size":{},"data":"{}"}}]}}"#,
+            phy.len(),
+            base64::engine::general_purpose::STANDARD.encode(&phy)
+        );
+
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(async {
+                let (tx, mut rx) = mpsc::channel(8);
+                let mut pokes = PokeRouter::new();
+                pokes.add(RouteRule::default(), tx);
+                let mut config = Config::default();
+                config.general.dry_run = true;
+                config.lorawan.devices = vec![device];
+                let ctx =
+                    PacketContext::new(&config, pokes, GatewayTracker::new(), None, None).unwrap();
+                let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+                let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                let gateway_addr = gateway.local_addr().unwrap();
+
+                let pull = GwmpPacket::pull_data(1, &[0xAA; 8]);
+                handle_datagram(&socket, gateway_addr, &pull, &ctx).await;
+                let push = GwmpPacket::push_data(2, &[0xAA; 8], &json);
+                handle_datagram(&socket, gateway_addr, &push, &ctx).await;
+                drop(ctx);
+                assert!(rx.recv().await.is_none(), "nothing is poked");
+
+                // The gateway gets its PULL_ACK and PUSH_ACK but no PULL_RESP
+                let mut buf = [0u8; 512];
+                let (len, _) = gateway.recv_from(&mut buf).await.unwrap();
+                assert_eq!(&buf[..len], GwmpPacket::pull_ack(1).as_slice());
+                let (len, _) = gateway.recv_from(&mut buf).await.unwrap();
+                assert_eq!(&buf[..len], GwmpPacket::push_ack(2).as_slice());
+                let extra = tokio::time::timeout(
+                    Duration::from_millis(200),
+                    gateway.recv_from(&mut buf),
+                )
+                .await;
+                assert!(extra.is_err(), "no downlink is sent");
+            });
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("[dry run] Would poke 1 target(s)"), "{}", logs);
+        assert!(logs.contains(r#""dev-addr":"260B1234""#), "{}", logs);
+        #[cfg(feature = "phase4")]
+        assert!(logs.contains("[dry run] Would send PULL_RESP"), "{}", logs);
+        #[cfg(not(feature = "phase4"))]
+        assert!(!logs.contains("[dry run] Would send PULL_RESP"), "{}", logs);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_drop_reasons() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut config = Config::default();
+            config.udp.denied_gateways = vec!["0000000000000001".to_string()];
+            let ctx =
+                PacketContext::new(&config, PokeRouter::new(), GatewayTracker::new(), None, None)
+                    .unwrap();
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let gateway_addr: SocketAddr = "127.0.0.1:9".parse().unwrap();
+
+            handle_datagram(&socket, gateway_addr, b"\x02\x12", &ctx).await;
+            let bad_json = GwmpPacket::push_data(1, &[0xAA; 8], r#"{"rxpk":"#);
+            handle_datagram(&socket, gateway_addr, &bad_json, &ctx).await;
+            let denied = GwmpPacket::pull_data(2, &[0, 0, 0, 0, 0, 0, 0, 1]);
+            handle_datagram(&socket, gateway_addr, &denied, &ctx).await;
+            let padding = " ".repeat(config.udp.max_json_len);
+            let oversized = GwmpPacket::push_data(3, &[0xAA; 8], &format!("{{}}{}", padding));
+            handle_datagram(&socket, gateway_addr, &oversized, &ctx).await;
+
+            let metrics = &ctx.uplink_metrics;
+            assert_eq!(metrics.drops(DropReason::Oversized), 1);
+            assert_eq!(metrics.drops(DropReason::ParseError), 2);
+            assert_eq!(metrics.drops(DropReason::GatewayDenied), 1);
+            assert_eq!(metrics.drops(DropReason::Replay), 0);
+        });
+    }
 
     #[test]
-    fn test_gateway_tracker() {
+    fn test_capture_round_trip() {
+        let path = std::env::temp_dir().join(format!("lora-urbit-{}.gwmpcap", std::process::id()));
+        let eui = [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF, 0x00, 0x11];
+        let pull_data = GwmpPacket::pull_data(0x1234, &eui);
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let gateway_addr = rt.block_on(async {
+            let config = Config::default();
+            let (capture, capture_task) = Capture::open(&path).await.unwrap();
+            let ctx = PacketContext::new(
+                &config,
+                PokeRouter::new(),
+                GatewayTracker::new(),
+                None,
+                Some(capture),
+            )
+            .unwrap();
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let gateway_addr = gateway.local_addr().unwrap();
+
+            handle_datagram(&socket, gateway_addr, &pull_data, &ctx).await;
+            // Unparseable datagrams are captured too
+            handle_datagram(&socket, gateway_addr, b"junk", &ctx).await;
+            assert_eq!(ctx.gateways_seen.count(), 1);
+
+            drop(ctx);
+            capture_task.await.unwrap();
+            gateway_addr
+        });
+
+        let datagrams = capture::read_capture(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let summary: Vec<_> = datagrams
+            .iter()
+            .map(|d| (d.direction, d.peer, d.data.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Direction::Received, gateway_addr, pull_data),
+                (Direction::Sent, gateway_addr, GwmpPacket::pull_ack(0x1234)),
+                (Direction::Received, gateway_addr, b"junk".to_vec()),
+            ]
+        );
+        assert!(datagrams[0].timestamp <= datagrams[2].timestamp);
+    }
+
+    #[test]
+    fn test_ipv6_pull_data_echo() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut config = Config::default();
+            config.udp.bind = vec!["[::1]:0".to_string()];
+            let socket = Arc::new(bind_socket(&config.udp.bind[0]).await.unwrap());
+            let server_addr = socket.local_addr().unwrap();
+            assert!(server_addr.is_ipv6());
+
+            let ctx =
+                PacketContext::new(&config, PokeRouter::new(), GatewayTracker::new(), None, None)
+                    .unwrap();
+            let gateway = UdpSocket::bind("[::1]:0").await.unwrap();
+            let eui = [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF, 0x00, 0x11];
+            let mut pull_data = vec![0x02, 0x12, 0x34, 0x02];
+            pull_data.extend_from_slice(&eui);
+            gateway.send_to(&pull_data, server_addr).await.unwrap();
+
+            let mut buf = [0u8; 64];
+            let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+            let packet = GwmpPacket::parse(&buf[..len]).unwrap();
+            handle_packet(&socket, src, PROTOCOL_VERSION, packet, &ctx).await;
+
+            // PULL_ACK echoes the token back to the IPv6 gateway
+            let (len, _) = tokio::time::timeout(
+                std::time::Duration::from_secs(2),
+                gateway.recv_from(&mut buf),
+            )
+            .await
+            .expect("no PULL_ACK")
+            .unwrap();
+            assert_eq!(&buf[..len], &[0x02, 0x12, 0x34, 0x04]);
+            assert_eq!(ctx.gateway.get().await, Some(gateway.local_addr().unwrap()));
+        });
+    }
+
+    #[test]
+    fn test_tx_error_mapping() {
+        let cases = [
+            ("TOO_LATE", TxError::TooLate, true),
+            ("TOO_EARLY", TxError::TooEarly, true),
+            ("COLLISION_PACKET", TxError::CollisionPacket, true),
+            ("COLLISION_BEACON", TxError::CollisionBeacon, true),
+            ("TX_FREQ", TxError::TxFreq, false),
+            ("TX_POWER", TxError::TxPower, false),
+            ("GPS_UNLOCKED", TxError::GpsUnlocked, false),
+        ];
+        for (s, expected, transient) in cases {
+            let json = format!(r#"{{"txpk_ack":{{"error":"{}"}}}}"#, s);
+            assert_eq!(TxResult::from_tx_ack(Some(&json)), TxResult::Error(expected.clone()));
+            assert_eq!(expected.is_transient(), transient, "{}", s);
+            assert_eq!(expected.to_string(), s);
+        }
+
+        let other = TxError::parse("HALT");
+        assert_eq!(other, TxError::Other("HALT".to_string()));
+        assert!(!other.is_transient());
+
+        // Missing payload, missing txpk_ack and "NONE" are all success
+        assert_eq!(TxResult::from_tx_ack(None), TxResult::Success);
+        assert_eq!(TxResult::from_tx_ack(Some("{}")), TxResult::Success);
+        let none = r#"{"txpk_ack":{"error":"NONE"}}"#;
+        assert_eq!(TxResult::from_tx_ack(Some(none)), TxResult::Success);
+        // A warning alone is not a failure
+        let warned = r#"{"txpk_ack":{"warn":"TX_POWER","value":14}}"#;
+        assert_eq!(TxResult::from_tx_ack(Some(warned)), TxResult::Success);
+    }
+
+    #[test]
+    fn test_duty_cycle_limiter() {
+        let config = DutyCycleConfig::default();
+        assert!(DutyCycleLimiter::from_config(&config, Region::US915).is_none());
+
+        // EU868 RX2 sub-band: 10% of a 10 s window = 1 s of airtime
+        let config = DutyCycleConfig {
+            window_secs: 10,
+            ..Default::default()
+        };
+        let mut limiter = DutyCycleLimiter::from_config(&config, Region::EU868).unwrap();
+        let start = Instant::now();
+        let airtime = Duration::from_millis(400);
+        assert!(limiter.try_reserve(869.525, airtime, start).is_ok());
+        assert!(limiter.try_reserve(869.525, airtime, start).is_ok());
+        assert_eq!(
+            limiter.try_reserve(869.525, airtime, start),
+            Err(TxError::DutyCycleExceeded)
+        );
+
+        // Other sub-bands have their own budget; unknown frequencies are free
+        assert!(limiter.try_reserve(868.1, Duration::from_millis(50), start).is_ok());
+        assert!(limiter.try_reserve(923.3, Duration::from_secs(60), start).is_ok());
+
+        // A refunded transmission leaves room for another
+        limiter.refund(869.525, airtime);
+        assert!(limiter.try_reserve(869.525, airtime, start).is_ok());
+        limiter.refund(923.3, airtime);
+
+        // Budget frees up as old transmissions leave the window
+        let later = start + Duration::from_secs(10);
+        assert!(limiter.try_reserve(869.525, airtime, later).is_ok());
+    }
+
+    #[test]
+    fn test_downlink_airtime_coding_rate() {
+        let plan = ChannelPlan::from_config(&Default::default()).unwrap();
+        let txpk = build_txpk(&plan, None, None, "AQIDBA==", 4).unwrap();
+        assert_eq!(txpk.codr.as_deref(), Some("4/5"));
+        let at_4_5 = downlink_airtime(&txpk);
+
+        // A 4/8 downlink takes longer on air and is charged accordingly
+        let slower = Txpk {
+            codr: Some("4/8".to_string()),
+            ..txpk.clone()
+        };
+        let expected = downlink_time_on_air(4, txpk.datr.clone(), 4).unwrap();
+        assert_eq!(downlink_airtime(&slower), expected);
+        assert!(expected > at_4_5);
+        let unset = Txpk { codr: None, ..txpk };
+        assert_eq!(downlink_airtime(&unset), at_4_5);
+    }
+
+    #[test]
+    fn test_pending_tx_acks() {
+        let acks = PendingTxAcks::default();
+        let rx = acks.register(0x1234);
+        assert!(!acks.resolve(0x9999, TxResult::Success));
+        assert!(acks.resolve(0x1234, TxResult::Error(TxError::TooLate)));
+        assert_eq!(rx.blocking_recv().unwrap(), TxResult::Error(TxError::TooLate));
+
+        // Cancelled tokens are not resolved later
+        let _rx = acks.register(0x5678);
+        acks.cancel(0x5678);
+        assert!(!acks.resolve(0x5678, TxResult::Success));
+    }
+
+    #[test]
+    fn test_to_gateway_tx_ack() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let gateway_addr = gateway.local_addr().unwrap();
+            let mut sender =
+                DownlinkSender::to_gateway("127.0.0.1:0", gateway_addr, Region::US915)
+                    .await
+                    .unwrap();
+            let band = DutyCycleBand {
+                min_freq: 923.0,
+                max_freq: 924.0,
+                percent: 10.0,
+            };
+            let limiter = DutyCycleLimiter::new(vec![band], Duration::from_secs(10));
+            sender.duty_cycle = Some(Arc::new(std::sync::Mutex::new(limiter)));
+
+            // Fake gateway: answer the PULL_RESP with a TOO_LATE TX_ACK
+            let fake = tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let (len, from) = gateway.recv_from(&mut buf).await.unwrap();
+                let token = match GwmpPacket::parse(&buf[..len]).unwrap() {
+                    GwmpPacket::PullResp { random_token, .. } => random_token,
+                    other => panic!("expected PULL_RESP, got {:?}", other),
+                };
+                let ack = GwmpPacket::tx_ack(
+                    token,
+                    &[0; 8],
+                    Some(r#"{"txpk_ack":{"error":"TOO_LATE"}}"#),
+                );
+                gateway.send_to(&ack, from).await.unwrap();
+            });
+
+            let plan = ChannelPlan::from_config(&Default::default()).unwrap();
+            let txpk = build_txpk(&plan, None, None, "AQIDBA==", 4).unwrap();
+            let result = sender
+                .send_downlink_acked(&txpk, Duration::from_secs(5))
+                .await
+                .unwrap();
+            assert_eq!(result, TxResult::Error(TxError::TooLate));
+            fake.await.unwrap();
+            // Not transmitted, so not charged
+            let limiter = sender.duty_cycle.as_ref().unwrap().lock().unwrap();
+            assert_eq!(limiter.used(0), Duration::ZERO);
+        });
+    }
+
+    #[test]
+    fn test_rx2_fallback() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let gateway_addr = gateway.local_addr().unwrap();
+            let sender = DownlinkSender::to_gateway("127.0.0.1:0", gateway_addr, Region::US915)
+                .await
+                .unwrap();
+
+            // Fake gateway: RX1 is TOO_LATE, RX2 goes out
+            let fake = tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let mut windows = Vec::new();
+                for error in ["TOO_LATE", "NONE"] {
+                    let (len, from) = gateway.recv_from(&mut buf).await.unwrap();
+                    let (token, json) = match GwmpPacket::parse(&buf[..len]).unwrap() {
+                        GwmpPacket::PullResp { random_token, json_payload } => {
+                            (random_token, json_payload)
+                        }
+                        other => panic!("expected PULL_RESP, got {:?}", other),
+                    };
+                    let txpk: serde_json::Value = serde_json::from_str(&json).unwrap();
+                    windows.push((txpk["txpk"]["freq"].as_f64(), txpk["txpk"]["tmst"].as_u64()));
+                    let ack = format!(r#"{{"txpk_ack":{{"error":"{}"}}}}"#, error);
+                    let ack = GwmpPacket::tx_ack(token, &[0; 8], Some(&ack));
+                    gateway.send_to(&ack, from).await.unwrap();
+                }
+                windows
+            });
+
+            let plan = ChannelPlan::from_config(&crate::config::ChannelPlanConfig {
+                sub_band: Some(2),
+                ..Default::default()
+            })
+            .unwrap();
+            let rxpk: Rxpk = serde_json::from_str(
+                r#"{"tmst":1000000,"freq":904.5,"rssi":-40,"datr":"SF9BW125","size":4,"data":"AQIDBA=="}"#,
+            )
+            .unwrap();
+            let txpks = ClassATxpks::build(&plan, None, &rxpk, 1, "AQIDBA==", 4);
+            let result = sender.send_class_a(&txpks, Duration::from_secs(5)).await.unwrap();
+            assert_eq!(result, TxResult::Success);
+            assert_eq!(
+                fake.await.unwrap(),
+                vec![(Some(925.1), Some(2_000_000)), (Some(923.3), Some(3_000_000))]
+            );
+        });
+    }
+
+    #[test]
+    fn test_payload_too_large() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let gateway_addr = gateway.local_addr().unwrap();
+            let sender = DownlinkSender::to_gateway("127.0.0.1:0", gateway_addr, Region::US915)
+                .await
+                .unwrap();
+
+            // SF12BW500 carries at most a 41-byte MACPayload (46-byte PHYPayload)
+            let plan = ChannelPlan::from_config(&Default::default()).unwrap();
+            let mut txpk = build_txpk(&plan, None, None, "AQIDBA==", 47).unwrap();
+            txpk.datr = DataRate::lora(12, 500);
+            let result = sender
+                .send_downlink_acked(&txpk, Duration::from_secs(1))
+                .await
+                .unwrap();
+            let err = TxError::PayloadTooLarge {
+                size: 42,
+                max: 41,
+                datr: DataRate::lora(12, 500),
+            };
+            assert!(!err.is_transient());
+            assert_eq!(
+                err.to_string(),
+                "PAYLOAD_TOO_LARGE (42-byte MACPayload, SF12BW500 allows 41)"
+            );
+            assert_eq!(result, TxResult::Error(err));
+
+            // Nothing reached the gateway; at the limit it goes out
+            txpk.size = 46;
+            assert!(check_payload_size(Region::US915, &txpk).is_ok());
+            let mut buf = [0u8; 64];
+            let recv = gateway.recv_from(&mut buf);
+            assert!(tokio::time::timeout(Duration::from_millis(50), recv).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_retry_until_gateway_known() {
+        use crate::urbit::outbox::DownlinkQueue;
+        use crate::urbit::types::OutboundMessage;
+
+        let outbox = vec![OutboundMessage {
+            id: 1,
+            dest_ship: "~bus".to_string(),
+            dest_addr: "01AB5678".to_string(),
+            src_addr: String::new(),
+            payload: "01020304".to_string(),
+            queued_at: serde_json::Value::Null,
+            confirmed: false,
+            join_accept: None,
+        }];
         let rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async {
+            let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
             let tracker = GatewayTracker::new();
-            assert!(tracker.get().await.is_none());
+            let sender = DownlinkSender {
+                socket: Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+                gateway: tracker.clone(),
+                tx_acks: PendingTxAcks::default(),
+                loop_guard: LoopGuard::default(),
+                duty_cycle: None,
+                capture: None,
+                class_b: Default::default(),
+                dry_run: false,
+                region: Region::US915,
+                best_gateways: BestGateways::new(),
+            joins: PendingJoins::new(),
+                #[cfg(feature = "helium-grpc")]
+                packet_router: None,
+            };
+            let plan = ChannelPlan::from_config(&Default::default()).unwrap();
+            let txpk = build_txpk(&plan, None, None, "AQIDBA==", 4).unwrap();
+            let mut queue = DownlinkQueue::new(0, false);
+            let mut now = Instant::now();
 
-            let addr: SocketAddr = "127.0.0.1:1700".parse().unwrap();
-            tracker.set(addr).await;
-            assert_eq!(tracker.get().await, Some(addr));
+            // No PULL_DATA yet: the first two sends fail and back off
+            for attempt in 1..=2 {
+                assert_eq!(queue.take_ready_at(&outbox, now).len(), 1);
+                let result = sender.send_downlink_acked(&txpk, Duration::from_millis(50)).await;
+                assert_eq!(result.unwrap(), TxResult::Error(TxError::NoGateway));
+                assert!(TxError::NoGateway.is_transient());
+                assert_eq!(queue.attempts(1), attempt);
+                let delay = queue.retry_later(1, now);
+                assert!(queue.take_ready_at(&outbox, now).is_empty());
+                now += delay;
+            }
 
-            // Update with new address
-            let addr2: SocketAddr = "127.0.0.1:1701".parse().unwrap();
-            tracker.set(addr2).await;
-            assert_eq!(tracker.get().await, Some(addr2));
+            // A gateway shows up before the third attempt
+            tracker.set(gateway.local_addr().unwrap()).await;
+            assert_eq!(queue.take_ready_at(&outbox, now).len(), 1);
+            let result = sender.send_downlink_acked(&txpk, Duration::from_millis(50)).await;
+            assert_eq!(result.unwrap(), TxResult::NoAck);
+            let mut buf = [0u8; 512];
+            let (len, _) = gateway.recv_from(&mut buf).await.unwrap();
+            match GwmpPacket::parse(&buf[..len]).unwrap() {
+                GwmpPacket::PullResp { json_payload, .. } => {
+                    assert!(json_payload.contains("AQIDBA=="))
+                }
+                other => panic!("expected PULL_RESP, got {:?}", other),
+            }
         });
     }
 
     #[test]
-    fn test_build_txpk() {
-        let txpk = build_txpk("AQIDBA==", 4);
-        assert_eq!(txpk.freq, 923.3);
-        assert_eq!(txpk.imme, Some(true));
-        assert_eq!(txpk.ipol, Some(true));
-        assert_eq!(txpk.datr, "SF12BW500");
-        assert_eq!(txpk.data, "AQIDBA==");
-        assert_eq!(txpk.size, 4);
+    fn test_match_socket_family() {
+        let v4: SocketAddr = "127.0.0.1:1680".parse().unwrap();
+        let v6: SocketAddr = "[::]:1680".parse().unwrap();
+        let gw4: SocketAddr = "192.168.1.20:1700".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:192.168.1.20]:1700".parse().unwrap();
+        let gw6: SocketAddr = "[2001:db8::1]:1700".parse().unwrap();
+
+        assert_eq!(match_socket_family(v6, gw4).unwrap(), mapped);
+        assert_eq!(match_socket_family(v4, mapped).unwrap(), gw4);
+        assert_eq!(match_socket_family(v6, gw6).unwrap(), gw6);
+        assert!(match_socket_family(v4, gw6).is_err());
+    }
+
+    #[test]
+    fn test_multiple_binds() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut config = Config::default();
+            config.udp.bind = vec!["127.0.0.1:0".to_string(), "127.0.0.1:0".to_string()];
+            config.udp.gateway_timeout_secs = 0;
+            let shutdown = CancellationToken::new();
+            let server = start_server(&config, PokeRouter::new(), shutdown.clone())
+                .await
+                .unwrap();
+            assert_eq!(server.local_addrs.len(), 2);
+            assert_eq!(server.local_addr, server.local_addrs[0]);
+            let tunnel_addr = server.local_addrs[1];
+            assert_ne!(server.local_addr, tunnel_addr);
+
+            // A gateway reaching the second port is answered, and sent downlinks, from it
+            let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let pull = GwmpPacket::pull_data(7, &[0xAA; 8]);
+            gateway.send_to(&pull, tunnel_addr).await.unwrap();
+            let mut buf = [0u8; 512];
+            let (len, from) = gateway.recv_from(&mut buf).await.unwrap();
+            assert_eq!((&buf[..len], from), (GwmpPacket::pull_ack(7).as_slice(), tunnel_addr));
+
+            let plan = ChannelPlan::from_config(&Default::default()).unwrap();
+            let txpk = build_txpk(&plan, None, None, "AQIDBA==", 4).unwrap();
+            server.downlink_sender.send_downlink(&txpk).await.unwrap();
+            let (len, from) = gateway.recv_from(&mut buf).await.unwrap();
+            assert!(matches!(GwmpPacket::parse(&buf[..len]), Ok(GwmpPacket::PullResp { .. })));
+            assert_eq!(from, tunnel_addr);
+
+            shutdown.cancel();
+            server.task.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_server_shutdown() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut config = Config::default();
+            config.udp.bind = vec!["127.0.0.1:0".to_string()];
+
+            let (tx, mut rx) = mpsc::channel::<LoRaAction>(8);
+            let shutdown = CancellationToken::new();
+            let mut pokes = PokeRouter::new();
+            pokes.add(RouteRule::default(), tx);
+            let server = start_server(&config, pokes, shutdown.clone())
+                .await
+                .unwrap();
+
+            shutdown.cancel();
+            tokio::time::timeout(std::time::Duration::from_secs(2), server.task)
+                .await
+                .expect("server task did not stop after cancel")
+                .unwrap();
+
+            // The packet channel is closed once the server exits
+            assert!(rx.recv().await.is_none());
+        });
     }
 }
@@ -0,0 +1,312 @@
+//! JSON-Lines log of decoded uplinks (`logging.packet_log`)
+//!
+//! Each uplink is written as one JSON object: the `LoRaPacket` sent to Urbit
+//! plus the raw PHY payload in hex. `handle_packet` only queues entries; a
+//! writer task does the disk I/O, so a slow disk never stalls the UDP loop
+//! (entries are dropped with a warning if the queue fills up). When the file
+//! reaches its size limit it is renamed to `<path>.1` and a new one started.
+//!
+//! `lora-urbit replay` reads a log back and re-sends each entry as the
+//! PUSH_DATA its gateway would have sent.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::config::LoggingConfig;
+use crate::udp::protocol::{GatewayEui, GwmpPacket, Rxpk};
+use crate::urbit::types::LoRaPacket;
+
+/// Entries buffered between the UDP loop and the writer task
+const QUEUE_DEPTH: usize = 1024;
+
+/// One line of the packet log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PacketLogEntry {
+    #[serde(flatten)]
+    pub packet: LoRaPacket,
+    /// Raw PHY payload (hex)
+    pub phy: String,
+}
+
+impl PacketLogEntry {
+    /// Rebuild the rxpk the entry was decoded from
+    ///
+    /// Fields the log does not keep are approximated: a missing `tmst` is
+    /// the reception time's microseconds (so Class A windows can still be
+    /// scheduled) and LoRa uplinks are given coding rate 4/5.
+    pub fn to_rxpk(&self) -> anyhow::Result<Rxpk> {
+        use base64::Engine;
+
+        let phy = hex::decode(&self.phy)
+            .map_err(|e| anyhow::anyhow!("Invalid phy hex {:?}: {}", self.phy, e))?;
+        let packet = &self.packet;
+        let fsk = packet.data_rate.is_fsk();
+        Ok(Rxpk {
+            time: Some(packet.received_at.to_rfc3339()),
+            tmst: packet
+                .tmst
+                .or(Some(packet.received_at.timestamp_micros() as u64 & 0xFFFF_FFFF)),
+            tmms: packet.tmms,
+            chan: packet.chan,
+            rfch: Some(0),
+            freq: packet.freq,
+            lsnr: packet.snr,
+            rssi: packet.rssi,
+            modu: Some(if fsk { "FSK" } else { "LORA" }.to_string()),
+            datr: packet.data_rate.clone(),
+            codr: (!fsk).then(|| "4/5".to_string()),
+            size: phy.len() as u16,
+            data: base64::engine::general_purpose::STANDARD.encode(&phy),
+        })
+    }
+
+    /// The PUSH_DATA datagram carrying `to_rxpk` from the entry's gateway
+    pub fn to_push_data(&self, random_token: u16) -> anyhow::Result<Vec<u8>> {
+        let eui: GatewayEui = hex::decode(&self.packet.gateway_eui)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| {
+                anyhow::anyhow!("Gateway EUI {:?} is not 8 bytes of hex", self.packet.gateway_eui)
+            })?;
+        let json = serde_json::json!({ "rxpk": [self.to_rxpk()?] }).to_string();
+        Ok(GwmpPacket::push_data(random_token, &eui, &json))
+    }
+}
+
+/// Parse a packet log's lines, skipping blank ones
+pub fn read_entries(text: &str) -> anyhow::Result<Vec<PacketLogEntry>> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| anyhow::anyhow!("Packet log line {}: {}", i + 1, e))
+        })
+        .collect()
+}
+
+/// Handle for queueing entries to the writer task
+#[derive(Debug, Clone)]
+pub struct PacketLog {
+    tx: mpsc::Sender<PacketLogEntry>,
+}
+
+impl PacketLog {
+    /// Open (append to) `path` and spawn the writer task
+    ///
+    /// The task exits once every `PacketLog` handle has been dropped.
+    pub async fn open(path: &Path, max_bytes: u64) -> anyhow::Result<(Self, JoinHandle<()>)> {
+        let writer = LogWriter::open(path, max_bytes).await?;
+        let (tx, rx) = mpsc::channel(QUEUE_DEPTH);
+        Ok((Self { tx }, tokio::spawn(writer.run(rx))))
+    }
+
+    /// Open `logging.packet_log`, if set
+    pub async fn from_config(
+        config: &LoggingConfig,
+    ) -> anyhow::Result<Option<(Self, JoinHandle<()>)>> {
+        let Some(path) = &config.packet_log else {
+            return Ok(None);
+        };
+        let max_bytes = config.packet_log_max_mb * 1024 * 1024;
+        Ok(Some(Self::open(Path::new(path), max_bytes).await?))
+    }
+
+    /// Queue an entry without waiting
+    pub fn record(&self, entry: PacketLogEntry) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(entry) {
+            warn!("Packet log queue full; dropping entry");
+        }
+    }
+}
+
+struct LogWriter {
+    path: PathBuf,
+    max_bytes: u64,
+    file: BufWriter<File>,
+    /// Size of the current file
+    written: u64,
+}
+
+impl LogWriter {
+    async fn open(path: &Path, max_bytes: u64) -> anyhow::Result<Self> {
+        let file = Self::open_file(path).await?;
+        let written = file.metadata().await?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_bytes,
+            file: BufWriter::new(file),
+            written,
+        })
+    }
+
+    async fn open_file(path: &Path) -> anyhow::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to open packet log {:?}: {}", path, e))
+    }
+
+    async fn run(mut self, mut rx: mpsc::Receiver<PacketLogEntry>) {
+        while let Some(entry) = rx.recv().await {
+            if let Err(e) = self.write(&entry).await {
+                warn!("Failed to write packet log {:?}: {}", self.path, e);
+            }
+            // Flush whenever we catch up so the file is readable while running
+            if rx.is_empty() {
+                if let Err(e) = self.file.flush().await {
+                    warn!("Failed to flush packet log {:?}: {}", self.path, e);
+                }
+            }
+        }
+        let _ = self.file.flush().await;
+    }
+
+    async fn write(&mut self, entry: &PacketLogEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let len = line.len() as u64;
+        if self.max_bytes > 0 && self.written > 0 && self.written + len > self.max_bytes {
+            self.rotate().await?;
+        }
+        self.file.write_all(&line).await?;
+        self.written += len;
+        Ok(())
+    }
+
+    /// Move the current file to `<path>.1` (replacing it) and start afresh
+    async fn rotate(&mut self) -> anyhow::Result<()> {
+        self.file.flush().await?;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        tokio::fs::rename(&self.path, &rotated).await?;
+        self.file = BufWriter::new(Self::open_file(&self.path).await?);
+        self.written = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::urbit::types::PacketSource;
+
+    fn entry(fcnt: u16) -> PacketLogEntry {
+        PacketLogEntry {
+            packet: LoRaPacket {
+                dev_addr: "260B1234".to_string(),
+                fcnt,
+                f_port: Some(1),
+                payload: "48656c6c6f".to_string(),
+                rssi: -42.0,
+                snr: Some(9.5),
+                freq: 904.5,
+                data_rate: "SF7BW125".parse().unwrap(),
+                gateway_eui: "aabbccddeeff0011".to_string(),
+                received_at: chrono::Utc::now(),
+                mtype: "UnconfirmedDataUp".to_string(),
+                source: PacketSource::Local,
+                decoded: None,
+                alt_receptions: Vec::new(),
+                mac: None,
+                tmst: None,
+                tmms: None,
+                chan: None,
+                receptions: Vec::new(),
+                missed_frames: None,
+                class: Default::default(),
+            },
+            phy: "4034120b2600010001".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_write_and_rotate() {
+        let dir = std::env::temp_dir()
+            .join(format!("lora-urbit-packet-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("packets.jsonl");
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (log, task) = PacketLog::open(&path, 0).await.unwrap();
+            log.record(entry(1));
+            drop(log);
+            task.await.unwrap();
+        });
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let read: PacketLogEntry = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(read.packet.fcnt, 1);
+        assert_eq!(read.packet.data_rate.to_string(), "SF7BW125");
+        assert_eq!(read.phy, "4034120b2600010001");
+        assert!(lines[0].contains(r#""dev-addr":"260B1234""#));
+
+        // Appends to the existing file; rolls over once a line would not fit
+        let limit = content.len() as u64 + 10;
+        rt.block_on(async {
+            let (log, task) = PacketLog::open(&path, limit).await.unwrap();
+            log.record(entry(2));
+            drop(log);
+            task.await.unwrap();
+        });
+        let rotated = std::fs::read_to_string(dir.join("packets.jsonl.1")).unwrap();
+        assert_eq!(rotated, content);
+        let current = std::fs::read_to_string(&path).unwrap();
+        let read: PacketLogEntry = serde_json::from_str(current.trim_end()).unwrap();
+        assert_eq!(read.packet.fcnt, 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_frames() {
+        use base64::Engine;
+
+        let first = serde_json::to_string(&entry(7)).unwrap();
+        let helium = PacketLogEntry {
+            packet: LoRaPacket {
+                gateway_eui: "00aabb".to_string(),
+                ..entry(8).packet
+            },
+            ..entry(8)
+        };
+        let text = format!("{}\n\n{}\n", first, serde_json::to_string(&helium).unwrap());
+        let entries = read_entries(&text).unwrap();
+        assert_eq!(entries.len(), 2);
+
+        let frame = entries[0].to_push_data(0x1234).unwrap();
+        let GwmpPacket::PushData {
+            random_token,
+            gateway_eui,
+            json_payload,
+        } = GwmpPacket::parse(&frame).unwrap()
+        else {
+            panic!("expected PUSH_DATA");
+        };
+        assert_eq!(random_token, 0x1234);
+        assert_eq!(hex::encode(gateway_eui), "aabbccddeeff0011");
+        let push: crate::udp::protocol::PushDataPayload =
+            serde_json::from_str(&json_payload).unwrap();
+        let rxpk = &push.rxpk.unwrap()[0];
+        assert_eq!((rxpk.freq, rxpk.rssi, rxpk.lsnr), (904.5, -42.0, Some(9.5)));
+        assert_eq!(rxpk.datr.to_string(), "SF7BW125");
+        assert_eq!(rxpk.size, 9);
+        let phy = base64::engine::general_purpose::STANDARD.decode(&rxpk.data).unwrap();
+        assert_eq!(hex::encode(phy), "4034120b2600010001");
+
+        // Packets relayed by gRPC have no 8-byte EUI to send from
+        assert!(entries[1].to_push_data(1).is_err());
+        assert!(read_entries("{\"dev-addr\":").unwrap_err().to_string().contains("line 1"));
+    }
+}
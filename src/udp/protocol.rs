@@ -4,13 +4,29 @@
 //!
 //! The protocol uses a simple binary header followed by JSON payload.
 //! All multi-byte integers are big-endian (network byte order).
+//!
+//! Legacy forwarders speak version 1, which frames every packet the same
+//! way except that PULL_RESP carries no token and TX_ACK does not exist.
+//! `parse_versioned` accepts it on request (`udp.accept_protocol_v1`).
+//!
+//! Some relays compress the JSON with gzip (magic `1f 8b`) or zlib (a
+//! `78 xx` header); with the `gzip` feature such payloads are inflated
+//! transparently before parsing.
+//!
+//! JSON longer than a limit (`udp.max_json_len`, after inflating) is
+//! refused with `PayloadTooLong` before it is copied into a `String`.
 
 use bytes::{Buf, BufMut, BytesMut};
 use serde::{Deserialize, Serialize};
 
-/// Protocol version (always 0x02)
+use crate::lorawan::datarate::DataRate;
+
+/// Protocol version of every packet built here
 pub const PROTOCOL_VERSION: u8 = 0x02;
 
+/// Legacy protocol version, accepted with `udp.accept_protocol_v1`
+pub const PROTOCOL_VERSION_1: u8 = 0x01;
+
 /// Packet types (identifier byte)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -39,6 +55,80 @@ impl TryFrom<u8> for PacketType {
     }
 }
 
+/// Largest JSON payload inflated from a compressed datagram
+#[cfg(feature = "gzip")]
+const MAX_INFLATED_LEN: u64 = 256 * 1024;
+
+/// Default limit on a datagram's JSON payload (`udp.max_json_len`)
+pub const MAX_JSON_LEN: usize = 16 * 1024;
+
+/// A datagram's JSON payload is over the length limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadTooLong {
+    pub what: &'static str,
+    pub max: usize,
+}
+
+impl std::fmt::Display for PayloadTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} JSON payload is over {} bytes (udp.max_json_len)", self.what, self.max)
+    }
+}
+
+impl std::error::Error for PayloadTooLong {}
+
+/// A gzip stream or a zlib stream (CMF 0x78 with a valid header checksum)
+fn is_compressed(payload: &[u8]) -> bool {
+    match payload {
+        [0x1f, 0x8b, ..] => true,
+        [0x78, flg, ..] => (0x78u16 * 256 + *flg as u16).is_multiple_of(31),
+        _ => false,
+    }
+}
+
+/// The JSON text of a datagram payload, inflating it if compressed
+///
+/// Fails with `PayloadTooLong` if the text would be over `max` bytes.
+fn json_text(payload: &[u8], what: &'static str, max: usize) -> anyhow::Result<String> {
+    let too_long = || anyhow::Error::new(PayloadTooLong { what, max });
+    if !is_compressed(payload) {
+        if payload.len() > max {
+            return Err(too_long());
+        }
+        return String::from_utf8(payload.to_vec())
+            .map_err(|e| anyhow::anyhow!("Invalid UTF-8 in {} payload: {}", what, e));
+    }
+    #[cfg(feature = "gzip")]
+    {
+        use std::io::Read;
+
+        // One byte past the limit tells an over-long payload from one at it
+        let limit = MAX_INFLATED_LEN.min(max as u64 + 1);
+        let mut json = String::new();
+        let result = if payload[0] == 0x1f {
+            flate2::read::GzDecoder::new(payload)
+                .take(limit)
+                .read_to_string(&mut json)
+        } else {
+            flate2::read::ZlibDecoder::new(payload)
+                .take(limit)
+                .read_to_string(&mut json)
+        };
+        if json.len() > max {
+            return Err(too_long());
+        }
+        result.map_err(|e| anyhow::anyhow!("Invalid compressed {} payload: {}", what, e))?;
+        Ok(json)
+    }
+    #[cfg(not(feature = "gzip"))]
+    {
+        Err(anyhow::anyhow!(
+            "Compressed {} payload requires the gzip feature",
+            what
+        ))
+    }
+}
+
 /// Gateway identifier (EUI-64, 8 bytes)
 pub type GatewayEui = [u8; 8];
 
@@ -76,9 +166,9 @@ pub enum GwmpPacket {
 pub struct Rxpk {
     /// UTC time of packet reception
     pub time: Option<String>,
-    /// GPS time (seconds since GPS epoch)
+    /// Concentrator timestamp (microseconds, wraps at 2^32)
     pub tmst: Option<u64>,
-    /// Concentrator timestamp (microseconds)
+    /// GPS time of reception (milliseconds since the GPS epoch), if GPS-synced
     pub tmms: Option<u64>,
     /// RF channel
     pub chan: Option<u8>,
@@ -92,8 +182,9 @@ pub struct Rxpk {
     pub rssi: f64,
     /// Modulation (LORA or FSK)
     pub modu: Option<String>,
-    /// LoRa datarate identifier (e.g., "SF7BW125")
-    pub datr: String,
+    /// LoRa datarate identifier (e.g., "SF7BW125"), or the FSK bitrate in
+    /// bits/s, which gateways send as a JSON number (e.g. 50000)
+    pub datr: DataRate,
     /// LoRa coding rate (e.g., "4/5")
     pub codr: Option<String>,
     /// RF packet payload size in bytes
@@ -102,6 +193,16 @@ pub struct Rxpk {
     pub data: String,
 }
 
+impl Rxpk {
+    /// Whether this is an FSK uplink
+    pub fn is_fsk(&self) -> bool {
+        match &self.modu {
+            Some(modu) => modu.eq_ignore_ascii_case("FSK"),
+            None => self.datr.is_fsk(),
+        }
+    }
+}
+
 /// Push data JSON wrapper
 #[derive(Debug, Deserialize)]
 pub struct PushDataPayload {
@@ -109,6 +210,99 @@ pub struct PushDataPayload {
     pub stat: Option<serde_json::Value>,
 }
 
+/// The rxpk entries that could be recovered from PUSH_DATA JSON that does not parse
+#[derive(Debug)]
+pub struct SalvagedRxpks {
+    pub rxpks: Vec<Rxpk>,
+    /// Byte offset of the first entry that was invalid or cut off
+    pub failed_at: Option<usize>,
+}
+
+/// Best-effort parse of the `rxpk` array of truncated or malformed PUSH_DATA JSON
+///
+/// Complete entries that are not valid rxpks are skipped; scanning stops at
+/// the first entry that is cut off. `stat` is not recovered.
+pub fn salvage_rxpks(json: &str) -> SalvagedRxpks {
+    let mut salvaged = SalvagedRxpks {
+        rxpks: Vec::new(),
+        failed_at: None,
+    };
+    let bytes = json.as_bytes();
+    let Some(key) = json.find("\"rxpk\"") else {
+        return salvaged;
+    };
+    let skip_ws = |mut pos: usize| {
+        while bytes.get(pos).is_some_and(u8::is_ascii_whitespace) {
+            pos += 1;
+        }
+        pos
+    };
+    let mut pos = skip_ws(key + "\"rxpk\"".len());
+    if bytes.get(pos) != Some(&b':') {
+        return salvaged;
+    }
+    pos = skip_ws(pos + 1);
+    if bytes.get(pos) != Some(&b'[') {
+        return salvaged;
+    }
+    pos += 1;
+
+    loop {
+        pos = skip_ws(pos);
+        match bytes.get(pos) {
+            Some(b',') => pos += 1,
+            Some(b'{') => match object_end(bytes, pos) {
+                Some(end) => {
+                    match serde_json::from_str::<Rxpk>(&json[pos..end]) {
+                        Ok(rxpk) => salvaged.rxpks.push(rxpk),
+                        Err(_) => {
+                            salvaged.failed_at.get_or_insert(pos);
+                        }
+                    }
+                    pos = end;
+                }
+                None => {
+                    salvaged.failed_at.get_or_insert(pos);
+                    return salvaged;
+                }
+            },
+            Some(b']') => return salvaged,
+            _ => {
+                salvaged.failed_at.get_or_insert(pos);
+                return salvaged;
+            }
+        }
+    }
+}
+
+/// Offset just past the `}` closing the object opened at `start`, if it is complete
+fn object_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let (mut depth, mut in_string, mut escaped) = (0usize, false, false);
+    for (i, &b) in bytes.iter().enumerate().skip(start) {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match b {
+            b'"' => in_string = true,
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' => {
+                depth = depth.checked_sub(1)?;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
 /// Txpk (transmit packet) for PULL_RESP downlinks (server → gateway)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Txpk {
@@ -124,8 +318,8 @@ pub struct Txpk {
     pub powe: Option<u8>,
     /// Modulation ("LORA" or "FSK")
     pub modu: Option<String>,
-    /// LoRa datarate identifier (e.g., "SF7BW125")
-    pub datr: String,
+    /// LoRa datarate identifier (e.g., "SF7BW125"), or FSK bitrate
+    pub datr: DataRate,
     /// LoRa coding rate (e.g., "4/5")
     pub codr: Option<String>,
     /// Invert LoRa polarization (true for downlinks)
@@ -144,26 +338,98 @@ pub struct PullRespPayload {
     pub txpk: Txpk,
 }
 
+/// TX_ACK report (`txpk_ack`, gateway → server)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxpkAck {
+    /// `"NONE"` on success, otherwise a TX error such as `"TOO_LATE"`
+    pub error: Option<String>,
+    /// Non-fatal condition, e.g. `"TX_POWER"` when another power was used
+    pub warn: Option<String>,
+    /// Detail of the error or warning (e.g. the power actually used)
+    pub value: Option<serde_json::Value>,
+}
+
+/// TX_ACK JSON wrapper
+#[derive(Debug, Deserialize)]
+struct TxAckPayload {
+    txpk_ack: Option<TxpkAck>,
+}
+
+impl TxpkAck {
+    /// Parse a TX_ACK's JSON payload
+    ///
+    /// Forwarders may send no payload, an empty object or invalid JSON;
+    /// all of these give the default, empty report.
+    pub fn parse(json_payload: Option<&str>) -> Self {
+        json_payload
+            .and_then(|json| serde_json::from_str::<TxAckPayload>(json).ok())
+            .and_then(|payload| payload.txpk_ack)
+            .unwrap_or_default()
+    }
+
+    /// The TX error, if any (`"NONE"` and `""` are not errors)
+    pub fn error(&self) -> Option<&str> {
+        self.error
+            .as_deref()
+            .filter(|error| !error.is_empty() && *error != "NONE")
+    }
+}
+
 impl GwmpPacket {
-    /// Parse a raw UDP datagram into a GWMP packet
+    /// Parse a raw UDP datagram into a GWMP packet (version 2 only)
     pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        Self::parse_versioned(data, false, MAX_JSON_LEN).map(|(_, packet)| packet)
+    }
+
+    /// Parse a datagram, also accepting version 1 if `accept_v1`
+    ///
+    /// Returns the version alongside the packet, so replies can match it.
+    /// JSON over `max_json_len` bytes fails with `PayloadTooLong`.
+    pub fn parse_versioned(
+        data: &[u8],
+        accept_v1: bool,
+        max_json_len: usize,
+    ) -> anyhow::Result<(u8, Self)> {
         if data.len() < 4 {
             return Err(anyhow::anyhow!("Packet too short: {} bytes", data.len()));
         }
 
-        let mut buf = &data[..];
+        let mut buf = data;
 
         let version = buf.get_u8();
-        if version != PROTOCOL_VERSION {
-            return Err(anyhow::anyhow!(
-                "Unsupported protocol version: 0x{:02x}",
-                version
-            ));
+        match version {
+            PROTOCOL_VERSION => {}
+            PROTOCOL_VERSION_1 if accept_v1 => {}
+            PROTOCOL_VERSION_1 => {
+                return Err(anyhow::anyhow!(
+                    "Unsupported protocol version: saw 0x01 (set udp.accept_protocol_v1 \
+                     to accept legacy forwarders)"
+                ));
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Unsupported protocol version: saw 0x{:02x}",
+                    version
+                ));
+            }
         }
 
         let random_token = buf.get_u16();
         let packet_type = PacketType::try_from(buf.get_u8())?;
+        if version == PROTOCOL_VERSION_1 && packet_type == PacketType::TxAck {
+            return Err(anyhow::anyhow!("TX_ACK does not exist in protocol v1"));
+        }
+        Self::parse_body(version, random_token, packet_type, buf, max_json_len)
+            .map(|packet| (version, packet))
+    }
 
+    fn parse_body(
+        version: u8,
+        random_token: u16,
+        packet_type: PacketType,
+        mut buf: &[u8],
+        max_json_len: usize,
+    ) -> anyhow::Result<Self> {
         match packet_type {
             PacketType::PushData => {
                 if buf.remaining() < 8 {
@@ -172,8 +438,7 @@ impl GwmpPacket {
                 let mut gateway_eui = [0u8; 8];
                 buf.copy_to_slice(&mut gateway_eui);
 
-                let json_payload = String::from_utf8(buf.to_vec())
-                    .map_err(|e| anyhow::anyhow!("Invalid UTF-8 in JSON payload: {}", e))?;
+                let json_payload = json_text(buf, "PUSH_DATA", max_json_len)?;
 
                 Ok(GwmpPacket::PushData {
                     random_token,
@@ -197,9 +462,13 @@ impl GwmpPacket {
                 Ok(GwmpPacket::PushAck { random_token })
             }
             PacketType::PullResp => {
-                let json_payload = String::from_utf8(buf.to_vec())
-                    .map_err(|e| anyhow::anyhow!("Invalid UTF-8 in PULL_RESP payload: {}", e))?;
+                let json_payload = json_text(buf, "PULL_RESP", max_json_len)?;
 
+                // v1 leaves the token bytes unused
+                let random_token = match version {
+                    PROTOCOL_VERSION_1 => 0,
+                    _ => random_token,
+                };
                 Ok(GwmpPacket::PullResp {
                     random_token,
                     json_payload,
@@ -216,10 +485,7 @@ impl GwmpPacket {
                 buf.copy_to_slice(&mut gateway_eui);
 
                 let json_payload = if buf.has_remaining() {
-                    Some(
-                        String::from_utf8(buf.to_vec())
-                            .map_err(|e| anyhow::anyhow!("Invalid UTF-8: {}", e))?,
-                    )
+                    Some(json_text(buf, "TX_ACK", max_json_len)?)
                 } else {
                     None
                 };
@@ -233,6 +499,14 @@ impl GwmpPacket {
         }
     }
 
+    /// Rewrite the version byte of a built packet, to answer a v1 forwarder
+    pub fn with_version(mut packet: Vec<u8>, version: u8) -> Vec<u8> {
+        if let Some(first) = packet.first_mut() {
+            *first = version;
+        }
+        packet
+    }
+
     /// Build a PUSH_ACK response
     pub fn push_ack(random_token: u16) -> Vec<u8> {
         let mut buf = BytesMut::with_capacity(4);
@@ -296,3 +570,256 @@ impl GwmpPacket {
         buf.to_vec()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GATEWAY_EUI: GatewayEui = [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF, 0x00, 0x11];
+
+    #[test]
+    fn test_fsk_rxpk() {
+        // EU868 DR7: FSK at 50 kbit/s, datr is a number
+        let payload: PushDataPayload = serde_json::from_str(
+            r#"{"rxpk":[{"tmst":3512348611,"chan":8,"rfch":0,"freq":868.8,"stat":1,
+                "modu":"FSK","datr":50000,"rssi":-75,"size":17,
+                "data":"QPF9vkkAAgABlUN4disR/w0="}]}"#,
+        )
+        .unwrap();
+        let rxpk = &payload.rxpk.unwrap()[0];
+        assert!(rxpk.is_fsk());
+        assert_eq!(rxpk.datr, DataRate::Fsk { bitrate: 50000 });
+        assert_eq!(rxpk.datr.to_string(), "FSK50000");
+
+        let lora: Rxpk = serde_json::from_str(
+            r#"{"freq":868.1,"modu":"LORA","datr":"SF7BW125","rssi":-40,"size":0,"data":""}"#,
+        )
+        .unwrap();
+        assert!(!lora.is_fsk());
+        assert_eq!(lora.datr, DataRate::lora(7, 125));
+    }
+
+    #[test]
+    fn test_lr_fhss_rxpk() {
+        // An LR-FHSS uplink doesn't cost the LoRa one next to it
+        let payload: PushDataPayload = serde_json::from_str(
+            r#"{"rxpk":[{"tmst":1000,"freq":902.3,"modu":"LR-FHSS","datr":"M0CW137",
+                "rssi":-90,"size":0,"data":""},
+                {"tmst":2000,"freq":902.5,"modu":"LORA","datr":"SF7BW125",
+                "rssi":-40,"size":0,"data":""}]}"#,
+        )
+        .unwrap();
+        let rxpks = payload.rxpk.unwrap();
+        assert_eq!(rxpks[0].datr, DataRate::Other("M0CW137".to_string()));
+        assert_eq!(rxpks[1].datr, DataRate::lora(7, 125));
+    }
+
+    #[test]
+    fn test_parse_pull_resp() {
+        let json = r#"{"txpk":{"imme":true,"freq":923.3,"datr":"SF12BW500","size":4,"data":"AQIDBA=="}}"#;
+        let mut data = vec![PROTOCOL_VERSION, 0x12, 0x34, PacketType::PullResp as u8];
+        data.extend_from_slice(json.as_bytes());
+
+        match GwmpPacket::parse(&data).unwrap() {
+            GwmpPacket::PullResp {
+                random_token,
+                json_payload,
+            } => {
+                assert_eq!(random_token, 0x1234);
+                assert_eq!(json_payload, json);
+                let payload: PullRespPayload = serde_json::from_str(&json_payload).unwrap();
+                assert_eq!(payload.txpk.freq, 923.3);
+                assert_eq!(payload.txpk.size, 4);
+            }
+            other => panic!("Expected PullResp, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_pull_resp_roundtrip() {
+        let json = r#"{"txpk":{"imme":true,"freq":923.3,"datr":"SF12BW500","size":0,"data":""}}"#;
+        let packet = GwmpPacket::pull_resp(0xBEEF, json);
+
+        // 4-byte header followed by the JSON
+        assert_eq!(&packet[..4], &[PROTOCOL_VERSION, 0xBE, 0xEF, 0x03]);
+        assert_eq!(packet.len(), 4 + json.len());
+
+        match GwmpPacket::parse(&packet).unwrap() {
+            GwmpPacket::PullResp {
+                random_token,
+                json_payload,
+            } => {
+                assert_eq!(random_token, 0xBEEF);
+                assert_eq!(json_payload, json);
+            }
+            other => panic!("Expected PullResp, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ack_roundtrip() {
+        match GwmpPacket::parse(&GwmpPacket::push_ack(0x0102)).unwrap() {
+            GwmpPacket::PushAck { random_token } => assert_eq!(random_token, 0x0102),
+            other => panic!("Expected PushAck, got {:?}", other),
+        }
+        match GwmpPacket::parse(&GwmpPacket::pull_ack(0x0304)).unwrap() {
+            GwmpPacket::PullAck { random_token } => assert_eq!(random_token, 0x0304),
+            other => panic!("Expected PullAck, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_pull_data_roundtrip() {
+        let packet = GwmpPacket::pull_data(0x0042, &GATEWAY_EUI);
+        match GwmpPacket::parse(&packet).unwrap() {
+            GwmpPacket::PullData {
+                random_token,
+                gateway_eui,
+            } => {
+                assert_eq!(random_token, 0x0042);
+                assert_eq!(gateway_eui, GATEWAY_EUI);
+            }
+            other => panic!("Expected PullData, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_txpk_ack() {
+        let ack = TxpkAck::parse(Some(r#"{"txpk_ack":{"error":"TX_POWER","value":20}}"#));
+        assert_eq!(ack.error(), Some("TX_POWER"));
+        assert_eq!(ack.value, Some(serde_json::json!(20)));
+        assert_eq!(ack.warn, None);
+
+        // Success, with a warning about the power used
+        let ack = TxpkAck::parse(Some(
+            r#"{"txpk_ack":{"error":"NONE","warn":"TX_POWER","value":14}}"#,
+        ));
+        assert_eq!(ack.error(), None);
+        assert_eq!(ack.warn.as_deref(), Some("TX_POWER"));
+        assert_eq!(ack.value, Some(serde_json::json!(14)));
+
+        for payload in [None, Some("{}"), Some(r#"{"txpk_ack":{}}"#), Some("not json")] {
+            assert_eq!(TxpkAck::parse(payload), TxpkAck::default());
+        }
+    }
+
+    #[test]
+    fn test_salvage_rxpks() {
+        // A good rxpk, one missing its data, a good one with a brace in a string, then cut off
+        let json = r#"{"rxpk":[{"freq":868.1,"datr":"SF7BW125","rssi":-40,"size":4,"data":"AQIDBA=="},
+            {"freq":868.3,"datr":"SF7BW125","rssi":-41,"size":4},
+            {"freq":868.5,"datr":"SF9BW125","rssi":-42,"size":4,"data":"AQIDBA==","codr":"}"},
+            {"freq":868.1,"datr":"SF7BW125","rssi":-43,"si"#;
+        assert!(serde_json::from_str::<PushDataPayload>(json).is_err());
+
+        let salvaged = salvage_rxpks(json);
+        let freqs: Vec<f64> = salvaged.rxpks.iter().map(|rxpk| rxpk.freq).collect();
+        assert_eq!(freqs, vec![868.1, 868.5]);
+        assert_eq!(salvaged.failed_at, json.find(r#"{"freq":868.3"#));
+
+        // Nothing to recover
+        assert!(salvage_rxpks(r#"{"stat":{"#).rxpks.is_empty());
+        assert!(salvage_rxpks(r#"{"rxpk":[{"freq":868.1,"#).rxpks.is_empty());
+    }
+
+    #[test]
+    fn test_unsupported_version_fails() {
+        let data = [0x01, 0x00, 0x01, PacketType::PushAck as u8];
+        let err = GwmpPacket::parse(&data).unwrap_err().to_string();
+        assert!(err.contains("saw 0x01") && err.contains("accept_protocol_v1"), "{}", err);
+        let data = [0x03, 0x00, 0x01, PacketType::PushAck as u8];
+        let err = GwmpPacket::parse_versioned(&data, true, MAX_JSON_LEN).unwrap_err().to_string();
+        assert_eq!(err, "Unsupported protocol version: saw 0x03");
+    }
+
+    #[test]
+    fn test_parse_v1_push_data() {
+        let json = r#"{"rxpk":[{"tmst":3512348611,"chan":2,"rfch":0,"freq":866.349812,"stat":1,"modu":"LORA","datr":"SF7BW125","codr":"4/6","rssi":-35,"lsnr":5.1,"size":32,"data":"QPF9vkkAAgABlUN4disR/w0="}]}"#;
+        let data = GwmpPacket::with_version(
+            GwmpPacket::push_data(0x1234, &GATEWAY_EUI, json),
+            PROTOCOL_VERSION_1,
+        );
+        assert!(GwmpPacket::parse(&data).is_err());
+
+        let (version, packet) = GwmpPacket::parse_versioned(&data, true, MAX_JSON_LEN).unwrap();
+        assert_eq!(version, PROTOCOL_VERSION_1);
+        match packet {
+            GwmpPacket::PushData {
+                random_token,
+                gateway_eui,
+                json_payload,
+            } => {
+                assert_eq!(random_token, 0x1234);
+                assert_eq!(gateway_eui, GATEWAY_EUI);
+                assert_eq!(json_payload, json);
+            }
+            other => panic!("expected PUSH_DATA, got {:?}", other),
+        }
+
+        // The ACK echoes v1 framing; v1 has no TX_ACK and no PULL_RESP token
+        let ack = GwmpPacket::with_version(GwmpPacket::push_ack(0x1234), version);
+        assert_eq!(ack, vec![0x01, 0x12, 0x34, 0x01]);
+        let tx_ack = GwmpPacket::with_version(GwmpPacket::tx_ack(1, &GATEWAY_EUI, None), 1);
+        assert!(GwmpPacket::parse_versioned(&tx_ack, true, MAX_JSON_LEN).is_err());
+        let resp = GwmpPacket::with_version(GwmpPacket::pull_resp(0xBEEF, "{}"), 1);
+        assert!(matches!(
+            GwmpPacket::parse_versioned(&resp, true, MAX_JSON_LEN).unwrap(),
+            (1, GwmpPacket::PullResp { random_token: 0, .. })
+        ));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_parse_compressed_push_data() {
+        use std::io::Write;
+
+        let json = r#"{"rxpk":[{"tmst":3512348611,"chan":2,"rfch":0,"freq":866.349812,"stat":1,"modu":"LORA","datr":"SF7BW125","codr":"4/6","rssi":-35,"lsnr":5.1,"size":32,"data":"QPF9vkkAAgABlUN4disR/w0="}]}"#;
+        let plain = GwmpPacket::push_data(0x1234, &GATEWAY_EUI, json);
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(json.as_bytes()).unwrap();
+        let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+        zlib.write_all(json.as_bytes()).unwrap();
+
+        let json_of = |data: &[u8]| match GwmpPacket::parse(data).unwrap() {
+            GwmpPacket::PushData { json_payload, .. } => json_payload,
+            other => panic!("expected PUSH_DATA, got {:?}", other),
+        };
+        for compressed in [gzip.finish().unwrap(), zlib.finish().unwrap()] {
+            let mut data = plain[..12].to_vec();
+            data.extend_from_slice(&compressed);
+            let json_payload = json_of(&data);
+            assert_eq!(json_payload, json_of(&plain));
+            let rxpk = serde_json::from_str::<PushDataPayload>(&json_payload).unwrap().rxpk;
+            assert_eq!(rxpk.unwrap()[0].data, "QPF9vkkAAgABlUN4disR/w0=");
+        }
+
+        // A truncated stream is an error, not a partial payload
+        let mut data = plain[..12].to_vec();
+        data.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x00]);
+        assert!(GwmpPacket::parse(&data).is_err());
+
+        // The length limit applies to the inflated JSON
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        gzip.write_all(&[b' '; 4096]).unwrap();
+        let mut data = plain[..12].to_vec();
+        data.extend_from_slice(&gzip.finish().unwrap());
+        assert!(data.len() < 1024);
+        let err = GwmpPacket::parse_versioned(&data, false, 1024).unwrap_err();
+        assert!(err.is::<PayloadTooLong>());
+    }
+
+    #[test]
+    fn test_json_length_limit() {
+        let json = format!(r#"{{"stat":{{"desc":"{}"}}}}"#, "x".repeat(100));
+        let data = GwmpPacket::push_data(0x1234, &GATEWAY_EUI, &json);
+        assert!(GwmpPacket::parse_versioned(&data, false, json.len()).is_ok());
+
+        let err = GwmpPacket::parse_versioned(&data, false, json.len() - 1).unwrap_err();
+        let too_long = PayloadTooLong { what: "PUSH_DATA", max: json.len() - 1 };
+        assert_eq!(err.downcast_ref::<PayloadTooLong>(), Some(&too_long));
+        assert_eq!(err.to_string(), "PUSH_DATA JSON payload is over 119 bytes (udp.max_json_len)");
+        let resp = GwmpPacket::pull_resp(1, &json);
+        assert!(GwmpPacket::parse_versioned(&resp, false, 64).is_err());
+    }
+}
@@ -0,0 +1,82 @@
+//! Grouping of one uplink heard by several gateways (`udp.dedup_window_ms`)
+//!
+//! Every gateway in range of a device forwards its uplink in its own
+//! PUSH_DATA. The first copy opens a group keyed by the PHY payload; copies
+//! arriving within the window only add their reception (gateway, RSSI/SNR,
+//! timestamps) and are dropped. When the window closes the first copy is
+//! forwarded once with all the receptions, which is what a TDOA solver needs.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::protocol::Rxpk;
+use crate::urbit::types::Reception;
+
+/// Open groups, shared by the receive loops and the tasks that close them
+#[derive(Debug, Clone)]
+pub struct ReceptionWindow {
+    window: Duration,
+    groups: Arc<Mutex<HashMap<Vec<u8>, Group>>>,
+}
+
+#[derive(Debug)]
+struct Group {
+    opened: Instant,
+    receptions: Vec<Reception>,
+}
+
+impl ReceptionWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            groups: Arc::default(),
+        }
+    }
+
+    /// How long a group stays open
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Add a reception of `phy`; true if it is the first one, opening a group
+    ///
+    /// Groups nobody closed (frames that were not forwarded) expire after
+    /// twice the window.
+    pub fn add(&self, phy: &[u8], reception: Reception, now: Instant) -> bool {
+        let mut groups = self.groups.lock().unwrap_or_else(|e| e.into_inner());
+        groups.retain(|_, group| now.duration_since(group.opened) < self.window * 2);
+        match groups.get_mut(phy) {
+            Some(group) => {
+                group.receptions.push(reception);
+                false
+            }
+            None => {
+                let group = Group {
+                    opened: now,
+                    receptions: vec![reception],
+                };
+                groups.insert(phy.to_vec(), group);
+                true
+            }
+        }
+    }
+
+    /// Close the group of `phy`, returning its receptions in arrival order
+    pub fn close(&self, phy: &[u8]) -> Vec<Reception> {
+        let mut groups = self.groups.lock().unwrap_or_else(|e| e.into_inner());
+        groups.remove(phy).map(|group| group.receptions).unwrap_or_default()
+    }
+}
+
+/// `rxpk`'s reception metadata as heard by `gateway_eui`
+pub fn reception(rxpk: &Rxpk, gateway_eui: &str) -> Reception {
+    Reception {
+        gateway_eui: gateway_eui.to_string(),
+        rssi: rxpk.rssi,
+        snr: rxpk.lsnr,
+        freq: rxpk.freq,
+        tmst: rxpk.tmst,
+        tmms: rxpk.tmms,
+    }
+}
@@ -0,0 +1,178 @@
+//! Packet origin classification (local gateway vs Helium Packet Router)
+//!
+//! Helium Packet Router delivers GWMP traffic to the same UDP port as local
+//! gateways, so origin is decided from `udp.helium_sources`. Each entry is
+//! either an IP address / CIDR range (matched against the datagram's source
+//! address) or a hex gateway EUI prefix (matched against the gateway EUI).
+//!
+//! ```toml
+//! [udp]
+//! helium_sources = ["52.8.80.0/24", "2001:db8::/32", "AABBCC"]
+//! ```
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::urbit::types::PacketSource;
+
+/// Rules identifying packets that arrived via the Helium Packet Router
+#[derive(Debug, Clone, Default)]
+pub struct SourceClassifier {
+    /// Lowercase hex gateway EUI prefixes
+    eui_prefixes: Vec<String>,
+    /// Network address and prefix length
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl SourceClassifier {
+    /// Build from `udp.helium_sources` entries
+    pub fn new(entries: &[String]) -> anyhow::Result<Self> {
+        let mut classifier = Self::default();
+        for entry in entries {
+            let entry = entry.trim();
+            if let Some(network) = parse_network(entry)? {
+                classifier.networks.push(network);
+            } else if !entry.is_empty()
+                && entry.len() <= 16
+                && entry.chars().all(|c| c.is_ascii_hexdigit())
+            {
+                classifier.eui_prefixes.push(entry.to_ascii_lowercase());
+            } else {
+                return Err(anyhow::anyhow!(
+                    "Invalid helium_sources entry {:?} (expected IP, CIDR or EUI prefix)",
+                    entry
+                ));
+            }
+        }
+        Ok(classifier)
+    }
+
+    /// Classify a packet by its gateway EUI (hex) and UDP source address
+    pub fn classify_source(&self, gateway_eui: &str, src_addr: SocketAddr) -> PacketSource {
+        let eui = gateway_eui.to_ascii_lowercase();
+        let ip = canonical_ip(src_addr.ip());
+        let helium = self.eui_prefixes.iter().any(|prefix| eui.starts_with(prefix))
+            || self
+                .networks
+                .iter()
+                .any(|&(network, prefix_len)| in_network(ip, network, prefix_len));
+
+        if helium {
+            PacketSource::Helium
+        } else {
+            PacketSource::Local
+        }
+    }
+}
+
+/// Parse "addr" or "addr/len"; Ok(None) if the entry is not an address
+///
+/// An IPv4-mapped range ("::ffff:52.8.80.0/120") becomes the IPv4 one it
+/// covers, so its length must be at least 96.
+fn parse_network(entry: &str) -> anyhow::Result<Option<(IpAddr, u8)>> {
+    let (addr, len) = match entry.split_once('/') {
+        Some((addr, len)) => (addr, Some(len)),
+        None => (entry, None),
+    };
+    let Ok(addr) = addr.parse::<IpAddr>() else {
+        return Ok(None);
+    };
+
+    let max_len = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix_len = match len {
+        Some(len) => len
+            .parse::<u8>()
+            .ok()
+            .filter(|&len| len <= max_len)
+            .ok_or_else(|| anyhow::anyhow!("Invalid prefix length in {:?}", entry))?,
+        None => max_len,
+    };
+    let network = canonical_ip(addr);
+    if network == addr {
+        return Ok(Some((network, prefix_len)));
+    }
+    let prefix_len = prefix_len.checked_sub(96).ok_or_else(|| {
+        anyhow::anyhow!("IPv4-mapped range {:?} needs a prefix length of at least 96", entry)
+    })?;
+    Ok(Some((network, prefix_len)))
+}
+
+/// Treat IPv4-mapped IPv6 addresses (dual-stack sockets) as IPv4
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix_len: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_source() {
+        let classifier = SourceClassifier::new(&[
+            "AABBCC".to_string(),
+            "52.8.80.0/24".to_string(),
+            "2001:db8::/32".to_string(),
+        ])
+        .unwrap();
+        let lan: SocketAddr = "192.168.1.20:1700".parse().unwrap();
+
+        // Helium-range gateway EUI
+        assert_eq!(
+            classifier.classify_source("aabbccddeeff0011", lan),
+            PacketSource::Helium
+        );
+        // Helium Packet Router address range, including via a dual-stack socket
+        for addr in ["52.8.80.17:1700", "[::ffff:52.8.80.17]:1700", "[2001:db8::1]:1700"] {
+            assert_eq!(
+                classifier.classify_source("0102030405060708", addr.parse().unwrap()),
+                PacketSource::Helium
+            );
+        }
+        // Everything else is a local gateway
+        assert_eq!(
+            classifier.classify_source("0102030405060708", lan),
+            PacketSource::Local
+        );
+    }
+
+    #[test]
+    fn test_ipv4_mapped_range() {
+        let classifier = SourceClassifier::new(&["::ffff:52.8.80.0/120".to_string()]).unwrap();
+        for (addr, source) in [
+            ("52.8.80.17:1700", PacketSource::Helium),
+            ("[::ffff:52.8.80.17]:1700", PacketSource::Helium),
+            ("52.8.81.17:1700", PacketSource::Local),
+        ] {
+            let addr = addr.parse().unwrap();
+            assert_eq!(classifier.classify_source("0102030405060708", addr), source);
+        }
+        let single = SourceClassifier::new(&["::ffff:52.8.80.17".to_string()]).unwrap();
+        let other = "52.8.80.18:1700".parse().unwrap();
+        assert_eq!(single.classify_source("0102030405060708", other), PacketSource::Local);
+
+        assert!(SourceClassifier::new(&["::ffff:52.8.80.0/64".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_entries() {
+        assert!(SourceClassifier::new(&["10.0.0.0/33".to_string()]).is_err());
+        assert!(SourceClassifier::new(&["hotspot".to_string()]).is_err());
+        assert!(SourceClassifier::new(&[]).is_ok());
+    }
+}
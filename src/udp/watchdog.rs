@@ -0,0 +1,178 @@
+//! Gateway-down detection (`udp.gateway_timeout_secs`)
+//!
+//! Packet forwarders send a PULL_DATA keepalive every 10-30 s. A gateway
+//! that has sent neither PULL_DATA nor PUSH_DATA for the timeout is flagged
+//! down, since downlinks to it will silently go nowhere; it is flagged up
+//! again as soon as it is heard from. Each transition is logged and, with
+//! `udp.gateway_status_pokes`, poked to the Urbit targets that receive the
+//! gateway's uplinks.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use super::{GatewaysSeen, PokeRouter};
+use crate::urbit::types::LoRaAction;
+
+/// A gateway going down or coming back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GatewayStatus {
+    pub gateway_eui: String,
+    pub up: bool,
+    /// How long the gateway had been silent
+    pub silent: Duration,
+}
+
+impl GatewayStatus {
+    pub fn to_action(&self) -> LoRaAction {
+        LoRaAction::GatewayStatus {
+            gateway_eui: self.gateway_eui.clone(),
+            up: self.up,
+            silent_secs: self.silent.as_secs(),
+        }
+    }
+}
+
+/// Tracks which gateways are down
+#[derive(Debug)]
+pub struct GatewayWatchdog {
+    timeout: Duration,
+    /// Down gateways and when they were last heard before going silent
+    down: HashMap<String, Instant>,
+}
+
+impl GatewayWatchdog {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            down: HashMap::new(),
+        }
+    }
+
+    /// Status changes since the last check, given when each gateway was last heard
+    pub fn check(
+        &mut self,
+        last_seen: impl IntoIterator<Item = (String, Instant)>,
+        now: Instant,
+    ) -> Vec<GatewayStatus> {
+        let mut changes = Vec::new();
+        for (gateway_eui, last) in last_seen {
+            let silent = now.saturating_duration_since(last);
+            match self.down.get(&gateway_eui) {
+                Some(&went_silent) if last > went_silent => {
+                    self.down.remove(&gateway_eui);
+                    changes.push(GatewayStatus {
+                        gateway_eui,
+                        up: true,
+                        silent: last.saturating_duration_since(went_silent),
+                    });
+                }
+                None if silent >= self.timeout => {
+                    self.down.insert(gateway_eui.clone(), last);
+                    changes.push(GatewayStatus {
+                        gateway_eui,
+                        up: false,
+                        silent,
+                    });
+                }
+                _ => {}
+            }
+        }
+        changes
+    }
+}
+
+/// Check `gateways` periodically until `shutdown`, logging (and poking) changes
+pub async fn run_watchdog(
+    gateways: GatewaysSeen,
+    timeout: Duration,
+    pokes: Option<PokeRouter>,
+    shutdown: CancellationToken,
+) {
+    let mut watchdog = GatewayWatchdog::new(timeout);
+    let mut interval = tokio::time::interval((timeout / 4).max(Duration::from_secs(1)));
+    info!("Gateway watchdog enabled (timeout {:?})", timeout);
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+
+        for status in watchdog.check(gateways.last_seen(), Instant::now()) {
+            if status.up {
+                info!(
+                    "Gateway {} is back up (silent for {}s)",
+                    status.gateway_eui,
+                    status.silent.as_secs()
+                );
+            } else {
+                warn!(
+                    "Gateway {} is down: nothing heard for {}s",
+                    status.gateway_eui,
+                    status.silent.as_secs()
+                );
+            }
+
+            let Some(pokes) = &pokes else { continue };
+            let action = status.to_action();
+            for tx in pokes.route_for(&action, &status.gateway_eui) {
+                if let Err(e) = tx.send(action.clone()).await {
+                    error!("Failed to forward gateway status to Airlock task: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_down_and_up() {
+        let timeout = Duration::from_secs(90);
+        let mut watchdog = GatewayWatchdog::new(timeout);
+        let gateways = GatewaysSeen::new();
+        let t0 = Instant::now();
+        gateways.record_at("aabbccddeeff0011", t0);
+        gateways.record_at("0000000000000001", t0 + Duration::from_secs(60));
+
+        assert!(watchdog.check(gateways.last_seen(), t0 + Duration::from_secs(89)).is_empty());
+
+        // Only the first gateway has been silent for the timeout
+        let changes = watchdog.check(gateways.last_seen(), t0 + Duration::from_secs(95));
+        assert_eq!(
+            changes,
+            vec![GatewayStatus {
+                gateway_eui: "aabbccddeeff0011".to_string(),
+                up: false,
+                silent: Duration::from_secs(95),
+            }]
+        );
+        // Reported once, not on every check
+        assert!(watchdog.check(gateways.last_seen(), t0 + Duration::from_secs(120)).is_empty());
+
+        // A keepalive brings it back; the other gateway now times out
+        gateways.record_at("aabbccddeeff0011", t0 + Duration::from_secs(200));
+        let mut changes = watchdog.check(gateways.last_seen(), t0 + Duration::from_secs(201));
+        changes.sort_by_key(|status| status.up);
+        assert_eq!(
+            changes,
+            vec![
+                GatewayStatus {
+                    gateway_eui: "0000000000000001".to_string(),
+                    up: false,
+                    silent: Duration::from_secs(141),
+                },
+                GatewayStatus {
+                    gateway_eui: "aabbccddeeff0011".to_string(),
+                    up: true,
+                    silent: Duration::from_secs(200),
+                },
+            ]
+        );
+    }
+}
@@ -11,9 +11,128 @@ use crate::config::UrbitConfig;
 use anyhow::{Context, Result};
 use reqwest::Client;
 use serde_json::json;
-use tracing::{debug, error, info, warn};
+use std::time::Duration;
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
+/// Outcome of `AirlockClient::resume_or_renew`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelResumption {
+    /// The existing channel id (and event ids) are still valid
+    Resumed,
+    /// The ship no longer knows the channel; a new id was generated
+    Renewed,
+}
+
+impl ChannelResumption {
+    /// Decide from the status of a GET on the existing channel
+    pub fn from_status(status: reqwest::StatusCode) -> Result<Self> {
+        match status.as_u16() {
+            200..=299 => Ok(ChannelResumption::Resumed),
+            404 => Ok(ChannelResumption::Renewed),
+            _ => anyhow::bail!("channel check failed with status {}", status),
+        }
+    }
+}
+
+/// Why an Airlock request failed, so callers can pick a recovery
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AirlockError {
+    /// Not logged in, login refused or the session cookie expired (401/403)
+    Auth(String),
+    /// The ship could not be reached, or answered with a gateway error
+    Network(String),
+    /// The ship refused the request itself: bad mark or JSON, or a crashing agent
+    AgentNack(String),
+    /// Eyre no longer knows the channel (404)
+    ChannelGone,
+    /// No answer within `request_timeout_ms` (or a 408/504)
+    Timeout,
+}
+
+/// What to do after an `AirlockError`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Log in again (on a new channel if it was gone) before the next request
+    Reconnect,
+    /// Wait before trying the ship again
+    Backoff,
+    /// Log the failure and move on; retrying the same poke won't help
+    Drop,
+}
+
+impl AirlockError {
+    /// Classify a non-success response status; `body` is kept for the message
+    pub fn from_status(status: reqwest::StatusCode, body: &str) -> Self {
+        let detail = if body.is_empty() {
+            format!("status {}", status)
+        } else {
+            format!("status {}: {}", status, body)
+        };
+        match status.as_u16() {
+            401 | 403 => AirlockError::Auth(detail),
+            404 => AirlockError::ChannelGone,
+            408 | 504 => AirlockError::Timeout,
+            400..=500 => AirlockError::AgentNack(detail),
+            _ => AirlockError::Network(detail),
+        }
+    }
+
+    /// Classify a request that got no response at all
+    pub fn from_reqwest(error: &reqwest::Error) -> Self {
+        if error.is_timeout() {
+            AirlockError::Timeout
+        } else {
+            AirlockError::Network(error.to_string())
+        }
+    }
+
+    /// How a caller should recover before its next request
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            AirlockError::Auth(_) | AirlockError::ChannelGone | AirlockError::Timeout => {
+                Recovery::Reconnect
+            }
+            AirlockError::Network(_) => Recovery::Backoff,
+            AirlockError::AgentNack(_) => Recovery::Drop,
+        }
+    }
+
+    /// An error from a step that returns anyhow (e.g. a reconnect)
+    fn from_anyhow(error: anyhow::Error) -> Self {
+        error
+            .downcast::<AirlockError>()
+            .unwrap_or_else(|e| AirlockError::Network(format!("{:#}", e)))
+    }
+}
+
+/// Delay before the next request after `failures` network errors in a row
+///
+/// 1 s, 2 s, 4 s, ... up to 32 s.
+pub fn network_backoff(failures: u32) -> Duration {
+    Duration::from_secs(1 << failures.saturating_sub(1).min(5))
+}
+
+impl std::fmt::Display for AirlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AirlockError::Auth(detail) => write!(f, "not authenticated ({})", detail),
+            AirlockError::Network(detail) => write!(f, "ship unreachable ({})", detail),
+            AirlockError::AgentNack(detail) => write!(f, "request refused ({})", detail),
+            AirlockError::ChannelGone => write!(f, "channel no longer exists"),
+            AirlockError::Timeout => write!(f, "ship did not answer in time"),
+        }
+    }
+}
+
+impl std::error::Error for AirlockError {}
+
+/// Interval of TCP keepalive probes on connections to the ship
+const TCP_KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// How long one event-stream GET may stay open before it is reopened
+const EVENT_STREAM_TIMEOUT: Duration = Duration::from_secs(3600);
+
 /// Lightweight Airlock HTTP client for poking Urbit agents
 pub struct AirlockClient {
     config: UrbitConfig,
@@ -21,6 +140,8 @@ pub struct AirlockClient {
     channel_id: String,
     next_id: u64,
     connected: bool,
+    /// Log pokes instead of sending them (`general.dry_run`)
+    dry_run: bool,
 }
 
 impl AirlockClient {
@@ -28,6 +149,9 @@ impl AirlockClient {
     pub fn new(config: UrbitConfig) -> Self {
         let http = Client::builder()
             .cookie_store(true)
+            .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+            .timeout(Duration::from_millis(config.request_timeout_ms))
+            .tcp_keepalive(TCP_KEEPALIVE)
             .build()
             .expect("failed to build reqwest client");
 
@@ -44,11 +168,19 @@ impl AirlockClient {
             channel_id,
             next_id: 1,
             connected: false,
+            dry_run: false,
         }
     }
 
+    /// Log pokes instead of sending them; scries still go to the ship
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
     /// Authenticate with the Urbit ship using the +code
-    pub async fn connect(&mut self) -> Result<()> {
+    ///
+    /// A refused login (any 4xx) is `AirlockError::Auth`.
+    pub async fn connect(&mut self) -> Result<(), AirlockError> {
         info!("Authenticating with ship {}...", self.config.ship);
 
         let login_url = format!("{}/~/login", self.config.url);
@@ -61,16 +193,21 @@ impl AirlockClient {
             .body(body)
             .send()
             .await
-            .context("failed to send login request")?;
+            .map_err(|e| AirlockError::from_reqwest(&e))?;
 
         let status = resp.status();
         if !status.is_success() && !status.is_redirection() {
-            let body_text = resp.text().await.unwrap_or_default();
-            anyhow::bail!(
-                "login failed with status {}: {}",
-                status,
-                body_text
-            );
+            let mut body_text = resp.text().await.unwrap_or_default();
+            // Never echo the +code, even if the ship's error page does
+            if !self.config.code.is_empty() {
+                body_text = body_text.replace(&self.config.code, "***");
+            }
+            let detail = format!("login failed with status {}: {}", status, body_text);
+            return Err(if status.is_client_error() {
+                AirlockError::Auth(detail)
+            } else {
+                AirlockError::Network(detail)
+            });
         }
 
         self.connected = true;
@@ -107,33 +244,49 @@ impl AirlockClient {
     }
 
     /// Poke a Gall agent with a JSON payload
+    ///
+    /// An expired session is renewed and the poke retried once. A 404 means
+    /// Eyre dropped the channel: a new channel id is picked for the next
+    /// poke and `AirlockError::ChannelGone` returned.
     pub async fn poke(
         &mut self,
         app: &str,
         mark: &str,
         json_data: serde_json::Value,
-    ) -> Result<()> {
+    ) -> Result<(), AirlockError> {
+        self.poke_batch(app, vec![(mark, json_data)]).await
+    }
+
+    /// Poke a Gall agent several times in one channel PUT
+    ///
+    /// Each `(mark, json)` is its own poke action with its own id, so the
+    /// agent sees them one by one, in order. Errors and retries are as for
+    /// `poke`, for the PUT as a whole.
+    pub async fn poke_batch(
+        &mut self,
+        app: &str,
+        pokes: Vec<(&str, serde_json::Value)>,
+    ) -> Result<(), AirlockError> {
+        if self.dry_run {
+            for (mark, json_data) in &pokes {
+                info!("[dry run] Would poke %{} ({}): {}", app, mark, json_data);
+            }
+            return Ok(());
+        }
         if !self.connected {
-            anyhow::bail!("not connected — call connect() first");
+            return Err(AirlockError::Auth("not connected — call connect() first".to_string()));
         }
 
-        let msg_id = self.next_id;
-        self.next_id += 1;
+        let first_id = self.next_id;
+        self.next_id += pokes.len() as u64;
 
         let channel_url = format!("{}/~/channel/{}", self.config.url, self.channel_id);
 
-        let poke_body = json!([{
-            "id": msg_id,
-            "action": "poke",
-            "ship": self.config.ship,
-            "app": app,
-            "mark": mark,
-            "json": json_data,
-        }]);
+        let poke_body = self.poke_body(app, pokes, first_id);
 
         debug!(
-            "Poking {}/{} (id={}, channel={})",
-            app, mark, msg_id, self.channel_id
+            "Poking {} (ids {}..{}, channel={})",
+            app, first_id, self.next_id, self.channel_id
         );
 
         let resp = self
@@ -142,25 +295,28 @@ impl AirlockClient {
             .json(&poke_body)
             .send()
             .await
-            .context("failed to send poke")?;
+            .map_err(|e| self.send_failed(&e))?;
 
         let status = resp.status();
         if !status.is_success() {
             let body_text = resp.text().await.unwrap_or_default();
+            let error = AirlockError::from_status(status, &body_text);
 
             // If we get a 401/403, try to reconnect
-            if status.as_u16() == 401 || status.as_u16() == 403 {
+            if let AirlockError::Auth(_) = error {
                 warn!("Auth expired, attempting reconnect...");
                 self.connected = false;
-                self.reconnect().await?;
+                self.reconnect().await.map_err(AirlockError::from_anyhow)?;
                 // Retry the poke once after reconnect
-                return self.poke_inner(app, mark, json_data, msg_id).await;
+                return self.poke_inner(poke_body, first_id).await;
             }
-
-            anyhow::bail!("poke failed with status {}: {}", status, body_text);
+            if error == AirlockError::ChannelGone {
+                self.renew_channel();
+            }
+            return Err(error);
         }
 
-        debug!("Poke {} acknowledged", msg_id);
+        debug!("Poke {} acknowledged", first_id);
 
         // Send ACK for any pending events (best effort)
         self.ack_events().await;
@@ -168,49 +324,206 @@ impl AirlockClient {
         Ok(())
     }
 
+    /// Channel actions for `pokes`, numbered from `first_id`
+    fn poke_body(
+        &self,
+        app: &str,
+        pokes: Vec<(&str, serde_json::Value)>,
+        first_id: u64,
+    ) -> serde_json::Value {
+        let actions = pokes
+            .into_iter()
+            .zip(first_id..)
+            .map(|((mark, json_data), id)| {
+                json!({
+                    "id": id,
+                    "action": "poke",
+                    "ship": self.config.ship,
+                    "app": app,
+                    "mark": mark,
+                    "json": json_data,
+                })
+            })
+            .collect();
+        serde_json::Value::Array(actions)
+    }
+
     /// Internal poke (used for retry after reconnect)
     async fn poke_inner(
         &mut self,
-        app: &str,
-        mark: &str,
-        json_data: serde_json::Value,
+        poke_body: serde_json::Value,
         msg_id: u64,
-    ) -> Result<()> {
+    ) -> Result<(), AirlockError> {
         let channel_url = format!("{}/~/channel/{}", self.config.url, self.channel_id);
 
-        let poke_body = json!([{
-            "id": msg_id,
-            "action": "poke",
-            "ship": self.config.ship,
-            "app": app,
-            "mark": mark,
-            "json": json_data,
-        }]);
-
         let resp = self
             .http
             .put(&channel_url)
             .json(&poke_body)
             .send()
             .await
-            .context("failed to send poke (retry)")?;
+            .map_err(|e| self.send_failed(&e))?;
 
         let status = resp.status();
         if !status.is_success() {
             let body_text = resp.text().await.unwrap_or_default();
-            anyhow::bail!("poke retry failed with status {}: {}", status, body_text);
+            let error = AirlockError::from_status(status, &body_text);
+            if let AirlockError::Auth(_) = error {
+                self.connected = false;
+            }
+            if error == AirlockError::ChannelGone {
+                self.renew_channel();
+            }
+            return Err(error);
         }
 
         debug!("Poke {} acknowledged (after reconnect)", msg_id);
         Ok(())
     }
 
-    /// Attempt to reconnect (new channel + re-login)
-    async fn reconnect(&mut self) -> Result<()> {
-        warn!("Reconnecting to ship {}...", self.config.ship);
+    /// A ship that stopped answering needs a fresh login before the next poke
+    fn mark_timed_out(&mut self, error: &reqwest::Error) {
+        if error.is_timeout() {
+            warn!("Ship {} timed out; will reconnect", self.config.ship);
+            self.connected = false;
+        }
+    }
+
+    /// Classify a poke that got no response
+    fn send_failed(&mut self, error: &reqwest::Error) -> AirlockError {
+        self.mark_timed_out(error);
+        AirlockError::from_reqwest(error)
+    }
+
+    /// Switch to a fresh channel id; Eyre creates the channel on the next PUT
+    fn renew_channel(&mut self) {
         self.channel_id = format!("loraurbit-{}", Uuid::new_v4());
         self.next_id = 1;
-        self.connect().await
+    }
+
+    /// Attempt to reconnect (re-login, keeping the channel if it still exists)
+    async fn reconnect(&mut self) -> Result<()> {
+        warn!("Reconnecting to ship {}...", self.config.ship);
+        self.resume_or_renew().await.map(|_| ())
+    }
+
+    /// Re-login and reuse the current channel, or open a new one if it's gone
+    ///
+    /// Eyre keeps a channel (and its subscriptions) across logins, so after
+    /// re-authenticating we GET the existing channel and only switch to a new
+    /// channel id if the ship answers 404. A channel we never PUT to does not
+    /// exist server-side yet and is kept as is.
+    pub async fn resume_or_renew(&mut self) -> Result<ChannelResumption> {
+        self.connect().await?;
+        if self.next_id == 1 {
+            return Ok(ChannelResumption::Resumed);
+        }
+
+        let channel_url = format!("{}/~/channel/{}", self.config.url, self.channel_id);
+        // Only the status matters; dropping the response closes the SSE stream
+        let resp = self
+            .http
+            .get(&channel_url)
+            .header("Accept", "text/event-stream")
+            .send()
+            .await
+            .context("failed to check channel")?;
+
+        let decision = ChannelResumption::from_status(resp.status())?;
+        match decision {
+            ChannelResumption::Resumed => {
+                info!("Resumed channel {} (next id {})", self.channel_id, self.next_id);
+            }
+            ChannelResumption::Renewed => {
+                self.renew_channel();
+                info!("Channel expired, opened new channel {}", self.channel_id);
+            }
+        }
+        Ok(decision)
+    }
+
+    /// Watch `path` on a Gall agent; facts arrive on `event_stream`
+    ///
+    /// Returns the subscription's request id, which its events carry.
+    pub async fn subscribe(&mut self, app: &str, path: &str) -> Result<u64> {
+        if !self.connected {
+            anyhow::bail!("not connected — call connect() first");
+        }
+
+        let sub_id = self.next_id;
+        self.next_id += 1;
+
+        let channel_url = format!("{}/~/channel/{}", self.config.url, self.channel_id);
+        let body = json!([{
+            "id": sub_id,
+            "action": "subscribe",
+            "ship": self.config.ship,
+            "app": app,
+            "path": path,
+        }]);
+
+        let resp = self
+            .http
+            .put(&channel_url)
+            .json(&body)
+            .send()
+            .await
+            .inspect_err(|e| self.mark_timed_out(e))
+            .context("failed to send subscribe")?;
+
+        let status = resp.status();
+        if !status.is_success() {
+            let body_text = resp.text().await.unwrap_or_default();
+            anyhow::bail!("subscribe failed with status {}: {}", status, body_text);
+        }
+
+        info!("Subscribed to {}{} (id={}, channel={})", app, path, sub_id, self.channel_id);
+        Ok(sub_id)
+    }
+
+    /// Open the channel's SSE stream; read it with `sse::SseParser`
+    pub async fn event_stream(&self) -> Result<reqwest::Response> {
+        let channel_url = format!("{}/~/channel/{}", self.config.url, self.channel_id);
+        let resp = self
+            .http
+            .get(&channel_url)
+            .header("Accept", "text/event-stream")
+            .timeout(EVENT_STREAM_TIMEOUT)
+            .send()
+            .await
+            .context("failed to open event stream")?;
+
+        let status = resp.status();
+        if !status.is_success() {
+            anyhow::bail!("event stream failed with status {}", status);
+        }
+        Ok(resp)
+    }
+
+    /// ACK events up to `event_id` so Eyre can drop them
+    pub async fn ack_event(&mut self, event_id: u64) -> Result<()> {
+        let channel_url = format!("{}/~/channel/{}", self.config.url, self.channel_id);
+
+        let ack_id = self.next_id;
+        self.next_id += 1;
+
+        let ack_body = json!([{
+            "id": ack_id,
+            "action": "ack",
+            "event-id": event_id,
+        }]);
+
+        let resp = self
+            .http
+            .put(&channel_url)
+            .json(&ack_body)
+            .send()
+            .await
+            .context("failed to send ack")?;
+        if !resp.status().is_success() {
+            anyhow::bail!("ack failed with status {}", resp.status());
+        }
+        Ok(())
     }
 
     /// ACK pending events (best effort, non-blocking)
@@ -332,14 +645,44 @@ impl AirlockClient {
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_airlock_client_creation() {
-        let config = UrbitConfig {
-            url: "http://localhost:8080".to_string(),
+    fn test_config(code: &str) -> UrbitConfig {
+        test_config_at("http://localhost:8080", code)
+    }
+
+    fn test_config_at(url: &str, code: &str) -> UrbitConfig {
+        UrbitConfig {
+            url: url.to_string(),
             ship: "zod".to_string(),
-            code: "lidlut-tabwed-pillex-ridrup".to_string(),
+            code: code.to_string(),
+            code_file: None,
+            code_env: None,
             agent: "lora-agent".to_string(),
-        };
+            max_in_flight: 8,
+            max_tx_attempts: 3,
+            prioritize_confirmed: true,
+            dev_addr_prefixes: Vec::new(),
+            gateway_euis: Vec::new(),
+            mark_routes: Vec::new(),
+            heartbeat_secs: 60,
+            typed_marks: false,
+            encoding: crate::config::PokeEncoding::Json,
+            authorize_joins: false,
+            poke_batch_max: 1,
+            poke_batch_ms: 20,
+            outbox_path: "/outbox".to_string(),
+            outbox_poll_ms: 2000,
+            outbox_poll_jitter_ms: 0,
+            outbox_subscribe_path: None,
+            config_path: None,
+            config_poll_secs: 300,
+            connect_timeout_ms: 1000,
+            request_timeout_ms: 1000,
+        }
+    }
+
+    #[test]
+    fn test_airlock_client_creation() {
+        let config = test_config("lidlut-tabwed-pillex-ridrup");
 
         let client = AirlockClient::new(config);
         assert!(!client.is_connected());
@@ -349,26 +692,47 @@ mod tests {
 
     #[test]
     fn test_channel_id_is_unique() {
-        let config = UrbitConfig {
-            url: "http://localhost:8080".to_string(),
-            ship: "zod".to_string(),
-            code: "test-code".to_string(),
-            agent: "lora-agent".to_string(),
-        };
+        let config = test_config("test-code");
 
         let client1 = AirlockClient::new(config.clone());
         let client2 = AirlockClient::new(config);
         assert_ne!(client1.channel_id, client2.channel_id);
     }
 
+    #[test]
+    fn test_unresponsive_ship_times_out() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            // Accepts connections but never answers
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                let mut held = Vec::new();
+                while let Ok((stream, _)) = listener.accept().await {
+                    held.push(stream);
+                }
+            });
+
+            let mut config = test_config_at(&url, "test-code");
+            config.request_timeout_ms = 200;
+            let mut client = AirlockClient::new(config);
+            let started = std::time::Instant::now();
+            assert!(client.connect().await.is_err());
+            assert!(started.elapsed() < Duration::from_secs(2));
+
+            // A timed-out poke errors and leaves the client to reconnect
+            client.connected = true;
+            let started = std::time::Instant::now();
+            let result = client.poke("lora-agent", "json", json!({})).await;
+            assert_eq!(result, Err(AirlockError::Timeout));
+            assert!(started.elapsed() < Duration::from_secs(2));
+            assert!(!client.is_connected());
+        });
+    }
+
     #[test]
     fn test_scry_requires_connection() {
-        let config = UrbitConfig {
-            url: "http://localhost:8080".to_string(),
-            ship: "zod".to_string(),
-            code: "test-code".to_string(),
-            agent: "lora-agent".to_string(),
-        };
+        let config = test_config("test-code");
 
         let client = AirlockClient::new(config);
         assert!(!client.is_connected());
@@ -379,4 +743,206 @@ mod tests {
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("not connected"));
     }
+
+    /// Serve canned HTTP responses, one per request, returning the base URL
+    /// and a receiver of the request lines (e.g. "GET /~/channel/...") and bodies
+    async fn mock_ship(
+        responses: Vec<&'static str>,
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<(String, String)>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let head_len = request.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+                let head = String::from_utf8_lossy(&request[..head_len]).to_string();
+                let content_length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map_or(0, |len| len.parse::<usize>().unwrap());
+                while request.len() < head_len + content_length {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let line = head.lines().next().unwrap_or_default().to_string();
+                let body = String::from_utf8_lossy(&request[head_len..]).to_string();
+                let _ = tx.send((line, body));
+                let reply = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    response
+                );
+                stream.write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn test_channel_resumption_decision() {
+        use reqwest::StatusCode;
+
+        assert_eq!(
+            ChannelResumption::from_status(StatusCode::OK).unwrap(),
+            ChannelResumption::Resumed
+        );
+        assert_eq!(
+            ChannelResumption::from_status(StatusCode::NOT_FOUND).unwrap(),
+            ChannelResumption::Renewed
+        );
+        assert!(ChannelResumption::from_status(StatusCode::FORBIDDEN).is_err());
+        assert!(ChannelResumption::from_status(StatusCode::INTERNAL_SERVER_ERROR).is_err());
+    }
+
+    #[test]
+    fn test_airlock_error_from_status() {
+        use reqwest::StatusCode;
+
+        let cases = [
+            (StatusCode::UNAUTHORIZED, Recovery::Reconnect),
+            (StatusCode::FORBIDDEN, Recovery::Reconnect),
+            (StatusCode::NOT_FOUND, Recovery::Reconnect),
+            (StatusCode::REQUEST_TIMEOUT, Recovery::Reconnect),
+            (StatusCode::GATEWAY_TIMEOUT, Recovery::Reconnect),
+            (StatusCode::BAD_REQUEST, Recovery::Drop),
+            (StatusCode::INTERNAL_SERVER_ERROR, Recovery::Drop),
+            (StatusCode::BAD_GATEWAY, Recovery::Backoff),
+            (StatusCode::SERVICE_UNAVAILABLE, Recovery::Backoff),
+        ];
+        for (status, recovery) in cases {
+            assert_eq!(AirlockError::from_status(status, "").recovery(), recovery, "{}", status);
+        }
+        assert_eq!(
+            AirlockError::from_status(StatusCode::FORBIDDEN, ""),
+            AirlockError::Auth("status 403 Forbidden".to_string())
+        );
+        assert_eq!(
+            AirlockError::from_status(StatusCode::NOT_FOUND, "gone"),
+            AirlockError::ChannelGone
+        );
+        assert_eq!(
+            AirlockError::from_status(StatusCode::GATEWAY_TIMEOUT, ""),
+            AirlockError::Timeout
+        );
+        assert_eq!(
+            AirlockError::from_status(StatusCode::INTERNAL_SERVER_ERROR, "bad mark"),
+            AirlockError::AgentNack("status 500 Internal Server Error: bad mark".to_string())
+        );
+        assert_eq!(
+            AirlockError::from_status(StatusCode::SERVICE_UNAVAILABLE, ""),
+            AirlockError::Network("status 503 Service Unavailable".to_string())
+        );
+
+        // Still usable as an anyhow error, and recoverable from one
+        let error = anyhow::Error::from(AirlockError::ChannelGone);
+        assert_eq!(error.to_string(), "channel no longer exists");
+        assert_eq!(AirlockError::from_anyhow(error), AirlockError::ChannelGone);
+        assert_eq!(network_backoff(1), Duration::from_secs(1));
+        assert_eq!(network_backoff(3), Duration::from_secs(4));
+        assert_eq!(network_backoff(20), Duration::from_secs(32));
+    }
+
+    #[test]
+    fn test_poke_errors() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut client = AirlockClient::new(test_config("test-code"));
+            let result = client.poke("lora-agent", "json", json!({})).await;
+            assert!(matches!(result, Err(AirlockError::Auth(_))));
+
+            // A dropped channel is replaced for the next poke
+            let responses = vec!["404 Not Found", "500 Internal Server Error"];
+            let (url, _requests) = mock_ship(responses).await;
+            let mut client = AirlockClient::new(test_config_at(&url, "test-code"));
+            client.connected = true;
+            let channel_id = client.channel_id.clone();
+            let result = client.poke("lora-agent", "json", json!({})).await;
+            assert_eq!(result, Err(AirlockError::ChannelGone));
+            assert_ne!(client.channel_id, channel_id);
+
+            let result = client.poke("lora-agent", "json", json!({})).await;
+            assert!(matches!(result, Err(AirlockError::AgentNack(_))));
+            assert!(client.is_connected());
+        });
+    }
+
+    #[test]
+    fn test_uplinks_batched_into_one_put() {
+        use crate::urbit::batch::{collect_uplinks, tests::uplink};
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (url, mut requests) = mock_ship(vec!["204 No Content"]).await;
+            let mut client = AirlockClient::new(test_config_at(&url, "test-code"));
+            client.connected = true;
+
+            // Four uplinks within the window: one PUT carrying four pokes
+            let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+            for fcnt in 2..=4 {
+                tx.send(uplink(fcnt)).await.unwrap();
+            }
+            let window = Duration::from_millis(50);
+            let (batch, _) = collect_uplinks(&mut rx, uplink(1), 8, window).await;
+            let pokes = batch
+                .iter()
+                .map(|action| ("json", serde_json::to_value(action).unwrap()))
+                .collect();
+            client.poke_batch("lora-agent", pokes).await.unwrap();
+
+            let (line, body) = requests.recv().await.unwrap();
+            assert_eq!(line, format!("PUT /~/channel/{} HTTP/1.1", client.channel_id));
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            let actions = body.as_array().unwrap();
+            assert_eq!(actions.len(), 4);
+            for (i, action) in actions.iter().enumerate() {
+                assert_eq!(action["id"], i as u64 + 1);
+                assert_eq!(action["action"], "poke");
+                assert_eq!(action["json"]["fcnt"], i as u64 + 1);
+            }
+        });
+    }
+
+    #[test]
+    fn test_resume_or_renew() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            // Live channel: login, then GET 200 keeps the id and event counter
+            let (url, mut requests) = mock_ship(vec!["204 No Content", "200 OK"]).await;
+            let mut client = AirlockClient::new(test_config_at(&url, "test-code"));
+            client.next_id = 5;
+            let channel_id = client.channel_id.clone();
+
+            let result = client.resume_or_renew().await.unwrap();
+            assert_eq!(result, ChannelResumption::Resumed);
+            assert_eq!(client.channel_id, channel_id);
+            assert_eq!(client.next_id, 5);
+            assert_eq!(requests.recv().await.unwrap().0, "POST /~/login HTTP/1.1");
+            assert_eq!(
+                requests.recv().await.unwrap().0,
+                format!("GET /~/channel/{} HTTP/1.1", channel_id)
+            );
+
+            // Expired channel: GET 404 switches to a fresh channel
+            let (url, _requests) = mock_ship(vec!["204 No Content", "404 Not Found"]).await;
+            let mut client = AirlockClient::new(test_config_at(&url, "test-code"));
+            client.next_id = 5;
+            let channel_id = client.channel_id.clone();
+
+            let result = client.resume_or_renew().await.unwrap();
+            assert_eq!(result, ChannelResumption::Renewed);
+            assert_ne!(client.channel_id, channel_id);
+            assert_eq!(client.next_id, 1);
+        });
+    }
 }
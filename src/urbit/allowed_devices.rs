@@ -0,0 +1,118 @@
+//! OTAA join authorization by the agent (`urbit.authorize_joins`)
+//!
+//! Join policy lives in the agent: before a JoinRequest or RejoinRequest is
+//! poked, the Airlock task scries `/allowed-devices` for the DevEUIs the
+//! agent lets join, `["0004A30B001C0530", ...]`, and drops requests from any
+//! other device. The list is reused for a short TTL so a burst of joins
+//! costs one scry. If a scry fails, the last list fetched still applies;
+//! with none yet, joins are refused until the agent answers.
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// The agent's join allow list, as last scried
+#[derive(Debug)]
+pub struct AllowedDevices {
+    ttl: Duration,
+    fetched: Option<(HashSet<u64>, Instant)>,
+}
+
+impl AllowedDevices {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, fetched: None }
+    }
+
+    /// Whether `dev_eui` may join, or None if the list must be scried first
+    pub fn check(&self, dev_eui: u64, now: Instant) -> Option<bool> {
+        let (allowed, fetched_at) = self.fetched.as_ref()?;
+        (now.saturating_duration_since(*fetched_at) < self.ttl)
+            .then(|| allowed.contains(&dev_eui))
+    }
+
+    /// Whether `dev_eui` was on the last list fetched, however old
+    pub fn check_stale(&self, dev_eui: u64) -> Option<bool> {
+        let (allowed, _) = self.fetched.as_ref()?;
+        Some(allowed.contains(&dev_eui))
+    }
+
+    pub fn update(&mut self, allowed: HashSet<u64>, now: Instant) {
+        self.fetched = Some((allowed, now));
+    }
+}
+
+/// DevEUIs from an `/allowed-devices` scry result
+pub fn parse_allowed_devices(value: &serde_json::Value) -> anyhow::Result<HashSet<u64>> {
+    let entries = value
+        .as_array()
+        .ok_or_else(|| anyhow::anyhow!("/allowed-devices is not a list: {}", value))?;
+    entries
+        .iter()
+        .map(|entry| {
+            entry
+                .as_str()
+                .filter(|eui| eui.len() == 16)
+                .and_then(|eui| u64::from_str_radix(eui, 16).ok())
+                .ok_or_else(|| anyhow::anyhow!("Invalid DevEUI {} in /allowed-devices", entry))
+        })
+        .collect()
+}
+
+/// Whether the agent lets `dev_eui` (16 hex digits) join
+///
+/// Scries `/allowed-devices` unless `cache` is fresh; see the module docs
+/// for what happens when the scry fails.
+#[cfg(feature = "phase2")]
+pub async fn authorize_join(
+    client: &super::AirlockClient,
+    cache: &mut AllowedDevices,
+    dev_eui: &str,
+) -> bool {
+    let Ok(eui) = u64::from_str_radix(dev_eui, 16) else {
+        return false;
+    };
+    let now = Instant::now();
+    if let Some(allowed) = cache.check(eui, now) {
+        return allowed;
+    }
+    let scried = client.scry(&client.config().agent, "/allowed-devices").await;
+    match scried.and_then(|value| parse_allowed_devices(&value)) {
+        Ok(allowed) => {
+            cache.update(allowed, now);
+            cache.check(eui, now).unwrap_or(false)
+        }
+        Err(e) => {
+            tracing::warn!("Could not fetch the join allow list: {}", e);
+            cache.check_stale(eui).unwrap_or(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow_and_deny() {
+        let whitelist = serde_json::json!(["0004A30B001C0530", "70b3d57ed0000001"]);
+        let allowed = parse_allowed_devices(&whitelist).unwrap();
+        let mut cache = AllowedDevices::new(Duration::from_secs(30));
+        let t0 = Instant::now();
+        assert_eq!(cache.check(0x0004A30B001C0530, t0), None);
+        assert_eq!(cache.check_stale(0x0004A30B001C0530), None);
+
+        cache.update(allowed, t0);
+        assert_eq!(cache.check(0x0004A30B001C0530, t0), Some(true));
+        assert_eq!(cache.check(0x70B3D57ED0000001, t0), Some(true));
+        assert_eq!(cache.check(0x0004A30B001C0531, t0), Some(false));
+
+        // Expired: scry again, but the old answer stands in if that fails
+        let later = t0 + Duration::from_secs(30);
+        assert_eq!(cache.check(0x0004A30B001C0530, later), None);
+        assert_eq!(cache.check_stale(0x0004A30B001C0530), Some(true));
+        assert_eq!(cache.check_stale(0x0004A30B001C0531), Some(false));
+
+        assert!(parse_allowed_devices(&serde_json::json!({"dev-euis": []})).is_err());
+        assert!(parse_allowed_devices(&serde_json::json!(["0004A30B"])).is_err());
+        assert!(parse_allowed_devices(&serde_json::json!([])).unwrap().is_empty());
+    }
+}
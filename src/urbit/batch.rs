@@ -0,0 +1,105 @@
+//! Uplink poke batching (`urbit.poke_batch_max`, `urbit.poke_batch_ms`)
+//!
+//! Eyre takes an array of channel actions per PUT, so under heavy traffic
+//! the Airlock task can send several uplink pokes in one request. After an
+//! uplink arrives it waits up to `poke_batch_ms` for more, sending as soon
+//! as the batch is full. Any other action ends the batch and is poked on
+//! its own right after it, so the agent still sees actions in order.
+
+use std::time::Duration;
+
+use tokio::sync::mpsc::Receiver;
+
+use super::types::LoRaAction;
+
+/// `first` and the uplinks that follow it within `window`, at most `max`
+///
+/// The second value is an action that arrived in the window but can't join
+/// the batch; poke it next.
+pub async fn collect_uplinks(
+    rx: &mut Receiver<LoRaAction>,
+    first: LoRaAction,
+    max: usize,
+    window: Duration,
+) -> (Vec<LoRaAction>, Option<LoRaAction>) {
+    let mut batch = vec![first];
+    if !matches!(batch[0], LoRaAction::Uplink(_)) {
+        return (batch, None);
+    }
+    let deadline = tokio::time::Instant::now() + window;
+    while batch.len() < max {
+        match tokio::time::timeout_at(deadline, rx.recv()).await {
+            Ok(Some(action @ LoRaAction::Uplink(_))) => batch.push(action),
+            Ok(Some(action)) => return (batch, Some(action)),
+            // Closed, or the window is over
+            Ok(None) | Err(_) => break,
+        }
+    }
+    (batch, None)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn uplink(fcnt: u16) -> LoRaAction {
+        let packet = serde_json::json!({
+            "dev-addr": "260B1234",
+            "fcnt": fcnt,
+            "f-port": 1,
+            "payload": "01",
+            "rssi": -80.0,
+            "snr": 5.0,
+            "freq": 904.5,
+            "data-rate": "SF7BW125",
+            "gateway-eui": "aabbccddeeff0011",
+            "received-at": "2026-02-18T17:30:00Z",
+            "mtype": "UnconfirmedDataUp",
+            "source": "local",
+        });
+        LoRaAction::Uplink(serde_json::from_value(packet).unwrap())
+    }
+
+    fn fcnts(batch: &[LoRaAction]) -> Vec<u16> {
+        batch
+            .iter()
+            .map(|action| match action {
+                LoRaAction::Uplink(packet) => packet.fcnt,
+                other => panic!("expected an uplink, got {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_collect_uplinks() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let window = Duration::from_millis(50);
+            let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+
+            // Full batches go at once; the rest wait for the window
+            for fcnt in 2..=5 {
+                tx.send(uplink(fcnt)).await.unwrap();
+            }
+            let (batch, next) = collect_uplinks(&mut rx, uplink(1), 3, window).await;
+            assert_eq!((fcnts(&batch), next.is_none()), (vec![1, 2, 3], true));
+            let first = rx.recv().await.unwrap();
+            let (batch, _) = collect_uplinks(&mut rx, first, 3, window).await;
+            assert_eq!(fcnts(&batch), vec![4, 5]);
+
+            // Anything else ends the batch and is handed back
+            tx.send(uplink(7)).await.unwrap();
+            tx.send(LoRaAction::Disconnecting { uptime_secs: 1 }).await.unwrap();
+            tx.send(uplink(8)).await.unwrap();
+            let (batch, next) = collect_uplinks(&mut rx, uplink(6), 10, window).await;
+            assert_eq!(fcnts(&batch), vec![6, 7]);
+            assert!(matches!(next, Some(LoRaAction::Disconnecting { .. })));
+
+            // Only uplinks are batched
+            let goodbye = LoRaAction::Disconnecting { uptime_secs: 2 };
+            let (batch, next) = collect_uplinks(&mut rx, goodbye, 10, window).await;
+            assert_eq!((batch.len(), next.is_none()), (1, true));
+            assert!(matches!(rx.try_recv(), Ok(LoRaAction::Uplink(_))));
+        });
+    }
+}
@@ -9,14 +9,23 @@
 //! 1. Authenticate with ship using +code
 //! 2. Poke %lora-agent with decoded packet data
 //! 3. ACK events to keep the channel healthy
+//! 4. Optionally watch the agent's outbox for downlinks (`sse`)
 
+pub mod allowed_devices;
+pub mod batch;
+pub mod msgpack;
+pub mod outbox;
+pub mod registry;
+pub mod remote_config;
+pub mod routing;
+pub mod sse;
 pub mod types;
 
 #[cfg(feature = "phase2")]
 pub mod airlock;
 
 #[cfg(feature = "phase2")]
-pub use airlock::AirlockClient;
+pub use airlock::{network_backoff, AirlockClient, AirlockError, Recovery};
 
 #[cfg(not(feature = "phase2"))]
 mod stub {
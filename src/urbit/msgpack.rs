@@ -0,0 +1,271 @@
+//! MessagePack encoding of poke bodies (`urbit.encoding = "msgpack"`)
+//!
+//! Hand-written for the subset JSON needs: nil, booleans, integers, float 64,
+//! strings, arrays and maps with string keys. Field names stay, but quotes,
+//! commas and number text go, so an action shrinks by a fair share. Eyre
+//! channel pokes still carry JSON: the bytes go base64 in a `%lora-msgpack`
+//! poke, whose mark on the agent's desk turns them back into the action.
+//!
+//! Reference: <https://github.com/msgpack/msgpack/blob/master/spec.md>
+
+use anyhow::{bail, Context};
+use serde_json::{Map, Number, Value};
+
+/// Mark of pokes carrying a MessagePack action
+pub const MSGPACK_MARK: &str = "lora-msgpack";
+
+/// MessagePack bytes of a JSON value
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_value(&mut out, value);
+    out
+}
+
+/// JSON value of MessagePack bytes; extension and binary types are refused
+pub fn decode(bytes: &[u8]) -> anyhow::Result<Value> {
+    let mut reader = Reader { bytes, pos: 0 };
+    let value = reader.value()?;
+    if reader.pos != bytes.len() {
+        bail!("{} trailing bytes after MessagePack value", bytes.len() - reader.pos);
+    }
+    Ok(value)
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                write_uint(out, u);
+            } else if let Some(i) = n.as_i64() {
+                write_negative(out, i);
+            } else {
+                out.push(0xcb);
+                out.extend_from_slice(&n.as_f64().unwrap_or(0.0).to_be_bytes());
+            }
+        }
+        Value::String(s) => write_str(out, s),
+        Value::Array(items) => {
+            write_len(out, items.len(), 0x90, 0xdc);
+            for item in items {
+                write_value(out, item);
+            }
+        }
+        Value::Object(fields) => {
+            write_len(out, fields.len(), 0x80, 0xde);
+            for (key, field) in fields {
+                write_str(out, key);
+                write_value(out, field);
+            }
+        }
+    }
+}
+
+fn write_uint(out: &mut Vec<u8>, u: u64) {
+    if u <= 0x7f {
+        out.push(u as u8);
+    } else if let Ok(u) = u8::try_from(u) {
+        out.extend_from_slice(&[0xcc, u]);
+    } else if let Ok(u) = u16::try_from(u) {
+        out.push(0xcd);
+        out.extend_from_slice(&u.to_be_bytes());
+    } else if let Ok(u) = u32::try_from(u) {
+        out.push(0xce);
+        out.extend_from_slice(&u.to_be_bytes());
+    } else {
+        out.push(0xcf);
+        out.extend_from_slice(&u.to_be_bytes());
+    }
+}
+
+/// `i` is below zero (`as_u64` took the rest)
+fn write_negative(out: &mut Vec<u8>, i: i64) {
+    if i >= -32 {
+        out.push(i as u8);
+    } else if let Ok(i) = i8::try_from(i) {
+        out.extend_from_slice(&[0xd0, i as u8]);
+    } else if let Ok(i) = i16::try_from(i) {
+        out.push(0xd1);
+        out.extend_from_slice(&i.to_be_bytes());
+    } else if let Ok(i) = i32::try_from(i) {
+        out.push(0xd2);
+        out.extend_from_slice(&i.to_be_bytes());
+    } else {
+        out.push(0xd3);
+        out.extend_from_slice(&i.to_be_bytes());
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    let len = s.len();
+    if len < 32 {
+        out.push(0xa0 | len as u8);
+    } else if let Ok(len) = u8::try_from(len) {
+        out.extend_from_slice(&[0xd9, len]);
+    } else if let Ok(len) = u16::try_from(len) {
+        out.push(0xda);
+        out.extend_from_slice(&len.to_be_bytes());
+    } else {
+        out.push(0xdb);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Array or map header: `fix` holds up to 15 entries, `wide` (16-bit) and
+/// `wide + 1` (32-bit) the rest
+fn write_len(out: &mut Vec<u8>, len: usize, fix: u8, wide: u8) {
+    if len < 16 {
+        out.push(fix | len as u8);
+    } else if let Ok(len) = u16::try_from(len) {
+        out.push(wide);
+        out.extend_from_slice(&len.to_be_bytes());
+    } else {
+        out.push(wide + 1);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        let end = self.pos + N;
+        let bytes = self.bytes.get(self.pos..end).context("truncated MessagePack")?;
+        self.pos = end;
+        Ok(bytes.try_into().expect("slice of N bytes"))
+    }
+
+    fn value(&mut self) -> anyhow::Result<Value> {
+        let [tag] = self.take()?;
+        let value = match tag {
+            0x00..=0x7f => Value::from(tag),
+            0x80..=0x8f => self.map(usize::from(tag & 0x0f))?,
+            0x90..=0x9f => self.array(usize::from(tag & 0x0f))?,
+            0xa0..=0xbf => self.string(usize::from(tag & 0x1f))?,
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xca => float(f64::from(f32::from_be_bytes(self.take()?)))?,
+            0xcb => float(f64::from_be_bytes(self.take()?))?,
+            0xcc => Value::from(u8::from_be_bytes(self.take()?)),
+            0xcd => Value::from(u16::from_be_bytes(self.take()?)),
+            0xce => Value::from(u32::from_be_bytes(self.take()?)),
+            0xcf => Value::from(u64::from_be_bytes(self.take()?)),
+            0xd0 => Value::from(i8::from_be_bytes(self.take()?)),
+            0xd1 => Value::from(i16::from_be_bytes(self.take()?)),
+            0xd2 => Value::from(i32::from_be_bytes(self.take()?)),
+            0xd3 => Value::from(i64::from_be_bytes(self.take()?)),
+            0xd9 => {
+                let len = u8::from_be_bytes(self.take()?);
+                self.string(usize::from(len))?
+            }
+            0xda => {
+                let len = u16::from_be_bytes(self.take()?);
+                self.string(usize::from(len))?
+            }
+            0xdb => {
+                let len = u32::from_be_bytes(self.take()?);
+                self.string(len as usize)?
+            }
+            0xdc => {
+                let len = u16::from_be_bytes(self.take()?);
+                self.array(usize::from(len))?
+            }
+            0xdd => {
+                let len = u32::from_be_bytes(self.take()?);
+                self.array(len as usize)?
+            }
+            0xde => {
+                let len = u16::from_be_bytes(self.take()?);
+                self.map(usize::from(len))?
+            }
+            0xdf => {
+                let len = u32::from_be_bytes(self.take()?);
+                self.map(len as usize)?
+            }
+            0xe0..=0xff => Value::from(tag as i8),
+            _ => bail!("unsupported MessagePack type 0x{:02x}", tag),
+        };
+        Ok(value)
+    }
+
+    fn string(&mut self, len: usize) -> anyhow::Result<Value> {
+        let end = self.pos.checked_add(len).context("truncated MessagePack")?;
+        let bytes = self.bytes.get(self.pos..end).context("truncated MessagePack")?;
+        self.pos = end;
+        let s = std::str::from_utf8(bytes).context("MessagePack string is not UTF-8")?;
+        Ok(Value::String(s.to_string()))
+    }
+
+    fn array(&mut self, len: usize) -> anyhow::Result<Value> {
+        // Every entry takes at least a byte, so a bogus length fails before
+        // it can allocate
+        let mut items = Vec::with_capacity(len.min(self.bytes.len() - self.pos));
+        for _ in 0..len {
+            items.push(self.value()?);
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn map(&mut self, len: usize) -> anyhow::Result<Value> {
+        let mut fields = Map::new();
+        for _ in 0..len {
+            let Value::String(key) = self.value()? else {
+                bail!("MessagePack map key is not a string");
+            };
+            fields.insert(key, self.value()?);
+        }
+        Ok(Value::Object(fields))
+    }
+}
+
+fn float(f: f64) -> anyhow::Result<Value> {
+    Number::from_f64(f).map(Value::Number).context("MessagePack float is not finite")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::urbit::batch::tests::uplink;
+    use crate::urbit::types::LoRaAction;
+    use serde_json::json;
+
+    #[test]
+    fn test_known_bytes() {
+        let value = json!({"a": [1, -1, 300, -200, 1.5, null, true, "hi"]});
+        let bytes = [
+            0x81, 0xa1, b'a', 0x98, 0x01, 0xff, 0xcd, 0x01, 0x2c, 0xd1, 0xff, 0x38, 0xcb, 0x3f,
+            0xf8, 0, 0, 0, 0, 0, 0, 0xc0, 0xc3, 0xa2, b'h', b'i',
+        ];
+        assert_eq!(encode(&value), bytes);
+        assert_eq!(decode(&bytes).unwrap(), value);
+
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode(&[0xc0, 0xc0]).is_err());
+        assert!(decode(&[0xc4, 0x01, 0x00]).is_err());
+        assert!(decode(&[0xdd, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn test_action_round_trip() {
+        let LoRaAction::Uplink(mut packet) = uplink(7) else {
+            unreachable!()
+        };
+        packet.payload = "ab".repeat(100);
+        let action = LoRaAction::Uplink(packet);
+        let json = serde_json::to_value(&action).unwrap();
+
+        let bytes = encode(&json);
+        assert!(bytes.len() < json.to_string().len());
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded, json);
+        let action: LoRaAction = serde_json::from_value(decoded).unwrap();
+        assert!(matches!(action, LoRaAction::Uplink(packet) if packet.fcnt == 7));
+    }
+}
@@ -0,0 +1,693 @@
+//! Outbox bookkeeping for the outbound task
+//!
+//! The agent only drops a message from `/outbox` once our tx-ack poke has
+//! been processed, so the next scry can return a message we already put on
+//! the air. `DownlinkQueue` remembers every id it has handed out until the
+//! message leaves the outbox, so each one is transmitted exactly once.
+//!
+//! A send that failed for a transient reason (no gateway yet, a timing
+//! collision) is retried on a later scry, after a backoff that doubles with
+//! each attempt: 2 s, 4 s, 8 s, ... up to a minute.
+//!
+//! Every `[[urbit]]` entry is an `OutboxSource`: its own Airlock connection,
+//! queue and registry cache, polled in turn by the one outbound task so
+//! downlink counters stay shared across agents. Each source is polled every
+//! `outbox_poll_ms` plus a random share of `outbox_poll_jitter_ms`.
+//!
+//! With `outbox_subscribe_path` set, a subscription task also watches the
+//! agent for newly queued messages and pushes them to the outbound task as
+//! they arrive. The same queue dedups pushed and polled copies. While the
+//! subscription is up and nothing is in flight, polls slow to
+//! `SUBSCRIBED_POLL_INTERVAL`; when it drops, polling resumes at once.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+
+use super::types::OutboundMessage;
+#[cfg(feature = "phase2")]
+use super::types::{TxAck, JSON_MARK};
+
+#[cfg(feature = "phase2")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "phase2")]
+use std::sync::Arc;
+#[cfg(feature = "phase2")]
+use super::airlock::{network_backoff, AirlockError, Recovery};
+
+/// Exactly-once, optionally prioritized view over scried outbox messages
+#[derive(Debug)]
+pub struct DownlinkQueue {
+    /// Ids handed out by `take_ready` that are still in the outbox
+    in_flight: HashSet<u64>,
+    /// Times each id still in the outbox has been handed out
+    attempts: HashMap<u64, u32>,
+    /// Released ids that must not be handed out before this time
+    retry_at: HashMap<u64, Instant>,
+    /// Maximum number of in-flight messages (0 = unlimited)
+    max_in_flight: usize,
+    /// Send confirmed messages before unconfirmed ones
+    prioritize_confirmed: bool,
+}
+
+impl DownlinkQueue {
+    pub fn new(max_in_flight: usize, prioritize_confirmed: bool) -> Self {
+        Self {
+            in_flight: HashSet::new(),
+            attempts: HashMap::new(),
+            retry_at: HashMap::new(),
+            max_in_flight,
+            prioritize_confirmed,
+        }
+    }
+
+    /// Pick the messages to send from a fresh outbox scry
+    ///
+    /// Ids no longer in the outbox are forgotten (the agent processed our
+    /// tx-ack/tx-fail). Everything returned is marked in flight, so later
+    /// scries that still contain it won't return it again.
+    pub fn take_ready(&mut self, outbox: &[OutboundMessage]) -> Vec<OutboundMessage> {
+        self.take_ready_at(outbox, Instant::now())
+    }
+
+    /// `take_ready` at time `now`, leaving out messages still backing off
+    pub fn take_ready_at(
+        &mut self,
+        outbox: &[OutboundMessage],
+        now: Instant,
+    ) -> Vec<OutboundMessage> {
+        let current: HashSet<u64> = outbox.iter().map(|msg| msg.id).collect();
+        self.in_flight.retain(|id| current.contains(id));
+        self.attempts.retain(|id, _| current.contains(id));
+        self.retry_at.retain(|id, at| current.contains(id) && *at > now);
+
+        let mut ready: Vec<OutboundMessage> = outbox
+            .iter()
+            .filter(|msg| {
+                !self.in_flight.contains(&msg.id) && !self.retry_at.contains_key(&msg.id)
+            })
+            .cloned()
+            .collect();
+        // Oldest (lowest id) first, confirmed ahead of unconfirmed if enabled
+        if self.prioritize_confirmed {
+            ready.sort_by_key(|msg| (!msg.confirmed, msg.id));
+        } else {
+            ready.sort_by_key(|msg| msg.id);
+        }
+        ready.dedup_by_key(|msg| msg.id);
+
+        if self.max_in_flight > 0 {
+            let room = self.max_in_flight.saturating_sub(self.in_flight.len());
+            ready.truncate(room);
+        }
+
+        for msg in &ready {
+            self.in_flight.insert(msg.id);
+            *self.attempts.entry(msg.id).or_default() += 1;
+        }
+        ready
+    }
+
+    /// Take a message the agent pushed on its subscription
+    ///
+    /// False if it is already in flight, backing off or over the in-flight
+    /// limit; a later poll hands it out then.
+    pub fn take_pushed(&mut self, msg: &OutboundMessage) -> bool {
+        self.take_pushed_at(msg, Instant::now())
+    }
+
+    pub fn take_pushed_at(&mut self, msg: &OutboundMessage, now: Instant) -> bool {
+        let backing_off = self.retry_at.get(&msg.id).is_some_and(|at| *at > now);
+        let full = self.max_in_flight > 0 && self.in_flight.len() >= self.max_in_flight;
+        if self.in_flight.contains(&msg.id) || backing_off || full {
+            return false;
+        }
+        self.retry_at.remove(&msg.id);
+        self.in_flight.insert(msg.id);
+        *self.attempts.entry(msg.id).or_default() += 1;
+        true
+    }
+
+    /// Nothing in flight or waiting to be retried
+    pub fn is_idle(&self) -> bool {
+        self.in_flight.is_empty() && self.retry_at.is_empty()
+    }
+
+    /// Hand `id` out again on the next scry (the send should be retried)
+    pub fn release(&mut self, id: u64) {
+        self.in_flight.remove(&id);
+    }
+
+    /// Hand `id` out again once its backoff has passed; returns the delay
+    pub fn retry_later(&mut self, id: u64, now: Instant) -> Duration {
+        let delay = retry_backoff(self.attempts(id));
+        self.in_flight.remove(&id);
+        self.retry_at.insert(id, now + delay);
+        delay
+    }
+
+    /// How many times `id` has been handed out
+    pub fn attempts(&self, id: u64) -> u32 {
+        self.attempts.get(&id).copied().unwrap_or(0)
+    }
+
+    /// Number of messages sent but still present in the outbox
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+/// First retry delay, doubled after each further attempt
+const RETRY_BASE: Duration = Duration::from_secs(2);
+
+/// Longest retry delay
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// Delay before retrying a message that has been sent `attempts` times
+pub fn retry_backoff(attempts: u32) -> Duration {
+    RETRY_BASE
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(RETRY_MAX)
+}
+
+/// Delay before the next outbox poll: `interval` plus up to `jitter`
+///
+/// `seed` picks the point in the jitter range; any value will do.
+pub fn poll_delay(interval: Duration, jitter: Duration, seed: u32) -> Duration {
+    let jitter_ms = jitter.as_millis() as u64;
+    interval + Duration::from_millis(seed as u64 % (jitter_ms + 1))
+}
+
+/// Pseudo-random seed for `poll_delay`
+#[cfg(feature = "phase2")]
+fn jitter_seed() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos()
+}
+
+/// Messages in an outbox scry result (a JSON array)
+pub fn parse_outbox(value: &serde_json::Value) -> Result<Vec<OutboundMessage>> {
+    if !value.is_array() {
+        return Err(anyhow!("outbox is not an array: {}", value));
+    }
+    serde_json::from_value(value.clone()).map_err(|e| anyhow!("unparseable outbox: {}", e))
+}
+
+/// Poll interval while an outbox subscription is up and the queue is idle
+pub const SUBSCRIBED_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Wait before resubscribing after an outbox subscription drops
+#[cfg(feature = "phase2")]
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(10);
+
+/// An event on an outbox subscription
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutboxEvent {
+    /// The agent queued a message
+    Queued(Box<OutboundMessage>),
+    /// The agent kicked us or refused the watch
+    Closed,
+    /// Another request's event, or an ack
+    Other,
+}
+
+/// Interpret an Eyre channel event (the SSE `data:` JSON) for subscription `sub_id`
+pub fn parse_outbox_event(data: &str, sub_id: u64) -> Result<OutboxEvent> {
+    let event: serde_json::Value =
+        serde_json::from_str(data).map_err(|e| anyhow!("unparseable channel event: {}", e))?;
+    if event["id"].as_u64() != Some(sub_id) {
+        return Ok(OutboxEvent::Other);
+    }
+    match event["response"].as_str() {
+        Some("diff") => serde_json::from_value(event["json"].clone())
+            .map(|msg| OutboxEvent::Queued(Box::new(msg)))
+            .map_err(|e| anyhow!("unparseable outbox fact: {}", e)),
+        Some("quit") => Ok(OutboxEvent::Closed),
+        Some("subscribe") if event.get("err").is_some() => Ok(OutboxEvent::Closed),
+        _ => Ok(OutboxEvent::Other),
+    }
+}
+
+/// What a subscription task tells the outbound task about source `.0`
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutboxPush {
+    /// Send this message now
+    Queued(usize, Box<OutboundMessage>),
+    /// The subscription dropped; poll now
+    Lost(usize),
+}
+
+/// One agent's outbox, with the connection used to poll it and poke tx-acks
+#[cfg(feature = "phase2")]
+pub struct OutboxSource {
+    pub client: super::AirlockClient,
+    pub queue: DownlinkQueue,
+    pub addr_cache: super::registry::AddrCache,
+    pub max_tx_attempts: u32,
+    pub poll_interval: Duration,
+    pub poll_jitter: Duration,
+    /// When the outbox is next due to be polled
+    pub next_poll: Instant,
+    /// Set by `run_subscription` while its subscription is up
+    pub subscribed: Arc<AtomicBool>,
+    /// Tx-ack pokes in a row that failed to reach the ship
+    pub network_failures: u32,
+}
+
+#[cfg(feature = "phase2")]
+impl OutboxSource {
+    pub fn new(config: crate::config::UrbitConfig, registry_ttl: Duration) -> Self {
+        Self {
+            queue: DownlinkQueue::new(config.max_in_flight, config.prioritize_confirmed),
+            max_tx_attempts: config.max_tx_attempts.max(1),
+            poll_interval: Duration::from_millis(config.outbox_poll_ms.max(1)),
+            poll_jitter: Duration::from_millis(config.outbox_poll_jitter_ms),
+            next_poll: Instant::now(),
+            subscribed: Arc::default(),
+            network_failures: 0,
+            addr_cache: super::registry::AddrCache::new(registry_ttl),
+            client: super::AirlockClient::new(config),
+        }
+    }
+
+    pub fn agent(&self) -> &str {
+        &self.client.config().agent
+    }
+
+    /// `%agent on ~ship`, for logs
+    pub fn target(&self) -> String {
+        let config = self.client.config();
+        format!("%{} on ~{}", config.agent, config.ship)
+    }
+
+    /// Poke a tx-ack or tx-fail to the agent, recovering from a failure
+    ///
+    /// Auth, channel and timeout errors leave the client to log in again on
+    /// the next poll; a ship that can't be reached pushes the next poll back
+    /// by `network_backoff`; a poke the agent refused is only logged.
+    pub async fn poke_tx_ack(&mut self, ack: serde_json::Value) -> Result<(), AirlockError> {
+        let agent = self.agent().to_string();
+        let mark = if self.client.config().typed_marks { TxAck::mark() } else { JSON_MARK };
+        let result = self.client.poke(&agent, mark, ack).await;
+        match &result {
+            Ok(()) => self.network_failures = 0,
+            Err(e) => match e.recovery() {
+                Recovery::Reconnect => {}
+                Recovery::Backoff => {
+                    self.network_failures += 1;
+                    let delay = network_backoff(self.network_failures);
+                    tracing::warn!("{} unreachable, next poll in {:?}", self.target(), delay);
+                    self.next_poll = Instant::now() + delay;
+                }
+                Recovery::Drop => tracing::warn!("{} refused the poke: {}", self.target(), e),
+            },
+        }
+        result
+    }
+
+    /// Scry the outbox (logging in first if needed) and take the messages to send
+    pub async fn poll(&mut self) -> Result<Vec<OutboundMessage>> {
+        if !self.client.is_connected() {
+            self.client.connect().await?;
+        }
+        let config = self.client.config();
+        let outbox = self.client.scry(&config.agent, &config.outbox_path).await?;
+        Ok(self.queue.take_ready(&parse_outbox(&outbox)?))
+    }
+}
+
+/// Poll every source due at `now`, pairing each message to send with its
+/// source's index
+///
+/// A source that can't be reached is logged and skipped until its next poll.
+#[cfg(feature = "phase2")]
+pub async fn poll_all(
+    sources: &mut [OutboxSource],
+    now: Instant,
+) -> Vec<(usize, OutboundMessage)> {
+    let mut ready = Vec::new();
+    for (index, source) in sources.iter_mut().enumerate() {
+        if source.next_poll > now {
+            continue;
+        }
+        match source.poll().await {
+            Ok(messages) => ready.extend(messages.into_iter().map(|msg| (index, msg))),
+            Err(e) => tracing::warn!("Failed to poll outbox of {}: {}", source.target(), e),
+        }
+        let interval = match source.subscribed.load(Ordering::Relaxed) && source.queue.is_idle() {
+            true => SUBSCRIBED_POLL_INTERVAL.max(source.poll_interval),
+            false => source.poll_interval,
+        };
+        source.next_poll = now + poll_delay(interval, source.poll_jitter, jitter_seed());
+    }
+    ready
+}
+
+/// Watch `path` on the agent of source `index`, pushing each queued message
+/// to the outbound task until `shutdown`
+///
+/// Uses a channel of its own. A dropped subscription is reported as
+/// `OutboxPush::Lost` and retried after `RESUBSCRIBE_DELAY`.
+#[cfg(feature = "phase2")]
+pub async fn run_subscription(
+    index: usize,
+    config: crate::config::UrbitConfig,
+    path: String,
+    pushes: tokio::sync::mpsc::Sender<OutboxPush>,
+    subscribed: Arc<AtomicBool>,
+    shutdown: tokio_util::sync::CancellationToken,
+) {
+    let target = format!("%{} on ~{}", config.agent, config.ship);
+    let mut client = super::AirlockClient::new(config);
+    loop {
+        let result = watch_outbox(&mut client, &path, index, &pushes, &subscribed, &shutdown).await;
+        let was_subscribed = subscribed.swap(false, Ordering::Relaxed);
+        if shutdown.is_cancelled() || pushes.is_closed() {
+            break;
+        }
+        if let Err(e) = result {
+            tracing::warn!("Outbox subscription to {} dropped: {}; polling instead", target, e);
+        }
+        if was_subscribed && pushes.send(OutboxPush::Lost(index)).await.is_err() {
+            break;
+        }
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = tokio::time::sleep(RESUBSCRIBE_DELAY) => {}
+        }
+    }
+    client.disconnect().await;
+}
+
+/// One subscription: log in, watch `path` and read the event stream until
+/// it ends (Err) or `shutdown` (Ok)
+#[cfg(feature = "phase2")]
+async fn watch_outbox(
+    client: &mut super::AirlockClient,
+    path: &str,
+    index: usize,
+    pushes: &tokio::sync::mpsc::Sender<OutboxPush>,
+    subscribed: &AtomicBool,
+    shutdown: &tokio_util::sync::CancellationToken,
+) -> Result<()> {
+    client.connect().await?;
+    let agent = client.config().agent.clone();
+    let sub_id = client.subscribe(&agent, path).await?;
+    let mut stream = client.event_stream().await?;
+    subscribed.store(true, Ordering::Relaxed);
+
+    let mut parser = super::sse::SseParser::new();
+    loop {
+        let chunk = tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            chunk = stream.chunk() => chunk?.ok_or_else(|| anyhow!("event stream ended"))?,
+        };
+        for event in parser.push(&chunk) {
+            if let Some(id) = event.id {
+                if let Err(e) = client.ack_event(id).await {
+                    tracing::debug!("Event ack failed (non-critical): {}", e);
+                }
+            }
+            match parse_outbox_event(&event.data, sub_id) {
+                Ok(OutboxEvent::Queued(msg)) => {
+                    tracing::debug!("Outbox message #{} pushed by {}", msg.id, agent);
+                    if pushes.send(OutboxPush::Queued(index, msg)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Ok(OutboxEvent::Closed) => return Err(anyhow!("agent closed the subscription")),
+                Ok(OutboxEvent::Other) => {}
+                Err(e) => tracing::warn!("Ignoring outbox event from {}: {}", agent, e),
+            }
+        }
+    }
+}
+
+/// When the next source is due to be polled (None without sources)
+#[cfg(feature = "phase2")]
+pub fn next_poll(sources: &[OutboxSource]) -> Option<Instant> {
+    sources.iter().map(|source| source.next_poll).min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(id: u64, confirmed: bool) -> OutboundMessage {
+        OutboundMessage {
+            id,
+            dest_ship: "~bus".to_string(),
+            dest_addr: "01AB5678".to_string(),
+            src_addr: String::new(),
+            payload: "48656c6c6f".to_string(),
+            queued_at: serde_json::Value::Null,
+            confirmed,
+            join_accept: None,
+        }
+    }
+
+    fn ids(msgs: &[OutboundMessage]) -> Vec<u64> {
+        msgs.iter().map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn test_dedup_by_id() {
+        let mut queue = DownlinkQueue::new(0, false);
+        let outbox = vec![msg(1, false), msg(2, false)];
+        assert_eq!(ids(&queue.take_ready(&outbox)), vec![1, 2]);
+
+        // Same messages reappear before the tx-ack propagates
+        let outbox = vec![msg(1, false), msg(2, false), msg(3, false)];
+        assert_eq!(ids(&queue.take_ready(&outbox)), vec![3]);
+        assert_eq!(queue.in_flight(), 3);
+
+        // 1 and 2 left the outbox; only 3 is still in flight
+        assert!(queue.take_ready(&[msg(3, false)]).is_empty());
+        assert_eq!(queue.in_flight(), 1);
+    }
+
+    #[test]
+    fn test_priority_and_limit() {
+        let outbox = vec![msg(1, false), msg(2, true), msg(3, false), msg(4, true)];
+
+        let mut queue = DownlinkQueue::new(0, true);
+        assert_eq!(ids(&queue.take_ready(&outbox)), vec![2, 4, 1, 3]);
+
+        let mut queue = DownlinkQueue::new(0, false);
+        assert_eq!(ids(&queue.take_ready(&outbox)), vec![1, 2, 3, 4]);
+
+        // At most 3 in flight: the 4th waits until something clears
+        let mut queue = DownlinkQueue::new(3, true);
+        assert_eq!(ids(&queue.take_ready(&outbox)), vec![2, 4, 1]);
+        assert!(queue.take_ready(&outbox).is_empty());
+        assert_eq!(ids(&queue.take_ready(&outbox[2..])), vec![3]);
+    }
+
+    #[test]
+    fn test_release_for_retry() {
+        let mut queue = DownlinkQueue::new(0, false);
+        let outbox = vec![msg(1, false)];
+        assert_eq!(ids(&queue.take_ready(&outbox)), vec![1]);
+        assert_eq!(queue.attempts(1), 1);
+
+        // Released after a transient TX error: the next scry retries it
+        queue.release(1);
+        assert_eq!(ids(&queue.take_ready(&outbox)), vec![1]);
+        assert_eq!(queue.attempts(1), 2);
+
+        // Attempts are forgotten once the message leaves the outbox
+        queue.take_ready(&[]);
+        assert_eq!(queue.attempts(1), 0);
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let mut queue = DownlinkQueue::new(0, false);
+        let outbox = vec![msg(1, false), msg(2, false)];
+        let t0 = Instant::now();
+        assert_eq!(ids(&queue.take_ready_at(&outbox, t0)), vec![1, 2]);
+
+        // 1 failed once: held back for 2 s while 2 stays in flight
+        assert_eq!(queue.retry_later(1, t0), Duration::from_secs(2));
+        assert!(queue.take_ready_at(&outbox, t0 + Duration::from_secs(1)).is_empty());
+        let t1 = t0 + Duration::from_secs(2);
+        assert_eq!(ids(&queue.take_ready_at(&outbox, t1)), vec![1]);
+
+        // Each further failure doubles the wait
+        assert_eq!(queue.retry_later(1, t1), Duration::from_secs(4));
+        assert!(queue.take_ready_at(&outbox, t1 + Duration::from_secs(3)).is_empty());
+        assert_eq!(ids(&queue.take_ready_at(&outbox, t1 + Duration::from_secs(4))), vec![1]);
+        assert_eq!(queue.attempts(1), 3);
+
+        assert_eq!(retry_backoff(5), Duration::from_secs(32));
+        assert_eq!(retry_backoff(6), RETRY_MAX);
+        assert_eq!(retry_backoff(100), RETRY_MAX);
+    }
+
+    #[test]
+    fn test_parse_outbox() {
+        let outbox = serde_json::json!([{
+            "id": 7,
+            "dest-ship": "~bus",
+            "dest-addr": "01AB5678",
+            "src-addr": "",
+            "payload": "48656c6c6f",
+            "queued-at": null
+        }]);
+        assert_eq!(ids(&parse_outbox(&outbox).unwrap()), vec![7]);
+        assert!(parse_outbox(&serde_json::json!([])).unwrap().is_empty());
+        assert!(parse_outbox(&serde_json::json!({"id": 7})).is_err());
+    }
+
+    /// A ship that accepts the login, then answers one scry with `outbox`
+    #[cfg(feature = "phase2")]
+    async fn mock_agent(outbox: serde_json::Value) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let responses = [("204 No Content", String::new()), ("200 OK", outbox.to_string())];
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let reply = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+        url
+    }
+
+    #[cfg(feature = "phase2")]
+    #[test]
+    fn test_poll_all_agents() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut sources = Vec::new();
+            for (ship, agent) in [("zod", "lora-agent"), ("nec", "lora-peer")] {
+                let outbox = serde_json::json!([{
+                    "id": 1,
+                    "dest-ship": format!("~{}", ship),
+                    "dest-addr": "01AB5678",
+                    "src-addr": "",
+                    "payload": "48656c6c6f",
+                    "queued-at": null
+                }]);
+                let config = crate::config::UrbitConfig {
+                    url: mock_agent(outbox).await,
+                    ship: ship.to_string(),
+                    code: "test-code".to_string(),
+                    code_file: None,
+                    code_env: None,
+                    agent: agent.to_string(),
+                    max_in_flight: 8,
+                    max_tx_attempts: 3,
+                    prioritize_confirmed: true,
+                    dev_addr_prefixes: Vec::new(),
+                    gateway_euis: Vec::new(),
+                    mark_routes: Vec::new(),
+                    heartbeat_secs: 0,
+                    typed_marks: false,
+                    encoding: crate::config::PokeEncoding::Json,
+                    authorize_joins: false,
+                    poke_batch_max: 1,
+                    poke_batch_ms: 20,
+                    outbox_path: "/outbox".to_string(),
+                    outbox_poll_ms: 2000,
+                    outbox_poll_jitter_ms: 500,
+                    outbox_subscribe_path: None,
+                    config_path: None,
+                    config_poll_secs: 300,
+                    connect_timeout_ms: 1000,
+                    request_timeout_ms: 1000,
+                };
+                sources.push(OutboxSource::new(config, Duration::from_secs(60)));
+            }
+
+            // Same id in both outboxes: each agent's queue tracks its own
+            let t0 = Instant::now();
+            let ready = poll_all(&mut sources, t0).await;
+            let got: Vec<(usize, u64, &str)> = ready
+                .iter()
+                .map(|(index, msg)| (*index, msg.id, msg.dest_ship.as_str()))
+                .collect();
+            assert_eq!(got, vec![(0, 1, "~zod"), (1, 1, "~nec")]);
+            assert_eq!(sources[1].target(), "%lora-peer on ~nec");
+            assert_eq!(sources[0].queue.in_flight(), 1);
+            assert_eq!(sources[1].queue.in_flight(), 1);
+
+            // Next polls are 2-2.5 s out
+            let next = next_poll(&sources).unwrap();
+            assert!(next >= t0 + Duration::from_secs(2));
+            assert!(next <= t0 + Duration::from_millis(2500));
+
+            // Both mocks have hung up: a failed poll is skipped, not fatal
+            assert!(poll_all(&mut sources, t0 + Duration::from_secs(3)).await.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_pushed_fact() {
+        use crate::urbit::sse::SseParser;
+
+        // A fact on the outbox subscription (id 2), as Eyre streams it
+        let stream = concat!(
+            "id: 7\n",
+            r#"data: {"id":2,"response":"diff","json":{"id":5,"dest-ship":"~bus","#,
+            r#""dest-addr":"01AB5678","src-addr":"","payload":"48656c6c6f","queued-at":null}}"#,
+            "\n\n",
+        );
+        let events = SseParser::new().push(stream.as_bytes());
+        assert_eq!(events[0].id, Some(7));
+        let OutboxEvent::Queued(pushed) = parse_outbox_event(&events[0].data, 2).unwrap() else {
+            panic!("not a queued message");
+        };
+        assert_eq!(*pushed, msg(5, false));
+
+        // Sent once, whether it comes in pushed again or in the next scry
+        let mut queue = DownlinkQueue::new(0, false);
+        assert!(queue.take_pushed(&pushed));
+        assert!(!queue.take_pushed(&pushed));
+        assert!(queue.take_ready(&[msg(5, false)]).is_empty());
+        assert!(!queue.is_idle());
+        assert!(queue.take_ready(&[]).is_empty());
+        assert!(queue.is_idle());
+
+        // Kicks end the subscription; other requests' events are ignored
+        let quit = r#"{"id":2,"response":"quit"}"#;
+        assert_eq!(parse_outbox_event(quit, 2).unwrap(), OutboxEvent::Closed);
+        let poke_ack = r#"{"id":3,"response":"poke","ok":"ok"}"#;
+        assert_eq!(parse_outbox_event(poke_ack, 2).unwrap(), OutboxEvent::Other);
+    }
+
+    #[test]
+    fn test_poll_delay() {
+        let interval = Duration::from_millis(2000);
+        assert_eq!(poll_delay(interval, Duration::ZERO, 12345), interval);
+
+        let jitter = Duration::from_millis(500);
+        assert_eq!(poll_delay(interval, jitter, 0), interval);
+        assert_eq!(poll_delay(interval, jitter, 500), Duration::from_millis(2500));
+        for seed in [1, 499, 501, 999_999_999, u32::MAX] {
+            let delay = poll_delay(interval, jitter, seed);
+            assert!(delay >= interval && delay <= interval + jitter, "{:?}", delay);
+        }
+    }
+}
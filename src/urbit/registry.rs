@@ -0,0 +1,102 @@
+//! DevAddr lookup for outbox messages addressed only by ship
+//!
+//! The agent may queue a peer message with an empty `dest-addr`. The
+//! outbound task then scries `/registry/~ship` for the peer's DevAddr; the
+//! agent answers `{"ship": "~bus", "dev-addr": "01AB5678"}`, or `null` for
+//! a ship it doesn't know. Answers are cached for a short TTL so a burst of
+//! messages to one ship costs a single scry. Misses are not cached: the peer
+//! may be registered a moment later.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Recently resolved ship → DevAddr mappings
+#[derive(Debug)]
+pub struct AddrCache {
+    ttl: Duration,
+    /// Keyed by ship name without the `~`
+    entries: HashMap<String, (String, Instant)>,
+}
+
+impl AddrCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// The cached DevAddr of `ship`, unless it is older than the TTL
+    pub fn get(&self, ship: &str, now: Instant) -> Option<&str> {
+        let (dev_addr, resolved_at) = self.entries.get(ship.trim_start_matches('~'))?;
+        (now.saturating_duration_since(*resolved_at) < self.ttl).then_some(dev_addr.as_str())
+    }
+
+    pub fn insert(&mut self, ship: &str, dev_addr: String, now: Instant) {
+        self.entries
+            .insert(ship.trim_start_matches('~').to_string(), (dev_addr, now));
+    }
+}
+
+/// DevAddr from a `/registry/~ship` scry result (None if the ship is unknown)
+pub fn parse_registry_entry(value: &serde_json::Value) -> Option<String> {
+    value
+        .get("dev-addr")
+        .and_then(|addr| addr.as_str())
+        .filter(|addr| !addr.is_empty())
+        .map(str::to_string)
+}
+
+/// Resolve `dest_ship` to a DevAddr via the cache or the agent's registry
+///
+/// Ok(None) means the agent has no DevAddr registered for the ship.
+#[cfg(feature = "phase2")]
+pub async fn resolve_dest_addr(
+    client: &super::AirlockClient,
+    cache: &mut AddrCache,
+    dest_ship: &str,
+) -> anyhow::Result<Option<String>> {
+    let now = Instant::now();
+    if let Some(dev_addr) = cache.get(dest_ship, now) {
+        return Ok(Some(dev_addr.to_string()));
+    }
+
+    let path = format!("/registry/~{}", dest_ship.trim_start_matches('~'));
+    let entry = client.scry(&client.config().agent, &path).await?;
+    let dev_addr = parse_registry_entry(&entry);
+    if let Some(dev_addr) = &dev_addr {
+        cache.insert(dest_ship, dev_addr.clone(), now);
+    }
+    Ok(dev_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_hit_and_miss() {
+        let mut cache = AddrCache::new(Duration::from_secs(60));
+        let t0 = Instant::now();
+        assert_eq!(cache.get("~bus", t0), None);
+
+        cache.insert("~bus", "01AB5678".to_string(), t0);
+        // With or without the sig
+        assert_eq!(cache.get("~bus", t0), Some("01AB5678"));
+        assert_eq!(cache.get("bus", t0 + Duration::from_secs(59)), Some("01AB5678"));
+        assert_eq!(cache.get("~nec", t0), None);
+
+        // Expired entries must be scried again
+        assert_eq!(cache.get("~bus", t0 + Duration::from_secs(60)), None);
+        cache.insert("~bus", "01AB9999".to_string(), t0 + Duration::from_secs(60));
+        assert_eq!(cache.get("~bus", t0 + Duration::from_secs(61)), Some("01AB9999"));
+    }
+
+    #[test]
+    fn test_parse_registry_entry() {
+        let entry = serde_json::json!({"ship": "~bus", "dev-addr": "01AB5678"});
+        assert_eq!(parse_registry_entry(&entry), Some("01AB5678".to_string()));
+        assert_eq!(parse_registry_entry(&serde_json::Value::Null), None);
+        assert_eq!(parse_registry_entry(&serde_json::json!({"dev-addr": ""})), None);
+    }
+}
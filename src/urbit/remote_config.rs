@@ -0,0 +1,52 @@
+//! Settings served by the agent (`urbit.config_path`)
+//!
+//! The first Urbit target with a `config_path` is scried for it at startup
+//! and every `config_poll_secs` after. The result (`RemoteConfig`) replaces
+//! the file's ABP devices, channel plan and downlink settings; the devices
+//! apply in place like a SIGHUP reload, the rest on the next restart. A
+//! scry that fails or returns something invalid is logged and the settings
+//! in force are kept.
+
+use std::time::Duration;
+
+use super::AirlockClient;
+use crate::config::{Config, RemoteConfig};
+
+/// Where to scry the agent's settings, and how often
+pub struct RemoteConfigSource {
+    #[cfg_attr(not(feature = "phase2"), allow(dead_code))]
+    client: AirlockClient,
+    #[cfg_attr(not(feature = "phase2"), allow(dead_code))]
+    path: String,
+    /// None: fetch at startup only
+    pub poll: Option<Duration>,
+}
+
+impl RemoteConfigSource {
+    /// The first `[[urbit]]` entry with a `config_path`, if any
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let urbit = config.urbit.iter().find(|urbit| urbit.config_path.is_some())?;
+        Some(Self {
+            client: AirlockClient::new(urbit.clone()),
+            path: urbit.config_path.clone()?,
+            poll: (urbit.config_poll_secs > 0)
+                .then(|| Duration::from_secs(urbit.config_poll_secs)),
+        })
+    }
+
+    /// Scry the agent's current settings
+    ///
+    /// Logs in each time, so an expired session doesn't outlive one poll.
+    #[cfg(feature = "phase2")]
+    pub async fn fetch(&mut self) -> anyhow::Result<RemoteConfig> {
+        self.client.connect().await?;
+        let agent = self.client.config().agent.clone();
+        let value = self.client.scry(&agent, &self.path).await?;
+        RemoteConfig::parse(&value)
+    }
+
+    #[cfg(not(feature = "phase2"))]
+    pub async fn fetch(&mut self) -> anyhow::Result<RemoteConfig> {
+        anyhow::bail!("urbit.config_path needs the phase2 feature")
+    }
+}
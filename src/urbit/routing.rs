@@ -0,0 +1,258 @@
+//! Uplink routing to multiple Urbit targets
+//!
+//! Each `[[urbit]]` entry may restrict which uplinks it receives by DevAddr
+//! prefix and/or receiving gateway EUI. An entry matches a packet if any of
+//! its rules match; an entry with no rules receives everything. A packet
+//! goes to every matching entry, so overlapping rules deliver it to each.
+//!
+//! ```toml
+//! [[urbit]]
+//! ship = "zod"                        # url, code, agent as for [urbit]
+//! dev_addr_prefixes = ["260B"]
+//!
+//! [[urbit]]
+//! ship = "nec"
+//! gateway_euis = ["aabbccddeeff0011"]
+//! ```
+//!
+//! JoinRequests carry no DevAddr, so they only match gateway rules (and
+//! entries without rules).
+//!
+//! Within one target, `mark_routes` pokes device groups with their own
+//! marks (`MarkTable`); the longest matching DevAddr prefix wins:
+//!
+//! ```toml
+//! mark_routes = [
+//!     { dev_addr_prefix = "26", mark = "sensor-uplink" },
+//!     { dev_addr_prefix = "260B", mark = "actuator-uplink" },
+//! ]
+//! ```
+
+use crate::config::{MarkRoute, UrbitConfig};
+
+use super::types::LoRaAction;
+
+/// Which uplinks an Urbit target receives
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RouteRule {
+    /// Uppercase hex DevAddr prefixes
+    dev_addr_prefixes: Vec<String>,
+    /// Lowercase hex gateway EUIs
+    gateway_euis: Vec<String>,
+}
+
+impl RouteRule {
+    pub fn from_config(config: &UrbitConfig) -> Self {
+        Self {
+            dev_addr_prefixes: config
+                .dev_addr_prefixes
+                .iter()
+                .map(|p| p.to_ascii_uppercase())
+                .collect(),
+            gateway_euis: config
+                .gateway_euis
+                .iter()
+                .map(|e| e.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Whether an action received by `gateway_eui` goes to this target
+    pub fn matches(&self, action: &LoRaAction, gateway_eui: &str) -> bool {
+        if self.dev_addr_prefixes.is_empty() && self.gateway_euis.is_empty() {
+            return true;
+        }
+        let dev_addr = match action {
+            LoRaAction::Uplink(packet) => Some(packet.dev_addr.to_ascii_uppercase()),
+            _ => None,
+        };
+        let dev_addr_match = dev_addr.is_some_and(|addr| {
+            self.dev_addr_prefixes
+                .iter()
+                .any(|prefix| addr.starts_with(prefix.as_str()))
+        });
+        dev_addr_match
+            || self
+                .gateway_euis
+                .iter()
+                .any(|eui| eui.eq_ignore_ascii_case(gateway_eui))
+    }
+}
+
+/// Targets (e.g. poke channels, one per configured ship) and their rules
+#[derive(Debug, Clone)]
+pub struct Router<T> {
+    routes: Vec<(RouteRule, T)>,
+}
+
+impl<T> Default for Router<T> {
+    fn default() -> Self {
+        Self { routes: Vec::new() }
+    }
+}
+
+impl<T> Router<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, rule: RouteRule, target: T) {
+        self.routes.push((rule, target));
+    }
+
+    /// No targets configured (Phase 1 mode)
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Every target whose rule matches an action received by `gateway_eui`
+    pub fn route_for<'a>(
+        &'a self,
+        action: &'a LoRaAction,
+        gateway_eui: &'a str,
+    ) -> impl Iterator<Item = &'a T> + 'a {
+        self.routes
+            .iter()
+            .filter(move |(rule, _)| rule.matches(action, gateway_eui))
+            .map(|(_, target)| target)
+    }
+}
+
+/// Marks for uplinks by DevAddr prefix (`urbit.mark_routes`)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MarkTable {
+    /// Uppercase hex prefixes and their marks, longest prefix first
+    routes: Vec<(String, String)>,
+}
+
+impl MarkTable {
+    pub fn new(mark_routes: &[MarkRoute]) -> Self {
+        let mut routes: Vec<(String, String)> = mark_routes
+            .iter()
+            .map(|route| (route.dev_addr_prefix.to_ascii_uppercase(), route.mark.clone()))
+            .collect();
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Self { routes }
+    }
+
+    /// Mark for an uplink whose DevAddr falls under a route; None for other
+    /// actions and unrouted devices
+    pub fn mark_for(&self, action: &LoRaAction) -> Option<&str> {
+        let LoRaAction::Uplink(packet) = action else {
+            return None;
+        };
+        let dev_addr = packet.dev_addr.to_ascii_uppercase();
+        self.routes
+            .iter()
+            .find(|(prefix, _)| dev_addr.starts_with(prefix.as_str()))
+            .map(|(_, mark)| mark.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::urbit::types::{LoRaPacket, PacketSource};
+
+    fn rule(dev_addr_prefixes: &[&str], gateway_euis: &[&str]) -> RouteRule {
+        RouteRule {
+            dev_addr_prefixes: dev_addr_prefixes.iter().map(|p| p.to_string()).collect(),
+            gateway_euis: gateway_euis.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    fn uplink(dev_addr: &str, gateway_eui: &str) -> LoRaAction {
+        LoRaAction::Uplink(LoRaPacket {
+            dev_addr: dev_addr.to_string(),
+            fcnt: 1,
+            f_port: Some(1),
+            payload: String::new(),
+            rssi: -40.0,
+            snr: None,
+            freq: 904.5,
+            data_rate: "SF7BW125".parse().unwrap(),
+            gateway_eui: gateway_eui.to_string(),
+            received_at: chrono::Utc::now(),
+            mtype: "UnconfirmedDataUp".to_string(),
+            source: PacketSource::Local,
+            decoded: None,
+            alt_receptions: Vec::new(),
+            mac: None,
+            tmst: None,
+            tmms: None,
+            chan: None,
+            receptions: Vec::new(),
+            missed_frames: None,
+            class: Default::default(),
+        })
+    }
+
+    fn targets<'a>(router: &Router<&'a str>, action: &LoRaAction, eui: &str) -> Vec<&'a str> {
+        router.route_for(action, eui).copied().collect()
+    }
+
+    #[test]
+    fn test_route_for() {
+        let mut router = Router::new();
+        router.add(rule(&["260B"], &[]), "zod");
+        router.add(rule(&["26"], &["aabbccddeeff0011"]), "nec");
+        router.add(rule(&["48"], &[]), "bud");
+
+        let gw = "aabbccddeeff0011";
+        let other_gw = "0000000000000001";
+
+        // Overlapping DevAddr prefixes: both targets get it
+        let action = uplink("260B1234", other_gw);
+        assert_eq!(targets(&router, &action, other_gw), vec!["zod", "nec"]);
+        // Gateway rule alone is enough
+        let action = uplink("01AB5678", gw);
+        assert_eq!(targets(&router, &action, gw), vec!["nec"]);
+        // Lowercase DevAddr hex still matches
+        let action = uplink("48abcdef", other_gw);
+        assert_eq!(targets(&router, &action, other_gw), vec!["bud"]);
+        // Nothing matches
+        let action = uplink("01AB5678", other_gw);
+        assert!(targets(&router, &action, other_gw).is_empty());
+
+        // JoinRequests only follow gateway rules
+        let join = LoRaAction::JoinRequest {
+            app_eui: "0000000000000001".to_string(),
+            dev_eui: "0000000000000002".to_string(),
+            dev_nonce: 1,
+        };
+        assert_eq!(targets(&router, &join, gw), vec!["nec"]);
+
+        // A target without rules takes everything
+        router.add(RouteRule::default(), "catch-all");
+        assert_eq!(targets(&router, &action, other_gw), vec!["catch-all"]);
+    }
+
+    #[test]
+    fn test_mark_table_longest_prefix() {
+        let routes: Vec<MarkRoute> = [("26", "sensor"), ("260b12", "valve"), ("260B", "actuator")]
+            .iter()
+            .map(|(prefix, mark)| MarkRoute {
+                dev_addr_prefix: prefix.to_string(),
+                mark: mark.to_string(),
+            })
+            .collect();
+        let table = MarkTable::new(&routes);
+
+        let gw = "aabbccddeeff0011";
+        let mark = |dev_addr: &str| table.mark_for(&uplink(dev_addr, gw));
+        // Overlapping prefixes: the longest match wins, whatever the order
+        assert_eq!(mark("260B1234"), Some("valve"));
+        assert_eq!(mark("260b5678"), Some("actuator"));
+        assert_eq!(mark("26001234"), Some("sensor"));
+        assert_eq!(mark("48001234"), None);
+
+        // Only uplinks carry a DevAddr
+        let join = LoRaAction::JoinRequest {
+            app_eui: "0000000000000001".to_string(),
+            dev_eui: "0000000000000002".to_string(),
+            dev_nonce: 1,
+        };
+        assert_eq!(table.mark_for(&join), None);
+        assert_eq!(MarkTable::default().mark_for(&uplink("260B1234", gw)), None);
+    }
+}
@@ -0,0 +1,92 @@
+//! Server-sent events from an Eyre channel
+//!
+//! A GET on `/~/channel/<uid>` with `Accept: text/event-stream` streams the
+//! channel's events: poke acks, watch acks, subscription facts (`diff`) and
+//! kicks (`quit`). Each event is an `id:` line and one or more `data:` lines
+//! holding JSON, ended by a blank line.
+
+/// One event from the stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct SseEvent {
+    /// Event id, to be acked so Eyre can drop the event
+    pub id: Option<u64>,
+    /// The `data:` lines, joined with newlines
+    pub data: String,
+}
+
+/// Incremental parser over the chunks of an event stream
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buf: Vec<u8>,
+}
+
+impl SseParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed a chunk, returning the events it completed
+    ///
+    /// A chunk may end mid-event (or mid-character); the rest is kept for
+    /// the next one.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buf.extend(chunk.iter().filter(|&&b| b != b'\r'));
+        let mut events = Vec::new();
+        while let Some(end) = self.buf.windows(2).position(|w| w == b"\n\n") {
+            let block: Vec<u8> = self.buf.drain(..end + 2).collect();
+            if let Some(event) = parse_event(&String::from_utf8_lossy(&block)) {
+                events.push(event);
+            }
+        }
+        events
+    }
+}
+
+/// One blank-line-terminated block; None for comments and keep-alives
+fn parse_event(block: &str) -> Option<SseEvent> {
+    let mut id = None;
+    let mut data: Option<String> = None;
+    for line in block.lines() {
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "id" => id = value.parse().ok(),
+            "data" => match &mut data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => data = Some(value.to_string()),
+            },
+            _ => {}
+        }
+    }
+    data.map(|data| SseEvent { id, data })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sse_parser() {
+        let mut parser = SseParser::new();
+
+        // A keep-alive comment, then an event split across chunks
+        assert!(parser.push(b":\n\nid: 4\r\ndata: {\"response\":").is_empty());
+        let events = parser.push(b"\"diff\"}\n\nid: 5\ndata: a\ndata: b\n\n");
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    id: Some(4),
+                    data: r#"{"response":"diff"}"#.to_string(),
+                },
+                SseEvent {
+                    id: Some(5),
+                    data: "a\nb".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -1,7 +1,16 @@
 //! Types for Urbit %lora-agent pokes and subscriptions
 
 use chrono::{DateTime, Utc};
+use base64::prelude::{Engine, BASE64_STANDARD};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::msgpack::MSGPACK_MARK;
+use crate::config::PokeEncoding;
+
+use crate::lorawan::datarate::DataRate;
+use crate::lorawan::keys::DeviceClass;
+use crate::lorawan::mac::{DevStatus, LinkAdrStatus, UplinkMacCommand};
 
 /// A decoded LoRa packet ready to be poked into %lora-agent
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,8 +30,9 @@ pub struct LoRaPacket {
     pub snr: Option<f64>,
     /// Frequency in MHz
     pub freq: f64,
-    /// Data rate (e.g., "SF7BW125")
-    pub data_rate: String,
+    /// Data rate (e.g., "SF7BW125", or "FSK50000")
+    #[serde(with = "crate::lorawan::datarate::as_string")]
+    pub data_rate: DataRate,
     /// Gateway EUI that received the packet
     pub gateway_eui: String,
     /// Timestamp of reception
@@ -31,10 +41,91 @@ pub struct LoRaPacket {
     pub mtype: String,
     /// Source: "local" (direct gateway) or "helium" (via OUI)
     pub source: PacketSource,
+    /// Payload decoded by the device's codec (`lorawan.codec`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decoded: Option<serde_json::Value>,
+    /// Weaker copies of this frame in the same PUSH_DATA (other antennas/channels)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alt_receptions: Vec<AltReception>,
+    /// Device status answers carried in FOpts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<MacStatus>,
+    /// Concentrator timestamp of reception (µs, wraps at 2^32)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tmst: Option<u64>,
+    /// GPS time of reception (ms since the GPS epoch), if the gateway is GPS-synced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tmms: Option<u64>,
+    /// Concentrator IF channel the frame arrived on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chan: Option<u8>,
+    /// Every gateway's reception of the frame, first one first
+    /// (`udp.dedup_window_ms`; empty when not grouping)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub receptions: Vec<Reception>,
+    /// Frames lost just before this one, from a gap in the device's FCnt
+    /// (only counted when the MIC verified)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub missed_frames: Option<u32>,
+    /// Device class as provisioned in the bridge ("A" when unknown)
+    #[serde(default)]
+    pub class: DeviceClass,
+}
+
+/// Device-health MAC command answers in an uplink
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MacStatus {
+    /// DevStatusAns: battery level and demodulation margin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dev_status: Option<DevStatus>,
+    /// LinkADRAns: which parts of the last ADR request were applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_adr_ans: Option<LinkAdrStatus>,
+}
+
+impl MacStatus {
+    /// The answers among `commands`, or None if there are none
+    pub fn from_commands(commands: &[UplinkMacCommand]) -> Option<Self> {
+        let mut status = Self::default();
+        for command in commands {
+            match command {
+                UplinkMacCommand::DevStatusAns(dev_status) => status.dev_status = Some(*dev_status),
+                UplinkMacCommand::LinkAdrAns(ans) => status.link_adr_ans = Some(*ans),
+                UplinkMacCommand::Other { .. } => {}
+            }
+        }
+        (status != Self::default()).then_some(status)
+    }
+}
+
+/// Signal metadata of a duplicate reception merged into a `LoRaPacket`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AltReception {
+    pub rssi: f64,
+    pub snr: Option<f64>,
+    pub freq: f64,
+}
+
+/// One gateway's reception of an uplink, for geolocation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Reception {
+    pub gateway_eui: String,
+    pub rssi: f64,
+    pub snr: Option<f64>,
+    pub freq: f64,
+    /// Concentrator timestamp (µs)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tmst: Option<u64>,
+    /// GPS time of reception (ms), if the gateway is GPS-synced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tmms: Option<u64>,
 }
 
 /// Where the packet originated
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum PacketSource {
     /// Direct from a local LoRa gateway via Semtech UDP
@@ -43,7 +134,14 @@ pub enum PacketSource {
     Helium,
 }
 
+/// Mark of every poke unless `urbit.typed_marks` is set
+pub const JSON_MARK: &str = "json";
+
 /// Actions that can be poked into %lora-agent
+///
+/// Nearly every action is an uplink, so `LoRaPacket` is kept inline rather
+/// than boxed.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "action")]
 pub enum LoRaAction {
@@ -59,6 +157,24 @@ pub enum LoRaAction {
         description: Option<String>,
     },
 
+    /// A device is attempting an OTAA join
+    #[serde(rename = "join-request", rename_all = "kebab-case")]
+    JoinRequest {
+        app_eui: String, // 16 hex digits
+        dev_eui: String, // 16 hex digits
+        dev_nonce: u16,
+    },
+
+    /// A LoRaWAN 1.1 device asking to rejoin (NetID for types 0/2, JoinEUI for type 1)
+    #[serde(rename = "rejoin-request", rename_all = "kebab-case")]
+    RejoinRequest {
+        rejoin_type: u8,
+        net_id: Option<String>,   // 6 hex digits
+        join_eui: Option<String>, // 16 hex digits
+        dev_eui: String,          // 16 hex digits
+        rj_count: u16,
+    },
+
     /// Request a downlink to a device
     #[serde(rename = "downlink")]
     Downlink {
@@ -67,6 +183,62 @@ pub enum LoRaAction {
         payload: String, // hex encoded
         confirmed: bool,
     },
+
+    /// The bridge is connected (sent every `urbit.heartbeat_secs`)
+    #[serde(rename = "heartbeat", rename_all = "kebab-case")]
+    Heartbeat {
+        bridge_version: String,
+        uptime_secs: u64,
+        /// Distinct gateways heard from since the bridge started
+        gateways_seen: usize,
+    },
+
+    /// The bridge is shutting down
+    #[serde(rename = "disconnecting", rename_all = "kebab-case")]
+    Disconnecting { uptime_secs: u64 },
+
+    /// A gateway went silent for `udp.gateway_timeout_secs`, or was heard again
+    #[serde(rename = "gateway-status", rename_all = "kebab-case")]
+    GatewayStatus {
+        gateway_eui: String,
+        up: bool,
+        /// How long the gateway had been silent
+        silent_secs: u64,
+    },
+}
+
+impl LoRaAction {
+    /// The action's own mark, used with `urbit.typed_marks`
+    ///
+    /// The poke still carries the action's JSON; the mark lets the agent's
+    /// `++on-poke` dispatch without reading the `action` field.
+    pub fn mark(&self) -> &'static str {
+        match self {
+            LoRaAction::Uplink(_) => "lora-uplink",
+            LoRaAction::RegisterDevice { .. } => "lora-device",
+            LoRaAction::JoinRequest { .. } | LoRaAction::RejoinRequest { .. } => "lora-join",
+            LoRaAction::Downlink { .. } => "lora-downlink",
+            LoRaAction::Heartbeat { .. }
+            | LoRaAction::Disconnecting { .. }
+            | LoRaAction::GatewayStatus { .. } => "lora-bridge",
+        }
+    }
+
+    /// Mark and body of the poke carrying this action
+    ///
+    /// With `PokeEncoding::Msgpack` the body is a base64 string of the
+    /// action's MessagePack under `%lora-msgpack`, whatever `typed_marks` says.
+    pub fn poke(&self, typed_marks: bool, encoding: PokeEncoding) -> (&'static str, Value) {
+        let json = serde_json::to_value(self).expect("failed to serialize LoRaAction");
+        match encoding {
+            PokeEncoding::Json if typed_marks => (self.mark(), json),
+            PokeEncoding::Json => (JSON_MARK, json),
+            PokeEncoding::Msgpack => {
+                let bytes = super::msgpack::encode(&json);
+                (MSGPACK_MARK, Value::String(BASE64_STANDARD.encode(bytes)))
+            }
+        }
+    }
 }
 
 /// Subscription update from %lora-agent
@@ -82,7 +254,7 @@ pub struct LoRaUpdate {
 ///
 /// Returned by scrying `/outbox` on %lora-agent.
 /// Each message has an ID, destination, payload, and timestamp.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct OutboundMessage {
     /// Unique message ID (assigned by the agent)
@@ -98,6 +270,40 @@ pub struct OutboundMessage {
     pub payload: String,
     /// When the message was queued (Urbit time as unix seconds)
     pub queued_at: serde_json::Value,
+    /// Whether the device must acknowledge the downlink
+    #[serde(default)]
+    pub confirmed: bool,
+    /// Answer an OTAA join instead of sending `payload` as a data frame
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub join_accept: Option<JoinAcceptRequest>,
+}
+
+/// Parameters for a JoinAccept requested by the agent
+///
+/// Sent in reply to a `join-request` poke; the bridge encrypts the accept
+/// with `app_key` and derives the session keys for `dev_addr`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct JoinAcceptRequest {
+    /// AppKey (32 hex digits)
+    pub app_key: String,
+    /// Server nonce (24 bits)
+    pub app_nonce: u32,
+    /// Network identifier (24 bits)
+    pub net_id: u32,
+    /// DevAddr assigned to the device (hex)
+    pub dev_addr: String,
+    /// DevNonce from the JoinRequest being answered
+    pub dev_nonce: u16,
+    /// RX1DRoffset / RX2 data rate
+    #[serde(default)]
+    pub dl_settings: u8,
+    /// RX1 delay in seconds (0 means 1)
+    #[serde(default)]
+    pub rx_delay: u8,
+    /// Optional CFList (32 hex digits)
+    #[serde(default)]
+    pub cf_list: Option<String>,
 }
 
 /// TX acknowledgment poke — tells the agent a message was sent
@@ -109,6 +315,11 @@ pub struct TxAck {
 }
 
 impl TxAck {
+    /// Mark of tx-ack and tx-fail pokes with `urbit.typed_marks`
+    pub fn mark() -> &'static str {
+        "lora-txack"
+    }
+
     pub fn success(msg_id: u64) -> serde_json::Value {
         serde_json::json!({
             "action": "tx-ack",
@@ -122,4 +333,260 @@ impl TxAck {
             "msg-id": msg_id,
         })
     }
+
+    /// `tx-fail` with why the downlink was not sent, for the agent's log
+    pub fn failure_with_reason(msg_id: u64, reason: &str) -> serde_json::Value {
+        serde_json::json!({
+            "action": "tx-fail",
+            "msg-id": msg_id,
+            "reason": reason,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_request_serialization() {
+        let action = LoRaAction::JoinRequest {
+            app_eui: "0807060504030201".to_string(),
+            dev_eui: "A8A7A6A5A4A3A2A1".to_string(),
+            dev_nonce: 66,
+        };
+        let json = serde_json::to_value(&action).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "action": "join-request",
+                "app-eui": "0807060504030201",
+                "dev-eui": "A8A7A6A5A4A3A2A1",
+                "dev-nonce": 66,
+            })
+        );
+
+        let parsed: LoRaAction = serde_json::from_value(json).unwrap();
+        assert!(matches!(parsed, LoRaAction::JoinRequest { dev_nonce: 66, .. }));
+    }
+
+    #[test]
+    fn test_rejoin_request_serialization() {
+        let action = LoRaAction::RejoinRequest {
+            rejoin_type: 0,
+            net_id: Some("00003C".to_string()),
+            join_eui: None,
+            dev_eui: "A8A7A6A5A4A3A2A1".to_string(),
+            rj_count: 7,
+        };
+        assert_eq!(
+            serde_json::to_value(&action).unwrap(),
+            serde_json::json!({
+                "action": "rejoin-request",
+                "rejoin-type": 0,
+                "net-id": "00003C",
+                "join-eui": null,
+                "dev-eui": "A8A7A6A5A4A3A2A1",
+                "rj-count": 7,
+            })
+        );
+    }
+
+    #[test]
+    fn test_uplink_mac_status_serialization() {
+        let commands = crate::lorawan::mac::parse_uplink_commands(&[0x06, 200, 0x3B]);
+        let packet = LoRaPacket {
+            dev_addr: "260B1234".to_string(),
+            fcnt: 1,
+            f_port: None,
+            payload: String::new(),
+            rssi: -80.0,
+            snr: Some(5.0),
+            freq: 902.3,
+            data_rate: DataRate::lora(7, 125),
+            gateway_eui: "aabbccddeeff0011".to_string(),
+            received_at: "2026-02-18T17:30:00Z".parse().unwrap(),
+            mtype: "UnconfirmedDataUp".to_string(),
+            source: PacketSource::Local,
+            decoded: None,
+            alt_receptions: Vec::new(),
+            mac: MacStatus::from_commands(&commands),
+            tmst: None,
+            tmms: None,
+            chan: None,
+            receptions: Vec::new(),
+            missed_frames: None,
+            class: Default::default(),
+        };
+        let json = serde_json::to_value(LoRaAction::Uplink(packet.clone())).unwrap();
+        assert_eq!(
+            json["mac"],
+            serde_json::json!({"dev-status": {"battery": 200, "margin": -5}})
+        );
+
+        // Absent without DevStatusAns/LinkADRAns
+        let plain = LoRaPacket {
+            mac: MacStatus::from_commands(&[]),
+            ..packet
+        };
+        assert!(serde_json::to_value(LoRaAction::Uplink(plain)).unwrap().get("mac").is_none());
+    }
+
+    #[test]
+    fn test_uplink_timing_serialization() {
+        let json = r#"{"dev-addr":"260B1234","fcnt":1,"f-port":1,"payload":"","rssi":-80.0,
+            "snr":5.0,"freq":902.3,"data-rate":"SF7BW125","gateway-eui":"aabbccddeeff0011",
+            "received-at":"2026-02-18T17:30:00Z","mtype":"UnconfirmedDataUp","source":"local"}"#;
+        let packet: LoRaPacket = serde_json::from_str(json).unwrap();
+        assert_eq!((packet.tmst, packet.tmms, packet.chan), (None, None, None));
+        let value = serde_json::to_value(LoRaAction::Uplink(packet.clone())).unwrap();
+        assert!(value.get("tmst").is_none() && value.get("chan").is_none());
+
+        let timed = LoRaPacket {
+            tmst: Some(3_512_348_611),
+            tmms: Some(1_455_000_000_123),
+            chan: Some(2),
+            ..packet
+        };
+        let value = serde_json::to_value(LoRaAction::Uplink(timed)).unwrap();
+        assert_eq!(value["tmst"], 3_512_348_611u64);
+        assert_eq!(value["tmms"], 1_455_000_000_123u64);
+        assert_eq!(value["chan"], 2);
+        let back: LoRaPacket = serde_json::from_value(value).unwrap();
+        assert_eq!(back.tmst, Some(3_512_348_611));
+    }
+
+    #[test]
+    fn test_uplink_class_serialization() {
+        // Pokes from bridges that predate `class` read as class A
+        let json = r#"{"dev-addr":"260B1234","fcnt":1,"f-port":1,"payload":"","rssi":-80.0,
+            "snr":5.0,"freq":902.3,"data-rate":"SF7BW125","gateway-eui":"aabbccddeeff0011",
+            "received-at":"2026-02-18T17:30:00Z","mtype":"UnconfirmedDataUp","source":"local"}"#;
+        let packet: LoRaPacket = serde_json::from_str(json).unwrap();
+        assert_eq!(packet.class, DeviceClass::A);
+        let value = serde_json::to_value(LoRaAction::Uplink(packet.clone())).unwrap();
+        assert_eq!(value["class"], "A");
+
+        let class_c = LoRaPacket {
+            class: DeviceClass::C,
+            ..packet
+        };
+        let value = serde_json::to_value(LoRaAction::Uplink(class_c)).unwrap();
+        assert_eq!(value["class"], "C");
+        let back: LoRaPacket = serde_json::from_value(value).unwrap();
+        assert_eq!(back.class, DeviceClass::C);
+    }
+
+    #[test]
+    fn test_heartbeat_serialization() {
+        let action = LoRaAction::Heartbeat {
+            bridge_version: "0.1.0".to_string(),
+            uptime_secs: 3600,
+            gateways_seen: 2,
+        };
+        assert_eq!(
+            serde_json::to_value(&action).unwrap(),
+            serde_json::json!({
+                "action": "heartbeat",
+                "bridge-version": "0.1.0",
+                "uptime-secs": 3600,
+                "gateways-seen": 2,
+            })
+        );
+
+        let action = LoRaAction::Disconnecting { uptime_secs: 3601 };
+        assert_eq!(
+            serde_json::to_value(&action).unwrap(),
+            serde_json::json!({"action": "disconnecting", "uptime-secs": 3601})
+        );
+
+        let action = LoRaAction::GatewayStatus {
+            gateway_eui: "aabbccddeeff0011".to_string(),
+            up: false,
+            silent_secs: 95,
+        };
+        assert_eq!(
+            serde_json::to_value(&action).unwrap(),
+            serde_json::json!({
+                "action": "gateway-status",
+                "gateway-eui": "aabbccddeeff0011",
+                "up": false,
+                "silent-secs": 95,
+            })
+        );
+    }
+
+    #[test]
+    fn test_marks() {
+        let heartbeat = LoRaAction::Heartbeat {
+            bridge_version: "0.1.0".to_string(),
+            uptime_secs: 0,
+            gateways_seen: 0,
+        };
+        let join = LoRaAction::JoinRequest {
+            app_eui: "0807060504030201".to_string(),
+            dev_eui: "A8A7A6A5A4A3A2A1".to_string(),
+            dev_nonce: 1,
+        };
+        let downlink = LoRaAction::Downlink {
+            dev_addr: "260B1234".to_string(),
+            f_port: 1,
+            payload: String::new(),
+            confirmed: false,
+        };
+        let register = LoRaAction::RegisterDevice {
+            dev_addr: "260B1234".to_string(),
+            name: None,
+            description: None,
+        };
+        let uplink: LoRaAction = serde_json::from_value(serde_json::json!({
+            "action": "uplink", "dev-addr": "260B1234", "fcnt": 1, "f-port": 1,
+            "payload": "", "rssi": -80.0, "snr": 5.0, "freq": 902.3,
+            "data-rate": "SF7BW125", "gateway-eui": "aabbccddeeff0011",
+            "received-at": "2026-02-18T17:30:00Z", "mtype": "UnconfirmedDataUp",
+            "source": "local",
+        }))
+        .unwrap();
+        assert_eq!(uplink.mark(), "lora-uplink");
+        assert_eq!(heartbeat.mark(), "lora-bridge");
+        assert_eq!(LoRaAction::Disconnecting { uptime_secs: 1 }.mark(), "lora-bridge");
+        assert_eq!(join.mark(), "lora-join");
+        assert_eq!(downlink.mark(), "lora-downlink");
+        assert_eq!(register.mark(), "lora-device");
+        assert_eq!(TxAck::mark(), "lora-txack");
+        assert_eq!(JSON_MARK, "json");
+    }
+
+    #[test]
+    fn test_msgpack_poke() {
+        let action = LoRaAction::Disconnecting { uptime_secs: 300 };
+        let (mark, body) = action.poke(true, PokeEncoding::Msgpack);
+        assert_eq!(mark, MSGPACK_MARK);
+        let bytes = BASE64_STANDARD.decode(body.as_str().unwrap()).unwrap();
+        let json = super::super::msgpack::decode(&bytes).unwrap();
+        assert_eq!(json, action.poke(false, PokeEncoding::Json).1);
+    }
+
+    #[test]
+    fn test_join_accept_outbox_message() {
+        let msg: OutboundMessage = serde_json::from_value(serde_json::json!({
+            "id": 7,
+            "dest-ship": "~zod",
+            "dest-addr": "260B1234",
+            "payload": "",
+            "queued-at": 0,
+            "join-accept": {
+                "app-key": "2b7e151628aed2a6abf7158809cf4f3c",
+                "app-nonce": 1193046,
+                "net-id": 19,
+                "dev-addr": "260B1234",
+                "dev-nonce": 66,
+            },
+        }))
+        .unwrap();
+        let accept = msg.join_accept.unwrap();
+        assert_eq!(accept.dev_nonce, 66);
+        assert_eq!(accept.rx_delay, 0);
+        assert_eq!(accept.cf_list, None);
+    }
 }
@@ -0,0 +1,51 @@
+//! `lora-urbit decode` against a known uplink
+
+use std::process::Command;
+
+/// Unconfirmed uplink, DevAddr 49BE7DF1, FCnt 2, FPort 1, payload "test"
+const FRAME_HEX: &str = "40F17DBE4900020001954378762B11FF0D";
+
+fn decode(args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_lora-urbit"))
+        .arg("decode")
+        .args(args)
+        .output()
+        .expect("failed to run lora-urbit");
+    (output.status.success(), String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[test]
+fn test_decode_hex_frame() {
+    let (ok, out) = decode(&["--hex", FRAME_HEX]);
+    assert!(ok);
+    assert!(out.contains("MType:     UnconfirmedDataUp"), "{}", out);
+    assert!(out.contains("DevAddr:   49BE7DF1"), "{}", out);
+    assert!(out.contains("FCnt:      2"), "{}", out);
+    assert!(out.contains("FPort:     1"), "{}", out);
+    assert!(out.contains("MIC:       0DFF112B (not checked)"), "{}", out);
+    assert!(out.contains("Payload:   95437876"), "{}", out);
+
+    // Same frame as base64
+    let (ok, b64_out) = decode(&["--base64", "QPF9vkkAAgABlUN4disR/w0="]);
+    assert!(ok);
+    assert_eq!(b64_out, out);
+
+    let (ok, _) = decode(&["--hex", "zz"]);
+    assert!(!ok);
+}
+
+#[cfg(feature = "phase4")]
+#[test]
+fn test_decode_with_keys() {
+    let (ok, out) = decode(&[
+        "--hex",
+        FRAME_HEX,
+        "--nwk-key",
+        "44024241ed4ce9a68c6a8bc055233fd3",
+        "--app-key",
+        "ec925802ae430ca77fd3dd73cb2cc588",
+    ]);
+    assert!(ok);
+    assert!(out.contains("(valid)"), "{}", out);
+    assert!(out.contains("Decrypted: 74657374 (\"test\")"), "{}", out);
+}
@@ -0,0 +1,67 @@
+//! End to end: PUSH_DATA over loopback UDP → decode → poke channel
+
+use std::time::Duration;
+
+use lora_urbit::config::Config;
+use lora_urbit::udp::protocol::GwmpPacket;
+use lora_urbit::udp::{start_server, PokeRouter};
+use lora_urbit::urbit::routing::RouteRule;
+use lora_urbit::urbit::types::LoRaAction;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+const GATEWAY_EUI: [u8; 8] = [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF, 0x00, 0x11];
+
+#[test]
+fn test_push_data_reaches_poke_channel() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut config = Config::default();
+        config.udp.bind = vec!["127.0.0.1:0".to_string()];
+
+        let (tx, mut rx) = mpsc::channel::<LoRaAction>(8);
+        let mut pokes = PokeRouter::new();
+        pokes.add(RouteRule::default(), tx);
+        let shutdown = CancellationToken::new();
+        let server = start_server(&config, pokes, shutdown.clone()).await.unwrap();
+        assert_ne!(server.local_addr.port(), 0);
+
+        // Unconfirmed uplink, DevAddr 49BE7DF1, FCnt 2, FPort 1
+        let json = r#"{"rxpk":[{"tmst":1000,"freq":902.3,"chan":0,"rfch":0,"stat":1,"modu":"LORA","datr":"SF7BW125","codr":"4/5","rssi":-42,"lsnr":9.5,"size":17,"data":"QPF9vkkAAgABlUN4disR/w0="}]}"#;
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        gateway
+            .send_to(&GwmpPacket::push_data(0x4242, &GATEWAY_EUI, json), server.local_addr)
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 64];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), gateway.recv_from(&mut buf))
+            .await
+            .expect("no PUSH_ACK")
+            .unwrap();
+        match GwmpPacket::parse(&buf[..len]).unwrap() {
+            GwmpPacket::PushAck { random_token } => assert_eq!(random_token, 0x4242),
+            other => panic!("expected PUSH_ACK, got {:?}", other),
+        }
+
+        let action = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("no uplink poked")
+            .unwrap();
+        let LoRaAction::Uplink(packet) = action else {
+            panic!("expected an uplink, got {:?}", action);
+        };
+        assert_eq!(packet.dev_addr, "49BE7DF1");
+        assert_eq!(packet.fcnt, 2);
+        assert_eq!(packet.f_port, Some(1));
+        assert_eq!(packet.payload, "95437876");
+        assert_eq!(packet.gateway_eui, "aabbccddeeff0011");
+        assert_eq!(packet.rssi, -42.0);
+        assert_eq!(server.gateways_seen.count(), 1);
+
+        shutdown.cancel();
+        server.task.await.unwrap();
+        assert!(rx.recv().await.is_none());
+    });
+}